@@ -0,0 +1,92 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rand::prelude::*;
+use silentserver::storage::{BlockData, BlockDataRef, TWEAK_SIZE};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A very busy block, where copying the tweaks out costs the most.
+const NUM_TWEAKS: usize = 50_000;
+
+/// Counts the bytes allocated, to show what each way of reading a record allocates.
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Bytes `f` allocates.
+fn allocated_by<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    black_box(f());
+    ALLOCATED.load(Ordering::Relaxed) - before
+}
+
+fn busy_block() -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(50);
+    let tweaks = (0..NUM_TWEAKS)
+        .map(|_| {
+            let mut tweak = [0u8; TWEAK_SIZE];
+            rng.fill(&mut tweak[..]);
+            tweak
+        })
+        .collect();
+    BlockData {
+        blockhash: rng.random(),
+        tweaks,
+        tweak_meta: None,
+    }
+    .serialize()
+}
+
+/// XORs the tweaks together, so every one of them is read.
+fn fold<'a>(tweaks: impl Iterator<Item = &'a [u8; TWEAK_SIZE]>) -> u8 {
+    tweaks.fold(0, |acc, tweak| acc ^ tweak[TWEAK_SIZE - 1])
+}
+
+fn bench_read_record(c: &mut Criterion) {
+    let serialized = busy_block();
+    println!(
+        "Reading a {} tweak record allocates {} bytes with deserialize, {} with BlockDataRef",
+        NUM_TWEAKS,
+        allocated_by(|| fold(BlockData::deserialize(&serialized).unwrap().tweaks.iter())),
+        allocated_by(|| fold(BlockDataRef::parse(&serialized).unwrap().tweaks())),
+    );
+
+    let mut group = c.benchmark_group("read_record");
+    group.throughput(Throughput::Bytes(serialized.len() as u64));
+    group.bench_function("deserialize", |b| {
+        b.iter(|| {
+            fold(
+                BlockData::deserialize(black_box(&serialized))
+                    .unwrap()
+                    .tweaks
+                    .iter(),
+            )
+        });
+    });
+    group.bench_function("block_data_ref", |b| {
+        b.iter(|| {
+            fold(
+                BlockDataRef::parse(black_box(&serialized))
+                    .unwrap()
+                    .tweaks(),
+            )
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_read_record);
+criterion_main!(benches);
@@ -0,0 +1,131 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::prelude::*;
+use silentserver::storage::{BlockData, Crc32, RecordChecksum, TWEAK_SIZE};
+
+// Tweak counts for a small, typical and busy post-taproot block.
+const TWEAK_COUNTS: [usize; 3] = [16, 800, 6000];
+// Number of records in the full-file verification scenario.
+const FILE_RECORDS: usize = 2_000;
+
+struct Xxh3(xxhash_rust::xxh3::Xxh3);
+
+impl RecordChecksum for Xxh3 {
+    const SIZE: usize = 8;
+
+    fn new() -> Self {
+        Xxh3(xxhash_rust::xxh3::Xxh3::new())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize_into(self, out: &mut [u8]) {
+        out.copy_from_slice(&self.0.digest().to_le_bytes());
+    }
+}
+
+/// blake3 truncated to 8 bytes.
+struct Blake3(blake3::Hasher);
+
+impl RecordChecksum for Blake3 {
+    const SIZE: usize = 8;
+
+    fn new() -> Self {
+        Blake3(blake3::Hasher::new())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize_into(self, out: &mut [u8]) {
+        out.copy_from_slice(&self.0.finalize().as_bytes()[..Self::SIZE]);
+    }
+}
+
+fn random_block(rng: &mut StdRng, num_tweaks: usize) -> BlockData {
+    let mut blockhash = [0u8; 32];
+    rng.fill(&mut blockhash);
+    let tweaks = (0..num_tweaks)
+        .map(|_| {
+            let mut tweak = [0u8; TWEAK_SIZE];
+            rng.fill(&mut tweak[..]);
+            tweak
+        })
+        .collect();
+    BlockData { blockhash, tweaks, tweak_meta: None }
+}
+
+/// Mostly modest blocks with the occasional busy one, like a stretch of mainnet.
+fn random_file(rng: &mut StdRng) -> Vec<BlockData> {
+    (0..FILE_RECORDS)
+        .map(|_| {
+            let num_tweaks = if rng.random_bool(0.1) {
+                rng.random_range(2_000..6_000)
+            } else {
+                rng.random_range(0..1_000)
+            };
+            random_block(rng, num_tweaks)
+        })
+        .collect()
+}
+
+fn bench_record<C: RecordChecksum>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group(format!("record_checksum/{}", name));
+    group.sample_size(20);
+    let mut rng = StdRng::seed_from_u64(42);
+
+    for num_tweaks in TWEAK_COUNTS {
+        let block = random_block(&mut rng, num_tweaks);
+        let serialized = block.serialize_with::<C>();
+        group.throughput(Throughput::Bytes(serialized.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("serialize", num_tweaks), &block, |b, block| {
+            b.iter(|| black_box(block.serialize_with::<C>()));
+        });
+        group.bench_with_input(BenchmarkId::new("verify", num_tweaks), &serialized, |b, data| {
+            b.iter(|| black_box(BlockData::deserialize_with::<C>(data).unwrap()));
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_file_verification<C: RecordChecksum>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group("file_verification");
+    group.sample_size(10);
+    let mut rng = StdRng::seed_from_u64(7);
+
+    let mut file = Vec::new();
+    for block in random_file(&mut rng) {
+        file.extend_from_slice(&block.serialize_with::<C>());
+    }
+    group.throughput(Throughput::Bytes(file.len() as u64));
+
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            let mut pos = 0;
+            while pos < file.len() {
+                let block = BlockData::deserialize_with::<C>(&file[pos..]).unwrap();
+                pos += 36 + C::SIZE + block.tweaks.len() * TWEAK_SIZE;
+            }
+            black_box(pos)
+        });
+    });
+
+    group.finish();
+}
+
+fn bench_checksums(c: &mut Criterion) {
+    bench_record::<Crc32>(c, "crc32");
+    bench_record::<Xxh3>(c, "xxh3");
+    bench_record::<Blake3>(c, "blake3");
+
+    bench_file_verification::<Crc32>(c, "crc32");
+    bench_file_verification::<Xxh3>(c, "xxh3");
+    bench_file_verification::<Blake3>(c, "blake3");
+}
+
+criterion_group!(benches, bench_checksums);
+criterion_main!(benches);
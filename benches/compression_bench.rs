@@ -0,0 +1,110 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use rand::prelude::*;
+use silentserver::storage::{
+    BlockData, FlatFileStore, FlatFileStoreOptions, BLOCK_DATA_DIR_NAME, TWEAK_SIZE,
+};
+use silentserver::test_support::{temp_dir, TestDir};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+const NUM_BLOCKS: u32 = 500;
+
+/// Blocks like mainnet's: a few hundred to a few thousand tweaks each, every tweak a
+/// compressed public key (parity byte and a random looking x coordinate).
+fn realistic_blocks() -> Vec<BlockData> {
+    let mut rng = StdRng::seed_from_u64(11);
+    (0..NUM_BLOCKS)
+        .map(|_| {
+            let mut blockhash = [0u8; 32];
+            rng.fill(&mut blockhash);
+            let tweaks = (0..rng.random_range(200..3_000))
+                .map(|_| {
+                    let mut tweak = [0u8; TWEAK_SIZE];
+                    tweak[0] = if rng.random() { 0x02 } else { 0x03 };
+                    rng.fill(&mut tweak[1..]);
+                    tweak
+                })
+                .collect();
+            BlockData {
+                blockhash,
+                tweaks,
+                tweak_meta: None,
+            }
+        })
+        .collect()
+}
+
+fn store_with(
+    name: &str,
+    compression_level: Option<i32>,
+    blocks: &[BlockData],
+) -> (FlatFileStore, TestDir) {
+    let dir = temp_dir(name);
+    let options = FlatFileStoreOptions {
+        compression_level,
+        ..Default::default()
+    };
+    let store = FlatFileStore::initialize_with_options(dir.to_path_buf(), options).unwrap();
+    let heights: Vec<u32> = (0..blocks.len() as u32).collect();
+    assert!(store.add_block_bulk(blocks, &heights).is_complete());
+    store.flush().unwrap();
+    (store, dir)
+}
+
+fn block_data_size(dir: &Path) -> u64 {
+    fs::read_dir(dir.join(BLOCK_DATA_DIR_NAME))
+        .unwrap()
+        .map(|file| file.unwrap().metadata().unwrap().len())
+        .sum()
+}
+
+/// Reads the whole chain back from an uncompressed store and from zstd compressed ones, and
+/// prints how much disk space compression saves on tweak data. Tweaks are public keys, close
+/// to random bytes, so expect next to nothing: only parity bytes and headers compress.
+fn bench_compression(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compression");
+    group.sample_size(20);
+
+    let blocks = realistic_blocks();
+    let stores = [
+        ("uncompressed", None),
+        ("zstd_1", Some(1)),
+        ("zstd_3", Some(3)),
+        ("zstd_19", Some(19)),
+    ];
+    let mut plain_size = 0;
+    for (name, compression_level) in stores {
+        let (store, dir) = store_with("bench_compression", compression_level, &blocks);
+        let size = block_data_size(&dir);
+        if compression_level.is_none() {
+            plain_size = size;
+        }
+        println!(
+            "{}: {} bytes of block data, {:.3} of uncompressed",
+            name,
+            size,
+            size as f64 / plain_size as f64
+        );
+
+        group.bench_function(format!("read_{}", name), |b| {
+            b.iter_batched(
+                Vec::new,
+                |mut served| {
+                    store
+                        .get_block_stream_range(0, NUM_BLOCKS - 1)
+                        .unwrap()
+                        .read_to_end(&mut served)
+                        .unwrap();
+                    black_box(served)
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_compression);
+criterion_main!(benches);
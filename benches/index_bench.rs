@@ -1,20 +1,10 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use rand::prelude::*;
 use silentserver::storage::{Index, IndexEntry};
-use std::env;
-use std::fs;
-use std::path::PathBuf;
+use silentserver::test_support::temp_dir;
 
 const MAX_HEIGHT: usize = 100_000;
 
-fn temp_dir(name: &str) -> PathBuf {
-    let mut dir = env::temp_dir();
-    dir.push(name);
-    let _ = fs::remove_dir_all(&dir);
-    fs::create_dir_all(&dir).unwrap();
-    dir
-}
-
 fn bench_index_operations(c: &mut Criterion) {
     let mut group = c.benchmark_group("index_operations");
     
@@ -23,7 +13,7 @@ fn bench_index_operations(c: &mut Criterion) {
     
     group.bench_function("insert_block", |b| {
         let index_dir = temp_dir("bench_block_index");
-        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        let (index, _) = Index::initialize(&index_dir).unwrap();
         
         // Pre-generate all the test data
         let mut rng = rand::rng();
@@ -47,14 +37,12 @@ fn bench_index_operations(c: &mut Criterion) {
             black_box(index.insert_block(i as u32, &blockhashes[i % MAX_HEIGHT], &entries[i % MAX_HEIGHT]).unwrap());
             i += 1;
         });
-        
-        let _ = fs::remove_dir_all(index_dir);
     });
 
 
     group.bench_function("random_read", |b| {
         let index_dir = temp_dir("bench_block_index_reads");
-        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        let (index, _) = Index::initialize(&index_dir).unwrap();
         
         // Pre-generate test data and insert it
         let mut rng = rand::rng();
@@ -78,13 +66,50 @@ fn bench_index_operations(c: &mut Criterion) {
             black_box(index.get_block_entry(&blockhashes[i % MAX_HEIGHT]).unwrap());
             i += 1;
         });
-
-        // Cleanup
-        let _ = fs::remove_dir_all(index_dir);
     });
 
     group.finish();
 }
 
-criterion_group!(benches, bench_index_operations);
+/// Reorg handling walks back from the tip looking for the fork point. Compare doing that
+/// through the in-memory recent chain map against going to sled for every height.
+fn bench_fork_point_search(c: &mut Criterion) {
+    const CHAIN_LENGTH: u32 = 10_000;
+    const REORG_DEPTH: u32 = 100;
+
+    let mut group = c.benchmark_group("fork_point_search");
+    group.sample_size(20);
+
+    for (name, window) in [("recent_window_1000", 1000), ("sled_only", 0)] {
+        let index_dir = temp_dir(&format!("bench_fork_point_{}", name));
+        let (index, _) = Index::initialize_with_recent_window(&index_dir, window).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(3);
+        for height in 0..CHAIN_LENGTH {
+            let mut blockhash = [0u8; 32];
+            rng.fill(&mut blockhash);
+            let entry = IndexEntry {
+                file_number: 0,
+                offset: height as u64 * 500,
+                length: 500,
+            };
+            index.insert_block(height, &blockhash, &entry).unwrap();
+        }
+        let fork_height = CHAIN_LENGTH - REORG_DEPTH;
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                black_box(
+                    index
+                        .find_fork_point(|height, _| height <= fork_height)
+                        .unwrap(),
+                )
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_index_operations, bench_fork_point_search);
 criterion_main!(benches); 
\ No newline at end of file
@@ -0,0 +1,216 @@
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use rand::prelude::*;
+use silentserver::storage::{
+    BlockData, DurabilityPolicy, FlatFileStore, FlatFileStoreOptions, Index, IndexEntry, TWEAK_SIZE,
+};
+use silentserver::test_support::temp_dir;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+
+const NUM_BLOCKS: usize = 100_000;
+/// Roughly what the sync writer hands over at once during IBD.
+const BULK_SIZE: usize = 500;
+/// Syncing every block is slow enough that fewer blocks make the point.
+const DURABILITY_BLOCKS: usize = 2_000;
+/// Tweak counts of a typical and a busy post-taproot block.
+const BUSY_TWEAK_COUNTS: [usize; 2] = [800, 6_000];
+
+fn small_blocks() -> Vec<BlockData> {
+    let mut rng = StdRng::seed_from_u64(7);
+    (0..NUM_BLOCKS)
+        .map(|_| {
+            let mut blockhash = [0u8; 32];
+            rng.fill(&mut blockhash);
+            let tweaks = (0..rng.random_range(1..4))
+                .map(|_| {
+                    let mut tweak = [0u8; TWEAK_SIZE];
+                    rng.fill(&mut tweak[..]);
+                    tweak
+                })
+                .collect();
+            BlockData {
+                blockhash,
+                tweaks,
+                tweak_meta: None,
+            }
+        })
+        .collect()
+}
+
+/// Ingests 100k small blocks, comparing add_block against how it used to write (reopening
+/// the block file and seeking to its end for every block) and against add_block_bulk.
+fn bench_ingest(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ingest");
+    group.sample_size(10);
+
+    let blocks = small_blocks();
+
+    group.bench_function("reopen_per_block", |b| {
+        b.iter_batched(
+            || {
+                let dir = temp_dir("bench_ingest_reopen");
+                let file_path = dir.join("sps000000.dat");
+                File::create(&file_path)
+                    .unwrap()
+                    .write_all(b"SPSDATA1")
+                    .unwrap();
+                let (index, _) = Index::initialize(&dir.join("index")).unwrap();
+                (file_path, index, dir)
+            },
+            |(file_path, index, dir)| {
+                for (height, block) in blocks.iter().enumerate() {
+                    let record = block.serialize();
+                    let mut file = File::options().append(true).open(&file_path).unwrap();
+                    let offset = file.seek(SeekFrom::End(0)).unwrap();
+                    file.write_all(&record).unwrap();
+                    let entry = IndexEntry {
+                        file_number: 0,
+                        offset,
+                        length: record.len() as u64,
+                    };
+                    index
+                        .insert_block(height as u32, &block.blockhash, &entry)
+                        .unwrap();
+                }
+                (index, dir)
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
+    group.bench_function("add_block", |b| {
+        b.iter_batched(
+            || {
+                let dir = temp_dir("bench_ingest_store");
+                (FlatFileStore::initialize(dir.to_path_buf()).unwrap(), dir)
+            },
+            |(store, dir)| {
+                for (height, block) in blocks.iter().enumerate() {
+                    store.add_block(block, height as u32).unwrap();
+                }
+                store.flush().unwrap();
+                (store, dir)
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
+    group.bench_function("add_block_bulk", |b| {
+        let heights: Vec<u32> = (0..NUM_BLOCKS as u32).collect();
+        b.iter_batched(
+            || {
+                let dir = temp_dir("bench_ingest_bulk");
+                (FlatFileStore::initialize(dir.to_path_buf()).unwrap(), dir)
+            },
+            |(store, dir)| {
+                for (blocks, heights) in blocks.chunks(BULK_SIZE).zip(heights.chunks(BULK_SIZE)) {
+                    assert!(store.add_block_bulk(blocks, heights).is_complete());
+                }
+                (store, dir)
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
+    group.finish();
+}
+
+/// Ingests blocks one add_block at a time under each DurabilityPolicy, to show what syncing
+/// costs.
+fn bench_durability(c: &mut Criterion) {
+    let mut group = c.benchmark_group("durability");
+    group.sample_size(10);
+
+    let blocks = &small_blocks()[..DURABILITY_BLOCKS];
+    let policies = [
+        ("always", DurabilityPolicy::Always),
+        ("every_100_blocks", DurabilityPolicy::EveryNBlocks(100)),
+        ("never", DurabilityPolicy::Never),
+    ];
+    for (name, durability) in policies {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let dir = temp_dir("bench_durability");
+                    let options = FlatFileStoreOptions {
+                        durability,
+                        ..Default::default()
+                    };
+                    let store =
+                        FlatFileStore::initialize_with_options(dir.to_path_buf(), options).unwrap();
+                    (store, dir)
+                },
+                |(store, dir)| {
+                    for (height, block) in blocks.iter().enumerate() {
+                        store.add_block(block, height as u32).unwrap();
+                    }
+                    (store, dir)
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+/// Writes a block into a reused buffer, as the write path does, through a Vec of its own
+/// (`serialize`) and straight (`serialize_into`).
+fn bench_serialize_into(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize_into");
+    let mut rng = StdRng::seed_from_u64(11);
+
+    for num_tweaks in BUSY_TWEAK_COUNTS {
+        let mut blockhash = [0u8; 32];
+        rng.fill(&mut blockhash);
+        let tweaks = (0..num_tweaks)
+            .map(|_| {
+                let mut tweak = [0u8; TWEAK_SIZE];
+                rng.fill(&mut tweak[..]);
+                tweak
+            })
+            .collect();
+        let block = BlockData {
+            blockhash,
+            tweaks,
+            tweak_meta: None,
+        };
+        group.throughput(Throughput::Bytes(block.serialized_len()));
+        let mut buffer = Vec::with_capacity(block.serialized_len() as usize);
+
+        group.bench_with_input(
+            BenchmarkId::new("serialize", num_tweaks),
+            &block,
+            |b, block| {
+                b.iter(|| {
+                    buffer.clear();
+                    buffer.extend_from_slice(&block.serialize());
+                    black_box(buffer.len())
+                });
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("serialize_into", num_tweaks),
+            &block,
+            |b, block| {
+                b.iter(|| {
+                    buffer.clear();
+                    block.serialize_into(&mut buffer).unwrap();
+                    black_box(buffer.len())
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_ingest,
+    bench_durability,
+    bench_serialize_into
+);
+criterion_main!(benches);
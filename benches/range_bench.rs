@@ -0,0 +1,53 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use silentserver::storage::{FlatFileStore, FlatFileStoreOptions};
+use silentserver::test_support::{generated_block, temp_dir};
+use std::io::Read;
+
+/// Blocks served per request.
+const RANGE_LEN: u32 = 10_000;
+
+/// Serves a 10k block range through the `Read` based stream and through memory mapped files.
+fn bench_range(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_range");
+    group.sample_size(20);
+
+    let dir = temp_dir("bench_block_range");
+    let options = FlatFileStoreOptions {
+        // Small files, so the range is spread over sealed files that get mapped
+        max_file_size: 256 * 1024,
+        ..Default::default()
+    };
+    let store = FlatFileStore::initialize_with_options(dir.to_path_buf(), options).unwrap();
+    let blocks: Vec<_> = (0..RANGE_LEN + 1_000)
+        .map(|height| generated_block(height, 1 + height as usize % 8))
+        .collect();
+    let heights: Vec<u32> = (0..blocks.len() as u32).collect();
+    assert!(store.add_block_bulk(&blocks, &heights).is_complete());
+
+    group.bench_function("read", |b| {
+        b.iter_batched(
+            Vec::new,
+            |mut served| {
+                store
+                    .get_block_stream_range(0, RANGE_LEN - 1)
+                    .unwrap()
+                    .read_to_end(&mut served)
+                    .unwrap();
+                black_box(served)
+            },
+            BatchSize::SmallInput,
+        );
+    });
+
+    group.bench_function("mmap", |b| {
+        b.iter(|| {
+            let slices = store.get_block_range_mmap(0, RANGE_LEN - 1).unwrap();
+            black_box(slices.iter().map(|slice| slice.len()).sum::<usize>())
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_range);
+criterion_main!(benches);
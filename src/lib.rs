@@ -1 +1,5 @@
-pub mod storage; 
\ No newline at end of file
+pub mod platform;
+pub mod storage;
+#[cfg(any(test, feature = "test-support"))]
+#[doc(hidden)]
+pub mod test_support;
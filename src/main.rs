@@ -1,13 +1,17 @@
 mod logging;
+mod platform;
 mod storage;
+#[cfg(test)]
+mod test_support;
 
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 
+use std::fs::File;
 use std::path::PathBuf;
-use storage::FlatFileStore;
+use storage::{DurabilityPolicy, EncryptionKey, FlatFileStore, FlatFileStoreOptions, StorageError};
 
 use env_logger::Env;
-use log::info;
+use log::{error, info};
 use logging::setup_logging;
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -47,19 +51,125 @@ struct Args {
     #[arg(short, long)]
     data_dir: PathBuf,
 
-    /// Bitcoin data directory (defaults to ~/.bitcoin)
+    /// Bitcoin data directory (defaults to ~/.bitcoin, ~/Library/Application Support/Bitcoin
+    /// on macOS and %APPDATA%\Bitcoin on Windows)
     #[arg(short, long, default_value_os_t = default_bitcoin_dir())]
     bitcoin_datadir: PathBuf,
 
     /// Bitcoin network type
     #[arg(short, long, default_value_t = Network::Mainnet)]
     network: Network,
+
+    /// File holding the block data encryption key (32 raw bytes or 64 hex characters).
+    /// The key can also be passed through the SILENTSERVER_ENCRYPTION_KEY environment variable.
+    #[arg(long)]
+    encryption_key_file: Option<PathBuf>,
+
+    /// Treat any internal storage inconsistency as fatal: stop accepting writes and
+    /// write an incident report to the data directory
+    #[arg(long)]
+    strict: bool,
+
+    /// Number of recent blocks to keep in memory for fast lookups during reorgs
+    #[arg(long, default_value_t = storage::DEFAULT_RECENT_WINDOW)]
+    recent_window: usize,
+
+    /// Largest serialized block data record to accept (and read back), in bytes
+    #[arg(long, default_value_t = storage::DEFAULT_MAX_RECORD_SIZE)]
+    max_record_size: usize,
+
+    /// Size at which to start a new block data file, in bytes. Only affects files written
+    /// from now on
+    #[arg(long, default_value_t = storage::DEFAULT_MAX_FILE_SIZE)]
+    max_file_size: u64,
+
+    /// Sync block data and the index to disk every this many added blocks (1 for every
+    /// block). Without it syncing is left to the OS, and a power loss can lose recent blocks
+    #[arg(long)]
+    sync_every: Option<u32>,
+
+    /// Bytes of recently read blocks to keep in memory, 0 to not cache blocks
+    #[arg(long, default_value_t = 0)]
+    block_cache_size: usize,
+
+    /// Compress block data records with zstd at this level (1 to 22, 3 is a good default).
+    /// Only affects files written from now on, existing ones stay as they are
+    #[arg(long)]
+    compression_level: Option<i32>,
+
+    /// Reserve the full --max-file-size on disk for every new block data file, so it doesn't
+    /// fragment as blocks are appended
+    #[arg(long)]
+    preallocate: bool,
+
+    /// Sort and deduplicate the tweaks of every block stored, so servers that scanned the
+    /// same blocks store the same records
+    #[arg(long)]
+    canonical_tweaks: bool,
+
+    /// Fraction of a hard limit (such as --max-record-size) at which to start warning
+    #[arg(long, default_value_t = storage::DEFAULT_SOFT_LIMIT_FRACTION)]
+    soft_limit_fraction: f64,
+
+    /// Open a data directory created by a pre-release version, which doesn't record its
+    /// network, as belonging to --network
+    #[arg(long)]
+    assume_network: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Re-encrypt all block data files with a new key
+    Rekey {
+        /// File holding the new encryption key
+        #[arg(long)]
+        new_key_file: PathBuf,
+    },
+    /// Migrate the data directory to the version this binary uses
+    Upgrade {
+        /// Only report the migrations that would run
+        #[arg(long)]
+        dry_run: bool,
+        /// Upgrade a data directory created by a pre-release version, which doesn't record
+        /// its network, as belonging to --network
+        #[arg(long)]
+        assume_network: bool,
+    },
+    /// Write a snapshot of the whole chain, to seed another server with
+    ExportSnapshot {
+        /// File to write the snapshot to
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Create the data directory from a snapshot written by export-snapshot
+    ImportSnapshot {
+        /// Snapshot file to read
+        #[arg(long)]
+        input: PathBuf,
+    },
+    /// Print how many blocks and tweaks the store holds and its size on disk, as JSON
+    Stats,
+    /// Rewrite the block data files holding the most records of reorged blocks without them
+    Compact {
+        /// Rewrite files in which more than this fraction of the data is dead
+        #[arg(long, default_value_t = 0.5)]
+        min_dead_ratio: f32,
+    },
 }
 
 fn default_bitcoin_dir() -> PathBuf {
-    dirs::home_dir()
-        .expect("Could not determine home directory")
-        .join(".bitcoin")
+    platform::default_bitcoin_dir().expect("Could not determine home directory")
+}
+
+/// The key file takes precedence over the environment variable.
+fn load_encryption_key(key_file: Option<&PathBuf>) -> Result<Option<EncryptionKey>, StorageError> {
+    match key_file {
+        Some(path) => EncryptionKey::from_file(path).map(Some),
+        None => EncryptionKey::from_env(),
+    }
 }
 
 fn join_network_dir(base: impl Into<PathBuf>, network: &Network) -> PathBuf {
@@ -72,8 +182,115 @@ fn main() {
 
     setup_logging().expect("Failed to setup logging");
 
+    let encryption_key = load_encryption_key(args.encryption_key_file.as_ref())
+        .expect("Failed to load encryption key");
     let data_dir = join_network_dir(args.data_dir, &args.network);
-    let store = FlatFileStore::initialize(data_dir).expect("Failed to initialize storage");
+
+    if let Some(Command::Upgrade {
+        dry_run,
+        assume_network,
+    }) = args.command
+    {
+        let network = args.network.to_string();
+        let assume_network = assume_network.then_some(network.as_str());
+        let plan = storage::upgrade(&data_dir, encryption_key.as_ref(), assume_network, dry_run)
+            .unwrap_or_else(|e| {
+                error!("Upgrade failed: {}", e);
+                std::process::exit(1);
+            });
+        if plan.steps.is_empty() {
+            info!("Data directory is already at version {}", plan.to);
+        } else if dry_run {
+            info!("Upgrading from version {} to {} would:", plan.from, plan.to);
+            for step in &plan.steps {
+                info!("  - {}", step);
+            }
+        }
+        return;
+    }
+
+    let options = FlatFileStoreOptions {
+        encryption_key,
+        strict: args.strict,
+        recent_window: args.recent_window,
+        max_record_size: args.max_record_size,
+        assume_network: args.assume_network.then(|| args.network.to_string()),
+        soft_limit_fraction: args.soft_limit_fraction,
+        max_file_size: args.max_file_size,
+        durability: args
+            .sync_every
+            .map_or(DurabilityPolicy::Never, DurabilityPolicy::EveryNBlocks),
+        block_cache_size: args.block_cache_size,
+        compression_level: args.compression_level,
+        preallocate: args.preallocate,
+        canonical_tweaks: args.canonical_tweaks,
+        network: Some(args.network.to_string()),
+    };
+    if let Some(Command::ImportSnapshot { input }) = &args.command {
+        let snapshot = File::open(input).expect("Failed to open snapshot");
+        let store = FlatFileStore::import_snapshot_with_options(data_dir, snapshot, options)
+            .unwrap_or_else(|e| {
+                error!("Snapshot import failed: {}", e);
+                std::process::exit(1);
+            });
+        info!(
+            "Imported snapshot up to height {}",
+            store.get_current_height()
+        );
+        return;
+    }
+
+    let mut store = FlatFileStore::initialize_with_options(data_dir, options).unwrap_or_else(|e| {
+        match e {
+            StorageError::NetworkMismatch { .. } => error!(
+                "{}. Check --network and --data-dir: mainnet data is kept in the data directory itself, other networks in a subdirectory of it",
+                e
+            ),
+            e => error!("Failed to initialize storage: {}", e),
+        }
+        std::process::exit(1);
+    });
+
+    if let Some(Command::ExportSnapshot { output }) = &args.command {
+        let snapshot = File::create(output).expect("Failed to create snapshot file");
+        let count = store.export_snapshot(snapshot).unwrap_or_else(|e| {
+            error!("Snapshot export failed: {}", e);
+            std::process::exit(1);
+        });
+        info!("Exported {} blocks to {}", count, output.display());
+        return;
+    }
+
+    if let Some(Command::Stats) = &args.command {
+        let stats = store.stats().unwrap_or_else(|e| {
+            error!("Failed to read store statistics: {}", e);
+            std::process::exit(1);
+        });
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&stats).expect("Failed to encode store statistics")
+        );
+        return;
+    }
+
+    if let Some(Command::Compact { min_dead_ratio }) = args.command {
+        let freed = store.compact(min_dead_ratio).unwrap_or_else(|e| {
+            error!("Compaction failed: {}", e);
+            std::process::exit(1);
+        });
+        info!("Compaction freed {} bytes", freed);
+        return;
+    }
+
+    if let Some(Command::Rekey { new_key_file }) = args.command {
+        let new_key =
+            EncryptionKey::from_file(&new_key_file).expect("Failed to load new encryption key");
+        store
+            .rekey(new_key)
+            .expect("Failed to re-encrypt block data");
+        info!("Block data re-encrypted, use the new key from now on");
+        return;
+    }
 
     let chain_dir = join_network_dir(&args.bitcoin_datadir, &args.network);
     info!("Using Bitcoin data directory: {}", chain_dir.display());
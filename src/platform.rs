@@ -0,0 +1,289 @@
+//! Platform specific bits: default directories, file locking and replacing files.
+//! Everything else in the crate should go through here instead of assuming Unix behaviour.
+#![allow(dead_code)]
+
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// How often `replace_file` retries a rename that failed because the file is in use.
+const REPLACE_RETRIES: u32 = 10;
+const REPLACE_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Directory layout conventions we care about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsFamily {
+    Unix,
+    MacOs,
+    Windows,
+}
+
+impl OsFamily {
+    pub fn current() -> Self {
+        if cfg!(windows) {
+            OsFamily::Windows
+        } else if cfg!(target_os = "macos") {
+            OsFamily::MacOs
+        } else {
+            OsFamily::Unix
+        }
+    }
+}
+
+/// Source of the user directories used to resolve default paths, so the resolution can be
+/// tested for every OS convention from any OS.
+pub trait HomeDirs {
+    fn home_dir(&self) -> Option<PathBuf>;
+    /// The roaming application data directory (%APPDATA%), only meaningful on Windows.
+    fn app_data_dir(&self) -> Option<PathBuf>;
+}
+
+/// The directories of the user running the process.
+pub struct SystemHomeDirs;
+
+impl HomeDirs for SystemHomeDirs {
+    fn home_dir(&self) -> Option<PathBuf> {
+        dirs::home_dir()
+    }
+
+    fn app_data_dir(&self) -> Option<PathBuf> {
+        if cfg!(windows) {
+            dirs::data_dir()
+        } else {
+            None
+        }
+    }
+}
+
+/// Bitcoin Core's default data directory for the given OS:
+/// - Windows: %APPDATA%\Bitcoin
+/// - macOS: ~/Library/Application Support/Bitcoin
+/// - everything else: ~/.bitcoin
+pub fn bitcoin_dir_for(os: OsFamily, dirs: &impl HomeDirs) -> Option<PathBuf> {
+    match os {
+        OsFamily::Windows => dirs
+            .app_data_dir()
+            .or_else(|| {
+                dirs.home_dir()
+                    .map(|home| home.join("AppData").join("Roaming"))
+            })
+            .map(|app_data| app_data.join("Bitcoin")),
+        OsFamily::MacOs => dirs.home_dir().map(|home| {
+            home.join("Library")
+                .join("Application Support")
+                .join("Bitcoin")
+        }),
+        OsFamily::Unix => dirs.home_dir().map(|home| home.join(".bitcoin")),
+    }
+}
+
+pub fn default_bitcoin_dir() -> Option<PathBuf> {
+    bitcoin_dir_for(OsFamily::current(), &SystemHomeDirs)
+}
+
+/// Takes an exclusive lock on `file` without blocking. Returns false if another handle holds it.
+///
+/// On Unix this is an advisory flock: it only keeps out other processes that also lock.
+/// On Windows the lock is mandatory and other processes can't even read the locked file, so
+/// only ever lock a dedicated lock file, never a block data or index file.
+pub fn try_lock_exclusive(file: &File) -> io::Result<bool> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+pub fn unlock(file: &File) -> io::Result<()> {
+    file.unlock()
+}
+
+/// Reserves disk space for `file` up to `len` bytes, so appending to it doesn't fragment it.
+/// The file reads as zeros past its data. On Linux the blocks are allocated with
+/// posix_fallocate, elsewhere the file is only extended, which may leave it sparse.
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        // SAFETY: the descriptor belongs to `file`, which outlives the call
+        match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    file.set_len(len)
+}
+
+/// Atomically replaces `to` with `from`.
+/// All of our own handles to both files must be closed before calling this. On Windows the
+/// rename can still fail while another process (a reader, a virus scanner, the indexer) has
+/// `to` open, so it is retried for a short while before giving up.
+pub fn replace_file(from: &Path, to: &Path) -> io::Result<()> {
+    let retries = if cfg!(windows) { REPLACE_RETRIES } else { 0 };
+    retry_while_in_use(retries, REPLACE_RETRY_DELAY, || fs::rename(from, to))
+}
+
+fn retry_while_in_use(
+    retries: u32,
+    delay: Duration,
+    mut op: impl FnMut() -> io::Result<()>,
+) -> io::Result<()> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_in_use(&e) => {
+                attempt += 1;
+                thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Windows reports a file held open by someone else as a sharing violation or access denied.
+fn is_in_use(e: &io::Error) -> bool {
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    e.kind() == io::ErrorKind::PermissionDenied
+        || matches!(
+            e.raw_os_error(),
+            Some(ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION)
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+
+    struct FakeHomeDirs {
+        home: Option<PathBuf>,
+        app_data: Option<PathBuf>,
+    }
+
+    impl HomeDirs for FakeHomeDirs {
+        fn home_dir(&self) -> Option<PathBuf> {
+            self.home.clone()
+        }
+
+        fn app_data_dir(&self) -> Option<PathBuf> {
+            self.app_data.clone()
+        }
+    }
+
+    #[test]
+    fn test_bitcoin_dir_per_os() {
+        let dirs = FakeHomeDirs {
+            home: Some(PathBuf::from("/home/satoshi")),
+            app_data: Some(PathBuf::from("C:\\Users\\satoshi\\AppData\\Roaming")),
+        };
+
+        assert_eq!(
+            bitcoin_dir_for(OsFamily::Unix, &dirs),
+            Some(PathBuf::from("/home/satoshi").join(".bitcoin"))
+        );
+        assert_eq!(
+            bitcoin_dir_for(OsFamily::MacOs, &dirs),
+            Some(
+                PathBuf::from("/home/satoshi")
+                    .join("Library")
+                    .join("Application Support")
+                    .join("Bitcoin")
+            )
+        );
+        assert_eq!(
+            bitcoin_dir_for(OsFamily::Windows, &dirs),
+            Some(PathBuf::from("C:\\Users\\satoshi\\AppData\\Roaming").join("Bitcoin"))
+        );
+    }
+
+    #[test]
+    fn test_bitcoin_dir_fallbacks() {
+        let no_app_data = FakeHomeDirs {
+            home: Some(PathBuf::from("C:\\Users\\satoshi")),
+            app_data: None,
+        };
+        assert_eq!(
+            bitcoin_dir_for(OsFamily::Windows, &no_app_data),
+            Some(
+                PathBuf::from("C:\\Users\\satoshi")
+                    .join("AppData")
+                    .join("Roaming")
+                    .join("Bitcoin")
+            )
+        );
+
+        let nothing = FakeHomeDirs {
+            home: None,
+            app_data: None,
+        };
+        for os in [OsFamily::Unix, OsFamily::MacOs, OsFamily::Windows] {
+            assert_eq!(bitcoin_dir_for(os, &nothing), None);
+        }
+    }
+
+    #[test]
+    fn test_replace_file() {
+        let dir = temp_dir("test_platform_replace_file");
+        let from = dir.join("new");
+        let to = dir.join("old");
+        fs::write(&from, b"new contents").unwrap();
+        fs::write(&to, b"old contents").unwrap();
+
+        replace_file(&from, &to).unwrap();
+        assert_eq!(fs::read(&to).unwrap(), b"new contents");
+        assert!(!from.exists());
+    }
+
+    #[test]
+    fn test_retry_while_in_use() {
+        let in_use = || io::Error::from(io::ErrorKind::PermissionDenied);
+
+        // Succeeds once the other handle goes away
+        let mut failures = 3;
+        let result = retry_while_in_use(5, Duration::ZERO, || {
+            if failures > 0 {
+                failures -= 1;
+                return Err(in_use());
+            }
+            Ok(())
+        });
+        assert!(result.is_ok());
+
+        // Gives up after the retries run out
+        let mut attempts = 0;
+        let result = retry_while_in_use(2, Duration::ZERO, || {
+            attempts += 1;
+            Err(in_use())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+
+        // Other errors are not retried
+        let mut attempts = 0;
+        let result = retry_while_in_use(5, Duration::ZERO, || {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_exclusive_lock() {
+        let dir = temp_dir("test_platform_lock");
+        let path = dir.join("LOCK");
+        let first = File::create(&path).unwrap();
+        let second = File::open(&path).unwrap();
+
+        assert!(try_lock_exclusive(&first).unwrap());
+        assert!(!try_lock_exclusive(&second).unwrap());
+        unlock(&first).unwrap();
+        assert!(try_lock_exclusive(&second).unwrap());
+
+        drop((first, second));
+    }
+}
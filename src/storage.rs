@@ -6,8 +6,31 @@ pub use flat_file_store::*;
 pub mod block_data;
 pub use block_data::*;
 
+pub mod block_cache;
+pub use block_cache::*;
+
 pub mod block_index;
 pub use block_index::*; 
 
+pub mod encryption;
+pub use encryption::*;
+
+pub mod integrity;
+pub use integrity::*;
+
+pub mod lock;
+pub use lock::*;
+
+pub mod version;
+pub use version::*;
+
+pub mod watermark;
+pub use watermark::*;
+
+pub mod store_stats;
+pub use store_stats::*;
+
+mod journal;
+
 pub mod errors;
 pub use errors::*;
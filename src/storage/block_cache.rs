@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Serialized blocks kept in memory, keyed by blockhash, so blocks that are asked for again
+/// and again (light clients catching up on the last day or so) don't go back to the files.
+/// Holds at most `budget` bytes of blocks and evicts the least recently used ones first.
+/// A budget of 0 turns it off.
+/// Entries are only ever looked up for blocks the index still has, so a stale entry is never
+/// served; removing blocks from the store still drops theirs to free the memory.
+pub struct BlockCache {
+    budget: usize,
+    entries: Mutex<CacheEntries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Counters of a BlockCache, for operators sizing it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub blocks: usize,
+    pub bytes: usize,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    /// blockhash -> (serialized block, when it was last used)
+    blocks: HashMap<[u8; 32], (Arc<[u8]>, u64)>,
+    /// when a block was last used -> blockhash, oldest first
+    by_use: BTreeMap<u64, [u8; 32]>,
+    bytes: usize,
+    next_use: u64,
+}
+
+impl CacheEntries {
+    fn touch(&mut self, blockhash: &[u8; 32]) -> Option<Arc<[u8]>> {
+        let next_use = self.next_use;
+        let (block, last_use) = self.blocks.get_mut(blockhash)?;
+        self.by_use.remove(last_use);
+        *last_use = next_use;
+        self.by_use.insert(next_use, *blockhash);
+        self.next_use += 1;
+        Some(Arc::clone(block))
+    }
+
+    fn remove(&mut self, blockhash: &[u8; 32]) {
+        if let Some((block, last_use)) = self.blocks.remove(blockhash) {
+            self.by_use.remove(&last_use);
+            self.bytes -= block.len();
+        }
+    }
+}
+
+impl BlockCache {
+    pub fn new(budget: usize) -> Self {
+        BlockCache {
+            budget,
+            entries: Mutex::new(CacheEntries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    fn entries(&self) -> MutexGuard<'_, CacheEntries> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The serialized block for `blockhash`, if cached.
+    pub fn get(&self, blockhash: &[u8; 32]) -> Option<Arc<[u8]>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let block = self.entries().touch(blockhash);
+        let counter = match block {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        block
+    }
+
+    /// Caches the serialized block for `blockhash`, evicting the least recently used blocks
+    /// to make room. Blocks bigger than the whole budget aren't cached.
+    pub fn insert(&self, blockhash: [u8; 32], block: Arc<[u8]>) {
+        if block.len() > self.budget {
+            return;
+        }
+        let mut entries = self.entries();
+        entries.remove(&blockhash);
+        while entries.bytes + block.len() > self.budget {
+            let Some((_, oldest)) = entries.by_use.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = entries.blocks.remove(&oldest) {
+                entries.bytes -= evicted.len();
+            }
+        }
+
+        let next_use = entries.next_use;
+        entries.next_use += 1;
+        entries.bytes += block.len();
+        entries.by_use.insert(next_use, blockhash);
+        entries.blocks.insert(blockhash, (block, next_use));
+    }
+
+    pub fn remove(&self, blockhash: &[u8; 32]) {
+        if self.is_enabled() {
+            self.entries().remove(blockhash);
+        }
+    }
+
+    pub fn clear(&self) {
+        *self.entries() = CacheEntries::default();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            blocks: entries.blocks.len(),
+            bytes: entries.bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(byte: u8, len: usize) -> Arc<[u8]> {
+        vec![byte; len].into()
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = BlockCache::new(300);
+        cache.insert([1; 32], block(1, 100));
+        cache.insert([2; 32], block(2, 100));
+        cache.insert([3; 32], block(3, 100));
+        // Block 1 was used last, block 2 goes first
+        assert!(cache.get(&[1; 32]).is_some());
+        cache.insert([4; 32], block(4, 100));
+        assert!(cache.get(&[2; 32]).is_none());
+        assert_eq!(&cache.get(&[1; 32]).unwrap()[..], &[1; 100][..]);
+
+        // Room for a bigger block is made from the oldest ones
+        cache.insert([5; 32], block(5, 250));
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 1,
+                blocks: 1,
+                bytes: 250,
+            }
+        );
+
+        // Too big to cache at all
+        cache.insert([6; 32], block(6, 301));
+        assert!(cache.get(&[6; 32]).is_none());
+        cache.remove(&[5; 32]);
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let cache = BlockCache::new(0);
+        cache.insert([1; 32], block(1, 10));
+        assert!(cache.get(&[1; 32]).is_none());
+        assert_eq!(cache.stats(), CacheStats::default());
+    }
+}
@@ -1,90 +1,642 @@
 use crc32fast::Hasher;
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::convert::TryInto;
-use super::StorageError;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use super::{StorageError, DEFAULT_MAX_RECORD_SIZE};
 
 pub const TWEAK_SIZE: usize = 33;
+/// Size of the fixed part of a serialized record: blockhash, lenTweaks and CRC32.
+pub const RECORD_HEADER_SIZE: usize = 32 + 4 + 4;
+const MAX_CHECKSUM_SIZE: usize = 32;
+/// Version of the records `serialize` writes, whose checksum covers the blockhash and
+/// lenTweaks along with the tweaks.
+pub const RECORD_VERSION: u16 = 2;
+/// Records written before, whose checksum only covers the tweaks. A flipped bit in their
+/// blockhash or lenTweaks goes unnoticed, they are only read to be checksummed again.
+pub const TWEAKS_CRC_RECORD_VERSION: u16 = 1;
+/// Size of a serialized TweakMeta: txid, vout, value and output key.
+pub const TWEAK_META_SIZE: usize = 32 + 4 + 8 + 32;
+/// Set in the stored lenTweaks of records carrying a TweakMeta per tweak. Records without
+/// one are the compact form, byte for byte what they were before metadata existed.
+pub const TWEAK_META_FLAG: u32 = 1 << 31;
 
-#[derive(Debug, PartialEq)]
+/// Checksum protecting a serialized record.
+/// Stored records always use `Crc32`. The trait exists so other checksums can be benchmarked
+/// and tested against the same serializer, and so switching algorithms stays a local change.
+pub trait RecordChecksum: Sized {
+    /// Number of checksum bytes stored in the record header (at most 32).
+    const SIZE: usize;
+
+    fn new() -> Self;
+    fn update(&mut self, data: &[u8]);
+    /// Writes the SIZE checksum bytes into `out`.
+    fn finalize_into(self, out: &mut [u8]);
+}
+
+/// CRC32 stored little-endian, the checksum used on disk.
+pub struct Crc32(Hasher);
+
+impl RecordChecksum for Crc32 {
+    const SIZE: usize = 4;
+
+    fn new() -> Self {
+        Crc32(Hasher::new())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize_into(self, out: &mut [u8]) {
+        out.copy_from_slice(&self.0.finalize().to_le_bytes());
+    }
+}
+
+/// The output a tweak was computed for, for clients that verify tweaks or do cut-through
+/// themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TweakMeta {
+    #[cfg_attr(feature = "serde", serde(with = "hex::reversed"))]
+    pub txid: [u8; 32],
+    pub vout: u32,
+    /// Value of the output, in satoshis.
+    pub value: u64,
+    /// The x-only taproot output key.
+    #[cfg_attr(feature = "serde", serde(with = "hex::bytes"))]
+    pub output_key: [u8; 32],
+}
+
+impl TweakMeta {
+    /// The output's scriptPubKey: OP_1 followed by a push of the output key.
+    pub fn script_pubkey(&self) -> [u8; 34] {
+        let mut script = [0u8; 34];
+        script[0] = 0x51;
+        script[1] = 0x20;
+        script[2..].copy_from_slice(&self.output_key);
+        script
+    }
+
+    fn encode(&self) -> [u8; TWEAK_META_SIZE] {
+        let mut buf = [0u8; TWEAK_META_SIZE];
+        buf[..32].copy_from_slice(&self.txid);
+        buf[32..36].copy_from_slice(&self.vout.to_le_bytes());
+        buf[36..44].copy_from_slice(&self.value.to_le_bytes());
+        buf[44..].copy_from_slice(&self.output_key);
+        buf
+    }
+
+    fn decode(data: &[u8]) -> TweakMeta {
+        TweakMeta {
+            txid: data[..32].try_into().unwrap(),
+            vout: u32::from_le_bytes(data[32..36].try_into().unwrap()),
+            value: u64::from_le_bytes(data[36..44].try_into().unwrap()),
+            output_key: data[44..76].try_into().unwrap(),
+        }
+    }
+}
+
+/// With the `serde` feature, BlockData serializes as
+/// `{"blockhash": "<64 hex>", "tweaks": ["<66 hex>", ...]}`, plus `"tweak_meta"` if it has
+/// any. Hashes (blockhash, txid) are in the reversed byte order Bitcoin displays them in,
+/// tweaks and keys as stored, all in lowercase.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BlockData {
+    #[cfg_attr(feature = "serde", serde(with = "hex::reversed"))]
     pub blockhash: [u8; 32],
+    #[cfg_attr(feature = "serde", serde(with = "hex::list"))]
     pub tweaks: Vec<[u8; TWEAK_SIZE]>,
+    /// The output of each tweak, in the same order, or None to store the tweaks alone. See
+    /// `check_tweak_meta`.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub tweak_meta: Option<Vec<TweakMeta>>,
 }
 
 impl BlockData {
     /// Serialize a BlockData record into our custom binary format.
     /// This is serialized as:
-    /// [blockhash (32 bytes)] [lenTweaks (u32 little-endian)] [CRC32 of tweaks (u32 little-endian)] [<tweaks> (each tweak is 33 bytes)]
+    /// [blockhash (32 bytes)] [lenTweaks (u32 little-endian)] [CRC32 of the blockhash, lenTweaks, tweaks and metadata (u32 little-endian)] [<tweaks> (each tweak is 33 bytes)] [<metadata> (each TweakMeta is 76 bytes)]
+    /// The metadata is only there if TWEAK_META_FLAG is set in lenTweaks.
     pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
+        self.serialize_with::<Crc32>()
+    }
 
-        buf.extend_from_slice(&self.blockhash);
-        let len_tweaks = self.tweaks.len() as u32;
-        buf.extend_from_slice(&len_tweaks.to_le_bytes());
+    /// Same as `serialize`, with the CRC32 replaced by C::SIZE bytes of checksum C.
+    pub fn serialize_with<C: RecordChecksum>(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(36 + C::SIZE + self.body_len());
+        self.write_with::<C, _>(&mut buf)
+            .expect("Writing to a Vec cannot fail");
+        buf
+    }
 
-        let crc = {
-            let mut hasher = Hasher::new();
-            for tweak in &self.tweaks {
-                hasher.update(tweak);
-            }
-            hasher.finalize()
-        };
+    /// Writes the record `serialize` returns to `writer`, without building it in memory
+    /// first, and returns its length.
+    pub fn serialize_into<W: Write>(&self, writer: &mut W) -> io::Result<u64> {
+        self.write_with::<Crc32, W>(writer)
+    }
 
-        buf.extend_from_slice(&crc.to_le_bytes());
+    fn write_with<C: RecordChecksum, W: Write>(&self, writer: &mut W) -> io::Result<u64> {
+        let mut header = [0u8; 36 + MAX_CHECKSUM_SIZE];
+        header[..32].copy_from_slice(&self.blockhash);
+        let mut len_tweaks = self.tweaks.len() as u32;
+        if self.tweak_meta.is_some() {
+            len_tweaks |= TWEAK_META_FLAG;
+        }
+        header[32..36].copy_from_slice(&len_tweaks.to_le_bytes());
+        let meta: Vec<[u8; TWEAK_META_SIZE]> = self.tweak_meta.iter().flatten().map(TweakMeta::encode).collect();
+
+        let mut hasher = C::new();
+        hasher.update(&header[..36]);
         for tweak in &self.tweaks {
-            buf.extend_from_slice(tweak);
+            hasher.update(tweak);
         }
+        hasher.update(meta.as_flattened());
+        hasher.finalize_into(&mut header[36..36 + C::SIZE]);
 
-        buf
+        writer.write_all(&header[..36 + C::SIZE])?;
+        writer.write_all(self.tweaks.as_flattened())?;
+        writer.write_all(meta.as_flattened())?;
+        Ok((36 + C::SIZE + self.body_len()) as u64)
     }
 
-    /// Deserialize a BlockData record from a byte slice.
+    /// Length of the tweaks and metadata of the record.
+    fn body_len(&self) -> usize {
+        self.tweaks.len() * TWEAK_SIZE + self.tweak_meta.as_ref().map_or(0, |meta| meta.len() * TWEAK_META_SIZE)
+    }
+
+    /// Length of the record `serialize` returns, without serializing it.
+    pub fn serialized_len(&self) -> u64 {
+        (RECORD_HEADER_SIZE + self.body_len()) as u64
+    }
+
+    /// Length of the whole serialized record that starts with `header`.
+    pub fn serialized_len_from_header(header: &[u8; RECORD_HEADER_SIZE]) -> usize {
+        let len_tweaks = u32::from_le_bytes(header[32..36].try_into().unwrap());
+        let (count, has_meta) = split_len_tweaks(len_tweaks);
+        RECORD_HEADER_SIZE + count as usize * entry_size(has_meta)
+    }
+
+    /// Tweaks in the record that starts with `header`, without the metadata flag.
+    pub fn tweak_count_from_header(header: &[u8; RECORD_HEADER_SIZE]) -> u32 {
+        split_len_tweaks(u32::from_le_bytes(header[32..36].try_into().unwrap())).0
+    }
+
+    /// Sorts the tweaks lexicographically and drops duplicates, so two scans of the same block
+    /// serialize to the same bytes whatever order they found its tweaks in. Metadata matching
+    /// the tweaks moves along with them, a duplicate keeps that of its first occurrence.
+    pub fn canonicalize(&mut self) {
+        match self.tweak_meta.take() {
+            Some(meta) if meta.len() == self.tweaks.len() => {
+                let mut entries: Vec<_> = self.tweaks.drain(..).zip(meta).collect();
+                entries.sort_by_key(|entry| entry.0);
+                entries.dedup_by(|later, earlier| later.0 == earlier.0);
+                let (tweaks, meta) = entries.into_iter().unzip();
+                self.tweaks = tweaks;
+                self.tweak_meta = Some(meta);
+            }
+            meta => {
+                self.tweaks.sort_unstable();
+                self.tweaks.dedup();
+                self.tweak_meta = meta;
+            }
+        }
+    }
+
+    /// Whether the tweaks are in the order `canonicalize` leaves them in: strictly increasing.
+    pub fn is_canonical(&self) -> bool {
+        self.tweaks.windows(2).all(|pair| pair[0] < pair[1])
+    }
+
+    /// What it takes to go from the tweaks of this block to those of `other`, a later version
+    /// of the same block. Both are expected sorted like `canonicalize` leaves them, tweaks out
+    /// of order may show up as both added and removed. A duplicate counts as a tweak of its
+    /// own. Blocks with different blockhashes can't be compared, that's an `InvalidData` error.
+    pub fn diff(&self, other: &BlockData) -> Result<TweakDiff, StorageError> {
+        if self.blockhash != other.blockhash {
+            return Err(StorageError::InvalidData("Can't diff the tweaks of two different blocks"));
+        }
+        let mut diff = TweakDiff::default();
+        let (mut old, mut new) = (self.tweaks.iter().peekable(), other.tweaks.iter().peekable());
+        loop {
+            match (old.peek(), new.peek()) {
+                (Some(a), Some(b)) if a == b => {
+                    old.next();
+                    new.next();
+                }
+                (Some(a), Some(b)) if a < b => diff.removed.push(*old.next().unwrap()),
+                (Some(_), Some(_)) => diff.added.push(*new.next().unwrap()),
+                (Some(_), None) => diff.removed.extend(old.by_ref().copied()),
+                (None, Some(_)) => diff.added.extend(new.by_ref().copied()),
+                (None, None) => return Ok(diff),
+            }
+        }
+    }
+
+    /// Keeps only the tweaks in `keep`, in their order, along with their metadata. For
+    /// cut-through, where `keep` holds the tweaks that still have unspent outputs.
+    pub fn retain_tweaks(&mut self, keep: &HashSet<[u8; TWEAK_SIZE]>) {
+        match self.tweak_meta.take() {
+            Some(meta) if meta.len() == self.tweaks.len() => {
+                let (tweaks, meta) = self.tweaks.drain(..).zip(meta)
+                    .filter(|(tweak, _)| keep.contains(tweak))
+                    .unzip();
+                self.tweaks = tweaks;
+                self.tweak_meta = Some(meta);
+            }
+            meta => {
+                self.tweaks.retain(|tweak| keep.contains(tweak));
+                self.tweak_meta = meta;
+            }
+        }
+    }
+
+    /// Metadata has to come with exactly one entry per tweak, or the record would be read back
+    /// with the wrong metadata, or none at all.
+    pub fn check_tweak_meta(&self) -> Result<(), StorageError> {
+        match &self.tweak_meta {
+            Some(meta) if meta.len() != self.tweaks.len() => {
+                Err(StorageError::InvalidData("Tweak metadata doesn't match the tweaks"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The serialized record `data` without its metadata, as clients that only want the tweaks
+    /// receive it. Compact records are returned as they are, without being checked, others
+    /// are checked and serialized again.
+    pub fn strip_tweak_meta(data: &[u8], max_record_size: usize) -> Result<Cow<'_, [u8]>, StorageError> {
+        let has_meta = data.get(32..36)
+            .is_some_and(|len| u32::from_le_bytes(len.try_into().unwrap()) & TWEAK_META_FLAG != 0);
+        if !has_meta {
+            return Ok(Cow::Borrowed(data));
+        }
+        let mut block = Self::deserialize_limited(data, max_record_size)?;
+        block.tweak_meta = None;
+        Ok(Cow::Owned(block.serialize()))
+    }
+
+    /// Deserialize a BlockData record from a byte slice, of at most DEFAULT_MAX_RECORD_SIZE.
     pub fn deserialize(data: &[u8]) -> Result<BlockData, StorageError> {
+        Self::deserialize_limited(data, DEFAULT_MAX_RECORD_SIZE)
+    }
+
+    /// Same as `deserialize`, for records of at most `max_record_size` bytes. A lenTweaks
+    /// asking for more is rejected with `InvalidTweakCount` before anything is allocated.
+    pub fn deserialize_limited(
+        data: &[u8],
+        max_record_size: usize,
+    ) -> Result<BlockData, StorageError> {
+        Self::deserialize_checked::<Crc32>(data, true, max_record_size)
+    }
+
+    /// Same as `deserialize_limited`, rejecting records whose tweaks aren't canonical (see
+    /// `canonicalize`) with `NonCanonicalTweaks`. The checksum is checked first, so that error
+    /// means the record was written that way.
+    pub fn deserialize_canonical(
+        data: &[u8],
+        max_record_size: usize,
+    ) -> Result<BlockData, StorageError> {
+        let block = Self::deserialize_limited(data, max_record_size)?;
+        if !block.is_canonical() {
+            return Err(StorageError::NonCanonicalTweaks);
+        }
+        Ok(block)
+    }
+
+    /// Deserialize a record of record version `version`, see RECORD_VERSION, of at most
+    /// `max_record_size` bytes.
+    pub fn deserialize_version(
+        data: &[u8],
+        version: u16,
+        max_record_size: usize,
+    ) -> Result<BlockData, StorageError> {
+        match version {
+            RECORD_VERSION => Self::deserialize_checked::<Crc32>(data, true, max_record_size),
+            TWEAKS_CRC_RECORD_VERSION => {
+                Self::deserialize_checked::<Crc32>(data, false, max_record_size)
+            }
+            _ => Err(StorageError::DeserializeError("unknown record version")),
+        }
+    }
+
+    /// Deserialize a record written by `serialize_with::<C>`.
+    pub fn deserialize_with<C: RecordChecksum>(data: &[u8]) -> Result<BlockData, StorageError> {
+        Self::deserialize_checked::<C>(data, true, DEFAULT_MAX_RECORD_SIZE)
+    }
+
+    /// Reads exactly one record from `reader`, of at most DEFAULT_MAX_RECORD_SIZE, and returns
+    /// it along with the number of bytes it took up. `EndOfStream` if the reader ends before
+    /// the record starts, a `DeserializeError` if it ends in the middle of it.
+    pub fn deserialize_from<R: Read>(reader: &mut R) -> Result<(BlockData, u64), StorageError> {
+        Self::deserialize_from_limited(reader, DEFAULT_MAX_RECORD_SIZE)
+    }
+
+    /// Same as `deserialize_from`, for records of at most `max_record_size` bytes.
+    pub fn deserialize_from_limited<R: Read>(
+        reader: &mut R,
+        max_record_size: usize,
+    ) -> Result<(BlockData, u64), StorageError> {
+        let header = read_record_header(reader)?;
+        let tweaks_bytes_len = checked_tweaks_len(&header, max_record_size)?;
+        let mut data = Vec::with_capacity(RECORD_HEADER_SIZE + tweaks_bytes_len);
+        data.extend_from_slice(&header);
+        data.resize(RECORD_HEADER_SIZE + tweaks_bytes_len, 0);
+        reader
+            .read_exact(&mut data[RECORD_HEADER_SIZE..])
+            .map_err(|e| truncated(e, "insufficient data for tweaks"))?;
+        let block = Self::deserialize_limited(&data, max_record_size)?;
+        Ok((block, data.len() as u64))
+    }
+
+    /// Skips over one record of `reader` without reading its tweaks, and returns its
+    /// blockhash and the number of bytes it took up. Nothing is checksummed, and a record
+    /// whose tweaks the stream ends in the middle of only shows on the next read.
+    pub fn skip_from<R: Read + Seek>(reader: &mut R) -> Result<([u8; 32], u64), StorageError> {
+        let header = read_record_header(reader)?;
+        let tweaks_bytes_len = checked_tweaks_len(&header, DEFAULT_MAX_RECORD_SIZE)?;
+        reader.seek(SeekFrom::Current(tweaks_bytes_len as i64))?;
+        let blockhash = header[..32].try_into().unwrap();
+        Ok((blockhash, (RECORD_HEADER_SIZE + tweaks_bytes_len) as u64))
+    }
+
+    /// Deserialize a record whose checksum covers the blockhash and lenTweaks if
+    /// `covers_header`, and only the tweaks otherwise.
+    fn deserialize_checked<C: RecordChecksum>(
+        data: &[u8],
+        covers_header: bool,
+        max_record_size: usize,
+    ) -> Result<BlockData, StorageError> {
+        Ok(BlockDataRef::parse_checked::<C>(data, covers_header, max_record_size)?.to_block_data())
+    }
+}
+
+/// Tweaks a block gained and lost between two versions of it, see `BlockData::diff`. Both lists
+/// are in canonical order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TweakDiff {
+    pub added: Vec<[u8; TWEAK_SIZE]>,
+    pub removed: Vec<[u8; TWEAK_SIZE]>,
+}
+
+impl TweakDiff {
+    /// Whether both versions hold the same tweaks.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// A serialized record, checked once by `parse` and then read in place: the blockhash and
+/// tweaks are handed out as references into it, without copying tens of thousands of tweaks
+/// into a BlockData first.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockDataRef<'a> {
+    /// The record, up to its end.
+    data: &'a [u8],
+    /// Where the tweaks start, after the checksum.
+    tweaks_start: usize,
+    tweak_count: usize,
+    has_meta: bool,
+}
+
+impl<'a> BlockDataRef<'a> {
+    /// Checks the record at the start of `data`, of at most DEFAULT_MAX_RECORD_SIZE, like
+    /// `BlockData::deserialize` does.
+    pub fn parse(data: &'a [u8]) -> Result<Self, StorageError> {
+        Self::parse_limited(data, DEFAULT_MAX_RECORD_SIZE)
+    }
+
+    /// Same as `parse`, for records of at most `max_record_size` bytes.
+    pub fn parse_limited(data: &'a [u8], max_record_size: usize) -> Result<Self, StorageError> {
+        Self::parse_checked::<Crc32>(data, true, max_record_size)
+    }
+
+    fn parse_checked<C: RecordChecksum>(
+        data: &'a [u8],
+        covers_header: bool,
+        max_record_size: usize,
+    ) -> Result<Self, StorageError> {
         let mut pos = 0;
 
         if data.len() < pos + 32 {
             return Err(StorageError::DeserializeError("insufficient data for blockhash"));
         }
-        let mut blockhash = [0u8; 32];
-        blockhash.copy_from_slice(&data[pos..pos+32]);
         pos += 32;
         
         // Read lenTweaks.
         if data.len() < pos + 4 {
             return Err(StorageError::DeserializeError("insufficient data for lenTweaks"));
         }
-        let len_tweaks = u32::from_le_bytes(data[pos..pos+4].try_into().unwrap()) as usize;
+        let stored_len_tweaks = u32::from_le_bytes(data[pos..pos+4].try_into().unwrap());
+        let (len_tweaks, has_meta) = split_len_tweaks(stored_len_tweaks);
+        let len_tweaks = len_tweaks as usize;
         pos += 4;
         
-        // Read CRC32.
-        if data.len() < pos + 4 {
+        // Read the checksum.
+        if data.len() < pos + C::SIZE {
             return Err(StorageError::DeserializeError("insufficient data for CRC"));
         }
-        let crc_stored = u32::from_le_bytes(data[pos..pos+4].try_into().unwrap());
-        pos += 4;
+        let checksum_stored = &data[pos..pos + C::SIZE];
+        pos += C::SIZE;
         
-        // Expected length for tweaks.
-        let tweaks_bytes_len = len_tweaks * TWEAK_SIZE;
-        if data.len() < pos + tweaks_bytes_len {
-            return Err(StorageError::DeserializeError("insufficient data for tweaks"));
+        // Expected length for tweaks and their metadata, which has to fit in the data and the
+        // size limit. On 32-bit targets it may not even fit in a usize.
+        let body_len = len_tweaks
+            .checked_mul(entry_size(has_meta))
+            .filter(|&len| len <= data.len() - pos && pos + len <= max_record_size)
+            .ok_or(StorageError::InvalidTweakCount(stored_len_tweaks))?;
+        let body = &data[pos..pos+body_len];
+        let mut hasher = C::new();
+        if covers_header {
+            hasher.update(&data[..36]);
         }
-        let tweaks_data = &data[pos..pos+tweaks_bytes_len];
-        let mut hasher = Hasher::new();
-        hasher.update(tweaks_data);
-        let crc_computed = hasher.finalize();
+        hasher.update(body);
+        let mut checksum_computed = [0u8; MAX_CHECKSUM_SIZE];
+        hasher.finalize_into(&mut checksum_computed[..C::SIZE]);
         
-        if crc_computed != crc_stored {
+        if &checksum_computed[..C::SIZE] != checksum_stored {
             return Err(StorageError::CrcMismatch);
         }
         
-        let mut tweaks = Vec::with_capacity(len_tweaks);
-        for i in 0..len_tweaks {
-            let start = i * TWEAK_SIZE;
-            let end = start + TWEAK_SIZE;
-            let mut tweak = [0u8; TWEAK_SIZE];
-            tweak.copy_from_slice(&tweaks_data[start..end]);
-            tweaks.push(tweak);
+        Ok(BlockDataRef { data: &data[..pos + body_len], tweaks_start: pos, tweak_count: len_tweaks, has_meta })
+    }
+
+    pub fn blockhash(&self) -> &'a [u8; 32] {
+        self.data[..32].try_into().unwrap()
+    }
+
+    pub fn tweak_count(&self) -> usize {
+        self.tweak_count
+    }
+
+    /// The `i`th tweak, None past the last one.
+    pub fn tweak(&self, i: usize) -> Option<&'a [u8; TWEAK_SIZE]> {
+        (i < self.tweak_count).then(|| {
+            let start = self.tweaks_start + i * TWEAK_SIZE;
+            self.data[start..start + TWEAK_SIZE].try_into().unwrap()
+        })
+    }
+
+    pub fn tweaks(&self) -> impl ExactSizeIterator<Item = &'a [u8; TWEAK_SIZE]> + 'a {
+        self.data[self.tweaks_start..self.tweaks_start + self.tweak_count * TWEAK_SIZE]
+            .chunks_exact(TWEAK_SIZE)
+            .map(|tweak| tweak.try_into().unwrap())
+    }
+
+    /// The metadata of the tweaks, in the same order, if the record has any.
+    pub fn tweak_meta(&self) -> Option<impl ExactSizeIterator<Item = TweakMeta> + 'a> {
+        let meta_start = self.tweaks_start + self.tweak_count * TWEAK_SIZE;
+        self.has_meta.then(|| self.data[meta_start..].chunks_exact(TWEAK_META_SIZE).map(TweakMeta::decode))
+    }
+
+    /// See `BlockData::is_canonical`.
+    pub fn is_canonical(&self) -> bool {
+        self.tweaks().zip(self.tweaks().skip(1)).all(|(tweak, next)| tweak < next)
+    }
+
+    /// The serialized record, without whatever followed it in the data it was parsed from.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Copies the record out into a BlockData, as `BlockData::deserialize` would have.
+    pub fn to_block_data(self) -> BlockData {
+        BlockData {
+            blockhash: *self.blockhash(),
+            tweaks: self.tweaks().copied().collect(),
+            tweak_meta: self.tweak_meta().map(Iterator::collect),
+        }
+    }
+}
+
+/// Reads the fixed part of a record. `EndOfStream` if the reader has nothing left.
+fn read_record_header<R: Read>(reader: &mut R) -> Result<[u8; RECORD_HEADER_SIZE], StorageError> {
+    let mut header = [0u8; RECORD_HEADER_SIZE];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    match filled {
+        0 => Err(StorageError::EndOfStream),
+        RECORD_HEADER_SIZE => Ok(header),
+        _ => Err(StorageError::DeserializeError("insufficient data for record header")),
+    }
+}
+
+/// The tweak count of a stored lenTweaks, and whether the record has metadata.
+fn split_len_tweaks(len_tweaks: u32) -> (u32, bool) {
+    (len_tweaks & !TWEAK_META_FLAG, len_tweaks & TWEAK_META_FLAG != 0)
+}
+
+/// Bytes a tweak takes up in a record, with or without its metadata.
+fn entry_size(has_meta: bool) -> usize {
+    if has_meta { TWEAK_SIZE + TWEAK_META_SIZE } else { TWEAK_SIZE }
+}
+
+/// Length of the tweaks (and metadata) of the record that starts with `header`, if the whole
+/// record fits in `max_record_size`.
+fn checked_tweaks_len(
+    header: &[u8; RECORD_HEADER_SIZE],
+    max_record_size: usize,
+) -> Result<usize, StorageError> {
+    let len_tweaks = u32::from_le_bytes(header[32..36].try_into().unwrap());
+    let (count, has_meta) = split_len_tweaks(len_tweaks);
+    (count as usize)
+        .checked_mul(entry_size(has_meta))
+        .filter(|&len| len <= max_record_size.saturating_sub(RECORD_HEADER_SIZE))
+        .ok_or(StorageError::InvalidTweakCount(len_tweaks))
+}
+
+/// A stream ending early is a truncated record, anything else stays an I/O error.
+fn truncated(e: io::Error, msg: &'static str) -> StorageError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => StorageError::DeserializeError(msg),
+        _ => e.into(),
+    }
+}
+
+/// Hex strings for the byte arrays of BlockData, for `#[serde(with)]`. Written lowercase,
+/// read in either case.
+#[cfg(feature = "serde")]
+mod hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    fn encode<'a>(bytes: impl Iterator<Item = &'a u8>) -> String {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        let mut hex = String::new();
+        for byte in bytes {
+            hex.push(DIGITS[(byte >> 4) as usize] as char);
+            hex.push(DIGITS[(byte & 0xf) as usize] as char);
+        }
+        hex
+    }
+
+    fn decode<const N: usize, E: Error>(hex: &str) -> Result<[u8; N], E> {
+        if hex.len() != 2 * N {
+            return Err(E::invalid_length(hex.len(), &format!("{} hex digits", 2 * N).as_str()));
+        }
+        let mut bytes = [0u8; N];
+        let digit = |c: u8| (c as char).to_digit(16).ok_or_else(|| E::custom(format!("invalid hex digit {:?}", c as char)));
+        for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+            *byte = (digit(pair[0])? << 4 | digit(pair[1])?) as u8;
+        }
+        Ok(bytes)
+    }
+
+    /// Bytes in the order they are stored.
+    pub mod bytes {
+        use super::*;
+
+        pub fn serialize<const N: usize, S: Serializer>(bytes: &[u8; N], serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&encode(bytes.iter()))
+        }
+
+        pub fn deserialize<'de, const N: usize, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; N], D::Error> {
+            decode(&String::deserialize(deserializer)?)
+        }
+    }
+
+    /// Hashes, in the reversed byte order Bitcoin displays them in.
+    pub mod reversed {
+        use super::*;
+
+        pub fn serialize<const N: usize, S: Serializer>(bytes: &[u8; N], serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&encode(bytes.iter().rev()))
+        }
+
+        pub fn deserialize<'de, const N: usize, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; N], D::Error> {
+            let mut bytes: [u8; N] = decode(&String::deserialize(deserializer)?)?;
+            bytes.reverse();
+            Ok(bytes)
+        }
+    }
+
+    /// A list of byte arrays, each in the order it is stored.
+    pub mod list {
+        use super::*;
+        use serde::ser::SerializeSeq;
+
+        pub fn serialize<const N: usize, S: Serializer>(list: &[[u8; N]], serializer: S) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(list.len()))?;
+            for bytes in list {
+                seq.serialize_element(&encode(bytes.iter()))?;
+            }
+            seq.end()
+        }
+
+        pub fn deserialize<'de, const N: usize, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<[u8; N]>, D::Error> {
+            Vec::<String>::deserialize(deserializer)?
+                .iter()
+                .map(|hex| decode(hex))
+                .collect()
         }
-        Ok(BlockData { blockhash, tweaks })
     }
 }
 
@@ -97,6 +649,7 @@ mod tests {
         let block = BlockData {
             blockhash: [1u8; 32],
             tweaks: vec![[2u8; TWEAK_SIZE], [3u8; TWEAK_SIZE]],
+            tweak_meta: None,
         };
 
         let serialized = block.serialize();
@@ -110,6 +663,7 @@ mod tests {
         let mut serialized = BlockData {
             blockhash: [1u8; 32],
             tweaks: vec![[2u8; TWEAK_SIZE]],
+            tweak_meta: None,
         }.serialize();
 
         // Corrupt the data by modifying a tweak
@@ -122,4 +676,560 @@ mod tests {
             Err(StorageError::CrcMismatch)
         ));
     }
-} 
\ No newline at end of file
+
+    struct Xxh3(xxhash_rust::xxh3::Xxh3);
+
+    impl RecordChecksum for Xxh3 {
+        const SIZE: usize = 8;
+
+        fn new() -> Self {
+            Xxh3(xxhash_rust::xxh3::Xxh3::new())
+        }
+
+        fn update(&mut self, data: &[u8]) {
+            self.0.update(data);
+        }
+
+        fn finalize_into(self, out: &mut [u8]) {
+            out.copy_from_slice(&self.0.digest().to_le_bytes());
+        }
+    }
+
+    struct Blake3(blake3::Hasher);
+
+    impl RecordChecksum for Blake3 {
+        const SIZE: usize = 8;
+
+        fn new() -> Self {
+            Blake3(blake3::Hasher::new())
+        }
+
+        fn update(&mut self, data: &[u8]) {
+            self.0.update(data);
+        }
+
+        fn finalize_into(self, out: &mut [u8]) {
+            out.copy_from_slice(&self.0.finalize().as_bytes()[..Self::SIZE]);
+        }
+    }
+
+    fn check_round_trip_and_corruption<C: RecordChecksum>() {
+        let block = BlockData {
+            blockhash: [1u8; 32],
+            tweaks: (0..4u8).map(|i| [i; TWEAK_SIZE]).collect(),
+            tweak_meta: None,
+        };
+
+        let serialized = block.serialize_with::<C>();
+        assert_eq!(serialized.len(), 36 + C::SIZE + 4 * TWEAK_SIZE);
+        assert_eq!(BlockData::deserialize_with::<C>(&serialized).unwrap(), block);
+
+        // Every single-bit flip in the blockhash, the checksum or the tweaks must be detected
+        for byte in (0..32).chain(36..serialized.len()) {
+            for bit in 0..8 {
+                let mut corrupted = serialized.clone();
+                corrupted[byte] ^= 1 << bit;
+                assert!(matches!(
+                    BlockData::deserialize_with::<C>(&corrupted),
+                    Err(StorageError::CrcMismatch)
+                ));
+            }
+        }
+        // A flipped bit in lenTweaks either asks for more tweaks than there are, or fails
+        // the checksum over fewer
+        for byte in 32..36 {
+            for bit in 0..8 {
+                let mut corrupted = serialized.clone();
+                corrupted[byte] ^= 1 << bit;
+                let len_tweaks = u32::from_le_bytes(corrupted[32..36].try_into().unwrap());
+                let result = BlockData::deserialize_with::<C>(&corrupted);
+                if len_tweaks < 4 {
+                    assert!(matches!(result, Err(StorageError::CrcMismatch)));
+                } else {
+                    assert!(matches!(result, Err(StorageError::InvalidTweakCount(n)) if n == len_tweaks));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_record_checksums() {
+        check_round_trip_and_corruption::<Crc32>();
+        check_round_trip_and_corruption::<Xxh3>();
+        check_round_trip_and_corruption::<Blake3>();
+    }
+
+    #[test]
+    fn test_crc32_format_unchanged() {
+        let block = BlockData {
+            blockhash: [1u8; 32],
+            tweaks: vec![[2u8; TWEAK_SIZE]],
+            tweak_meta: None,
+        };
+        let serialized = block.serialize();
+        let mut hasher = Hasher::new();
+        hasher.update(&[1u8; 32]);
+        hasher.update(&1u32.to_le_bytes());
+        hasher.update(&[2u8; TWEAK_SIZE]);
+        assert_eq!(&serialized[36..40], &hasher.finalize().to_le_bytes());
+    }
+
+    #[test]
+    fn test_tweaks_crc_records() {
+        let block = BlockData {
+            blockhash: [1u8; 32],
+            tweaks: vec![[2u8; TWEAK_SIZE], [3u8; TWEAK_SIZE]],
+            tweak_meta: None,
+        };
+        let mut legacy = block.serialize();
+        let mut hasher = Hasher::new();
+        hasher.update(&legacy[RECORD_HEADER_SIZE..]);
+        legacy[36..40].copy_from_slice(&hasher.finalize().to_le_bytes());
+
+        // Each version is only read with its own checksum
+        assert_eq!(
+            BlockData::deserialize_version(&legacy, TWEAKS_CRC_RECORD_VERSION, DEFAULT_MAX_RECORD_SIZE).unwrap(),
+            block
+        );
+        assert!(matches!(
+            BlockData::deserialize(&legacy),
+            Err(StorageError::CrcMismatch)
+        ));
+        assert!(matches!(
+            BlockData::deserialize_version(&block.serialize(), TWEAKS_CRC_RECORD_VERSION, DEFAULT_MAX_RECORD_SIZE),
+            Err(StorageError::CrcMismatch)
+        ));
+        assert!(matches!(
+            BlockData::deserialize_version(&legacy, RECORD_VERSION + 1, DEFAULT_MAX_RECORD_SIZE),
+            Err(StorageError::DeserializeError(_))
+        ));
+
+        // Which is why they're checksummed again: their blockhash isn't covered
+        legacy[0] ^= 1;
+        assert!(BlockData::deserialize_version(&legacy, TWEAKS_CRC_RECORD_VERSION, DEFAULT_MAX_RECORD_SIZE).is_ok());
+    }
+
+    #[test]
+    fn test_serialize_into() {
+        for num_tweaks in [0, 1, 3000] {
+            let block = BlockData {
+                blockhash: [9u8; 32],
+                tweaks: (0..num_tweaks).map(|i| [i as u8; TWEAK_SIZE]).collect(),
+                tweak_meta: None,
+            };
+            let mut written = vec![0xaa];
+            let len = block.serialize_into(&mut written).unwrap();
+            assert_eq!(&written[1..], &block.serialize()[..]);
+            assert_eq!(len, block.serialized_len());
+            assert_eq!(len, written.len() as u64 - 1);
+            let header = written[1..1 + RECORD_HEADER_SIZE].try_into().unwrap();
+            assert_eq!(BlockData::serialized_len_from_header(header) as u64, len);
+        }
+    }
+
+    #[test]
+    fn test_oversized_tweak_count() {
+        let block = BlockData {
+            blockhash: [1u8; 32],
+            tweaks: vec![[2u8; TWEAK_SIZE]; 3],
+            tweak_meta: None,
+        };
+        let serialized = block.serialize();
+
+        // A length whose byte size overflows a 32-bit usize, with a few tweaks behind it
+        let mut oversized = serialized.clone();
+        oversized[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            BlockData::deserialize(&oversized),
+            Err(StorageError::InvalidTweakCount(u32::MAX))
+        ));
+
+        // Within the data, but over the size limit
+        assert!(matches!(
+            BlockData::deserialize_limited(&serialized, serialized.len() - 1),
+            Err(StorageError::InvalidTweakCount(3))
+        ));
+        assert_eq!(
+            BlockData::deserialize_limited(&serialized, serialized.len()).unwrap(),
+            block
+        );
+        let mut over_default = vec![0u8; DEFAULT_MAX_RECORD_SIZE + TWEAK_SIZE];
+        let len_tweaks = ((over_default.len() - RECORD_HEADER_SIZE) / TWEAK_SIZE) as u32;
+        over_default[32..36].copy_from_slice(&len_tweaks.to_le_bytes());
+        assert!(matches!(
+            BlockData::deserialize(&over_default),
+            Err(StorageError::InvalidTweakCount(n)) if n == len_tweaks
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_from_stream() {
+        let blocks: Vec<BlockData> = (0..5u8)
+            .map(|i| BlockData {
+                blockhash: [i; 32],
+                tweaks: vec![[i; TWEAK_SIZE]; i as usize],
+                tweak_meta: None,
+            })
+            .collect();
+        let buffer: Vec<u8> = blocks.iter().flat_map(|block| block.serialize()).collect();
+
+        let mut reader = &buffer[..];
+        for block in &blocks {
+            let (read, consumed) = BlockData::deserialize_from(&mut reader).unwrap();
+            assert_eq!(&read, block);
+            assert_eq!(consumed, block.serialize().len() as u64);
+        }
+        assert!(matches!(
+            BlockData::deserialize_from(&mut reader),
+            Err(StorageError::EndOfStream)
+        ));
+
+        // Skipping lands on the same record boundaries
+        let mut cursor = io::Cursor::new(&buffer);
+        for block in &blocks {
+            let (blockhash, consumed) = BlockData::skip_from(&mut cursor).unwrap();
+            assert_eq!(blockhash, block.blockhash);
+            assert_eq!(consumed, block.serialize().len() as u64);
+        }
+        assert_eq!(cursor.position(), buffer.len() as u64);
+        assert!(matches!(
+            BlockData::skip_from(&mut cursor),
+            Err(StorageError::EndOfStream)
+        ));
+        cursor.set_position(0);
+        BlockData::skip_from(&mut cursor).unwrap();
+        assert_eq!(BlockData::deserialize_from(&mut cursor).unwrap().0, blocks[1]);
+
+        // A stream ending in the header or the tweaks of a record is not its end
+        for end in [buffer.len() - 1, buffer.len() - 4 * TWEAK_SIZE - 2] {
+            let mut reader = &buffer[..end];
+            for _ in 0..4 {
+                BlockData::deserialize_from(&mut reader).unwrap();
+            }
+            assert!(matches!(
+                BlockData::deserialize_from(&mut reader),
+                Err(StorageError::DeserializeError(_))
+            ));
+        }
+
+        // Nor is a damaged record
+        let mut damaged = buffer.clone();
+        damaged[0] ^= 1;
+        assert!(matches!(
+            BlockData::deserialize_from(&mut &damaged[..]),
+            Err(StorageError::CrcMismatch)
+        ));
+        let mut oversized = buffer.clone();
+        oversized[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            BlockData::deserialize_from(&mut &oversized[..]),
+            Err(StorageError::InvalidTweakCount(u32::MAX))
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_random_prefixes() {
+        use rand::Rng;
+        let mut rng = rand::rng();
+        let block = BlockData {
+            blockhash: rng.random(),
+            tweaks: (0..20).map(|_| [rng.random(); TWEAK_SIZE]).collect(),
+            tweak_meta: None,
+        };
+        let serialized = block.serialize();
+        // Every cut short record is an error, never a panic or a huge allocation
+        for end in 0..serialized.len() {
+            assert!(BlockData::deserialize(&serialized[..end]).is_err());
+        }
+
+        for _ in 0..10_000 {
+            let mut data = vec![0u8; rng.random_range(0..200)];
+            rng.fill(&mut data[..]);
+            if data.len() >= 36 && rng.random_bool(0.5) {
+                // Mostly huge lengths, the interesting ones
+                let len_tweaks: u32 = rng.random_range(u32::MAX / 2..=u32::MAX);
+                data[32..36].copy_from_slice(&len_tweaks.to_le_bytes());
+            }
+            let _ = BlockData::deserialize(&data);
+        }
+    }
+
+    fn block_with_meta(num_tweaks: u8) -> BlockData {
+        BlockData {
+            blockhash: [7u8; 32],
+            tweaks: (0..num_tweaks).map(|i| [i; TWEAK_SIZE]).collect(),
+            tweak_meta: Some((0..num_tweaks).map(|i| TweakMeta {
+                txid: [i; 32],
+                vout: i as u32,
+                value: 1000 * i as u64,
+                output_key: [!i; 32],
+            }).collect()),
+        }
+    }
+
+    #[test]
+    fn test_tweak_meta_serialization() {
+        let block = block_with_meta(3);
+        let serialized = block.serialize();
+        assert_eq!(serialized.len(), RECORD_HEADER_SIZE + 3 * (TWEAK_SIZE + TWEAK_META_SIZE));
+        assert_eq!(serialized.len() as u64, block.serialized_len());
+        let header = serialized[..RECORD_HEADER_SIZE].try_into().unwrap();
+        assert_eq!(BlockData::serialized_len_from_header(header), serialized.len());
+        assert_eq!(BlockData::deserialize(&serialized).unwrap(), block);
+        let mut written = Vec::new();
+        block.serialize_into(&mut written).unwrap();
+        assert_eq!(written, serialized);
+
+        // The checksum covers the metadata as well
+        for byte in RECORD_HEADER_SIZE + 3 * TWEAK_SIZE..serialized.len() {
+            let mut corrupted = serialized.clone();
+            corrupted[byte] ^= 1;
+            assert!(matches!(
+                BlockData::deserialize(&corrupted),
+                Err(StorageError::CrcMismatch)
+            ));
+        }
+        // And a record cut off in its metadata is too short for its tweak count
+        assert!(matches!(
+            BlockData::deserialize(&serialized[..serialized.len() - 1]),
+            Err(StorageError::InvalidTweakCount(n)) if n == 3 | TWEAK_META_FLAG
+        ));
+
+        // Without metadata the record is the compact one, flag unset
+        let compact = BlockData { tweak_meta: None, ..block_with_meta(3) };
+        let compact_serialized = compact.serialize();
+        assert_eq!(&compact_serialized[32..36], &3u32.to_le_bytes());
+        assert_eq!(compact_serialized.len(), RECORD_HEADER_SIZE + 3 * TWEAK_SIZE);
+
+        // Stripping the metadata gives the compact record, which is left as it is
+        let stripped = BlockData::strip_tweak_meta(&serialized, DEFAULT_MAX_RECORD_SIZE).unwrap();
+        assert_eq!(&stripped[..], &compact_serialized[..]);
+        assert!(matches!(
+            BlockData::strip_tweak_meta(&compact_serialized, DEFAULT_MAX_RECORD_SIZE).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        let mut corrupted = serialized.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(BlockData::strip_tweak_meta(&corrupted, DEFAULT_MAX_RECORD_SIZE).is_err());
+
+        // Both forms in one stream
+        let buffer: Vec<u8> = [compact_serialized, serialized].concat();
+        let mut reader = &buffer[..];
+        assert_eq!(BlockData::deserialize_from(&mut reader).unwrap().0, compact);
+        assert_eq!(BlockData::deserialize_from(&mut reader).unwrap().0, block);
+        let mut cursor = io::Cursor::new(&buffer);
+        BlockData::skip_from(&mut cursor).unwrap();
+        assert_eq!(BlockData::skip_from(&mut cursor).unwrap().1, block.serialized_len());
+        assert_eq!(cursor.position(), buffer.len() as u64);
+    }
+
+    #[test]
+    fn test_tweak_meta() {
+        let mut block = block_with_meta(2);
+        assert!(block.check_tweak_meta().is_ok());
+        block.tweaks.pop();
+        assert!(matches!(block.check_tweak_meta(), Err(StorageError::InvalidData(_))));
+
+        let meta = block.tweak_meta.unwrap()[1];
+        let script = meta.script_pubkey();
+        assert_eq!(&script[..2], &[0x51, 0x20]);
+        assert_eq!(&script[2..], &meta.output_key);
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let mut block = BlockData {
+            blockhash: [1u8; 32],
+            tweaks: vec![[3u8; TWEAK_SIZE], [1u8; TWEAK_SIZE], [3u8; TWEAK_SIZE], [2u8; TWEAK_SIZE], [1u8; TWEAK_SIZE]],
+            tweak_meta: None,
+        };
+        assert!(!block.is_canonical());
+        let shuffled = block.serialize();
+        assert!(matches!(
+            BlockData::deserialize_canonical(&shuffled, DEFAULT_MAX_RECORD_SIZE),
+            Err(StorageError::NonCanonicalTweaks)
+        ));
+
+        block.canonicalize();
+        assert_eq!(block.tweaks, vec![[1u8; TWEAK_SIZE], [2u8; TWEAK_SIZE], [3u8; TWEAK_SIZE]]);
+        assert!(block.is_canonical());
+        // The checksum is over the canonical tweaks, and any other order is another record
+        let canonical = block.serialize();
+        assert_eq!(BlockData::deserialize_canonical(&canonical, DEFAULT_MAX_RECORD_SIZE).unwrap(), block);
+        let mut swapped = canonical.clone();
+        swapped[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + 2 * TWEAK_SIZE].rotate_left(TWEAK_SIZE);
+        assert!(matches!(BlockData::deserialize(&swapped), Err(StorageError::CrcMismatch)));
+
+        // Sorted input, or none at all, is left as it is
+        let before = block.clone();
+        block.canonicalize();
+        assert_eq!(block, before);
+        let mut empty = BlockData { blockhash: [1u8; 32], tweaks: Vec::new(), tweak_meta: None };
+        empty.canonicalize();
+        assert!(empty.is_canonical() && empty.tweaks.is_empty());
+
+        // Two scans finding the tweaks in another order store the same bytes
+        let mut other_scan = BlockData {
+            blockhash: [1u8; 32],
+            tweaks: vec![[2u8; TWEAK_SIZE], [3u8; TWEAK_SIZE], [1u8; TWEAK_SIZE]],
+            tweak_meta: None,
+        };
+        other_scan.canonicalize();
+        assert_eq!(other_scan.serialize(), canonical);
+    }
+
+    #[test]
+    fn test_canonicalize_tweak_meta() {
+        let mut block = block_with_meta(3);
+        block.tweaks.reverse();
+        block.tweaks.push(block.tweaks[0]);
+        let mut meta = block.tweak_meta.take().unwrap();
+        meta.push(meta[0]);
+        meta[3].vout = 99;
+        block.tweak_meta = Some(meta.clone());
+
+        block.canonicalize();
+        assert_eq!(block.tweaks, (0..3u8).map(|i| [i; TWEAK_SIZE]).collect::<Vec<_>>());
+        // Each tweak keeps its own metadata, the duplicate that of its first occurrence
+        assert_eq!(block.tweak_meta, Some(vec![meta[2], meta[1], meta[0]]));
+        assert_eq!(BlockData::deserialize_canonical(&block.serialize(), DEFAULT_MAX_RECORD_SIZE).unwrap(), block);
+    }
+
+    #[test]
+    fn test_diff() {
+        let tweaks = |bytes: &[u8]| bytes.iter().map(|&b| [b; TWEAK_SIZE]).collect::<Vec<_>>();
+        let block = |bytes: &[u8]| BlockData { blockhash: [1u8; 32], tweaks: tweaks(bytes), tweak_meta: None };
+        let old = block(&[1, 3, 4, 6]);
+
+        assert!(old.diff(&old).unwrap().is_empty());
+        assert!(block(&[]).diff(&block(&[])).unwrap().is_empty());
+        let diff = old.diff(&block(&[2, 3, 6, 7])).unwrap();
+        assert_eq!(diff, TweakDiff { added: tweaks(&[2, 7]), removed: tweaks(&[1, 4]) });
+        // Cut-through removing everything, and the way back
+        let all_cut = old.diff(&block(&[])).unwrap();
+        assert_eq!(all_cut, TweakDiff { added: Vec::new(), removed: old.tweaks.clone() });
+        assert_eq!(block(&[]).diff(&old).unwrap().added, old.tweaks);
+        // Dropping a duplicate changes the block
+        let diff = block(&[1, 1, 3]).diff(&block(&[1, 3])).unwrap();
+        assert_eq!(diff, TweakDiff { added: Vec::new(), removed: tweaks(&[1]) });
+
+        let mut other_block = old.clone();
+        other_block.blockhash = [2u8; 32];
+        assert!(matches!(old.diff(&other_block), Err(StorageError::InvalidData(_))));
+    }
+
+    #[test]
+    fn test_retain_tweaks() {
+        let mut block = block_with_meta(4);
+        let meta = block.tweak_meta.clone().unwrap();
+        let keep = HashSet::from([[1u8; TWEAK_SIZE], [3u8; TWEAK_SIZE], [9u8; TWEAK_SIZE]]);
+        block.retain_tweaks(&keep);
+        assert_eq!(block.tweaks, vec![[1u8; TWEAK_SIZE], [3u8; TWEAK_SIZE]]);
+        assert_eq!(block.tweak_meta, Some(vec![meta[1], meta[3]]));
+        block.check_tweak_meta().unwrap();
+
+        block.tweak_meta = None;
+        block.retain_tweaks(&HashSet::new());
+        assert!(block.tweaks.is_empty() && block.tweak_meta.is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_json() {
+        let mut blockhash = [0u8; 32];
+        blockhash[0] = 0x6f;
+        blockhash[31] = 0xab;
+        let mut tweak = [0u8; TWEAK_SIZE];
+        tweak[0] = 0x02;
+        tweak[32] = 0xef;
+        let block = BlockData { blockhash, tweaks: vec![tweak], tweak_meta: None };
+
+        // Blockhash reversed, tweaks as stored, lowercase, no metadata unless there is some
+        let json = serde_json::to_string(&block).unwrap();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"blockhash":"ab{}6f","tweaks":["02{}ef"]}}"#,
+                "00".repeat(30),
+                "00".repeat(31)
+            )
+        );
+        assert_eq!(serde_json::from_str::<BlockData>(&json).unwrap(), block);
+        let uppercase = json.replace("ab", "AB").replace("ef", "EF");
+        assert_eq!(serde_json::from_str::<BlockData>(&uppercase).unwrap(), block);
+
+        let block = block_with_meta(2);
+        let value: serde_json::Value = serde_json::to_value(&block).unwrap();
+        assert_eq!(value["tweak_meta"][1]["txid"], "01".repeat(32));
+        assert_eq!(value["tweak_meta"][1]["output_key"], "fe".repeat(32));
+        assert_eq!(value["tweak_meta"][1]["vout"], 1);
+        assert_eq!(value["tweak_meta"][1]["value"], 1000);
+        assert_eq!(serde_json::from_value::<BlockData>(value).unwrap(), block);
+
+        // Hex of the wrong length, or not hex at all
+        for bad in [
+            format!(r#"{{"blockhash":"{}","tweaks":[]}}"#, "00".repeat(31)),
+            format!(r#"{{"blockhash":"{}","tweaks":[]}}"#, "0g".repeat(32)),
+            format!(r#"{{"blockhash":"{}","tweaks":["{}"]}}"#, "00".repeat(32), "00".repeat(32)),
+        ] {
+            assert!(serde_json::from_str::<BlockData>(&bad).is_err());
+        }
+    }
+
+    #[test]
+    fn test_block_data_ref() {
+        use rand::Rng;
+        let mut rng = rand::rng();
+        for num_tweaks in [0, 1, 7, 2000] {
+            for with_meta in [false, true] {
+                let mut block = BlockData {
+                    blockhash: rng.random(),
+                    tweaks: (0..num_tweaks).map(|_| [rng.random(); TWEAK_SIZE]).collect(),
+                    tweak_meta: None,
+                };
+                if with_meta {
+                    block.tweak_meta = Some(block.tweaks.iter().map(|tweak| TweakMeta {
+                        txid: rng.random(),
+                        vout: rng.random(),
+                        value: rng.random(),
+                        output_key: tweak[1..].try_into().unwrap(),
+                    }).collect());
+                }
+                let mut serialized = block.serialize();
+                let len = serialized.len();
+                // Whatever follows the record isn't part of it
+                serialized.extend_from_slice(&[0xaa; 10]);
+
+                let view = BlockDataRef::parse(&serialized).unwrap();
+                assert_eq!(view.as_bytes().len(), len);
+                assert_eq!(view.blockhash(), &block.blockhash);
+                assert_eq!(view.tweak_count(), num_tweaks);
+                assert!(view.tweaks().eq(block.tweaks.iter()));
+                assert_eq!(view.tweaks().len(), num_tweaks);
+                for (i, tweak) in block.tweaks.iter().enumerate() {
+                    assert_eq!(view.tweak(i), Some(tweak));
+                }
+                assert_eq!(view.tweak(num_tweaks), None);
+                assert_eq!(view.tweak_meta().map(Iterator::collect), block.tweak_meta);
+                assert_eq!(view.is_canonical(), block.is_canonical());
+                assert_eq!(view.to_block_data(), BlockData::deserialize(&serialized).unwrap());
+            }
+        }
+
+        // Rejected for the same reasons as by deserialize
+        let serialized = block_with_meta(3).serialize();
+        for end in 0..serialized.len() {
+            assert_eq!(
+                BlockDataRef::parse(&serialized[..end]).unwrap_err().to_string(),
+                BlockData::deserialize(&serialized[..end]).unwrap_err().to_string()
+            );
+        }
+        let mut corrupted = serialized.clone();
+        corrupted[50] ^= 1;
+        assert!(matches!(BlockDataRef::parse(&corrupted), Err(StorageError::CrcMismatch)));
+        assert!(matches!(
+            BlockDataRef::parse_limited(&serialized, serialized.len() - 1),
+            Err(StorageError::InvalidTweakCount(_))
+        ));
+    }
+}
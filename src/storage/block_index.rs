@@ -1,15 +1,46 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
 use std::path::PathBuf;
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use sled::Db;
+use log::{info, warn};
+use sled::transaction::TransactionError;
+use sled::{Db, Transactional};
 
 use super::StorageError;
 
+const HEIGHT_TO_HASH_TREE: &str = "height_to_hash";
+const HASH_TO_HEIGHT_TREE: &str = "hash_to_height";
+const QUARANTINE_TREE: &str = "quarantine";
+const META_TREE: &str = "meta";
+const DEAD_BYTES_TREE: &str = "dead_bytes";
+const TIERS_TREE: &str = "tiers";
+const CHECKPOINT_TREE: &str = "checkpoint";
+const RANGE_BYTES_TREE: &str = "range_bytes";
+
+/// Keys of the checkpoint tree.
+const CHECKPOINT_TIP_KEY: &[u8] = b"tip";
+const SYNC_STATE_KEY: &[u8] = b"sync_state";
+/// Length of the checkpoint of the tip:
+/// [height (4 bytes)] [blockhash (32 bytes)] [last added (8 bytes)]
+const CHECKPOINT_TIP_SIZE: usize = 44;
+
+/// How long opening the index waits for a lock that sled hasn't released yet.
+const OPEN_LOCK_RETRIES: u32 = 40;
+const OPEN_LOCK_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Number of recent blocks kept in memory by default (see RecentChain).
+pub const DEFAULT_RECENT_WINDOW: usize = 1_000;
+
 // TODO: Benchmark this with a HashMap Implementation
 // I have a inkling the BTree used by sled is going to be a perform better than a HashMap based implementation.
 
 /// IndexEntry represents the file number, offset, and length of a block
 /// (number of outputs) in the flat file store.
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct IndexEntry {
     pub file_number: u64,
     pub offset: u64,
@@ -40,6 +71,133 @@ impl IndexEntry {
     }
 }
 
+/// A (key, value) pair from the metadata tree.
+pub type MetaEntry = (Vec<u8>, Vec<u8>);
+
+/// A block off the chain, with its entry unless it was marked orphaned.
+pub type OffChainEntry = ([u8; 32], Option<IndexEntry>);
+
+/// The tree an IndexEntry is kept in, and its key there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKey {
+    /// A block in the entry tree, keyed by blockhash.
+    Block([u8; 32]),
+    /// A quarantined block, keyed by [height (4 bytes)][blockhash (32 bytes)].
+    Quarantined(Vec<u8>),
+    /// A dust tier of a block, keyed by [blockhash (32 bytes)][dust threshold (8 bytes BE)].
+    Tier(Vec<u8>),
+}
+
+/// In-memory height <-> hash map of the last `window` blocks of the chain.
+/// Reorg handling only ever walks the most recent few hundred blocks, so keeping them here
+/// saves a sled lookup per height. It is updated together with the trees on every insert and
+/// removal, so it never disagrees with them.
+struct RecentChain {
+    window: usize,
+    /// Height of by_height[0]
+    start_height: u32,
+    by_height: VecDeque<[u8; 32]>,
+    by_hash: HashMap<[u8; 32], u32>,
+}
+
+impl RecentChain {
+    fn new(window: usize) -> Self {
+        RecentChain {
+            window,
+            start_height: 0,
+            by_height: VecDeque::with_capacity(window),
+            by_hash: HashMap::with_capacity(window),
+        }
+    }
+
+    fn get_blockhash(&self, height: u32) -> Option<[u8; 32]> {
+        let position = height.checked_sub(self.start_height)? as usize;
+        self.by_height.get(position).copied()
+    }
+
+    fn get_height(&self, blockhash: &[u8; 32]) -> Option<u32> {
+        self.by_hash.get(blockhash).copied()
+    }
+
+    /// Appends the new tip, evicting the oldest block once the window is full.
+    fn push_tip(&mut self, height: u32, blockhash: [u8; 32]) {
+        if self.window == 0 {
+            return;
+        }
+        if self.by_height.is_empty() {
+            self.start_height = height;
+        }
+        debug_assert_eq!(height, self.start_height + self.by_height.len() as u32);
+
+        self.by_height.push_back(blockhash);
+        self.by_hash.insert(blockhash, height);
+        if self.by_height.len() > self.window {
+            if let Some(evicted) = self.by_height.pop_front() {
+                self.by_hash.remove(&evicted);
+            }
+            self.start_height += 1;
+        }
+    }
+
+    /// Drops the tip. `older` supplies the block just below the window so it stays full
+    /// during deep reorgs.
+    fn pop_tip(&mut self, older: impl FnOnce(u32) -> Option<[u8; 32]>) {
+        if let Some(blockhash) = self.by_height.pop_back() {
+            self.by_hash.remove(&blockhash);
+        }
+        if self.by_height.is_empty() || self.start_height == 0 {
+            return;
+        }
+        if let Some(blockhash) = older(self.start_height - 1) {
+            self.start_height -= 1;
+            self.by_height.push_front(blockhash);
+            self.by_hash.insert(blockhash, self.start_height);
+        }
+    }
+}
+
+/// A block moved out of the index because it was found past a hole in the height mappings.
+#[derive(Debug, PartialEq, Eq)]
+pub struct QuarantinedBlock {
+    pub height: u32,
+    pub blockhash: [u8; 32],
+    pub entry: Option<IndexEntry>,
+}
+
+/// Where syncing left off, for resuming it after a restart: the tip of the chain, kept in step
+/// with the height mappings on every insert and removal, along with the sync layer's own state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub height: u32,
+    pub blockhash: [u8; 32],
+    /// When a block was last added, in seconds since the Unix epoch. 0 if it isn't known,
+    /// for an index from before checkpoints were kept.
+    pub last_added: u64,
+    /// Opaque to the index (a chain-work or locator blob), see `set_sync_state`. Empty until
+    /// it is first set.
+    pub sync_state: Vec<u8>,
+}
+
+fn encode_checkpoint_tip(height: u32, blockhash: &[u8; 32], last_added: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(CHECKPOINT_TIP_SIZE);
+    buf.extend_from_slice(&height.to_le_bytes());
+    buf.extend_from_slice(blockhash);
+    buf.extend_from_slice(&last_added.to_le_bytes());
+    buf
+}
+
+/// (height, blockhash, last added) of a checkpoint of the tip.
+fn decode_checkpoint_tip(data: &[u8]) -> Option<(u32, [u8; 32], u64)> {
+    if data.len() != CHECKPOINT_TIP_SIZE {
+        return None;
+    }
+    Some((
+        u32::from_le_bytes(data[0..4].try_into().unwrap()),
+        data[4..36].try_into().unwrap(),
+        u64::from_le_bytes(data[36..44].try_into().unwrap()),
+    ))
+}
+
 pub struct Index {
     /// Maps blockhash -> IndexEntry
     index_db: Db,
@@ -51,72 +209,595 @@ pub struct Index {
     // TODO: Fix this shit.
     height_to_hash: sled::Tree,
     hash_to_height: sled::Tree,
+    /// Blocks found beyond a hole in the height mappings on startup,
+    /// keyed by [height (4 bytes)][blockhash (32 bytes)] -> serialized IndexEntry (if any)
+    quarantine: sled::Tree,
+    /// Store level metadata (data directory version, ...), keyed by name
+    meta: sled::Tree,
+    /// Bytes of each block data file taken by records no entry points to anymore,
+    /// keyed by [file number (8 bytes BE)] -> [dead bytes (8 bytes LE)]
+    dead_bytes: sled::Tree,
+    /// Records of the dust tiers of blocks,
+    /// keyed by [blockhash (32 bytes)][dust threshold (8 bytes BE)] -> serialized IndexEntry
+    tiers: sled::Tree,
+    /// The Checkpoint, keyed by CHECKPOINT_TIP_KEY and SYNC_STATE_KEY
+    checkpoint: sled::Tree,
+    /// Bytes every block of the chain is streamed as, from height 0, kept as a Fenwick tree:
+    /// the bytes of any range of heights take O(log n) lookups, and so does changing those of
+    /// one block. Keyed by [position (height + 1, 4 bytes BE)] -> [bytes of the lowbit(position)
+    /// heights up to it (8 bytes LE)]
+    range_bytes: sled::Tree,
+    /// Where the chain ends. Changes to the chain hold the write lock throughout, so readers
+    /// never see the trees and the tip disagree, and changes never interleave.
+    chain: RwLock<ChainState>,
+    /// First missing height, if entries past it had to be quarantined when opening.
+    hole_on_open: Option<u32>,
+}
+
+struct ChainState {
     next_height: u32,
+    recent: RecentChain,
+}
+
+/// Opens the sled database, waiting a little if it is still locked. sled releases the lock
+/// from its background threads, so a database that was just closed by this process can stay
+/// locked for a moment. A lock held by another process is still an error once that runs out.
+pub(crate) fn open_db(db_path: &PathBuf) -> Result<Db, StorageError> {
+    let mut attempt = 0;
+    loop {
+        match sled::open(db_path) {
+            Err(sled::Error::Io(e)) if attempt < OPEN_LOCK_RETRIES && is_lock_error(&e) => {
+                attempt += 1;
+                thread::sleep(OPEN_LOCK_RETRY_DELAY);
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// sled reports a held lock as a plain io error, only the message tells it apart.
+fn is_lock_error(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Other && e.to_string().starts_with("could not acquire lock")
+}
+
+impl Drop for Index {
+    fn drop(&mut self) {
+        // sled only flushes in the background every so often, don't lose the last writes
+        if let Err(e) = self.index_db.flush() {
+            warn!(target: "Index", "Failed to flush the index on close: {}", e);
+        }
+    }
 }
 
 impl Index {
     /// Returns (Index, bool) where the bool indicates if the database was newly created (true) or already existed (false)
     pub fn initialize(db_path: &PathBuf) -> Result<(Self, bool), StorageError> {
-        let index_db = sled::open(db_path)?;
-        let height_to_hash = index_db.open_tree("height_to_hash")?;
-        let hash_to_height = index_db.open_tree("hash_to_height")?;
+        Self::initialize_with_recent_window(db_path, DEFAULT_RECENT_WINDOW)
+    }
+
+    /// Same as `initialize`, keeping the last `recent_window` blocks in memory (0 disables it).
+    pub fn initialize_with_recent_window(
+        db_path: &PathBuf,
+        recent_window: usize,
+    ) -> Result<(Self, bool), StorageError> {
+        let (mut index, is_new) = Self::open(db_path, recent_window)?;
+        if !is_new {
+            let next_height = index.recover_next_height()?;
+            index.load_recent_chain(next_height)?;
+            index.repair_checkpoint()?;
+        }
+
+        Ok((index, is_new))
+    }
+
+    /// Opens the trees without looking for the tip, for migrations that have to run before
+    /// the height mappings can be read (see `reencode_height_keys`). Only the metadata and the
+    /// migrations themselves can be trusted on the returned index.
+    pub fn open_for_migration(db_path: &PathBuf) -> Result<Self, StorageError> {
+        Ok(Self::open(db_path, 0)?.0)
+    }
+
+    fn open(db_path: &PathBuf, recent_window: usize) -> Result<(Self, bool), StorageError> {
+        let index_db = open_db(db_path)?;
+        let height_to_hash = index_db.open_tree(HEIGHT_TO_HASH_TREE)?;
+        let hash_to_height = index_db.open_tree(HASH_TO_HEIGHT_TREE)?;
+        let quarantine = index_db.open_tree(QUARANTINE_TREE)?;
+        let meta = index_db.open_tree(META_TREE)?;
+        let dead_bytes = index_db.open_tree(DEAD_BYTES_TREE)?;
+        let tiers = index_db.open_tree(TIERS_TREE)?;
+        let checkpoint = index_db.open_tree(CHECKPOINT_TREE)?;
+        let range_bytes = index_db.open_tree(RANGE_BYTES_TREE)?;
 
         // was_recovered() returns true if the database was recovered from a previous instance
         let is_new = !index_db.was_recovered();
 
-        let next_height = if is_new {
-            0
-        } else {
-            let data = height_to_hash.last()?;
-
-            if let Some((height, _)) = data {
-                let height_bytes: [u8; 4] = height
-                    .as_ref()
-                    .try_into()
-                    .expect("IndexDb corrupted, height is not 4 bytes");
-                u32::from_le_bytes(height_bytes) + 1
-            } else {
-                0
+        let index = Index {
+            index_db,
+            height_to_hash,
+            hash_to_height,
+            quarantine,
+            meta,
+            dead_bytes,
+            tiers,
+            checkpoint,
+            range_bytes,
+            chain: RwLock::new(ChainState {
+                next_height: 0,
+                recent: RecentChain::new(recent_window),
+            }),
+            hole_on_open: None,
+        };
+        Ok((index, is_new))
+    }
+
+    /// Rewrites the little-endian height_to_hash keys of data directory version 1 and older
+    /// big-endian, in a single batch. hash_to_height tells which height every entry is for,
+    /// so keys that are already big-endian are left alone and running it again is harmless.
+    /// Returns the number of keys rewritten.
+    pub fn reencode_height_keys(&self) -> Result<usize, StorageError> {
+        let count = self.height_to_hash.len();
+        let mut moved = Vec::new();
+        for item in self.height_to_hash.iter() {
+            let (key, blockhash) = item?;
+            let height = self.db_height_by_blockhash(&decode_blockhash(&blockhash)?)?;
+            if key[..] == height_key(height) {
+                continue;
+            }
+            if key[..] != height.to_le_bytes() {
+                return Err(StorageError::CorruptDB(
+                    "height_to_hash key disagrees with hash_to_height",
+                ));
+            }
+            moved.push((key, height, blockhash));
+        }
+
+        // An old key can be the new key of another height (256 little-endian is 65536
+        // big-endian), so every removal goes in before the inserts that may override it
+        let mut batch = sled::Batch::default();
+        for (key, _, _) in &moved {
+            batch.remove(key.clone());
+        }
+        for (_, height, blockhash) in &moved {
+            batch.insert(&height_key(*height)[..], blockhash.clone());
+        }
+        self.height_to_hash.apply_batch(batch)?;
+
+        if self.height_to_hash.len() != count {
+            return Err(StorageError::CorruptDB(
+                "height_to_hash lost entries while re-encoding its keys",
+            ));
+        }
+        Ok(moved.len())
+    }
+
+    /// Works out where the next block goes on an existing database.
+    /// An interrupted rebuild or repair can leave a hole in height_to_hash, or leave the other
+    /// trees with entries past the end of it, so we can't just trust the last key. The tip is
+    /// placed at the end of the contiguous run of heights starting at 0, and anything beyond
+    /// that is moved into the quarantine tree.
+    fn recover_next_height(&mut self) -> Result<u32, StorageError> {
+        let next_height = self.contiguous_height_end()?;
+
+        let mut beyond_hole = Vec::new();
+        for item in self.height_to_hash.iter() {
+            let (height, blockhash) = item?;
+            let height = decode_height_key(&height)?;
+            if height >= next_height {
+                beyond_hole.push((height, decode_blockhash(&blockhash)?));
+            }
+        }
+        for item in self.hash_to_height.iter() {
+            let (blockhash, height) = item?;
+            let height = decode_height(&height)?;
+            if height >= next_height {
+                beyond_hole.push((height, decode_blockhash(&blockhash)?));
             }
+        }
+
+        if !beyond_hole.is_empty() {
+            beyond_hole.sort();
+            beyond_hole.dedup();
+            warn!(target: "Index", "Height mappings are not contiguous past height {}, quarantining {} entries",
+                  next_height, beyond_hole.len());
+            for (height, blockhash) in beyond_hole {
+                self.quarantine_block(height, &blockhash)?;
+            }
+            self.hole_on_open = Some(next_height);
+        }
+
+        Ok(next_height)
+    }
+
+    /// Places the tip below `next_height` and fills the recent chain map with the last blocks
+    /// up to it.
+    fn load_recent_chain(&mut self, next_height: u32) -> Result<(), StorageError> {
+        let mut recent = RecentChain::new(self.chain_mut().recent.window);
+        let start = next_height.saturating_sub(recent.window as u32);
+        for height in start..next_height {
+            recent.push_tip(height, self.db_blockhash_by_height(height)?);
+        }
+        *self.chain_mut() = ChainState {
+            next_height,
+            recent,
         };
+        Ok(())
+    }
 
-        Ok((
-            Index {
-                index_db,
-                height_to_hash,
-                hash_to_height,
-                next_height,
-            },
-            is_new,
-        ))
+    fn chain(&self) -> RwLockReadGuard<'_, ChainState> {
+        self.chain.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn chain_write(&self) -> RwLockWriteGuard<'_, ChainState> {
+        self.chain.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn chain_mut(&mut self) -> &mut ChainState {
+        self.chain.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the first height missing from height_to_hash.
+    /// Heights are normally contiguous, so a binary search finds the end of the run. Its
+    /// answer is only trusted if exactly that many keys lie below it, otherwise there's a
+    /// hole the search skipped over and we fall back to walking the keys in height order.
+    fn contiguous_height_end(&self) -> Result<u32, StorageError> {
+        let (mut low, mut high) = (0u32, self.height_to_hash.len() as u32);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.height_to_hash.contains_key(height_key(mid))? {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        let mut heights = Vec::new();
+        for key in self.height_to_hash.iter().keys() {
+            heights.push(decode_height_key(&key?)?);
+        }
+        if heights.iter().filter(|&&height| height < low).count() == low as usize {
+            return Ok(low);
+        }
+
+        let end = heights
+            .iter()
+            .enumerate()
+            .find(|(expected, &height)| height != *expected as u32)
+            .map_or(heights.len(), |(expected, _)| expected);
+        Ok(end as u32)
+    }
+
+    /// Moves a block out of the height mappings and the entry tree into the quarantine tree.
+    fn quarantine_block(&mut self, height: u32, blockhash: &[u8; 32]) -> Result<(), StorageError> {
+        let entry = self.index_db.get(blockhash)?;
+
+        let mut key = Vec::with_capacity(36);
+        key.extend_from_slice(&height.to_le_bytes());
+        key.extend_from_slice(blockhash);
+        self.quarantine
+            .insert(key, entry.as_ref().map_or(&[][..], |e| e.as_ref()))?;
+
+        if self.height_to_hash.get(height_key(height))?.as_deref() == Some(&blockhash[..]) {
+            self.height_to_hash.remove(height_key(height))?;
+        }
+        if self.hash_to_height.get(blockhash)?.as_deref() == Some(&height.to_le_bytes()[..]) {
+            self.hash_to_height.remove(blockhash)?;
+        }
+        self.index_db.remove(blockhash)?;
+        Ok(())
+    }
+
+    /// The first missing height found when opening the database, if entries past it had to be
+    /// moved into quarantine.
+    pub fn hole_on_open(&self) -> Option<u32> {
+        self.hole_on_open
+    }
+
+    /// Number of entries moved into quarantine because they were found past a hole in the heights.
+    pub fn quarantined_count(&self) -> usize {
+        self.quarantine.len()
+    }
+
+    /// Entries moved into quarantine, ordered by height.
+    pub fn quarantined_blocks(&self) -> Result<Vec<QuarantinedBlock>, StorageError> {
+        let mut blocks = Vec::new();
+        for item in self.quarantine.iter() {
+            let (key, entry) = item?;
+            if key.len() != 36 {
+                return Err(StorageError::CorruptDB("Invalid quarantine key"));
+            }
+            blocks.push(QuarantinedBlock {
+                height: decode_height(&key[0..4])?,
+                blockhash: decode_blockhash(&key[4..36])?,
+                entry: IndexEntry::deserialize(&entry),
+            });
+        }
+        blocks.sort_by_key(|block| block.height);
+        Ok(blocks)
     }
 
     pub fn insert_block(
-        &mut self,
+        &self,
         height: u32,
         blockhash: &[u8; 32],
         entry: &IndexEntry,
     ) -> Result<(), StorageError> {
-        if height != self.next_height {
+        let mut chain = self.chain_write();
+        if height != chain.next_height {
             return Err(StorageError::InvalidHeight);
         }
-        // TODO: Make this "atomic".
-        {
-            self.height_to_hash
-                .insert(&height.to_le_bytes(), blockhash)?;
-            self.next_height += 1;
-
-            // these panic on failure for now.
-            self.hash_to_height
-                .insert(blockhash, &height.to_le_bytes())
-                .expect("Failed to insert hash to height");
-            self.index_db
-                .insert(blockhash, &entry.serialize())
-                .expect("Failed to insert blockhash to index");
+        // height_to_hash goes last: it decides where the chain ends on open, and anything the
+        // other trees hold past that end gets quarantined. If an insert fails, the earlier ones
+        // are put back the way they were (an orphan tombstone, or nothing).
+        let previous_entry = self.index_db.insert(blockhash, &entry.serialize())?;
+        let mut heights = sled::Batch::default();
+        heights.insert(&height_key(height)[..], &blockhash[..]);
+        let result = self
+            .hash_to_height
+            .insert(blockhash, &height.to_le_bytes())
+            .map_err(StorageError::from)
+            .and_then(|_| self.apply_heights(&heights, Some((height, *blockhash)), true));
+        if let Err(e) = result {
+            self.hash_to_height.remove(blockhash)?;
+            match previous_entry {
+                Some(previous) => self.index_db.insert(blockhash, previous)?,
+                None => self.index_db.remove(blockhash)?,
+            };
+            return Err(e);
+        }
+
+        chain.next_height += 1;
+        chain.recent.push_tip(height, *blockhash);
+        Ok(())
+    }
+
+    /// Inserts consecutive blocks starting at `start_height`, all of them or none.
+    /// Every tree takes a single batch, applied in the same order as in `insert_block`.
+    pub fn insert_blocks(
+        &self,
+        start_height: u32,
+        blocks: &[([u8; 32], IndexEntry)],
+    ) -> Result<(), StorageError> {
+        let mut chain = self.chain_write();
+        if start_height != chain.next_height {
+            return Err(StorageError::InvalidHeight);
+        }
+        let Some((tip_hash, _)) = blocks.last() else {
+            return Ok(());
+        };
+        let tip = (start_height + blocks.len() as u32 - 1, *tip_hash);
+
+        let mut entries = sled::Batch::default();
+        let mut hashes = sled::Batch::default();
+        let mut heights = sled::Batch::default();
+        let mut undo_entries = sled::Batch::default();
+        let mut undo_hashes = sled::Batch::default();
+        for (height, (blockhash, entry)) in (start_height..).zip(blocks) {
+            match self.index_db.get(blockhash)? {
+                Some(previous) => undo_entries.insert(&blockhash[..], previous),
+                None => undo_entries.remove(&blockhash[..]),
+            }
+            entries.insert(&blockhash[..], &entry.serialize()[..]);
+            hashes.insert(&blockhash[..], &height.to_le_bytes()[..]);
+            undo_hashes.remove(&blockhash[..]);
+            heights.insert(&height_key(height)[..], &blockhash[..]);
+        }
+
+        self.index_db.apply_batch(entries)?;
+        let result = self
+            .hash_to_height
+            .apply_batch(hashes)
+            .map_err(StorageError::from)
+            .and_then(|()| self.apply_heights(&heights, Some(tip), true));
+        if let Err(e) = result {
+            self.hash_to_height.apply_batch(undo_hashes)?;
+            self.index_db.apply_batch(undo_entries)?;
+            return Err(e);
+        }
+
+        for (blockhash, _) in blocks {
+            let height = chain.next_height;
+            chain.recent.push_tip(height, *blockhash);
+            chain.next_height += 1;
+        }
+        Ok(())
+    }
+
+    /// Applies `heights` to height_to_hash and checkpoints `tip`, the tip they leave (None for
+    /// an empty chain), in one transaction. `added` tells whether the change added blocks,
+    /// otherwise the time a block was last added is kept.
+    fn apply_heights(
+        &self,
+        heights: &sled::Batch,
+        tip: Option<(u32, [u8; 32])>,
+        added: bool,
+    ) -> Result<(), StorageError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        (&self.height_to_hash, &self.checkpoint)
+            .transaction(|(height_to_hash, checkpoint)| {
+                height_to_hash.apply_batch(heights)?;
+                let Some((height, blockhash)) = tip else {
+                    checkpoint.remove(CHECKPOINT_TIP_KEY)?;
+                    return Ok(());
+                };
+                let last_added = match added {
+                    true => now,
+                    false => checkpoint
+                        .get(CHECKPOINT_TIP_KEY)?
+                        .and_then(|tip| decode_checkpoint_tip(&tip))
+                        .map_or(0, |(_, _, last_added)| last_added),
+                };
+                checkpoint.insert(
+                    CHECKPOINT_TIP_KEY,
+                    encode_checkpoint_tip(height, &blockhash, last_added),
+                )?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError<()>| match e {
+                TransactionError::Storage(e) => StorageError::from(e),
+                TransactionError::Abort(()) => unreachable!("the transaction never aborts"),
+            })
+    }
+
+    /// Brings the checkpoint in line with the tip found on open: an index from before
+    /// checkpoints were kept has none, and blocks quarantined past a hole in the heights take
+    /// the tip down without it.
+    fn repair_checkpoint(&self) -> Result<(), StorageError> {
+        let tip = self.tip()?;
+        let stored = self
+            .checkpoint
+            .get(CHECKPOINT_TIP_KEY)?
+            .and_then(|stored| decode_checkpoint_tip(&stored));
+        match (tip, stored) {
+            (None, None) => return Ok(()),
+            (Some(tip), Some((height, blockhash, _))) if tip == (height, blockhash) => {
+                return Ok(())
+            }
+            (Some((height, _)), None) => {
+                info!(target: "Index", "Checkpointing the tip at height {}", height)
+            }
+            (tip, Some((height, _, _))) => {
+                warn!(target: "Index", "Checkpoint at height {} is not the tip (height {:?}), moving it",
+                      height, tip.map(|(height, _)| height))
+            }
+        }
+        let mut heights = sled::Batch::default();
+        // Rewrites the mapping of the tip as it is, so only the checkpoint changes
+        if let Some((height, blockhash)) = tip {
+            heights.insert(&height_key(height)[..], &blockhash[..]);
         }
+        self.apply_heights(&heights, tip, false)
+    }
+
+    /// The checkpoint of the tip, None for an empty chain.
+    pub fn checkpoint(&self) -> Result<Option<Checkpoint>, StorageError> {
+        // Not in the middle of a change to the chain
+        let _chain = self.chain();
+        let Some(tip) = self.checkpoint.get(CHECKPOINT_TIP_KEY)? else {
+            return Ok(None);
+        };
+        let (height, blockhash, last_added) = decode_checkpoint_tip(&tip)
+            .ok_or(StorageError::CorruptDB("Invalid checkpoint of the tip"))?;
+        let sync_state = self
+            .checkpoint
+            .get(SYNC_STATE_KEY)?
+            .map_or_else(Vec::new, |state| state.to_vec());
+        Ok(Some(Checkpoint {
+            height,
+            blockhash,
+            last_added,
+            sync_state,
+        }))
+    }
+
+    /// Stores `sync_state` in the checkpoint, for the sync layer to find on startup and tell
+    /// whether it has to rewind before continuing. It stays until replaced, whatever happens
+    /// to the chain.
+    pub fn set_sync_state(&self, sync_state: &[u8]) -> Result<(), StorageError> {
+        let _chain = self.chain_write();
+        self.checkpoint.insert(SYNC_STATE_KEY, sync_state)?;
+        Ok(())
+    }
+
+    /// Number of heights, from 0, whose bytes are counted, see `push_range_bytes`.
+    pub fn range_bytes_count(&self) -> Result<u32, StorageError> {
+        match self.range_bytes.last()? {
+            Some((position, _)) => decode_height_key(&position),
+            None => Ok(0),
+        }
+    }
+
+    fn range_node(&self, position: u32) -> Result<u64, StorageError> {
+        let node = self
+            .range_bytes
+            .get(height_key(position))?
+            .ok_or(StorageError::CorruptDB("Missing range bytes"))?;
+        Ok(u64::from_le_bytes(node[..].try_into().map_err(|_| {
+            StorageError::CorruptDB("Invalid range bytes")
+        })?))
+    }
+
+    /// Bytes of the first `count` heights.
+    fn range_prefix(&self, count: u32) -> Result<u64, StorageError> {
+        let mut bytes = 0;
+        let mut position = count;
+        while position > 0 {
+            bytes += self.range_node(position)?;
+            position -= lowbit(position);
+        }
+        Ok(bytes)
+    }
+
+    /// Counts `bytes` as those of the block at `height`, the first height not counted yet.
+    pub fn push_range_bytes(&self, height: u32, bytes: u64) -> Result<(), StorageError> {
+        if height != self.range_bytes_count()? {
+            return Err(StorageError::InvalidHeight);
+        }
+        let position = height + 1;
+        let node =
+            bytes + self.range_prefix(height)? - self.range_prefix(position - lowbit(position))?;
+        self.range_bytes
+            .insert(height_key(position), &node.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Stops counting the bytes of `height` and the heights above it.
+    pub fn truncate_range_bytes(&self, height: u32) -> Result<(), StorageError> {
+        let mut batch = sled::Batch::default();
+        for position in self.range_bytes.range(height_key(height + 1)..).keys() {
+            batch.remove(position?);
+        }
+        self.range_bytes.apply_batch(batch)?;
         Ok(())
     }
 
+    /// Bytes of the blocks at heights `start..=end`. `EntryNotFound` if they aren't all counted.
+    pub fn range_bytes(&self, start: u32, end: u32) -> Result<u64, StorageError> {
+        if start > end || end >= self.range_bytes_count()? {
+            return Err(StorageError::EntryNotFound);
+        }
+        Ok(self.range_prefix(end + 1)? - self.range_prefix(start)?)
+    }
+
+    /// Points the entry of `blockhash`, a block of the chain, at `entry`, a record streamed as
+    /// `bytes`, and sets the metadata `meta_key` along with it, in one transaction. For
+    /// records that replace another, see `FlatFileStore::replace_block_tweaks`.
+    pub fn repoint_block(
+        &self,
+        blockhash: &[u8; 32],
+        entry: &IndexEntry,
+        bytes: u64,
+        meta_key: &[u8],
+        meta_value: &[u8],
+    ) -> Result<(), StorageError> {
+        let _chain = self.chain_write();
+        let height = self.db_height_by_blockhash(blockhash)?;
+        let count = self.range_bytes_count()?;
+        let mut nodes = Vec::new();
+        if height < count {
+            let old = self.range_prefix(height + 1)? - self.range_prefix(height)?;
+            let mut position = height + 1;
+            while position <= count {
+                nodes.push((position, self.range_node(position)? - old + bytes));
+                position += lowbit(position);
+            }
+        }
+        (&*self.index_db, &self.range_bytes, &self.meta)
+            .transaction(|(entries, range_bytes, meta)| {
+                entries.insert(&blockhash[..], &entry.serialize()[..])?;
+                for (position, node) in &nodes {
+                    range_bytes.insert(&height_key(*position)[..], &node.to_le_bytes()[..])?;
+                }
+                meta.insert(meta_key, meta_value)?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError<()>| match e {
+                TransactionError::Storage(e) => StorageError::from(e),
+                TransactionError::Abort(()) => unreachable!("the transaction never aborts"),
+            })
+    }
+
     pub fn get_block_entry(&self, blockhash: &[u8; 32]) -> Result<IndexEntry, StorageError> {
         let data = self
             .index_db
@@ -133,9 +814,17 @@ impl Index {
     }
 
     pub fn get_blockhash_by_height(&self, height: u32) -> Result<[u8; 32], StorageError> {
+        let recent = self.chain().recent.get_blockhash(height);
+        match recent {
+            Some(blockhash) => Ok(blockhash),
+            None => self.db_blockhash_by_height(height),
+        }
+    }
+
+    fn db_blockhash_by_height(&self, height: u32) -> Result<[u8; 32], StorageError> {
         let data = self
             .height_to_hash
-            .get(&height.to_le_bytes())?
+            .get(height_key(height))?
             .ok_or(StorageError::EntryNotFound)?;
         if data.len() != 32 {
             return Err(StorageError::InvalidData("Invalid blockhash length"));
@@ -146,6 +835,14 @@ impl Index {
     }
 
     pub fn get_height_by_blockhash(&self, blockhash: &[u8; 32]) -> Result<u32, StorageError> {
+        let recent = self.chain().recent.get_height(blockhash);
+        match recent {
+            Some(height) => Ok(height),
+            None => self.db_height_by_blockhash(blockhash),
+        }
+    }
+
+    fn db_height_by_blockhash(&self, blockhash: &[u8; 32]) -> Result<u32, StorageError> {
         let data = self
             .hash_to_height
             .get(blockhash)?
@@ -156,58 +853,455 @@ impl Index {
         Ok(u32::from_le_bytes(data[..].try_into().unwrap()))
     }
 
+    /// Takes the tip out of the index entirely, as if it had never been inserted. Only for
+    /// undoing a write that never made it to disk, reorgs go through `remove_block`.
+    pub fn discard_tip(&self, blockhash: &[u8; 32]) -> Result<(), StorageError> {
+        let mut chain = self.chain_write();
+        self.remove_block_with(&mut chain, blockhash)?;
+        self.index_db.remove(blockhash)?;
+        Ok(())
+    }
+
     /// Marks a block as orphaned by setting its entry to a special value
     /// and removes its height mappings, this is helpful in case a client requests
     /// a block that has been reorganized away.
-    pub fn remove_block(&mut self, blockhash: &[u8; 32]) -> Result<(), StorageError> {
+    pub fn remove_block(&self, blockhash: &[u8; 32]) -> Result<(), StorageError> {
+        self.remove_block_with(&mut self.chain_write(), blockhash)
+    }
+
+    fn remove_block_with(
+        &self,
+        chain: &mut ChainState,
+        blockhash: &[u8; 32],
+    ) -> Result<(), StorageError> {
         // First check if the block exists in the index
         if self.index_db.get(blockhash)?.is_none() {
             return Err(StorageError::EntryNotFound);
         }
 
-        if let Ok(height) = self.get_height_by_blockhash(blockhash) {
-            if height != self.next_height - 1 {
-                // TODO: Technically, we should allow removing a deeper block and remove
-                // all blocks in the chain leading from it.
-                // This is a safeguard for now.
+        if let Ok(height) = self.db_height_by_blockhash(blockhash) {
+            if height != chain.next_height - 1 {
+                // Deeper reorgs go through remove_blocks_above
                 return Err(StorageError::InvalidHeight); // Remove block should only attempt to remove tip
             }
-            self.next_height -= 1;
-            self.height_to_hash.remove(&height.to_le_bytes())?;
-            self.hash_to_height.remove(blockhash)?;
-            // Mark the entry as orphaned with a special zero value
-            self.index_db.insert(blockhash, &[0u8; 1])?;
-
-            Ok(())
+            self.remove_tip(chain, height, blockhash)
         } else {
             Err(StorageError::EntryNotFound)
         }
     }
+
+    /// Removes every block above `height`, tip first, as if by repeated `remove_block` calls.
+    /// Returns how many blocks were removed. Each block is gone once its height mapping is, so
+    /// an interrupted call leaves a shorter chain whose tip may be half removed, and calling
+    /// it again with the same height finishes the job.
+    pub fn remove_blocks_above(&self, height: u32) -> Result<u32, StorageError> {
+        let mut chain = self.chain_write();
+        let mut removed = 0;
+        while chain.next_height > 0 && chain.next_height - 1 > height {
+            let tip = chain.next_height - 1;
+            let blockhash = self.db_blockhash_by_height(tip)?;
+            self.remove_tip(&mut chain, tip, &blockhash)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Takes the tip at `height` off the chain. The height mapping goes last: until then the
+    /// block is still the tip, and every step is safe to repeat.
+    fn remove_tip(
+        &self,
+        chain: &mut ChainState,
+        height: u32,
+        blockhash: &[u8; 32],
+    ) -> Result<(), StorageError> {
+        // Mark the entry as orphaned with a special zero value
+        self.index_db.insert(blockhash, &[0u8; 1])?;
+        self.hash_to_height.remove(blockhash)?;
+        let new_tip = match height.checked_sub(1) {
+            Some(below) => Some((below, self.db_blockhash_by_height(below)?)),
+            None => None,
+        };
+        let mut heights = sled::Batch::default();
+        heights.remove(&height_key(height)[..]);
+        self.apply_heights(&heights, new_tip, false)?;
+        chain.next_height -= 1;
+        let height_to_hash = &self.height_to_hash;
+        chain.recent.pop_tip(|older| {
+            height_to_hash
+                .get(height_key(older))
+                .ok()
+                .flatten()
+                .and_then(|data| decode_blockhash(&data).ok())
+        });
+        Ok(())
+    }
+
+    pub fn get_meta(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.meta.get(key)?.map(|value| value.to_vec()))
+    }
+
+    /// Syncs everything written to the index to disk.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.index_db.flush()?;
+        Ok(())
+    }
+
+    pub fn set_meta(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.meta.insert(key, value)?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    pub fn remove_meta(&self, key: &[u8]) -> Result<(), StorageError> {
+        self.meta.remove(key)?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    /// Every metadata entry, in key order.
+    pub fn meta_entries(&self) -> Result<Vec<MetaEntry>, StorageError> {
+        self.meta
+            .iter()
+            .map(|item| {
+                let (key, value) = item?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    /// Every entry pointing into the block data files, quarantined ones and dust tiers
+    /// included, for migrations that move records and for pruning. Orphaned blocks have no
+    /// location and are left out.
+    pub fn located_entries(&self) -> Result<Vec<(EntryKey, IndexEntry)>, StorageError> {
+        let mut entries = Vec::new();
+        for item in self.index_db.iter() {
+            let (blockhash, entry) = item?;
+            if let Some(entry) = IndexEntry::deserialize(&entry) {
+                entries.push((EntryKey::Block(decode_blockhash(&blockhash)?), entry));
+            }
+        }
+        for item in self.quarantine.iter() {
+            let (key, entry) = item?;
+            if let Some(entry) = IndexEntry::deserialize(&entry) {
+                entries.push((EntryKey::Quarantined(key.to_vec()), entry));
+            }
+        }
+        for item in self.tiers.iter() {
+            let (key, entry) = item?;
+            let entry = IndexEntry::deserialize(&entry)
+                .ok_or(StorageError::CorruptDB("Invalid dust tier entry"))?;
+            entries.push((EntryKey::Tier(key.to_vec()), entry));
+        }
+        Ok(entries)
+    }
+
+    /// Blocks in the entry tree that aren't on the chain, with their entry if it still points
+    /// into the block data files: reorged away (marked orphaned) or left behind by an
+    /// interrupted write.
+    pub fn off_chain_entries(&self) -> Result<Vec<OffChainEntry>, StorageError> {
+        let mut entries = Vec::new();
+        for item in self.index_db.iter() {
+            let (blockhash, entry) = item?;
+            if !self.hash_to_height.contains_key(&blockhash)? {
+                entries.push((
+                    decode_blockhash(&blockhash)?,
+                    IndexEntry::deserialize(&entry),
+                ));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Removes the given entries, e.g. those whose records were pruned. The height mappings
+    /// stay, so the chain itself is unchanged. Removing an entry that is gone already is fine.
+    pub fn remove_entries(&self, keys: &[EntryKey]) -> Result<(), StorageError> {
+        let mut entries = sled::Batch::default();
+        let mut quarantine = sled::Batch::default();
+        let mut tiers = sled::Batch::default();
+        for key in keys {
+            match key {
+                EntryKey::Block(blockhash) => entries.remove(&blockhash[..]),
+                EntryKey::Quarantined(key) => quarantine.remove(&key[..]),
+                EntryKey::Tier(key) => tiers.remove(&key[..]),
+            }
+        }
+        self.index_db.apply_batch(entries)?;
+        self.quarantine.apply_batch(quarantine)?;
+        self.tiers.apply_batch(tiers)?;
+        self.index_db.flush()?;
+        Ok(())
+    }
+
+    /// Points entries at where their records moved and sets a metadata value, all in one
+    /// transaction, so a migration can record its progress along with what it did. Unlike the
+    /// hot paths this can afford a sled transaction: it runs once per block data file.
+    pub fn move_entries(
+        &self,
+        moved: &[(EntryKey, IndexEntry)],
+        meta_key: &[u8],
+        meta_value: &[u8],
+    ) -> Result<(), StorageError> {
+        (&*self.index_db, &self.quarantine, &self.tiers, &self.meta)
+            .transaction(|(entries, quarantine, tiers, meta)| {
+                for (key, entry) in moved {
+                    match key {
+                        EntryKey::Block(blockhash) => {
+                            entries.insert(&blockhash[..], &entry.serialize()[..])?
+                        }
+                        EntryKey::Quarantined(key) => {
+                            quarantine.insert(&key[..], &entry.serialize()[..])?
+                        }
+                        EntryKey::Tier(key) => tiers.insert(&key[..], &entry.serialize()[..])?,
+                    };
+                }
+                meta.insert(meta_key, meta_value)?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError<()>| match e {
+                TransactionError::Storage(e) => StorageError::from(e),
+                TransactionError::Abort(()) => unreachable!("the transaction never aborts"),
+            })?;
+        self.index_db.flush()?;
+        Ok(())
+    }
+
+    /// Records where the dust tier `dust_threshold` of a block is stored.
+    pub fn insert_tier(
+        &self,
+        blockhash: &[u8; 32],
+        dust_threshold: u64,
+        entry: &IndexEntry,
+    ) -> Result<(), StorageError> {
+        self.tiers
+            .insert(tier_key(blockhash, dust_threshold), &entry.serialize())?;
+        Ok(())
+    }
+
+    /// The entry of the dust tier `dust_threshold` of a block, `EntryNotFound` if the block
+    /// was never stored with that tier.
+    pub fn get_tier_entry(
+        &self,
+        blockhash: &[u8; 32],
+        dust_threshold: u64,
+    ) -> Result<IndexEntry, StorageError> {
+        let data = self
+            .tiers
+            .get(tier_key(blockhash, dust_threshold))?
+            .ok_or(StorageError::EntryNotFound)?;
+        IndexEntry::deserialize(&data).ok_or(StorageError::CorruptDB("Invalid dust tier entry"))
+    }
+
+    /// The dust tiers a block is stored with, as (dust threshold, entry) by threshold.
+    pub fn tier_entries(
+        &self,
+        blockhash: &[u8; 32],
+    ) -> Result<Vec<(u64, IndexEntry)>, StorageError> {
+        self.tiers
+            .scan_prefix(blockhash)
+            .map(|item| {
+                let (key, entry) = item?;
+                match (
+                    <[u8; 8]>::try_from(&key[32..]),
+                    IndexEntry::deserialize(&entry),
+                ) {
+                    (Ok(threshold), Some(entry)) => Ok((u64::from_be_bytes(threshold), entry)),
+                    _ => Err(StorageError::CorruptDB("Invalid dust tier entry")),
+                }
+            })
+            .collect()
+    }
+
+    /// Removes the dust tiers of a block, e.g. one taken off the chain, and returns their
+    /// entries.
+    pub fn remove_tiers(&self, blockhash: &[u8; 32]) -> Result<Vec<IndexEntry>, StorageError> {
+        let tiers = self.tier_entries(blockhash)?;
+        let mut batch = sled::Batch::default();
+        for (dust_threshold, _) in &tiers {
+            batch.remove(&tier_key(blockhash, *dust_threshold)[..]);
+        }
+        self.tiers.apply_batch(batch)?;
+        Ok(tiers.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// Counts `bytes` more of block data file `file_number` as dead, i.e. taken by a record
+    /// no entry points to anymore.
+    pub fn add_dead_bytes(&self, file_number: u64, bytes: u64) -> Result<(), StorageError> {
+        self.dead_bytes
+            .update_and_fetch(file_number.to_be_bytes(), |old| {
+                let old = old
+                    .and_then(|data| data.try_into().ok())
+                    .map_or(0, u64::from_le_bytes);
+                Some((old + bytes).to_le_bytes().to_vec())
+            })?;
+        Ok(())
+    }
+
+    /// The dead bytes counted per block data file, as (file number, bytes) in file order.
+    /// Files without any are left out.
+    pub fn dead_bytes(&self) -> Result<Vec<(u64, u64)>, StorageError> {
+        self.dead_bytes
+            .iter()
+            .map(|item| {
+                let (key, value) = item?;
+                match (
+                    <[u8; 8]>::try_from(&key[..]),
+                    <[u8; 8]>::try_from(&value[..]),
+                ) {
+                    (Ok(key), Ok(value)) => {
+                        Ok((u64::from_be_bytes(key), u64::from_le_bytes(value)))
+                    }
+                    _ => Err(StorageError::CorruptDB("Invalid dead bytes counter")),
+                }
+            })
+            .collect()
+    }
+
+    /// Forgets the dead bytes of the given block data files, once they are rewritten or gone.
+    pub fn clear_dead_bytes(&self, file_numbers: &[u64]) -> Result<(), StorageError> {
+        let mut batch = sled::Batch::default();
+        for file_number in file_numbers {
+            batch.remove(&file_number.to_be_bytes()[..]);
+        }
+        self.dead_bytes.apply_batch(batch)?;
+        self.dead_bytes.flush()?;
+        Ok(())
+    }
+
+    /// Names of trees in the database that this Index doesn't use, i.e. written by something else.
+    pub fn unknown_trees(&self) -> Vec<String> {
+        let default_tree = self.index_db.name();
+        self.index_db
+            .tree_names()
+            .into_iter()
+            .filter(|name| *name != default_tree)
+            .map(|name| String::from_utf8_lossy(&name).into_owned())
+            .filter(|name| {
+                ![
+                    HEIGHT_TO_HASH_TREE,
+                    HASH_TO_HEIGHT_TREE,
+                    QUARANTINE_TREE,
+                    META_TREE,
+                    DEAD_BYTES_TREE,
+                    TIERS_TREE,
+                    CHECKPOINT_TREE,
+                    RANGE_BYTES_TREE,
+                ]
+                .contains(&name.as_str())
+            })
+            .collect()
+    }
+
+    /// Checks that every height below the tip maps to a blockhash that maps back to it and
+    /// has a live index entry.
+    pub fn check_consistency(&self) -> Result<(), StorageError> {
+        let next_height = self.chain().next_height;
+        for height in 0..next_height {
+            let blockhash = self.db_blockhash_by_height(height)?;
+            if self.db_height_by_blockhash(&blockhash)? != height {
+                return Err(StorageError::CorruptDB(
+                    "hash_to_height disagrees with height_to_hash",
+                ));
+            }
+            self.get_block_entry(&blockhash)?;
+            if height > 0 && height % 100_000 == 0 {
+                info!(target: "Index", "Checked index up to height {}", height);
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks back from the tip and returns the highest height whose blockhash `is_on_chain`
+    /// accepts, i.e. the fork point with another chain. Returns None if no stored block matches.
+    pub fn find_fork_point(
+        &self,
+        mut is_on_chain: impl FnMut(u32, &[u8; 32]) -> bool,
+    ) -> Result<Option<u32>, StorageError> {
+        let next_height = self.chain().next_height;
+        for height in (0..next_height).rev() {
+            let blockhash = self.get_blockhash_by_height(height)?;
+            if is_on_chain(height, &blockhash) {
+                return Ok(Some(height));
+            }
+        }
+        Ok(None)
+    }
+
     /// Returns the height of chain
     /// returns -1 if the chain is empty
     pub fn get_current_height(&self) -> i32 {
-        self.next_height as i32 - 1
+        self.chain().next_height as i32 - 1
+    }
+
+    /// Size of the database on disk.
+    pub fn size_on_disk(&self) -> Result<u64, StorageError> {
+        Ok(self.index_db.size_on_disk()?)
+    }
+
+    /// Height and blockhash of the tip, None for an empty chain. Both are read under the chain
+    /// lock, so a concurrent insert or removal can't make them disagree.
+    pub fn tip(&self) -> Result<Option<(u32, [u8; 32])>, StorageError> {
+        let chain = self.chain();
+        let Some(height) = chain.next_height.checked_sub(1) else {
+            return Ok(None);
+        };
+        let blockhash = match chain.recent.get_blockhash(height) {
+            Some(blockhash) => blockhash,
+            None => self.db_blockhash_by_height(height)?,
+        };
+        Ok(Some((height, blockhash)))
     }
 }
 
+/// Dust tier keys are big-endian in the threshold, so a block's tiers are walked from the
+/// smallest threshold up.
+fn tier_key(blockhash: &[u8; 32], dust_threshold: u64) -> [u8; 40] {
+    let mut key = [0u8; 40];
+    key[..32].copy_from_slice(blockhash);
+    key[32..].copy_from_slice(&dust_threshold.to_be_bytes());
+    key
+}
+
+/// height_to_hash keys are big-endian, so that sled's byte order is height order and the
+/// tree can be walked and range-scanned from height 0 up.
+fn height_key(height: u32) -> [u8; 4] {
+    height.to_be_bytes()
+}
+
+/// Heights the range bytes node at `position` sums up.
+fn lowbit(position: u32) -> u32 {
+    position & position.wrapping_neg()
+}
+
+fn decode_height_key(data: &[u8]) -> Result<u32, StorageError> {
+    let bytes: [u8; 4] = data
+        .try_into()
+        .map_err(|_| StorageError::CorruptDB("height key is not 4 bytes"))?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// Heights stored as values (hash_to_height) and in quarantine keys, which are never scanned
+/// in order, are little-endian.
+fn decode_height(data: &[u8]) -> Result<u32, StorageError> {
+    let bytes: [u8; 4] = data
+        .try_into()
+        .map_err(|_| StorageError::CorruptDB("height is not 4 bytes"))?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn decode_blockhash(data: &[u8]) -> Result<[u8; 32], StorageError> {
+    data.try_into()
+        .map_err(|_| StorageError::CorruptDB("blockhash is not 32 bytes"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::env;
-    use std::fs;
-
-    fn temp_dir(name: &str) -> PathBuf {
-        let mut dir = env::temp_dir();
-        dir.push(name);
-        let _ = fs::remove_dir_all(&dir);
-        fs::create_dir_all(&dir).unwrap();
-        dir
-    }
+    use crate::test_support::temp_dir;
 
     #[test]
     fn test_index_operations() {
         let index_dir = temp_dir("test_block_index");
-        let (mut index, was_created) = Index::initialize(&index_dir).unwrap();
+        let (index, was_created) = Index::initialize(&index_dir).unwrap();
         assert!(
             was_created,
             "First initialization should create new database"
@@ -231,8 +1325,19 @@ mod tests {
 
         let retrieved_height = index.get_height_by_blockhash(&blockhash).unwrap();
         assert_eq!(height, retrieved_height);
+    }
 
-        let _ = fs::remove_dir_all(index_dir);
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_index_entry_serde_json() {
+        let entry = IndexEntry {
+            file_number: 3,
+            offset: 1024,
+            length: 377,
+        };
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(json, r#"{"file_number":3,"offset":1024,"length":377}"#);
+        assert_eq!(serde_json::from_str::<IndexEntry>(&json).unwrap(), entry);
     }
 
     #[test]
@@ -257,14 +1362,12 @@ mod tests {
             index.get_height_by_blockhash(&nonexistent_blockhash),
             Err(StorageError::EntryNotFound)
         ));
-
-        let _ = fs::remove_dir_all(index_dir);
     }
 
     #[test]
     fn test_multiple_blocks() {
         let index_dir = temp_dir("test_multiple_blocks");
-        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        let (index, _) = Index::initialize(&index_dir).unwrap();
 
         // Insert multiple blocks
         for i in 0..256 {
@@ -300,15 +1403,12 @@ mod tests {
             let retrieved_height = index.get_height_by_blockhash(&expected_blockhash).unwrap();
             assert_eq!(retrieved_height, height);
         }
-
-        // Clean up
-        let _ = fs::remove_dir_all(index_dir);
     }
 
     #[test]
     fn test_orphaned_blocks() {
         let index_dir = temp_dir("test_orphaned_blocks");
-        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        let (index, _) = Index::initialize(&index_dir).unwrap();
 
         // Insert a block
         let height = 0u32;
@@ -341,15 +1441,12 @@ mod tests {
             index.get_height_by_blockhash(&blockhash),
             Err(StorageError::EntryNotFound)
         ));
-
-        // Clean up
-        let _ = fs::remove_dir_all(index_dir);
     }
 
     #[test]
     fn test_orphan_nonexistent_block() {
         let index_dir = temp_dir("test_orphan_nonexistent");
-        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        let (index, _) = Index::initialize(&index_dir).unwrap();
 
         let nonexistent_blockhash = [0u8; 32];
 
@@ -358,9 +1455,6 @@ mod tests {
             index.remove_block(&nonexistent_blockhash),
             Err(StorageError::EntryNotFound)
         ));
-
-        // Clean up
-        let _ = fs::remove_dir_all(index_dir);
     }
 
     #[test]
@@ -381,7 +1475,553 @@ mod tests {
             !was_created2,
             "Second initialization should open existing database"
         );
+    }
+
+    fn insert_test_blocks(index: &mut Index, count: u32) {
+        for height in 0..count {
+            let entry = IndexEntry {
+                file_number: 0,
+                offset: height as u64 * 100,
+                length: 100,
+            };
+            index
+                .insert_block(height, &[height as u8; 32], &entry)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_tip() {
+        // Without a recent window the tip's hash comes from sled
+        for window in [0, 16] {
+            let index_dir = temp_dir(&format!("test_tip_{}", window));
+            let (mut index, _) = Index::initialize_with_recent_window(&index_dir, window).unwrap();
+            assert_eq!(index.tip().unwrap(), None);
+
+            insert_test_blocks(&mut index, 1);
+            assert_eq!(index.tip().unwrap(), Some((0, [0; 32])));
+
+            let entry = IndexEntry {
+                file_number: 0,
+                offset: 100,
+                length: 100,
+            };
+            index.insert_block(1, &[1; 32], &entry).unwrap();
+            index.insert_block(2, &[2; 32], &entry).unwrap();
+            assert_eq!(index.tip().unwrap(), Some((2, [2; 32])));
+            index.remove_blocks_above(0).unwrap();
+            assert_eq!(index.tip().unwrap(), Some((0, [0; 32])));
+        }
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let index_dir = temp_dir("test_checkpoint");
+        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        assert_eq!(index.checkpoint().unwrap(), None);
+        let entry = |height: u32| IndexEntry {
+            file_number: 0,
+            offset: height as u64 * 100,
+            length: 100,
+        };
+
+        insert_test_blocks(&mut index, 3);
+        let checkpoint = index.checkpoint().unwrap().unwrap();
+        assert_eq!((checkpoint.height, checkpoint.blockhash), (2, [2; 32]));
+        assert!(checkpoint.last_added > 0);
+        assert!(checkpoint.sync_state.is_empty());
+        index.set_sync_state(b"chain work").unwrap();
+
+        // Removals move it down, keeping when a block was last added and the sync state
+        index.remove_block(&[2; 32]).unwrap();
+        let removed = index.checkpoint().unwrap().unwrap();
+        assert_eq!((removed.height, removed.blockhash), (1, [1; 32]));
+        assert_eq!(removed.last_added, checkpoint.last_added);
+        assert_eq!(removed.sync_state, b"chain work");
+
+        index
+            .insert_blocks(2, &[([12; 32], entry(2)), ([13; 32], entry(3))])
+            .unwrap();
+        let checkpoint = index.checkpoint().unwrap().unwrap();
+        assert_eq!((checkpoint.height, checkpoint.blockhash), (3, [13; 32]));
+        index.remove_blocks_above(1).unwrap();
+        index.insert_block(2, &[22; 32], &entry(2)).unwrap();
+        index.set_sync_state(b"more chain work").unwrap();
+        let checkpoint = index.checkpoint().unwrap().unwrap();
+        assert_eq!((checkpoint.height, checkpoint.blockhash), (2, [22; 32]));
+
+        drop(index);
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        assert_eq!(index.checkpoint().unwrap(), Some(checkpoint.clone()));
+
+        // An index without one (from before they were kept) gets one on open, and so does one
+        // whose tip moved past a hole in the heights
+        index.checkpoint.remove(CHECKPOINT_TIP_KEY).unwrap();
+        drop(index);
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        let repaired = index.checkpoint().unwrap().unwrap();
+        assert_eq!((repaired.height, repaired.blockhash), (2, [22; 32]));
+        assert_eq!(repaired.last_added, 0);
+        assert_eq!(repaired.sync_state, b"more chain work");
+        index.height_to_hash.remove(height_key(1)).unwrap();
+        drop(index);
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        let repaired = index.checkpoint().unwrap().unwrap();
+        assert_eq!((repaired.height, repaired.blockhash), (0, [0; 32]));
+
+        index.remove_blocks_above(0).unwrap();
+        index.remove_block(&[0; 32]).unwrap();
+        assert_eq!(index.checkpoint().unwrap(), None);
+        drop(index);
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        assert_eq!(index.checkpoint().unwrap(), None);
+        assert!(index.unknown_trees().is_empty());
+    }
+
+    #[test]
+    fn test_recover_truncated_height_tree() {
+        let index_dir = temp_dir("test_recover_truncated_height_tree");
+        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        insert_test_blocks(&mut index, 10);
+
+        // A partial rebuild only got as far as height 5 in height_to_hash,
+        // the other trees still hold the later blocks.
+        for height in 5..10u32 {
+            index.height_to_hash.remove(height_key(height)).unwrap();
+        }
+        drop(index);
+
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        assert_eq!(index.get_current_height(), 4);
+        assert_eq!(index.quarantined_count(), 5);
+
+        let quarantined = index.quarantined_blocks().unwrap();
+        assert_eq!(quarantined[0].height, 5);
+        assert_eq!(quarantined[0].blockhash, [5u8; 32]);
+        assert_eq!(quarantined[0].entry.as_ref().unwrap().offset, 500);
+
+        assert!(matches!(
+            index.get_block_entry(&[7u8; 32]),
+            Err(StorageError::EntryNotFound)
+        ));
+        assert!(matches!(
+            index.get_height_by_blockhash(&[7u8; 32]),
+            Err(StorageError::EntryNotFound)
+        ));
+        assert_eq!(index.get_blockhash_by_height(4).unwrap(), [4u8; 32]);
+
+        // The chain continues from the end of the contiguous prefix
+        let entry = IndexEntry {
+            file_number: 0,
+            offset: 500,
+            length: 100,
+        };
+        index.insert_block(5, &[55u8; 32], &entry).unwrap();
+    }
+
+    #[test]
+    fn test_recover_hole_in_height_tree() {
+        let index_dir = temp_dir("test_recover_hole_in_height_tree");
+        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        insert_test_blocks(&mut index, 300);
+
+        index.height_to_hash.remove(height_key(120)).unwrap();
+        drop(index);
+
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        assert_eq!(index.get_current_height(), 119);
+        // 120 only survives in hash_to_height, 121..300 in every tree
+        assert_eq!(index.quarantined_count(), 180);
+        for height in 120..300u32 {
+            assert!(matches!(
+                index.get_blockhash_by_height(height),
+                Err(StorageError::EntryNotFound)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_reopen_contiguous_db() {
+        let index_dir = temp_dir("test_reopen_contiguous_db");
+        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        insert_test_blocks(&mut index, 300);
+        drop(index);
+
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        assert_eq!(index.get_current_height(), 299);
+        assert_eq!(index.quarantined_count(), 0);
+    }
+
+    fn unique_blockhash(height: u32) -> [u8; 32] {
+        let mut blockhash = [0xbb; 32];
+        blockhash[..4].copy_from_slice(&height.to_be_bytes());
+        blockhash
+    }
+
+    #[test]
+    fn test_reopen_tall_db() {
+        // Past 255 blocks little-endian keys no longer sort by height
+        let index_dir = temp_dir("test_reopen_tall_db");
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        for height in 0..1000u32 {
+            let entry = IndexEntry {
+                file_number: 0,
+                offset: height as u64 * 100,
+                length: 100,
+            };
+            index
+                .insert_block(height, &unique_blockhash(height), &entry)
+                .unwrap();
+        }
+        drop(index);
+
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        assert_eq!(index.get_current_height(), 999);
+        assert_eq!(index.quarantined_count(), 0);
+        let (last, _) = index.height_to_hash.last().unwrap().unwrap();
+        assert_eq!(decode_height_key(&last).unwrap(), 999);
+        let heights: Vec<u32> = index
+            .height_to_hash
+            .range(height_key(500)..height_key(505))
+            .keys()
+            .map(|key| decode_height_key(&key.unwrap()).unwrap())
+            .collect();
+        assert_eq!(heights, vec![500, 501, 502, 503, 504]);
+        index.check_consistency().unwrap();
+    }
+
+    #[test]
+    fn test_reencode_height_keys() {
+        let index_dir = temp_dir("test_reencode_height_keys");
+        {
+            // The key layout of version 1, little-endian heights. 256 little-endian is the
+            // big-endian key of 65536, which must survive the rewrite.
+            let index = Index::open_for_migration(&index_dir).unwrap();
+            for height in (0..300u32).chain([65536]) {
+                let blockhash = unique_blockhash(height);
+                let entry = IndexEntry {
+                    file_number: 0,
+                    offset: height as u64,
+                    length: 1,
+                };
+                index
+                    .index_db
+                    .insert(blockhash, &entry.serialize())
+                    .unwrap();
+                index
+                    .hash_to_height
+                    .insert(blockhash, &height.to_le_bytes())
+                    .unwrap();
+                index
+                    .height_to_hash
+                    .insert(height.to_le_bytes(), &blockhash)
+                    .unwrap();
+            }
+
+            // Everything but height 0, whose key reads the same both ways
+            assert_eq!(index.reencode_height_keys().unwrap(), 300);
+            assert_eq!(index.height_to_hash.len(), 301);
+            // Already done
+            assert_eq!(index.reencode_height_keys().unwrap(), 0);
+        }
+
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        // 65536 lies past the end of the chain
+        assert_eq!(index.get_current_height(), 299);
+        assert_eq!(index.hole_on_open(), Some(300));
+        for height in [0, 1, 255, 256, 299] {
+            assert_eq!(
+                index.get_blockhash_by_height(height).unwrap(),
+                unique_blockhash(height)
+            );
+        }
+        let quarantined = index.quarantined_blocks().unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].height, 65536);
+        assert_eq!(quarantined[0].blockhash, unique_blockhash(65536));
+        index.check_consistency().unwrap();
+    }
+
+    #[test]
+    fn test_insert_blocks() {
+        let index_dir = temp_dir("test_insert_blocks");
+        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        insert_test_blocks(&mut index, 3);
+
+        let blocks: Vec<([u8; 32], IndexEntry)> = (3..8u8)
+            .map(|height| {
+                let entry = IndexEntry {
+                    file_number: 1,
+                    offset: height as u64 * 100,
+                    length: 100,
+                };
+                ([height; 32], entry)
+            })
+            .collect();
+
+        // The batch has to continue the chain
+        assert!(matches!(
+            index.insert_blocks(4, &blocks),
+            Err(StorageError::InvalidHeight)
+        ));
+        assert_eq!(index.get_current_height(), 2);
+
+        index.insert_blocks(3, &blocks).unwrap();
+        assert_eq!(index.get_current_height(), 7);
+        for (height, (blockhash, entry)) in (3..).zip(&blocks) {
+            assert_eq!(index.get_blockhash_by_height(height).unwrap(), *blockhash);
+            assert_eq!(index.get_height_by_blockhash(blockhash).unwrap(), height);
+            assert_eq!(&index.get_block_entry(blockhash).unwrap(), entry);
+        }
+        drop(index);
+
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        assert_eq!(index.get_current_height(), 7);
+        index.check_consistency().unwrap();
+    }
+
+    #[test]
+    fn test_find_fork_point() {
+        let index_dir = temp_dir("test_find_fork_point");
+        let (mut index, _) = Index::initialize_with_recent_window(&index_dir, 16).unwrap();
+        insert_test_blocks(&mut index, 100);
+
+        // The other chain shares our blocks up to height 60
+        let fork = index
+            .find_fork_point(|height, blockhash| height <= 60 && blockhash == &[height as u8; 32])
+            .unwrap();
+        assert_eq!(fork, Some(60));
+        assert_eq!(index.find_fork_point(|_, _| false).unwrap(), None);
+    }
+
+    #[test]
+    fn test_remove_blocks_above() {
+        let index_dir = temp_dir("test_remove_blocks_above");
+        let (mut index, _) = Index::initialize_with_recent_window(&index_dir, 4).unwrap();
+        insert_test_blocks(&mut index, 30);
+
+        assert_eq!(index.remove_blocks_above(29).unwrap(), 0);
+        assert_eq!(index.remove_blocks_above(19).unwrap(), 10);
+        assert_eq!(index.get_current_height(), 19);
+        for height in 20..30u32 {
+            assert!(matches!(
+                index.get_blockhash_by_height(height),
+                Err(StorageError::EntryNotFound)
+            ));
+            assert!(matches!(
+                index.get_block_entry(&[height as u8; 32]),
+                Err(StorageError::OrphanedEntry)
+            ));
+        }
+        let seen: Vec<[u8; 32]> = (0..30).map(|height| [height as u8; 32]).collect();
+        assert_recent_chain_consistent(&index, &seen);
+        index.check_consistency().unwrap();
+
+        // Interrupted while removing the block at 19: orphaned, but its height is still mapped
+        index.index_db.insert([19u8; 32], &[0u8; 1]).unwrap();
+        index.hash_to_height.remove([19u8; 32]).unwrap();
+        drop(index);
+        let (index, _) = Index::initialize_with_recent_window(&index_dir, 4).unwrap();
+        assert_eq!(index.get_current_height(), 19);
+        assert_eq!(index.quarantined_count(), 0);
+
+        // Running it again finishes the removal
+        assert_eq!(index.remove_blocks_above(18).unwrap(), 1);
+        assert_eq!(index.get_current_height(), 18);
+        index.check_consistency().unwrap();
+        assert_recent_chain_consistent(&index, &seen);
+
+        assert_eq!(index.remove_blocks_above(0).unwrap(), 18);
+        assert_eq!(index.get_current_height(), 0);
+    }
+
+    /// Every lookup routed through the recent chain map must agree with sled.
+    fn assert_recent_chain_consistent(index: &Index, seen: &[[u8; 32]]) {
+        for height in 0..index.chain().next_height + 2 {
+            let routed = index.get_blockhash_by_height(height).ok();
+            let direct = index.db_blockhash_by_height(height).ok();
+            assert_eq!(routed, direct, "height {}", height);
+        }
+        for blockhash in seen {
+            let routed = index.get_height_by_blockhash(blockhash).ok();
+            let direct = index.db_height_by_blockhash(blockhash).ok();
+            assert_eq!(routed, direct);
+        }
+    }
+
+    #[test]
+    fn test_recent_chain_matches_db() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let index_dir = temp_dir("test_recent_chain_matches_db");
+        let (index, _) = Index::initialize_with_recent_window(&index_dir, 8).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut seen = Vec::new();
+        let mut chain: Vec<[u8; 32]> = Vec::new();
+
+        for _ in 0..400 {
+            if !chain.is_empty() && rng.random_bool(0.4) {
+                // Roll back a few blocks, sometimes past the window
+                for _ in 0..rng.random_range(1..=12).min(chain.len()) {
+                    let tip = chain.pop().unwrap();
+                    index.remove_block(&tip).unwrap();
+                }
+            } else {
+                let mut blockhash = [0u8; 32];
+                rng.fill(&mut blockhash);
+                let entry = IndexEntry {
+                    file_number: 0,
+                    offset: 0,
+                    length: 1,
+                };
+                index
+                    .insert_block(chain.len() as u32, &blockhash, &entry)
+                    .unwrap();
+                chain.push(blockhash);
+                seen.push(blockhash);
+            }
+            assert_recent_chain_consistent(&index, &seen);
+        }
+
+        drop(index);
+        let (index, _) = Index::initialize_with_recent_window(&index_dir, 8).unwrap();
+        assert_recent_chain_consistent(&index, &seen);
+    }
+
+    #[test]
+    fn test_meta_and_consistency() {
+        let index_dir = temp_dir("test_meta_and_consistency");
+        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        insert_test_blocks(&mut index, 50);
+        index.check_consistency().unwrap();
+
+        index.set_meta(b"version", &1u32.to_le_bytes()).unwrap();
+        assert_eq!(
+            index.get_meta(b"version").unwrap(),
+            Some(1u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(index.get_meta(b"missing").unwrap(), None);
 
-        let _ = fs::remove_dir_all(index_dir);
+        // Break the hash -> height mapping of one block
+        let blockhash = index.get_blockhash_by_height(20).unwrap();
+        index
+            .hash_to_height
+            .insert(blockhash, &21u32.to_le_bytes())
+            .unwrap();
+        assert!(matches!(
+            index.check_consistency(),
+            Err(StorageError::CorruptDB(_))
+        ));
+
+        drop(index);
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        assert_eq!(index.meta_entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_move_entries() {
+        let index_dir = temp_dir("test_move_entries");
+        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        insert_test_blocks(&mut index, 5);
+        let orphan = index.get_blockhash_by_height(4).unwrap();
+        index.remove_block(&orphan).unwrap();
+        let mut quarantine_key = 7u32.to_le_bytes().to_vec();
+        quarantine_key.extend_from_slice(&[7; 32]);
+        let quarantined = IndexEntry {
+            file_number: 1,
+            offset: 8,
+            length: 50,
+        };
+        index
+            .quarantine
+            .insert(&quarantine_key[..], &quarantined.serialize()[..])
+            .unwrap();
+
+        let mut entries = index.located_entries().unwrap();
+        assert_eq!(entries.len(), 5);
+        assert!(entries.contains(&(EntryKey::Quarantined(quarantine_key.clone()), quarantined)));
+        assert!(!entries
+            .iter()
+            .any(|(key, _)| *key == EntryKey::Block(orphan)));
+
+        for (_, entry) in entries.iter_mut() {
+            entry.offset += 100;
+        }
+        index.move_entries(&entries, b"progress", b"1").unwrap();
+        assert_eq!(index.get_meta(b"progress").unwrap(), Some(b"1".to_vec()));
+        for (key, entry) in &entries {
+            if let EntryKey::Block(blockhash) = key {
+                assert_eq!(&index.get_block_entry(blockhash).unwrap(), entry);
+            }
+        }
+        assert_eq!(
+            index.quarantined_blocks().unwrap()[0]
+                .entry
+                .as_ref()
+                .unwrap()
+                .offset,
+            108
+        );
+        index.remove_meta(b"progress").unwrap();
+        assert_eq!(index.get_meta(b"progress").unwrap(), None);
+    }
+
+    #[test]
+    fn test_dead_bytes() {
+        let index_dir = temp_dir("test_dead_bytes");
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        assert!(index.dead_bytes().unwrap().is_empty());
+
+        index.add_dead_bytes(300, 10).unwrap();
+        index.add_dead_bytes(2, 5).unwrap();
+        index.add_dead_bytes(300, 7).unwrap();
+        // In file order, not the order of the little-endian bytes
+        assert_eq!(index.dead_bytes().unwrap(), vec![(2, 5), (300, 17)]);
+
+        drop(index);
+        let (index, _) = Index::initialize(&index_dir).unwrap();
+        assert_eq!(index.dead_bytes().unwrap(), vec![(2, 5), (300, 17)]);
+        assert!(index.unknown_trees().is_empty());
+        index.clear_dead_bytes(&[300, 4]).unwrap();
+        assert_eq!(index.dead_bytes().unwrap(), vec![(2, 5)]);
+    }
+
+    #[test]
+    fn test_tiers() {
+        let index_dir = temp_dir("test_tiers");
+        let (mut index, _) = Index::initialize(&index_dir).unwrap();
+        insert_test_blocks(&mut index, 2);
+        let blockhash = index.get_blockhash_by_height(1).unwrap();
+        let other = index.get_blockhash_by_height(0).unwrap();
+        let entry = |offset| IndexEntry {
+            file_number: 0,
+            offset,
+            length: 10,
+        };
+        index.insert_tier(&blockhash, 10_000, &entry(300)).unwrap();
+        index.insert_tier(&blockhash, 1_000, &entry(200)).unwrap();
+        index.insert_tier(&other, 1_000, &entry(100)).unwrap();
+
+        assert_eq!(index.get_tier_entry(&blockhash, 1_000).unwrap(), entry(200));
+        assert!(matches!(
+            index.get_tier_entry(&blockhash, 500),
+            Err(StorageError::EntryNotFound)
+        ));
+        assert_eq!(
+            index.tier_entries(&blockhash).unwrap(),
+            vec![(1_000, entry(200)), (10_000, entry(300))]
+        );
+        assert_eq!(index.located_entries().unwrap().len(), 2 + 3);
+
+        assert_eq!(
+            index.remove_tiers(&blockhash).unwrap(),
+            vec![entry(200), entry(300)]
+        );
+        assert!(index.tier_entries(&blockhash).unwrap().is_empty());
+        assert_eq!(
+            index.tier_entries(&other).unwrap(),
+            vec![(1_000, entry(100))]
+        );
     }
 }
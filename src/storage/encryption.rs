@@ -0,0 +1,268 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fs;
+use std::path::Path;
+
+use super::{file_header_prefix, StorageError, HEADER_PREFIX_SIZE};
+
+/// Environment variable the encryption key can be supplied through (64 hex characters).
+pub const ENCRYPTION_KEY_ENV: &str = "SILENTSERVER_ENCRYPTION_KEY";
+
+/// Magic bytes at the start of every encrypted block data file, followed by the file format
+/// version like in plaintext files (see FILE_FORMAT_VERSION).
+pub const ENCRYPTED_MAGIC_BYTES: [u8; 4] = *b"SPSE";
+/// Encrypted files of format version 1 started with these bytes instead. They remain the
+/// associated data of the key check, so a header can be upgraded without the key.
+pub const LEGACY_ENCRYPTED_MAGIC_BYTES: [u8; 8] = *b"SPSENC01";
+
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+const SALT_SIZE: usize = 8;
+
+/// Encrypted files start with:
+/// [ENCRYPTED_MAGIC_BYTES (4 bytes)] [format version (u16 LE)] [reserved (2 bytes)]
+/// [key check nonce (24 bytes)] [key check tag (16 bytes)]
+/// The key check is the auth tag of an empty message, which lets us tell a wrong key
+/// apart from a tampered record.
+pub const ENCRYPTED_HEADER_SIZE: usize = HEADER_PREFIX_SIZE + NONCE_SIZE + TAG_SIZE;
+
+/// Every encrypted record is stored as:
+/// [ciphertext length (u32 little-endian)] [salt (8 bytes)] [ciphertext] [auth tag (16 bytes)]
+pub const RECORD_OVERHEAD: usize = 4 + SALT_SIZE + TAG_SIZE;
+
+/// Store-level key used to encrypt block data records at rest.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_SIZE]);
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptionKey(<redacted>)")
+    }
+}
+
+impl EncryptionKey {
+    pub fn from_bytes(bytes: [u8; KEY_SIZE]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Parses a key from 64 hex characters (surrounding whitespace is ignored).
+    pub fn from_hex(hex: &str) -> Result<Self, StorageError> {
+        let hex = hex.trim().as_bytes();
+        if hex.len() != KEY_SIZE * 2 {
+            return Err(StorageError::EncryptionError(
+                "key must be 64 hex characters",
+            ));
+        }
+
+        let mut key = [0u8; KEY_SIZE];
+        for (i, pair) in hex.chunks(2).enumerate() {
+            let hi = hex_value(pair[0]);
+            let lo = hex_value(pair[1]);
+            match (hi, lo) {
+                (Some(hi), Some(lo)) => key[i] = (hi << 4) | lo,
+                _ => {
+                    return Err(StorageError::EncryptionError(
+                        "key contains non-hex characters",
+                    ))
+                }
+            }
+        }
+        Ok(EncryptionKey(key))
+    }
+
+    /// Reads a key file containing either exactly 32 raw bytes or 64 hex characters.
+    pub fn from_file(path: &Path) -> Result<Self, StorageError> {
+        let data = fs::read(path)?;
+        if data.len() == KEY_SIZE {
+            let mut key = [0u8; KEY_SIZE];
+            key.copy_from_slice(&data);
+            return Ok(EncryptionKey(key));
+        }
+        let text = std::str::from_utf8(&data).map_err(|_| {
+            StorageError::EncryptionError("key file is neither 32 raw bytes nor hex")
+        })?;
+        Self::from_hex(text)
+    }
+
+    /// Reads the key from ENCRYPTION_KEY_ENV, returns None if the variable is not set.
+    pub fn from_env() -> Result<Option<Self>, StorageError> {
+        match std::env::var(ENCRYPTION_KEY_ENV) {
+            Ok(hex) => Self::from_hex(&hex).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+
+    /// The nonce is derived from the position of the record in the flat files, plus a random
+    /// salt stored with the record. The salt matters because records can be truncated away
+    /// (failed writes, reorgs) and a different record written at the same position later.
+    fn record_nonce(file_number: u64, offset: u64, salt: &[u8]) -> XNonce {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[0..8].copy_from_slice(&file_number.to_le_bytes());
+        nonce[8..16].copy_from_slice(&offset.to_le_bytes());
+        nonce[16..24].copy_from_slice(salt);
+        nonce.into()
+    }
+
+    /// Builds the header written at the start of each new encrypted file, in format
+    /// `version`.
+    pub fn file_header(&self, version: u16) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let tag = self
+            .cipher()
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &[],
+                    aad: &LEGACY_ENCRYPTED_MAGIC_BYTES,
+                },
+            )
+            .expect("Encrypting an empty message cannot fail");
+
+        let mut header = Vec::with_capacity(ENCRYPTED_HEADER_SIZE);
+        header.extend_from_slice(&file_header_prefix(ENCRYPTED_MAGIC_BYTES, version));
+        header.extend_from_slice(&nonce);
+        header.extend_from_slice(&tag);
+        header
+    }
+
+    /// Checks that the header of an encrypted file was written with this key. The magic and
+    /// format version in front of the key check are up to the caller.
+    pub fn check_file_header(&self, header: &[u8]) -> Result<(), StorageError> {
+        if header.len() != ENCRYPTED_HEADER_SIZE {
+            return Err(StorageError::InvalidData("Invalid encrypted file header"));
+        }
+        let nonce =
+            XNonce::from_slice(&header[HEADER_PREFIX_SIZE..HEADER_PREFIX_SIZE + NONCE_SIZE]);
+        self.cipher()
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &header[HEADER_PREFIX_SIZE + NONCE_SIZE..],
+                    aad: &LEGACY_ENCRYPTED_MAGIC_BYTES,
+                },
+            )
+            .map_err(|_| StorageError::WrongKey)?;
+        Ok(())
+    }
+
+    /// Encrypts a serialized record that will be written at (file_number, offset).
+    pub fn encrypt_record(&self, file_number: u64, offset: u64, plaintext: &[u8]) -> Vec<u8> {
+        let mut salt = [0u8; SALT_SIZE];
+        salt.copy_from_slice(&XChaCha20Poly1305::generate_nonce(&mut OsRng)[..SALT_SIZE]);
+        let nonce = Self::record_nonce(file_number, offset, &salt);
+
+        let sealed = self
+            .cipher()
+            .encrypt(&nonce, plaintext)
+            .expect("Record encryption cannot fail");
+
+        let mut record = Vec::with_capacity(plaintext.len() + RECORD_OVERHEAD);
+        record.extend_from_slice(&(plaintext.len() as u32).to_le_bytes());
+        record.extend_from_slice(&salt);
+        record.extend_from_slice(&sealed);
+        record
+    }
+
+    /// Decrypts a full encrypted record read from (file_number, offset).
+    pub fn decrypt_record(
+        &self,
+        file_number: u64,
+        offset: u64,
+        record: &[u8],
+    ) -> Result<Vec<u8>, StorageError> {
+        let len = encrypted_record_len(record)?;
+        if record.len() != len {
+            return Err(StorageError::InvalidData(
+                "Encrypted record length mismatch",
+            ));
+        }
+        let nonce = Self::record_nonce(file_number, offset, &record[4..4 + SALT_SIZE]);
+        self.cipher()
+            .decrypt(&nonce, &record[4 + SALT_SIZE..])
+            .map_err(|_| StorageError::DecryptionFailed)
+    }
+}
+
+/// Returns the full on-disk length of an encrypted record given (at least) its 4 byte length prefix.
+pub fn encrypted_record_len(prefix: &[u8]) -> Result<usize, StorageError> {
+    if prefix.len() < 4 {
+        return Err(StorageError::DeserializeError(
+            "insufficient data for encrypted record length",
+        ));
+    }
+    let len = u32::from_le_bytes(prefix[0..4].try_into().unwrap()) as usize;
+    Ok(len + RECORD_OVERHEAD)
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::FRAMED_FORMAT_VERSION;
+    use super::*;
+
+    #[test]
+    fn test_record_round_trip() {
+        let key = EncryptionKey::from_bytes([7u8; KEY_SIZE]);
+        let plaintext = b"some serialized block data".to_vec();
+
+        let record = key.encrypt_record(3, 1000, &plaintext);
+        assert_eq!(record.len(), plaintext.len() + RECORD_OVERHEAD);
+        assert_eq!(encrypted_record_len(&record).unwrap(), record.len());
+        assert_eq!(key.decrypt_record(3, 1000, &record).unwrap(), plaintext);
+
+        // The nonce is bound to the position of the record
+        assert!(matches!(
+            key.decrypt_record(3, 1001, &record),
+            Err(StorageError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_wrong_key_and_tampering() {
+        let key = EncryptionKey::from_bytes([7u8; KEY_SIZE]);
+        let other = EncryptionKey::from_bytes([8u8; KEY_SIZE]);
+
+        let header = key.file_header(FRAMED_FORMAT_VERSION);
+        assert!(key.check_file_header(&header).is_ok());
+        assert!(matches!(
+            other.check_file_header(&header),
+            Err(StorageError::WrongKey)
+        ));
+
+        let mut record = key.encrypt_record(0, 48, b"tweaks");
+        assert!(matches!(
+            other.decrypt_record(0, 48, &record),
+            Err(StorageError::DecryptionFailed)
+        ));
+
+        record[14] ^= 1;
+        assert!(matches!(
+            key.decrypt_record(0, 48, &record),
+            Err(StorageError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_key_parsing() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF\n";
+        let key = EncryptionKey::from_hex(hex).unwrap();
+        assert_eq!(key.0[1], 0x11);
+        assert_eq!(key.0[31], 0xff);
+
+        assert!(EncryptionKey::from_hex("abcd").is_err());
+        assert!(EncryptionKey::from_hex(&"zz".repeat(32)).is_err());
+    }
+}
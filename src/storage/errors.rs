@@ -1,4 +1,5 @@
 use std::io;
+use std::path::PathBuf;
 use sled;
 
 #[derive(Debug)]
@@ -14,6 +15,39 @@ pub enum StorageError {
     // could probably imply that a new block to be added
     InvalidHeight, 
     CorruptDB(&'static str),
+    EncryptionError(&'static str),
+    // The key does not match the one the store was encrypted with.
+    WrongKey,
+    // A record failed authentication, it has been tampered with or corrupted.
+    DecryptionFailed,
+    // The caller's view of the tip is stale.
+    TipMismatch,
+    // Strict mode froze the store after an integrity violation.
+    Frozen,
+    // The data directory was written by an older version and needs `silentserver upgrade`.
+    UpgradeRequired(u32),
+    // The data directory was written by a newer version, we never downgrade.
+    DataDirTooNew(u32),
+    // A serialized record is bigger than the store accepts.
+    RecordTooLarge { size: usize, max: usize },
+    // A data directory from before version stamps needs --assume-network to be adopted.
+    AssumeNetworkRequired,
+    // A data directory from before version stamps doesn't look like one we can safely adopt.
+    IncompatibleDataDir(&'static str),
+    // A block data file was written in a newer file format than this binary reads.
+    UnsupportedVersion(u16),
+    // The block's record was in a block data file deleted by pruning.
+    Pruned,
+    // Another store has the data directory open, holding the lock file at this path.
+    AlreadyLocked(PathBuf),
+    // The data directory holds data for another network than the one asked for.
+    NetworkMismatch { stored: String, requested: String },
+    // A serialized record claims more tweaks than it holds or than a record may have.
+    InvalidTweakCount(u32),
+    // A stream of records ended cleanly, between two records.
+    EndOfStream,
+    // A record's tweaks aren't sorted and free of duplicates, see BlockData::canonicalize.
+    NonCanonicalTweaks,
 }
 
 impl From<io::Error> for StorageError {
@@ -32,7 +66,7 @@ impl std::fmt::Display for StorageError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             StorageError::DeserializeError(msg) => write!(f, "Deserialization error: {}", msg),
-            StorageError::CrcMismatch => write!(f, "CRC mismatch for tweaks"),
+            StorageError::CrcMismatch => write!(f, "CRC mismatch for block data record"),
             StorageError::InvalidData(msg) => write!(f, "Invalid data: {}", msg),
             StorageError::IoError(e) => write!(f, "IO error: {}", e),
             StorageError::DbError(e) => write!(f, "Database error: {}", e),
@@ -40,6 +74,59 @@ impl std::fmt::Display for StorageError {
             StorageError::OrphanedEntry => write!(f, "Entry is marked as orphaned"),
             StorageError::InvalidHeight => write!(f, "Invalid height"),
             StorageError::CorruptDB(msg) => write!(f, "Corrupt database: {}", msg),
+            StorageError::EncryptionError(msg) => write!(f, "Encryption error: {}", msg),
+            StorageError::WrongKey => write!(f, "Wrong encryption key for this store"),
+            StorageError::DecryptionFailed => write!(f, "Record failed authentication (tampered or corrupted)"),
+            StorageError::TipMismatch => write!(f, "Block is not the current tip"),
+            StorageError::Frozen => write!(f, "Store is frozen after an integrity violation (strict mode)"),
+            StorageError::UpgradeRequired(version) => write!(
+                f,
+                "Data directory is at version {}, this binary needs version {}. Back it up and run `silentserver --data-dir <dir> upgrade` to migrate it",
+                version,
+                super::DATA_DIR_VERSION
+            ),
+            StorageError::DataDirTooNew(version) => write!(
+                f,
+                "Data directory is at version {}, newer than the version {} this binary supports. Downgrades are not supported, use a newer binary",
+                version,
+                super::DATA_DIR_VERSION
+            ),
+            StorageError::RecordTooLarge { size, max } => {
+                write!(f, "Record of {} bytes exceeds the {} byte limit", size, max)
+            }
+            StorageError::AssumeNetworkRequired => write!(
+                f,
+                "Data directory predates version stamps and doesn't record its network, pass --assume-network to confirm it belongs to --network"
+            ),
+            StorageError::IncompatibleDataDir(msg) => write!(
+                f,
+                "Refusing to adopt data directory from a pre-release version: {}",
+                msg
+            ),
+            StorageError::UnsupportedVersion(version) => write!(
+                f,
+                "Block data file format version {} is newer than the version {} this binary reads",
+                version,
+                super::FILE_FORMAT_VERSION
+            ),
+            StorageError::Pruned => write!(f, "Block data has been pruned from this store"),
+            StorageError::AlreadyLocked(path) => write!(
+                f,
+                "Data directory is in use by another process (lock held on {})",
+                path.display()
+            ),
+            StorageError::NetworkMismatch { stored, requested } => write!(
+                f,
+                "Data directory holds {} data, not {}",
+                stored, requested
+            ),
+            StorageError::InvalidTweakCount(len_tweaks) => write!(
+                f,
+                "Record claims {} tweaks, more than it holds or the record size limit allows",
+                len_tweaks
+            ),
+            StorageError::EndOfStream => write!(f, "End of the record stream"),
+            StorageError::NonCanonicalTweaks => write!(f, "Tweaks are not in canonical order"),
         }
     }
 }
@@ -1,16 +1,133 @@
-use log::{debug, info};
+use log::{debug, info, warn};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
 use std::fs::File;
-use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+#[cfg(feature = "mmap")]
+use {
+    memmap2::Mmap,
+    std::marker::PhantomData,
+    std::ops::{Deref, Range},
+    std::sync::Weak,
+};
 
-use super::{BlockData, Index, IndexEntry, StorageError};
+use crate::platform;
+
+use super::journal::{Intent, Journal};
+use super::{
+    check_data_dir_version, check_meta_version, encrypted_record_len, stamp_data_dir_version,
+    BlockCache, BlockData, BlockDataRef, CacheStats, ChainTotals, Checkpoint, DataDirLock,
+    DataDirState, EncryptionKey, EntryKey, Index, IndexEntry, IntegrityGuard, StorageError,
+    StoreStats, TweakDiff, Violation, ViolationKind, Watermark, WatermarkStatus, DATA_DIR_VERSION,
+    DEFAULT_RECENT_WINDOW, DEFAULT_SOFT_LIMIT_FRACTION, ENCRYPTED_HEADER_SIZE,
+    ENCRYPTED_MAGIC_BYTES, LEGACY_ENCRYPTED_MAGIC_BYTES, NETWORK_META_KEY, RECORD_HEADER_SIZE,
+    RECORD_OVERHEAD, RECORD_VERSION, TWEAKS_CRC_RECORD_VERSION, TWEAK_SIZE,
+};
 
 pub const BLOCK_DATA_DIR_NAME: &str = "block_data";
 pub const INDEX_DIR_NAME: &str = "index_db";
 
-const MAGIC_BYTES: [u8; 8] = *b"SPSDATA1";
-const MAX_BLOCKDATA_SIZE: u64 = 128 * 1024 * 1024; // 128 MB
+/// Block data files start with [magic (4 bytes)][format version (u16 LE)][reserved (2 bytes)],
+/// encrypted ones followed by a key check (see EncryptionKey::file_header). Version 1 files,
+/// from before the format version, started with SPSDATA1 or SPSENC01 instead. Up to version 2
+/// records were stored bare, version 3 frames them (see RECORD_MAGIC) and version 4 compresses
+/// them. Versions 5 and 6 are versions 3 and 4 with records of RECORD_VERSION 2, whose
+/// checksum covers their blockhash and tweak count too. Files are written in version 5 or 6
+/// depending on `compression_level`, and a store can hold both.
+pub const FILE_FORMAT_VERSION: u16 = COMPRESSED_FORMAT_VERSION;
+/// The last format version whose records aren't framed.
+const UNFRAMED_FORMAT_VERSION: u16 = 2;
+/// Records are framed, and stored as they are.
+pub const FRAMED_FORMAT_VERSION: u16 = 5;
+/// Records are framed, and their payload compressed (see compress_record).
+pub const COMPRESSED_FORMAT_VERSION: u16 = 6;
+/// FRAMED_FORMAT_VERSION with records of TWEAKS_CRC_RECORD_VERSION, as framed by
+/// `migrate_record_frames`. Only read by `migrate_record_checksums`.
+pub const TWEAKS_CRC_FORMAT_VERSION: u16 = 3;
+/// COMPRESSED_FORMAT_VERSION with records of TWEAKS_CRC_RECORD_VERSION.
+pub const TWEAKS_CRC_COMPRESSED_FORMAT_VERSION: u16 = 4;
+const MAGIC_BYTES: [u8; 4] = *b"SPSD";
+const LEGACY_MAGIC_BYTES: [u8; 8] = *b"SPSDATA1";
+/// Size of the magic, format version and reserved bytes every file header starts with.
+pub const HEADER_PREFIX_SIZE: usize = 8;
+/// Every record is framed as [RECORD_MAGIC][payload length (u32 LE)][payload], the payload
+/// being a serialized BlockData or, in an encrypted store, an encrypted record. The frame lets
+/// a file be walked without the index (see FrameScanner); index entries cover all of it.
+pub const RECORD_MAGIC: [u8; 4] = *b"SPSR";
+/// Frames the record of a dust tier (see DustTier) instead of RECORD_MAGIC, which is stored
+/// like any other record. Which block and tier it holds is only known to the index.
+pub const TIER_RECORD_MAGIC: [u8; 4] = *b"SPST";
+/// Frames the record `replace_block_tweaks` writes for a block already stored, so rebuilding
+/// the index can tell it from a block of its own height.
+pub const REPLACED_RECORD_MAGIC: [u8; 4] = *b"SPSC";
+/// Takes the place of RECORD_MAGIC once `replace_block_tweaks` stored the block again: the
+/// record still gives the block its height when rebuilding the index, but only while the
+/// block has a REPLACED_RECORD_MAGIC record to take its place.
+pub const SUPERSEDED_RECORD_MAGIC: [u8; 4] = *b"SPSS";
+/// Takes the place of the magic of a record whose block was reorged away, or of a replacement
+/// record replaced again, so rebuilding the index doesn't give it a height.
+pub const ORPHANED_RECORD_MAGIC: [u8; 4] = *b"SPSO";
+/// The magics a record of a block of the chain can be framed with.
+const BLOCK_RECORD_MAGICS: [[u8; 4]; 2] = [RECORD_MAGIC, REPLACED_RECORD_MAGIC];
+/// Every magic a record can be framed with.
+const RECORD_MAGICS: [[u8; 4]; 5] = [
+    RECORD_MAGIC,
+    TIER_RECORD_MAGIC,
+    REPLACED_RECORD_MAGIC,
+    SUPERSEDED_RECORD_MAGIC,
+    ORPHANED_RECORD_MAGIC,
+];
+pub const FRAME_HEADER_SIZE: usize = 8;
+/// Size of the uncompressed length in front of a compressed payload, see compress_record.
+const COMPRESSED_HEADER_SIZE: usize = 4;
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 128 * 1024 * 1024; // 128 MB
+/// Index metadata holding the `max_file_size` the store was last opened with (u64 LE).
+pub const MAX_FILE_SIZE_META_KEY: &[u8] = b"max_file_size";
+/// A worst case mainnet block yields tens of thousands of tweaks, a couple of MB serialized.
+pub const DEFAULT_MAX_RECORD_SIZE: usize = 8 * 1024 * 1024; // 8 MB
+/// Index metadata kept while `migrate_record_frames` runs: the number of the first block data
+/// file whose index entries haven't been moved yet (u64 LE).
+const FRAME_MIGRATION_META_KEY: &[u8] = b"frame_migration";
+/// Same for `migrate_record_checksums`.
+const CHECKSUM_MIGRATION_META_KEY: &[u8] = b"checksum_migration";
+/// Index metadata of a pruned store: [first kept block data file (u64 LE)][first kept height
+/// (u32 LE)], see `prune_below`.
+pub const PRUNED_META_KEY: &[u8] = b"pruned";
+/// Index metadata holding the ChainTotals of the store, see ChainTotals::encode.
+const CHAIN_TOTALS_META_KEY: &[u8] = b"chain_totals";
+/// Index metadata kept while `compact` swaps in a rewritten block data file: the number of
+/// the file (u64 LE), whose index entries already point into the copy.
+const COMPACTION_META_KEY: &[u8] = b"compaction";
+/// Index metadata holding where the last record written out of height order (a block whose
+/// tweaks were replaced) ends, as [file number (u64 LE)] [offset (u64 LE)]. Opening the store
+/// keeps the block data up to there even if the tip's record ends before.
+const REPLACED_TAIL_META_KEY: &[u8] = b"replaced_tail";
+/// Index metadata kept while `rekey` swaps in the re-encrypted copies of the block data files:
+/// [first file number (u64 LE)] [last file number (u64 LE)]. Every copy is complete by then.
+const REKEY_META_KEY: &[u8] = b"rekey";
+/// Extension of the copy `rekey` re-encrypts a block data file into.
+const REKEY_EXTENSION: &str = "rekey";
+/// Extension of the copy `compact` rewrites a block data file into.
+const COMPACTION_EXTENSION: &str = "compact";
+/// How often rebuilding the index logs its progress, in blocks.
+const REBUILD_PROGRESS_INTERVAL: u32 = 100_000;
+/// How often `verify` logs its progress, in blocks.
+const VERIFY_PROGRESS_INTERVAL: u32 = 10_000;
+/// Snapshots start with [SNAPSHOT_MAGIC][SNAPSHOT_VERSION (u16 LE)][network length (u8)]
+/// [network][number of blocks (u32 LE)], followed by every block from height 0 to the tip as
+/// [length (u32 LE)][serialized BlockData]. See `export_snapshot`.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"SPSS";
+pub const SNAPSHOT_VERSION: u16 = 2;
+/// Snapshots of this version hold BlockData of TWEAKS_CRC_RECORD_VERSION. Still imported.
+const TWEAKS_CRC_SNAPSHOT_VERSION: u16 = 1;
+/// How many blocks `import_snapshot` stores at a time.
+const SNAPSHOT_IMPORT_BATCH: usize = 1_000;
+/// How often exporting and importing snapshots log their progress, in blocks.
+const SNAPSHOT_PROGRESS_INTERVAL: u32 = 100_000;
 
 macro_rules! block_file_name {
     ($file_number:expr) => {
@@ -19,398 +136,8207 @@ macro_rules! block_file_name {
 }
 
 // FlatFileStore stores block data in the following format:
-// [MAGIC_BYTES][Serialized BlockData]*
+// [File header][Frame][Serialized BlockData] [Frame][Serialized BlockData]...
+// or, when the store is encrypted (see encryption.rs):
+// [Encrypted file header][Frame][Encrypted BlockData record]...
+
+/// Options controlling how a FlatFileStore is opened.
+#[derive(Debug, Clone)]
+pub struct FlatFileStoreOptions {
+    /// Encrypt block data records at rest. A store created with a key can only be opened
+    /// with the same key, and a plaintext store can not be opened with a key.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Treat every internal inconsistency as fatal: the first one freezes the store
+    /// (see IntegrityGuard).
+    pub strict: bool,
+    /// Number of recent blocks the index keeps in memory for fast lookups during reorgs.
+    pub recent_window: usize,
+    /// Largest serialized block data record `add_block` accepts, anything bigger is rejected
+    /// with `StorageError::RecordTooLarge`. Records are read back with the same limit, so
+    /// lowering it leaves bigger stored records unreadable.
+    pub max_record_size: usize,
+    /// Network to record when adopting a data directory created before version stamps,
+    /// which don't say what network they hold. Opening one fails without it.
+    pub assume_network: Option<String>,
+    /// Fraction of a hard limit (such as `max_record_size`) at which a warning is logged,
+    /// in (0, 1]. See Watermark.
+    pub soft_limit_fraction: f64,
+    /// A new block data file is started once a record would take the current one past this
+    /// size. Changing it for an existing store only affects records written from then on.
+    pub max_file_size: u64,
+    /// When added blocks are synced to disk, see DurabilityPolicy.
+    pub durability: DurabilityPolicy,
+    /// Bytes of recently read blocks to keep in memory (see BlockCache), 0 to not cache any.
+    pub block_cache_size: usize,
+    /// Compress records with zstd at this level, None to store them uncompressed. Only
+    /// affects files written from now on: records always go in a file of the format they are
+    /// written in, so the first record after a change starts a new file.
+    pub compression_level: Option<i32>,
+    /// Reserve `max_file_size` bytes of disk for every new block data file up front, instead
+    /// of growing it record by record, which fragments files on ext4 and xfs. The current file
+    /// then runs past its data, which ends at the last record the index references.
+    pub preallocate: bool,
+    /// Network the store holds data for ("mainnet", "signet", ...). Recorded in the metadata
+    /// the first time the store is opened with it; opening it for another network after that
+    /// fails with `StorageError::NetworkMismatch`. None opens the store whatever it holds.
+    pub network: Option<String>,
+    /// Sort and deduplicate the tweaks of every block (and dust tier) written, see
+    /// `BlockData::canonicalize`, so stores that scanned the same blocks hold the same
+    /// records. Records already stored are left as they are, `verify` lists those that aren't
+    /// canonical.
+    pub canonical_tweaks: bool,
+}
+
+/// When `add_block` and `add_block_bulk` sync what they wrote (block data file and index) to
+/// disk. Whatever isn't synced yet can be lost to a power loss, even though the call
+/// succeeded; the store itself stays consistent either way. `FlatFileStore::flush` syncs on
+/// demand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DurabilityPolicy {
+    /// After every call.
+    Always,
+    /// Once at least this many blocks were added since the last sync.
+    EveryNBlocks(u32),
+    /// Leave it to the OS and sled's background flushes.
+    #[default]
+    Never,
+}
+
+impl Default for FlatFileStoreOptions {
+    fn default() -> Self {
+        FlatFileStoreOptions {
+            encryption_key: None,
+            strict: false,
+            recent_window: DEFAULT_RECENT_WINDOW,
+            max_record_size: DEFAULT_MAX_RECORD_SIZE,
+            assume_network: None,
+            soft_limit_fraction: DEFAULT_SOFT_LIMIT_FRACTION,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            durability: DurabilityPolicy::default(),
+            block_cache_size: 0,
+            compression_level: None,
+            preallocate: false,
+            network: None,
+            canonical_tweaks: false,
+        }
+    }
+}
+
+/// The tweaks of a block left after dropping those of outputs worth less than `dust_threshold`
+/// sats, stored along with the block (see `add_block_with_tiers`) so light clients can trade
+/// completeness for bandwidth.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DustTier {
+    pub dust_threshold: u64,
+    pub tweaks: Vec<[u8; TWEAK_SIZE]>,
+}
+
+/// A block taken off the tip of the store by `remove_tip_block`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RemovedBlock {
+    pub height: u32,
+    pub blockhash: [u8; 32],
+    pub entry: IndexEntry,
+}
+
+/// Outcome of `add_block_bulk`. The first `committed` blocks of the batch are stored, file and
+/// index both; nothing after them is. `error` holds the height that failed and why.
+#[derive(Debug)]
+pub struct BulkResult {
+    pub committed: u32,
+    pub error: Option<(u32, StorageError)>,
+}
+
+impl BulkResult {
+    pub fn is_complete(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The number of blocks added, or the error that stopped the batch.
+    pub fn into_result(self) -> Result<u32, StorageError> {
+        match self.error {
+            None => Ok(self.committed),
+            Some((_, e)) => Err(e),
+        }
+    }
+}
+
+/// Outcome of `verify`. Problems are listed as they were found, the totals cover everything
+/// that was looked at.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Heights whose record reads fine but isn't the block the index has there: it holds
+    /// another blockhash, or its frame disagrees with the length of the entry.
+    pub mismatched_heights: Vec<u32>,
+    /// Heights whose record can't be read back: no index entry, missing or cut short, or
+    /// failing its checksum.
+    pub unreadable_heights: Vec<u32>,
+    /// Blocks in the index that aren't on the chain, removed by a reorg or left behind by an
+    /// interrupted write.
+    pub orphaned_entries: Vec<[u8; 32]>,
+    /// Bytes of the block data files that no index entry covers, such as the records of
+    /// reorged blocks.
+    pub dead_space: Vec<DeadSpace>,
+    /// Heights whose record reads fine but whose tweaks aren't canonical (see
+    /// `BlockData::canonicalize`), as written before `canonical_tweaks` was set. No damage.
+    pub non_canonical_heights: Vec<u32>,
+    pub blocks_checked: u32,
+    pub files_checked: u64,
+    /// Bytes of records of the chain.
+    pub referenced_bytes: u64,
+    pub dead_bytes: u64,
+}
+
+impl VerifyReport {
+    /// Whether every block of the chain read back as the index describes it. Orphaned entries
+    /// and dead space are left by reorgs and are no damage in themselves.
+    pub fn is_clean(&self) -> bool {
+        self.mismatched_heights.is_empty() && self.unreadable_heights.is_empty()
+    }
+}
+
+/// A range of a block data file that nothing in the index points at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadSpace {
+    pub file_number: u64,
+    pub offset: u64,
+    pub length: u64,
+}
 
 /// FlatFileStore manages appending BlockData records into files.
-/// It creates a new file (with a magic header) when MAX_BLOCKDATA_SIZE is reached.
+/// It creates a new file (with a magic header) when the maximum file size is reached.
 /// It also persists:
 ///  - blockhash -> IndexEntry (in the default sled tree)
 ///  - height (u32) -> blockhash (in "height_to_hash" tree)
 ///  - blockhash -> height (in "hash_to_height" tree)
+///
+/// Reads and writes take `&self`, so one store can be shared between threads in an `Arc`.
+/// Writes run one at a time; maintenance that deletes or rewrites files (`prune_below`,
+/// `rekey`) takes `&mut self`.
 
 pub struct FlatFileStore {
     block_data_dir: PathBuf,
     index_dir: PathBuf,
     index: Index,
+    /// Files before this one were deleted by pruning.
+    first_file_number: u64,
+    /// Blocks below this height went with the pruned files.
+    pruned_up_to: u32,
+    encryption_key: Option<EncryptionKey>,
+    integrity: Arc<IntegrityGuard>,
+    max_record_size: usize,
+    /// A new file is started once a record would take the current one past this size.
+    max_file_size: u64,
+    durability: DurabilityPolicy,
+    cache: BlockCache,
+    /// zstd level records are compressed at, None if they aren't.
+    compression_level: Option<i32>,
+    /// Block data files in COMPRESSED_FORMAT_VERSION.
+    compressed_files: RwLock<HashSet<u64>>,
+    /// Whether the current file is preallocated up to max_file_size.
+    preallocate: bool,
+    canonical_tweaks: bool,
+    /// Everything writes change, behind one lock so the store can be shared across threads.
+    state: Mutex<WriteState>,
+    /// Block data files mapped by `get_block_range_mmap`, for as long as a slice uses them.
+    #[cfg(feature = "mmap")]
+    mapped_files: Mutex<HashMap<u64, Weak<Mmap>>>,
+    /// Held for as long as the store is open. Last, so it is only released once everything
+    /// else is closed.
+    _lock: DataDirLock,
+}
+
+/// The write side of a FlatFileStore.
+struct WriteState {
     current_file_number: u64,
+    /// Where the next record goes in the current file, buffered bytes included.
+    write_offset: u64,
+    /// Appends to the current file. Opened on the first write and kept open across add_block
+    /// calls; flushed before anything reads the files and closed on rotation.
+    writer: Option<BufWriter<File>>,
+    /// Warns as records approach max_record_size.
+    record_size: Watermark,
+    /// Blocks added since the last sync.
+    unsynced_blocks: u32,
+    /// Tweaks and record bytes of the chain, for `stats`.
+    totals: ChainTotals,
+    /// Intents of `add_block`, see `replay_journal`.
+    journal: Journal,
+}
+
+impl WriteState {
+    /// Flushes and closes the writer, the next write reopens the current file.
+    fn close_writer(&mut self) -> io::Result<()> {
+        match self.writer.take() {
+            Some(writer) => writer.into_inner().map(drop).map_err(|e| e.into_error()),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes, and syncs the current file's data to disk.
+    fn sync_data(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => {
+                writer.flush()?;
+                writer.get_ref().sync_data()
+            }
+            None => Ok(()),
+        }
+    }
 }
 
 impl FlatFileStore {
     pub fn initialize(data_dir: PathBuf) -> Result<Self, StorageError> {
+        Self::initialize_with_options(data_dir, FlatFileStoreOptions::default())
+    }
+
+    pub fn initialize_with_options(
+        data_dir: PathBuf,
+        options: FlatFileStoreOptions,
+    ) -> Result<Self, StorageError> {
+        let lock = DataDirLock::acquire(&data_dir)?;
+        let (journal, pending) = Journal::open(&data_dir)?;
+        let data_dir_state = check_data_dir_version(&data_dir)?;
+        if !Watermark::valid_soft_fraction(options.soft_limit_fraction) {
+            return Err(StorageError::InvalidData(
+                "Soft limit fraction must be above 0 and at most 1",
+            ));
+        }
+        if options.durability == DurabilityPolicy::EveryNBlocks(0) {
+            return Err(StorageError::InvalidData(
+                "Durability policy must sync every 1 or more blocks",
+            ));
+        }
+        if options
+            .compression_level
+            .is_some_and(|level| !zstd::compression_level_range().contains(&level))
+        {
+            return Err(StorageError::InvalidData(
+                "Compression level is outside of what zstd supports",
+            ));
+        }
+        let format_version = written_format_version(options.compression_level);
+
+        let encryption_key = options.encryption_key;
+        let integrity = Arc::new(IntegrityGuard::new(options.strict, data_dir.clone()));
         let block_data_dir = data_dir.join(BLOCK_DATA_DIR_NAME);
         // files are named in format sps00000.dat, sps00001.dat, etc.
         info!(target: "FileStore", "Checking for existing FileStore in: {}", block_data_dir.display());
 
-        let mut current_file_number: u64 = 0;
+        if data_dir_state == DataDirState::Version0 {
+            Self::prepare_version_0(
+                &data_dir.join(INDEX_DIR_NAME),
+                &block_data_dir,
+                encryption_key.is_some(),
+                options.assume_network.is_some(),
+            )?;
+        }
+
+        let index_dir = data_dir.join(INDEX_DIR_NAME);
+        let (index, is_new) =
+            Index::initialize_with_recent_window(&index_dir, options.recent_window)?;
+
+        if is_new {
+            info!(target: "FileStore", "Created new index database at: {}", index_dir.display());
+        } else {
+            let current_height = index.get_current_height();
+            info!(target: "FileStore", "Recovered existing index database from: {} (current height: {})", index_dir.display(), current_height);
+        }
+
+        // Before anything in the data directory is touched
+        check_network(&index, options.network.as_deref())?;
+        if let Some(file_number) = finish_compaction(&index, &block_data_dir)? {
+            warn!(target: "FileStore", "Finished the interrupted compaction of block data file {}", file_number);
+        }
+        if let Some(swapped) = finish_rekey(&index, &block_data_dir)? {
+            warn!(target: "FileStore", "Finished the interrupted re-encryption, swapped in {} block data files", swapped);
+        }
 
-        let block_data_exists = block_data_dir.join(&block_file_name!(0)).exists();
+        // A pruned store starts at a later file
+        let (first_file_number, pruned_up_to) = read_prune_state(&index)?;
+        let mut current_file_number = first_file_number;
+        let mut compressed_files = HashSet::new();
+
+        fs::create_dir_all(&block_data_dir)?;
+        // Files before the first kept one are what an interrupted prune left, finish_prune
+        // removes them
+        let file_numbers: Vec<u64> = find_block_files(&block_data_dir)?
+            .into_iter()
+            .filter(|&file_number| file_number >= first_file_number)
+            .collect();
+        let block_data_exists = file_numbers.first() == Some(&first_file_number);
+        if !block_data_exists && first_file_number > 0 {
+            return Err(StorageError::CorruptDB(
+                "Missing the first block data file kept by pruning",
+            ));
+        }
         if !block_data_exists {
-            // ensure no other file of form spsxxxxx.dat exists
-            fs::create_dir_all(&block_data_dir)?;
-            for file in fs::read_dir(&block_data_dir)? {
-                let file = file?;
-                if file.file_type()?.is_file() && file.path().to_string_lossy().starts_with("sps") {
-                    return Err(StorageError::CorruptDB(
-                        "Missing sps00000.dat, but other files present",
-                    ));
-                }
+            if !file_numbers.is_empty() {
+                return Err(StorageError::CorruptDB(
+                    "Missing sps000000.dat, but other block data files present",
+                ));
             }
 
             info!(target: "FileStore", "Creating initial block data directory and file");
             // create sps00000.dat file
             let mut file = File::create(&block_data_dir.join(&block_file_name!(0)))?;
-            file.write_all(&MAGIC_BYTES)?;
+            file.write_all(&new_file_header(encryption_key.as_ref(), format_version))?;
+            if format_version == COMPRESSED_FORMAT_VERSION {
+                compressed_files.insert(0);
+            }
         } else {
-            // Find the highest numbered file
-            while Path::new(&block_data_dir.join(&block_file_name!(current_file_number + 1)))
-                .exists()
+            // Records can't be told apart from those of the files around a missing one, so
+            // the store can't go on without it
+            current_file_number = file_numbers[file_numbers.len() - 1];
+            if let Some(missing) = (first_file_number..current_file_number)
+                .find(|n| file_numbers.binary_search(n).is_err())
             {
-                current_file_number += 1;
+                warn!(target: "FileStore", "Block data file {} is missing, but files after it are present",
+                      block_file_name!(missing));
+                return Err(StorageError::CorruptDB(
+                    "Block data files are not numbered contiguously",
+                ));
             }
-            debug!(target: "FileStore", "Found {} block data files, ", current_file_number);
-        }
+            debug!(target: "FileStore", "Found {} block data files, ", current_file_number - first_file_number + 1);
 
-        let index_dir = data_dir.join(INDEX_DIR_NAME);
-        let (index, is_exists) = Index::initialize(&index_dir)?;
+            // A crash right after rotating can leave the new file without a complete header.
+            // It holds no records yet, so it is started over and used as the current file.
+            let last_file_path = block_data_dir.join(block_file_name!(current_file_number));
+            let header_len = match encryption_key {
+                Some(_) => ENCRYPTED_HEADER_SIZE,
+                None => HEADER_PREFIX_SIZE,
+            };
+            let holds_tip = match index.tip()? {
+                Some((_, blockhash)) => index
+                    .get_block_entry(&blockhash)
+                    .map_or(true, |entry| entry.file_number == current_file_number),
+                None => false,
+            };
+            if !holds_tip && header_is_torn(&last_file_path, header_len)? {
+                warn!(target: "FileStore", "Block data file {} was started but its header never made it to disk, writing it again",
+                      last_file_path.display());
+                File::create(&last_file_path)?
+                    .write_all(&new_file_header(encryption_key.as_ref(), format_version))?;
+            }
 
-        if !is_exists {
-            info!(target: "FileStore", "Created new index database at: {}", index_dir.display());
-        } else {
-            let current_height = index.get_current_height();
-            info!(target: "FileStore", "Recovered existing index database from: {} (current height: {})", index_dir.display(), current_height);
+            for file_number in first_file_number..=current_file_number {
+                let version = check_file_header(
+                    &block_data_dir.join(block_file_name!(file_number)),
+                    encryption_key.as_ref(),
+                )?;
+                if version == COMPRESSED_FORMAT_VERSION {
+                    compressed_files.insert(file_number);
+                }
+            }
         }
-        
-        if !is_exists && block_data_exists {
-            // this should rebuild the index, but that's a problem for future me.
-            // TODO: Fix this.
-            panic!("Block data directory already exists but index is newly created");
+
+        // Where the data really ends in a preallocated file is only known once the tail is
+        // recovered
+        let write_offset =
+            fs::metadata(block_data_dir.join(block_file_name!(current_file_number)))?.len();
+
+        if let Some(hole) = index.hole_on_open() {
+            integrity.report(Violation::new(
+                ViolationKind::ChainInvariant,
+                format!(
+                    "heights are not contiguous past {}, {} entries quarantined",
+                    hole,
+                    index.quarantined_count()
+                ),
+            ));
         }
 
-        Ok(Self {
+        let mut store = Self {
             block_data_dir,
             index_dir,
             index,
-            current_file_number,
-        })
-    }
+            first_file_number,
+            pruned_up_to,
+            encryption_key,
+            integrity,
+            max_record_size: options.max_record_size,
+            max_file_size: options.max_file_size,
+            durability: options.durability,
+            cache: BlockCache::new(options.block_cache_size),
+            compression_level: options.compression_level,
+            compressed_files: RwLock::new(compressed_files),
+            preallocate: options.preallocate,
+            canonical_tweaks: options.canonical_tweaks,
+            state: Mutex::new(WriteState {
+                current_file_number,
+                write_offset,
+                writer: None,
+                record_size: Watermark::new(
+                    "Block data record size",
+                    options.max_record_size as u64,
+                    options.soft_limit_fraction,
+                ),
+                unsynced_blocks: 0,
+                totals: ChainTotals::default(),
+                journal,
+            }),
+            #[cfg(feature = "mmap")]
+            mapped_files: Mutex::new(HashMap::new()),
+            _lock: lock,
+        };
 
-    fn get_current_file_path(&self) -> PathBuf {
-        self.block_data_dir
-            .join(&block_file_name!(self.current_file_number))
-    }
+        // The index is gone (corrupted and deleted, or removed by accident) but the block data
+        // is still there
+        let rebuilt = is_new && block_data_exists;
+        if rebuilt {
+            store.rebuild_index()?;
+        }
 
-    fn get_current_file_size(&self) -> Result<u64, StorageError> {
-        let file_path = self.get_current_file_path();
-        let metadata = fs::metadata(file_path)?;
-        Ok(metadata.len())
+        match data_dir_state {
+            DataDirState::New => stamp_data_dir_version(&data_dir, &store.index, DATA_DIR_VERSION)?,
+            // The metadata went along with the rest of the index
+            DataDirState::Current if rebuilt => {
+                stamp_data_dir_version(&data_dir, &store.index, DATA_DIR_VERSION)?
+            }
+            DataDirState::Current => {
+                check_meta_version(&store.index)?;
+                store.replay_journal(pending)?;
+                store.recover_tail()?;
+            }
+            DataDirState::Version0 => {
+                store.adopt_version_0(&data_dir, options.assume_network.as_deref())?
+            }
+        }
+        // Replayed above, a rebuilt or adopted index found the record if it's there
+        store.state_mut().journal.finish()?;
+        store.record_max_file_size()?;
+        store.finish_prune()?;
+        store.prepare_current_file()?;
+        store.load_chain_totals()?;
+        store.trim_range_bytes()?;
+        Ok(store)
     }
 
-    fn create_new_file(&mut self) -> Result<(), StorageError> {
-        self.current_file_number += 1;
-        let new_file_path = self.get_current_file_path();
-        info!(target: "FileStore", "Creating new block data file: {}", new_file_path.display());
-        let mut file = File::create(&new_file_path)?;
-        file.write_all(&MAGIC_BYTES)?;
+    /// Picks up the ChainTotals saved in the metadata, or counts them again if they weren't
+    /// saved at the current tip.
+    fn load_chain_totals(&mut self) -> Result<(), StorageError> {
+        let tip = self.index.tip()?;
+        let saved = self.index.get_meta(CHAIN_TOTALS_META_KEY)?;
+        let totals =
+            match saved.and_then(|saved| ChainTotals::decode(&saved, self.pruned_up_to, tip)) {
+                Some(totals) => totals,
+                None => {
+                    info!(target: "FileStore", "Counting the tweaks of the stored blocks");
+                    let totals = self.count_chain_totals()?;
+                    self.save_chain_totals(&totals);
+                    totals
+                }
+            };
+        self.state_mut().totals = totals;
         Ok(())
     }
-    /// Adds a block data record to the end of the current file.
-    /// If the file will be full after the addition, it creates a new file and updates the index.
-    pub fn add_block(&mut self, block_data: &BlockData, height: u32) -> Result<(), StorageError> {
-        let file_path = self.get_current_file_path();
-        let mut file = File::options().append(true).open(&file_path)?;
-        // Get current position for index
-        let offset = file.seek(SeekFrom::End(0))?;
-
-        let serialized = block_data.serialize();
-        if offset + serialized.len() as u64 >= MAX_BLOCKDATA_SIZE {
-            debug!(target: "FileStore", "Current file size limit reached ({} bytes), creating new file", offset);
-            self.create_new_file()?;
-            file = File::options().append(true).open(&file_path)?;
-        }
-
-        // This should be one "Atomic" Operation
-        // Failure should revert file changes.
-        // TODO: Fix this.
-        {
-            file.write_all(&serialized)?;
 
-            let entry = IndexEntry {
-                file_number: self.current_file_number,
-                offset,
-                length: serialized.len() as u64,
-            };
+    /// Totals of the chain, counted from every stored record.
+    fn count_chain_totals(&self) -> Result<ChainTotals, StorageError> {
+        let mut totals = ChainTotals::default();
+        let next_height = (self.index.get_current_height() + 1) as u32;
+        for height in self.pruned_up_to..next_height {
+            let blockhash = self.index.get_blockhash_by_height(height)?;
+            let entry = self.block_entry(&blockhash)?;
+            totals.add(self.stored_tweak_count(&blockhash, &entry), entry.length);
+            for (_, tier) in self.index.tier_entries(&blockhash)? {
+                totals.add(0, tier.length);
+            }
+        }
+        Ok(totals)
+    }
 
-            info!(target: "FileStore", "Adding block at height {} (hash: {:?}) to file {} at offset {}", 
-                  height, &block_data.blockhash[..4], self.current_file_number, offset);
+    /// Tweaks in the stored block `entry` points to. The totals are only statistics, so a
+    /// record that can't be read counts as empty rather than failing whatever is counting.
+    fn stored_tweak_count(&self, blockhash: &[u8; 32], entry: &IndexEntry) -> u64 {
+        match self.count_record_tweaks(blockhash, entry, &mut None) {
+            Ok(count) => count as u64,
+            Err(e) => {
+                warn!(target: "FileStore", "Could not count the tweaks of block {:?}: {}", &blockhash[..4], e);
+                0
+            }
+        }
+    }
 
-            // Panic if this fails, for now.
-            self.index
-                .insert_block(height, &block_data.blockhash, &entry)
-                .expect("Failed to insert block into index");
+    /// Saves `totals` as those of the chain as it is now. A failure only costs counting them
+    /// again on the next open, so it doesn't fail the write that changed them.
+    fn save_chain_totals(&self, totals: &ChainTotals) {
+        let saved = self
+            .index
+            .tip()
+            .map(|tip| totals.encode(self.pruned_up_to, tip))
+            .and_then(|saved| self.index.set_meta(CHAIN_TOTALS_META_KEY, &saved));
+        if let Err(e) = saved {
+            warn!(target: "FileStore", "Could not save the chain totals, they are counted again on the next start: {}", e);
         }
+    }
 
+    /// Drops the bytes the index counts (see `size_of_range`) for heights above the tip.
+    /// Heights missing are left for `count_range_bytes` to count once a range needs them:
+    /// reading every record of a store from before they were counted would slow down the
+    /// open, and fail it over a single bad record.
+    fn trim_range_bytes(&self) -> Result<(), StorageError> {
+        let next_height = (self.index.get_current_height() + 1) as u32;
+        if self.index.range_bytes_count()? > next_height {
+            self.index.truncate_range_bytes(next_height)?;
+        }
         Ok(())
     }
 
-    pub fn add_block_bulk(
-        &mut self,
-        blocks: &[BlockData],
-        heights: &[u32],
-    ) -> Result<(), StorageError> {
-        for (block, height) in blocks.iter().zip(heights.iter()) {
-            self.add_block(block, *height)?;
+    /// Counts the bytes of the heights up to `end` the index is missing (a store from before
+    /// they were counted, or a write that failed or crashed before counting them) from their
+    /// records. Pruned heights count as empty, they can't be read anyway.
+    fn count_range_bytes(&self, end: u32) -> Result<(), StorageError> {
+        self.trim_range_bytes()?;
+        let counted = self.index.range_bytes_count()?;
+        if counted > end {
+            return Ok(());
+        }
+        info!(target: "FileStore", "Counting the bytes of the blocks from height {}", counted);
+        for height in counted..=end {
+            let bytes = if height < self.pruned_up_to {
+                0
+            } else {
+                let blockhash = self.index.get_blockhash_by_height(height)?;
+                let entry = self.block_entry(&blockhash)?;
+                let block = self.block_from_record(&blockhash, &entry, self.read_entry(&entry))?;
+                block.serialize().len() as u64
+            };
+            self.index.push_range_bytes(height, bytes)?;
         }
         Ok(())
     }
 
-    /// This is an uninterrupted Buffered Stream of data that can be served to the client
-    /// It automatically moves to a new file (skips over magic bytes) when the end of current
-    /// file is reached.
-    pub fn get_block_stream_from_offset<'a>(
-        &'a self,
-        entry: &IndexEntry,
-    ) -> Result<impl Read + 'a, StorageError> {
-        let file_path = self
-            .block_data_dir
-            .join(&block_file_name!(entry.file_number));
-        let file = File::open(&file_path)?;
-        let reader = BufReader::new(file);
+    /// Counts `bytes` as the size of the block just added at `height`. Like the totals, a
+    /// failure only costs counting it again from the record, so the write doesn't fail.
+    fn push_range_bytes(&self, height: u32, bytes: u64) {
+        if let Err(e) = self.index.push_range_bytes(height, bytes) {
+            warn!(target: "FileStore", "Could not count the bytes of the block at height {}, they are counted again when asked for: {}",
+                  height, e);
+        }
+    }
 
-        // Create a BlockDataReader that will handle reading across file boundaries if needed
-        Ok(BlockDataReader {
-            store: self,
-            current_file_number: entry.file_number,
-            reader,
-            current_position: entry.offset,
-        })
+    /// Gets the current file ready for records to be appended, once `write_offset` is where
+    /// its data ends: a file without records is switched to the format records are written in
+    /// now, and the file is preallocated.
+    fn prepare_current_file(&self) -> Result<(), StorageError> {
+        let state = self.state();
+        let file_path = self.current_file_path(&state);
+        let format_version = written_format_version(self.compression_level);
+        let compressed = format_version == COMPRESSED_FORMAT_VERSION;
+        if state.write_offset == self.header_len()
+            && self.is_compressed(state.current_file_number) != compressed
+        {
+            let header = new_file_header(self.encryption_key.as_ref(), format_version);
+            File::create(&file_path)?.write_all(&header)?;
+            let mut compressed_files = self
+                .compressed_files
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            match compressed {
+                true => compressed_files.insert(state.current_file_number),
+                false => compressed_files.remove(&state.current_file_number),
+            };
+        }
+        let file = File::options().write(true).open(&file_path)?;
+        self.preallocate_file(&file, state.write_offset)?;
+        Ok(())
     }
 
-    fn get_block_stream<'a>(
-        &'a self,
-        blockhash: &[u8; 32],
-    ) -> Result<impl Read + 'a, StorageError> {
-        let entry = self.index.get_block_entry(blockhash)?;
-        self.get_block_stream_from_offset(&entry)
+    /// Preallocates `file`, whose data ends at `end`, up to max_file_size if the store
+    /// preallocates.
+    fn preallocate_file(&self, file: &File, end: u64) -> io::Result<()> {
+        if self.preallocate && end < self.max_file_size {
+            platform::preallocate(file, self.max_file_size)?;
+        }
+        Ok(())
     }
 
-    /// Just for testing.
-    fn get_block_stream_from_height<'a>(
-        &'a self,
-        height: u32,
-    ) -> Result<impl Read + 'a, StorageError> {
-        let blockhash = self.index.get_blockhash_by_height(height)?;
-        self.get_block_stream(&blockhash)
+    /// Cuts the current file back to `end`, preallocated again (and so zeroed past `end`) if
+    /// the store preallocates.
+    fn cut_current_file(&self, state: &WriteState, end: u64) -> io::Result<()> {
+        let file = File::options()
+            .write(true)
+            .open(self.current_file_path(state))?;
+        file.set_len(end)?;
+        self.preallocate_file(&file, end)
     }
 
-    fn get_block_stream_from_genesis<'a>(&'a self) -> Result<impl Read + 'a, StorageError> {
-        self.get_block_stream_from_height(0)
+    /// Keeps the file size the store is opened with in the metadata. A different size than
+    /// last time is fine, files already written just keep theirs, but it's worth a warning as
+    /// it is rarely intended.
+    fn record_max_file_size(&self) -> Result<(), StorageError> {
+        let previous = match self.index.get_meta(MAX_FILE_SIZE_META_KEY)? {
+            Some(value) => Some(u64::from_le_bytes(value.as_slice().try_into().map_err(
+                |_| StorageError::CorruptDB("max_file_size metadata is not 8 bytes"),
+            )?)),
+            None => None,
+        };
+        match previous {
+            Some(previous) if previous == self.max_file_size => return Ok(()),
+            Some(previous) => {
+                warn!(target: "FileStore", "Block data files were written with a maximum size of {} bytes, new files will be up to {} bytes",
+                      previous, self.max_file_size)
+            }
+            None => {}
+        }
+        self.index
+            .set_meta(MAX_FILE_SIZE_META_KEY, &self.max_file_size.to_le_bytes())
     }
-}
 
-/// A reader that reads block data from flat files, automatically handling file boundaries
-struct BlockDataReader<'a> {
-    store: &'a FlatFileStore,
-    current_file_number: u64,
-    reader: BufReader<File>,
-    current_position: u64,
-}
+    /// Repopulates an empty index from the block data files. Heights are assigned from 0 in
+    /// file order, which is the order add_block wrote the records in.
+    /// A partial record at the end of the last file (a write cut short by a crash) is cut off;
+    /// anything else that isn't a readable record is a corrupt store, as the heights of the
+    /// records past it can't be told.
+    /// Dust tier records are skipped: their threshold is only kept in the index, so the tiers
+    /// have to be added again. So are the records of blocks reorged away, which the removal
+    /// marked ORPHANED_RECORD_MAGIC, letting the blocks that took their heights have them.
+    /// Records written by `replace_block_tweaks` take the place of their block's; one whose
+    /// block has no record left (compacted away) can't be given a height, and the store is
+    /// refused as corrupt.
+    fn rebuild_index(&mut self) -> Result<(), StorageError> {
+        warn!(target: "FileStore", "Found block data without an index, rebuilding the index from the block data files");
+        // Whether a superseded record keeps its height is only known once every replacement
+        // record has been seen, so the chain is put together before anything is inserted
+        let mut chain = Vec::new();
+        let mut superseded = HashSet::new();
+        let mut replaced = Vec::new();
+        let mut skipped_tiers = 0u64;
+        let mut skipped_orphans = 0u64;
+        let last_file = self.state_mut().current_file_number;
+        for file_number in 0..=last_file {
+            let file_path = self.block_data_dir.join(block_file_name!(file_number));
+            let mut scanner = FrameScanner::open(&file_path, self.header_len())?;
 
-impl<'a> BlockDataReader<'a> {
-    /// Opens the next file and positions the reader at the start of the data (after magic bytes)
-    fn move_to_next_file(&mut self) -> Result<(), StorageError> {
-        self.current_file_number += 1;
-        let file_path = self
-            .store
-            .block_data_dir
-            .join(&block_file_name!(self.current_file_number));
+            while let Some(frame) = scanner.next_frame()? {
+                let (offset, record) = match frame {
+                    ScannedFrame::Record { record, .. }
+                        if frame_magic(&record) == TIER_RECORD_MAGIC =>
+                    {
+                        skipped_tiers += 1;
+                        continue;
+                    }
+                    ScannedFrame::Record { record, .. }
+                        if frame_magic(&record) == ORPHANED_RECORD_MAGIC =>
+                    {
+                        skipped_orphans += 1;
+                        continue;
+                    }
+                    ScannedFrame::Record { offset, record } => (offset, record),
+                    ScannedFrame::Partial { offset } => {
+                        self.cut_partial_record(file_number, offset, scanner.file_size - offset)?;
+                        break;
+                    }
+                    // Space preallocated for records that were never written
+                    ScannedFrame::Zeros { offset } if file_number == last_file => {
+                        File::options()
+                            .write(true)
+                            .open(&file_path)?
+                            .set_len(offset)?;
+                        self.state_mut().write_offset = offset;
+                        break;
+                    }
+                    ScannedFrame::Zeros { offset } => {
+                        return Err(self.unreadable_while_rebuilding(
+                            file_number,
+                            offset,
+                            "the file ends in zeros, but it isn't the last one".to_string(),
+                        ));
+                    }
+                    ScannedFrame::Skipped { offset, length } => {
+                        return Err(self.unreadable_while_rebuilding(
+                            file_number,
+                            offset,
+                            format!("{} bytes up to the next record don't frame one", length),
+                        ));
+                    }
+                };
+                let block = self
+                    .decode_stored_record(file_number, offset, &record)
+                    .map_err(|e| {
+                        self.unreadable_while_rebuilding(file_number, offset, e.to_string())
+                    })?;
+                let entry = IndexEntry {
+                    file_number,
+                    offset,
+                    length: record.len() as u64,
+                };
+                match frame_magic(&record) {
+                    REPLACED_RECORD_MAGIC => {
+                        replaced.push((block.blockhash, entry, block.serialize().len() as u64));
+                        continue;
+                    }
+                    SUPERSEDED_RECORD_MAGIC => {
+                        superseded.insert(block.blockhash);
+                    }
+                    _ => {}
+                }
+                chain.push((block.blockhash, entry));
 
-        // Check if the next file exists
-        if !file_path.exists() {
-            return Err(StorageError::InvalidData("Next block file does not exist"));
+                if (chain.len() as u32).is_multiple_of(REBUILD_PROGRESS_INTERVAL) {
+                    info!(target: "FileStore", "Rebuilding index: {} blocks scanned", chain.len());
+                }
+            }
         }
 
-        debug!(target: "FileStore", "Moving to next block file: {}", file_path.display());
-        let file = File::open(&file_path)?;
-        self.reader = BufReader::new(file);
-
-        // Skip the magic bytes at the beginning of the file
-        self.reader
-            .seek(SeekFrom::Start(MAGIC_BYTES.len() as u64))?;
-        self.current_position = MAGIC_BYTES.len() as u64;
-
+        // A superseded record whose replacement is gone was left by a block reorged away
+        let replacements: HashSet<[u8; 32]> =
+            replaced.iter().map(|(blockhash, _, _)| *blockhash).collect();
+        chain.retain(|(blockhash, _)| {
+            !superseded.contains(blockhash) || replacements.contains(blockhash)
+        });
+        self.index.insert_blocks(0, &chain)?;
+        for (blockhash, entry, bytes) in &replaced {
+            if self.index.get_block_entry(blockhash).is_err() {
+                warn!(target: "FileStore", "Record in file {} at offset {} replaces the tweaks of block {:?}, which has no record left to take its height from",
+                      entry.file_number, entry.offset, &blockhash[..4]);
+                return Err(StorageError::CorruptDB(
+                    "a replaced block has no record to take its height from",
+                ));
+            }
+            self.repoint_block(blockhash, entry, *bytes)?;
+        }
+        info!(target: "FileStore", "Rebuilt the index from {} block data files ({} blocks)",
+              last_file + 1, chain.len());
+        if skipped_orphans > 0 {
+            info!(target: "FileStore", "Left {} records of blocks reorged away out of the rebuilt index",
+                  skipped_orphans);
+        }
+        if skipped_tiers > 0 {
+            warn!(target: "FileStore", "Left {} dust tier records out of the rebuilt index, add the tiers again to serve them",
+                  skipped_tiers);
+        }
         Ok(())
     }
-}
 
-impl<'a> Read for BlockDataReader<'a> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        // Position the reader at the current position if needed
-        let current_pos = self.reader.stream_position()?;
-        if current_pos != self.current_position {
-            self.reader.seek(SeekFrom::Start(self.current_position))?;
-        }
+    fn unreadable_while_rebuilding(
+        &self,
+        file_number: u64,
+        offset: u64,
+        reason: String,
+    ) -> StorageError {
+        self.integrity.report(Violation::new(
+            ViolationKind::Checksum,
+            format!(
+                "record in file {} at offset {} is unreadable while rebuilding the index: {}",
+                file_number, offset, reason
+            ),
+        ));
+        StorageError::CorruptDB("block data record failed its checksum")
+    }
 
-        let mut bytes_read = self.reader.read(buf)?;
+    /// Decodes a record as stored, frame included.
+    fn decode_stored_record(
+        &self,
+        file_number: u64,
+        offset: u64,
+        record: &[u8],
+    ) -> Result<BlockData, StorageError> {
+        let payload = frame_payload(record).ok_or(StorageError::DeserializeError(
+            "record frame does not match the record",
+        ))?;
+        self.decode_payload(file_number, offset, payload)
+    }
 
-        // Update position
-        self.current_position += bytes_read as u64;
+    /// Decodes the payload of the record framed at `offset`.
+    fn decode_payload(
+        &self,
+        file_number: u64,
+        offset: u64,
+        payload: &[u8],
+    ) -> Result<BlockData, StorageError> {
+        let serialized = self.serialized_payload(file_number, offset, payload)?;
+        BlockData::deserialize_limited(&serialized, self.max_record_size)
+    }
 
-        // If we've reached the end of the file, try moving to the next file
-        if bytes_read == 0 {
-            match self.move_to_next_file() {
-                Ok(_) => {
-                    // Try reading from the new file
-                    let additional = self.read(&mut buf[bytes_read..])?;
-                    bytes_read += additional;
-                }
-                Err(_) => {
-                    // End of all files reached, return 0 bytes read
-                    return Ok(0);
-                }
-            }
+    /// The serialized block in the payload of the record at `offset` of file `file_number`,
+    /// decrypted and decompressed, or the payload itself if it is neither.
+    fn serialized_payload<'p>(
+        &self,
+        file_number: u64,
+        offset: u64,
+        payload: &'p [u8],
+    ) -> Result<Cow<'p, [u8]>, StorageError> {
+        let payload = match &self.encryption_key {
+            Some(key) => Cow::Owned(key.decrypt_record(file_number, offset, payload)?),
+            None => Cow::Borrowed(payload),
+        };
+        if self.is_compressed(file_number) {
+            return Ok(Cow::Owned(decompress_record(&payload)?));
         }
-
-        Ok(bytes_read)
+        Ok(payload)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::super::block_data::TWEAK_SIZE;
-    use super::*;
-    use rand::Rng;
-    use std::env;
-    use std::fs;
-    use std::io::Read;
-
-    fn temp_dir(name: &str) -> PathBuf {
-        let mut dir = env::temp_dir();
-        dir.push(name);
-        let _ = fs::remove_dir_all(&dir);
-        fs::create_dir_all(&dir).unwrap();
-        dir
+    /// Whether the records of block data file `file_number` are compressed.
+    fn is_compressed(&self, file_number: u64) -> bool {
+        self.compressed_files
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(&file_number)
     }
 
-    fn create_random_block_data() -> BlockData {
-        let mut rng = rand::rng();
-        let mut blockhash = [0u8; 32];
-        for i in 0..32 {
-            blockhash[i] = rng.random();
+    fn file_format_version(&self, file_number: u64) -> u16 {
+        match self.is_compressed(file_number) {
+            true => COMPRESSED_FORMAT_VERSION,
+            false => FRAMED_FORMAT_VERSION,
         }
+    }
 
-        let num_tweaks = rng.random_range(1..10);
-        let mut tweaks = Vec::with_capacity(num_tweaks);
+    /// Finishes the `add_block` a crash interrupted, if the journal holds its intent: a record
+    /// that made it to disk whole is indexed, one that didn't is cut off. Intents that were
+    /// finished but not cleared (the block is indexed, or the chain has moved on) are left be.
+    fn replay_journal(&mut self, pending: Option<Intent>) -> Result<(), StorageError> {
+        let Some(intent) = pending else {
+            return Ok(());
+        };
+        let next_height = (self.index.get_current_height() + 1) as u32;
+        let indexed = self.index.get_block_entry(&intent.blockhash).ok();
+        if intent.height != next_height || indexed.is_some() {
+            return Ok(());
+        }
+        let entry = &intent.entry;
+        let holds_block = self.record_is_intact(entry)?
+            && self
+                .read_entry(entry)
+                .map_err(StorageError::from)
+                .and_then(|record| {
+                    self.decode_stored_record(entry.file_number, entry.offset, &record)
+                })
+                .is_ok_and(|block| block.blockhash == intent.blockhash);
+        if holds_block {
+            self.index
+                .insert_block(intent.height, &intent.blockhash, entry)?;
+            info!(target: "FileStore", "Indexed block at height {} (hash: {:?}) from the journal, its record was written before a crash",
+                  intent.height, &intent.blockhash[..4]);
+            return Ok(());
+        }
+        let mut state = self.state();
+        if entry.file_number == state.current_file_number && entry.offset < state.write_offset {
+            warn!(target: "FileStore", "Block at height {} was not fully written before a crash, cutting file {} back to offset {}",
+                  intent.height, entry.file_number, entry.offset);
+            self.cut_current_file(&state, entry.offset)?;
+            state.write_offset = entry.offset;
+        }
+        Ok(())
+    }
 
-        for _ in 0..num_tweaks {
-            let mut tweak = [0u8; TWEAK_SIZE];
-            for i in 0..TWEAK_SIZE {
-                tweak[i] = rng.random();
+    /// Makes the end of the block data agree with the index after a crash. A write cut short
+    /// leaves a partial record behind the tip, and as records are buffered, a crash can also
+    /// lose records the index already points to. Tips whose record is missing, incomplete or
+    /// unreadable are taken out of the index, and so are the dust tiers of the tip if any of
+    /// them is lost, then everything past the last referenced record is cut off.
+    fn recover_tail(&mut self) -> Result<(), StorageError> {
+        loop {
+            let height = self.index.get_current_height();
+            // With everything pruned, the files hold no block of the chain
+            if height < self.pruned_up_to as i32 {
+                return self.truncate_tail(&[]);
             }
-            tweaks.push(tweak);
+            let blockhash = self.index.get_blockhash_by_height(height as u32)?;
+            let entry = self.index.get_block_entry(&blockhash)?;
+            if self.record_is_intact(&entry)? {
+                let mut referenced = vec![entry];
+                referenced.extend(self.recover_tip_tiers(&blockhash)?);
+                return self.truncate_tail(&referenced);
+            }
+            warn!(target: "FileStore", "Block at height {} was not fully written (file {}, offset {}, length {}), rolling it back",
+                  height, entry.file_number, entry.offset, entry.length);
+            self.index.discard_tip(&blockhash)?;
         }
-
-        BlockData { blockhash, tweaks }
     }
 
-    #[test]
-    fn test_add_and_read_single_block() {
-        let test_dir = temp_dir("test_flat_file_store_single");
+    /// The entries of the tip's dust tiers, written after its record. Tiers go together: if a
+    /// crash lost any of them, they are all taken out of the index for `add_block_with_tiers`
+    /// to add again.
+    fn recover_tip_tiers(&self, blockhash: &[u8; 32]) -> Result<Vec<IndexEntry>, StorageError> {
+        let tiers = self.index.tier_entries(blockhash)?;
+        for (dust_threshold, entry) in &tiers {
+            if !self.record_is_intact(entry)? {
+                warn!(target: "FileStore", "Dust tier {} of the tip was not fully written (file {}, offset {}), dropping the tip's dust tiers",
+                      dust_threshold, entry.file_number, entry.offset);
+                self.index.remove_tiers(blockhash)?;
+                return Ok(Vec::new());
+            }
+        }
+        Ok(tiers.into_iter().map(|(_, entry)| entry).collect())
+    }
 
-        // Initialize store
-        let mut store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+    fn record_is_intact(&self, entry: &IndexEntry) -> Result<bool, StorageError> {
+        let file_path = self
+            .block_data_dir
+            .join(block_file_name!(entry.file_number));
+        let file_size = match fs::metadata(&file_path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        if entry.offset + entry.length > file_size {
+            return Ok(false);
+        }
 
-        // Create and add a block
-        let block = create_random_block_data();
-        let height = 0;
-        store.add_block(&block, height).unwrap();
+        let record = self.read_entry(entry)?;
+        Ok(self
+            .decode_stored_record(entry.file_number, entry.offset, &record)
+            .is_ok())
+    }
 
-        // Read the block back
-        let mut reader = store.get_block_stream_from_height(height).unwrap();
-        let mut buffer = Vec::new();
-        reader.read_to_end(&mut buffer).unwrap();
+    /// Reads the stored record `entry` points to, as it is on disk.
+    fn read_entry(&self, entry: &IndexEntry) -> io::Result<Vec<u8>> {
+        let mut file = File::open(
+            self.block_data_dir
+                .join(block_file_name!(entry.file_number)),
+        )?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        read_record(&mut file, entry)
+    }
 
-        // Deserialize and verify
-        let read_block = BlockData::deserialize(&buffer).unwrap();
-        assert_eq!(block.blockhash, read_block.blockhash);
-        assert_eq!(block.tweaks, read_block.tweaks);
+    /// Cuts off whatever follows the last record the index references: the tip (`tip` holds
+    /// its record and those of its dust tiers), a replacement record written after it by
+    /// `replace_block_tweaks`, or a quarantined block kept for inspection.
+    fn truncate_tail(&mut self, tip: &[IndexEntry]) -> Result<(), StorageError> {
+        let quarantined = self.index.quarantined_blocks()?;
+        let (last_file, end) = tip
+            .iter()
+            .chain(quarantined.iter().filter_map(|block| block.entry.as_ref()))
+            .map(|entry| (entry.file_number, entry.offset + entry.length))
+            .chain(self.replaced_tail()?)
+            .max()
+            .unwrap_or((self.first_file_number, self.header_len()));
 
-        // Clean up
-        let _ = fs::remove_dir_all(test_dir);
+        for file_number in last_file..=self.state_mut().current_file_number {
+            let keep = if file_number == last_file {
+                end
+            } else {
+                self.header_len()
+            };
+            let file_path = self.block_data_dir.join(block_file_name!(file_number));
+            let size = fs::metadata(&file_path)?.len();
+            if size > keep && size == self.max_file_size && self.preallocate {
+                // Preallocated again once the store is open
+                debug!(target: "FileStore", "Cutting file {} back to the end of its data (offset {})",
+                       file_number, keep);
+            } else if size > keep {
+                warn!(target: "FileStore", "Cutting off {} unreferenced bytes at the end of file {} (offset {})",
+                      size - keep, file_number, keep);
+            }
+            if size > keep {
+                File::options()
+                    .write(true)
+                    .open(&file_path)?
+                    .set_len(keep)?;
+            }
+        }
+        let state = self.state.get_mut().unwrap_or_else(PoisonError::into_inner);
+        state.write_offset = fs::metadata(
+            self.block_data_dir
+                .join(block_file_name!(state.current_file_number)),
+        )?
+        .len();
+        Ok(())
     }
 
-    #[test]
-    fn test_add_and_read_multiple_blocks() {
-        let test_dir = temp_dir("test_flat_file_store_multiple");
-
-        let mut store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+    /// Where the last record written by `replace_block_tweaks` ends, as (file number, offset).
+    fn replaced_tail(&self) -> Result<Option<(u64, u64)>, StorageError> {
+        let Some(value) = self.index.get_meta(REPLACED_TAIL_META_KEY)? else {
+            return Ok(None);
+        };
+        if value.len() != 16 {
+            return Err(StorageError::CorruptDB(
+                "replaced tail metadata is not 16 bytes",
+            ));
+        }
+        Ok(Some((
+            u64::from_le_bytes(value[..8].try_into().unwrap()),
+            u64::from_le_bytes(value[8..].try_into().unwrap()),
+        )))
+    }
 
-        // Create and add multiple blocks
+    /// Points the entry of `blockhash` at `entry`, a record written out of height order that
+    /// is streamed as `bytes`, and records where it ends for `truncate_tail`.
+    fn repoint_block(
+        &self,
+        blockhash: &[u8; 32],
+        entry: &IndexEntry,
+        bytes: u64,
+    ) -> Result<(), StorageError> {
+        let mut tail = entry.file_number.to_le_bytes().to_vec();
+        tail.extend_from_slice(&(entry.offset + entry.length).to_le_bytes());
+        self.index
+            .repoint_block(blockhash, entry, bytes, REPLACED_TAIL_META_KEY, &tail)
+    }
+
+    /// Truncates a partial record found while rebuilding the index, so new records don't end
+    /// up behind it. Only the tail of the last file can be a write that was cut short.
+    fn cut_partial_record(
+        &mut self,
+        file_number: u64,
+        offset: u64,
+        remaining: u64,
+    ) -> Result<(), StorageError> {
+        let max_payload_len = match self.is_compressed(file_number) {
+            true => COMPRESSED_HEADER_SIZE + zstd::zstd_safe::compress_bound(self.max_record_size),
+            false => self.max_record_size,
+        };
+        let max_record_len = self.stored_len(max_payload_len) as u64;
+        if file_number != self.state_mut().current_file_number || remaining > max_record_len {
+            return Err(StorageError::CorruptDB(
+                "block data file ends in something that is not a record",
+            ));
+        }
+        warn!(target: "FileStore", "Cutting off a partial record of {} bytes at the end of file {} (offset {})",
+              remaining, file_number, offset);
+        File::options()
+            .write(true)
+            .open(self.block_data_dir.join(block_file_name!(file_number)))?
+            .set_len(offset)?;
+        self.state_mut().write_offset = offset;
+        Ok(())
+    }
+
+    /// First half of adopting a version 0 store, before anything is opened: checks what
+    /// doesn't need the height mappings, then re-encodes their keys like the version 1 to 2
+    /// migration does (the index can't find its tip in the old key order) and brings the block
+    /// data files to the current format like the version 2 to 3 and 3 to 4 ones.
+    fn prepare_version_0(
+        index_dir: &PathBuf,
+        block_data_dir: &Path,
+        encrypted: bool,
+        network_assumed: bool,
+    ) -> Result<(), StorageError> {
+        info!(target: "FileStore", "Found a data directory without a version stamp, checking whether it can be adopted");
+        let index = Self::check_version_0(index_dir, block_data_dir, encrypted, network_assumed)?;
+        let rewritten = index.reencode_height_keys()?;
+        info!(target: "FileStore", "Re-encoded {} height keys", rewritten);
+        let migrated = migrate_block_data_files(block_data_dir)?;
+        info!(target: "FileStore", "Upgraded the header of {} block data files", migrated);
+        let framed = migrate_record_frames(block_data_dir, &index, None)?;
+        info!(target: "FileStore", "Framed the records of {} block data files", framed);
+        let checksummed = migrate_record_checksums(block_data_dir, &index, None)?;
+        info!(target: "FileStore", "Checksummed the records of {} block data files again", checksummed);
+        Ok(())
+    }
+
+    /// Refuses a version 0 data directory that doesn't look like what the pre-release code
+    /// could have produced, or whose network wasn't confirmed, and returns its index opened
+    /// for migration. Shared by adopting one on open and `upgrade`.
+    pub(crate) fn check_version_0(
+        index_dir: &PathBuf,
+        block_data_dir: &Path,
+        encrypted: bool,
+        network_assumed: bool,
+    ) -> Result<Index, StorageError> {
+        if encrypted {
+            return Err(StorageError::IncompatibleDataDir(
+                "pre-release stores are not encrypted",
+            ));
+        }
+        if !network_assumed {
+            return Err(StorageError::AssumeNetworkRequired);
+        }
+
+        let index = Index::open_for_migration(index_dir)?;
+        if !index.unknown_trees().is_empty() {
+            return Err(StorageError::IncompatibleDataDir("index has unknown trees"));
+        }
+        if !index.meta_entries()?.is_empty() {
+            return Err(StorageError::IncompatibleDataDir(
+                "index has metadata but the version file is missing",
+            ));
+        }
+        Self::check_entries_within_files(&index, block_data_dir)?;
+        Ok(index)
+    }
+
+    /// Takes over a data directory written by the pre-release code, before version stamps:
+    /// plaintext SPSDATA1 files and the bare index trees. The height keys and the block data
+    /// files have been brought to the current format by `prepare_version_0`, what's left is
+    /// recording the missing metadata. Anything that doesn't match what that code could have
+    /// produced is refused rather than guessed at, by `upgrade` as well.
+    fn adopt_version_0(
+        &self,
+        data_dir: &Path,
+        assume_network: Option<&str>,
+    ) -> Result<(), StorageError> {
+        self.index.check_consistency()?;
+
+        let network = assume_network.ok_or(StorageError::AssumeNetworkRequired)?;
+        self.index.set_meta(NETWORK_META_KEY, network.as_bytes())?;
+        stamp_data_dir_version(data_dir, &self.index, DATA_DIR_VERSION)?;
+        info!(target: "FileStore", "Adopted data directory as version {} ({})", DATA_DIR_VERSION, network);
+        Ok(())
+    }
+
+    /// Every index entry of a version 0 store has to point inside its file, before the files
+    /// are rewritten around them. The pre-release code could break this when rotating to a
+    /// new file.
+    fn check_entries_within_files(
+        index: &Index,
+        block_data_dir: &Path,
+    ) -> Result<(), StorageError> {
+        let mut file_sizes = Vec::new();
+        loop {
+            let file_path = block_data_dir.join(block_file_name!(file_sizes.len()));
+            if !file_path.exists() {
+                break;
+            }
+            file_sizes.push(fs::metadata(file_path)?.len());
+        }
+
+        for (_, entry) in index.located_entries()? {
+            let within_file = file_sizes
+                .get(entry.file_number as usize)
+                .is_some_and(|&size| {
+                    entry.offset >= HEADER_PREFIX_SIZE as u64 && entry.offset + entry.length <= size
+                });
+            if !within_file {
+                return Err(StorageError::IncompatibleDataDir(
+                    "index entries point outside the block data files",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Height of the tip, -1 for an empty store.
+    pub fn get_current_height(&self) -> i32 {
+        self.index.get_current_height()
+    }
+
+    /// Height and blockhash of the tip, read together so that a block being added or removed
+    /// at the same time can't pair one block's height with another's hash. None for an empty
+    /// store.
+    pub fn tip(&self) -> Option<(u32, [u8; 32])> {
+        match self.index.tip() {
+            Ok(tip) => tip,
+            Err(e) => {
+                warn!(target: "FileStore", "Failed to look up the tip: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Where syncing left off, see `Index::checkpoint`. None for an empty store.
+    pub fn checkpoint(&self) -> Option<Checkpoint> {
+        match self.index.checkpoint() {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                warn!(target: "FileStore", "Failed to look up the sync checkpoint: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Keeps `sync_state` with the checkpoint, see `Index::set_sync_state`.
+    pub fn set_sync_state(&self, sync_state: &[u8]) -> Result<(), StorageError> {
+        self.integrity.check_writable()?;
+        self.index.set_sync_state(sync_state)
+    }
+
+    /// Where the store stands against its limits, for status reporting.
+    pub fn watermarks(&self) -> Vec<WatermarkStatus> {
+        vec![self.state().record_size.status()]
+    }
+
+    /// The guard storage code reports inconsistencies to, shared with whoever needs to know
+    /// whether the store has been frozen.
+    pub fn integrity_guard(&self) -> Arc<IntegrityGuard> {
+        Arc::clone(&self.integrity)
+    }
+
+    /// Size of the header at the start of every block data file.
+    fn header_len(&self) -> u64 {
+        match self.encryption_key {
+            Some(_) => ENCRYPTED_HEADER_SIZE as u64,
+            None => HEADER_PREFIX_SIZE as u64,
+        }
+    }
+
+    /// Locks the write side of the store. Writes hold it from start to end, so they never
+    /// interleave; reads only take it to flush what is buffered.
+    fn state(&self) -> MutexGuard<'_, WriteState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn state_mut(&mut self) -> &mut WriteState {
+        self.state.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn current_file_path(&self, state: &WriteState) -> PathBuf {
+        self.block_data_dir
+            .join(block_file_name!(state.current_file_number))
+    }
+
+    fn get_current_file_size(&self) -> Result<u64, StorageError> {
+        Ok(self.state().write_offset)
+    }
+
+    fn writer<'s>(&self, state: &'s mut WriteState) -> io::Result<&'s mut BufWriter<File>> {
+        if state.writer.is_none() {
+            // Not opened for appending: a preallocated file runs past the end of its data
+            let mut file = File::options()
+                .write(true)
+                .open(self.current_file_path(state))?;
+            file.seek(SeekFrom::Start(state.write_offset))?;
+            state.writer = Some(BufWriter::new(file));
+        }
+        Ok(state.writer.as_mut().expect("writer was just opened"))
+    }
+
+    /// Writes out buffered records and syncs the block data and the index to disk, whatever
+    /// the DurabilityPolicy. A barrier for callers that need everything added so far to
+    /// survive a crash, e.g. before announcing the tip.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.sync(&mut self.state())
+    }
+
+    fn sync(&self, state: &mut WriteState) -> Result<(), StorageError> {
+        state.sync_data()?;
+        self.index.flush()?;
+        state.unsynced_blocks = 0;
+        Ok(())
+    }
+
+    /// Counts `blocks` just added and syncs if the DurabilityPolicy says it is time.
+    fn sync_if_due(&self, state: &mut WriteState, blocks: u32) -> Result<(), StorageError> {
+        state.unsynced_blocks = state.unsynced_blocks.saturating_add(blocks);
+        let due = match self.durability {
+            DurabilityPolicy::Always => true,
+            DurabilityPolicy::EveryNBlocks(interval) => state.unsynced_blocks >= interval,
+            DurabilityPolicy::Never => false,
+        };
+        if due {
+            self.sync(state)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes, and returns where the data written so far ends as (file number, offset).
+    /// Streams stop there, so records appended while they run are never read half written.
+    fn flushed_end(&self) -> Result<(u64, u64), StorageError> {
+        let mut state = self.state();
+        state.flush()?;
+        Ok((state.current_file_number, state.write_offset))
+    }
+
+    fn create_new_file(&self, state: &mut WriteState) -> Result<(), StorageError> {
+        // The next sync only reaches the new file
+        if self.durability != DurabilityPolicy::Never {
+            state.sync_data()?;
+        }
+        state.close_writer()?;
+        if self.preallocate {
+            // Files before the current one end where their data does
+            File::options()
+                .write(true)
+                .open(self.current_file_path(state))?
+                .set_len(state.write_offset)?;
+        }
+        let new_file_path = self
+            .block_data_dir
+            .join(block_file_name!(state.current_file_number + 1));
+        info!(target: "FileStore", "Creating new block data file: {}", new_file_path.display());
+        let format_version = written_format_version(self.compression_level);
+        let header = new_file_header(self.encryption_key.as_ref(), format_version);
+        let mut file = File::create(&new_file_path)?;
+        // Counted as soon as it exists, so a failed bulk write knows to remove it again
+        state.current_file_number += 1;
+        let mut compressed_files = self
+            .compressed_files
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        match format_version {
+            COMPRESSED_FORMAT_VERSION => compressed_files.insert(state.current_file_number),
+            _ => compressed_files.remove(&state.current_file_number),
+        };
+        drop(compressed_files);
+        file.write_all(&header)?;
+        state.write_offset = header.len() as u64;
+        self.preallocate_file(&file, state.write_offset)?;
+        Ok(())
+    }
+
+    /// Bytes the record for a payload (see `record_payload`) of `payload_len` bytes takes in
+    /// a file.
+    fn stored_len(&self, payload_len: usize) -> usize {
+        let payload_len = match self.encryption_key {
+            Some(_) => payload_len + RECORD_OVERHEAD,
+            None => payload_len,
+        };
+        FRAME_HEADER_SIZE + payload_len
+    }
+
+    /// What is stored of a block: compressed if the store compresses. A store that neither
+    /// compresses nor encrypts serializes the block straight into its record.
+    fn record_payload<'b>(&self, block: &'b BlockData) -> io::Result<RecordPayload<'b>> {
+        match (self.compression_level, &self.encryption_key) {
+            (Some(level), _) => {
+                compress_record(&block.serialize(), level).map(RecordPayload::Encoded)
+            }
+            (None, Some(_)) => Ok(RecordPayload::Encoded(block.serialize())),
+            (None, None) => Ok(RecordPayload::Block(block)),
+        }
+    }
+
+    /// Appends the record to store for `payload` at `offset` of file `file_number` to `out`,
+    /// framed with `magic` and, if the store is encrypted, encrypted.
+    fn encode_payload_into(
+        &self,
+        magic: [u8; 4],
+        file_number: u64,
+        offset: u64,
+        payload: &RecordPayload,
+        out: &mut Vec<u8>,
+    ) {
+        match (payload, &self.encryption_key) {
+            (RecordPayload::Block(block), None) => {
+                out.extend_from_slice(&magic);
+                out.extend_from_slice(&(block.serialized_len() as u32).to_le_bytes());
+                block
+                    .serialize_into(out)
+                    .expect("Writing to a Vec cannot fail");
+            }
+            (RecordPayload::Block(block), Some(key)) => frame_into(
+                magic,
+                &key.encrypt_record(file_number, offset, &block.serialize()),
+                out,
+            ),
+            (RecordPayload::Encoded(payload), None) => frame_into(magic, payload, out),
+            (RecordPayload::Encoded(payload), Some(key)) => frame_into(
+                magic,
+                &key.encrypt_record(file_number, offset, payload),
+                out,
+            ),
+        }
+    }
+
+    /// The record to store for `payload` (serialized, and compressed if the store
+    /// compresses) at `offset` of file `file_number`, framed with `magic` and, if the store is
+    /// encrypted, encrypted.
+    fn encode_record_as(
+        &self,
+        magic: [u8; 4],
+        file_number: u64,
+        offset: u64,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        match &self.encryption_key {
+            Some(key) => frame_record_as(magic, &key.encrypt_record(file_number, offset, payload)),
+            None => frame_record_as(magic, payload),
+        }
+    }
+
+    /// Whether a record of `record_len` bytes has to start a new file: it doesn't fit, or the
+    /// current file is in another format than records are written in.
+    /// A record bigger than a whole file still goes into a fresh one instead of leaving empty
+    /// files behind.
+    fn needs_new_file(&self, state: &WriteState, record_len: usize) -> bool {
+        let full = state.write_offset + record_len as u64 >= self.max_file_size;
+        let other_format =
+            self.is_compressed(state.current_file_number) != self.compression_level.is_some();
+        (full || other_format) && state.write_offset > self.header_len()
+    }
+
+    /// Adds a block data record to the end of the current file.
+    /// If the file will be full after the addition, it creates a new file and updates the index.
+    /// Adding a block that is already stored at `height` does nothing, so a batch that failed
+    /// partway can be retried from any block it already committed.
+    pub fn add_block(&self, block_data: &BlockData, height: u32) -> Result<(), StorageError> {
+        self.integrity.check_writable()?;
+        let mut state = self.state();
+        let tip = self.index.get_current_height();
+        if tip >= 0 && height <= tip as u32 {
+            if self.index.get_blockhash_by_height(height)? != block_data.blockhash {
+                return Err(StorageError::InvalidHeight);
+            }
+            debug!(target: "FileStore", "Block at height {} is already stored", height);
+            return Ok(());
+        }
+        block_data.check_tweak_meta()?;
+        let block_data = self.canonical(block_data);
+        let serialized_len = block_data.serialized_len();
+        state.record_size.observe(serialized_len);
+        if serialized_len > self.max_record_size as u64 {
+            return Err(StorageError::RecordTooLarge {
+                size: serialized_len as usize,
+                max: self.max_record_size,
+            });
+        }
+
+        let payload = self.record_payload(&block_data)?;
+        let journaled = Some((height, &block_data.blockhash));
+        let entry = self.append_record(&mut state, RECORD_MAGIC, &payload, journaled, |entry| {
+            self.index
+                .insert_block(height, &block_data.blockhash, entry)
+        })?;
+        state
+            .totals
+            .add(block_data.tweaks.len() as u64, entry.length);
+        self.save_chain_totals(&state.totals);
+        self.push_range_bytes(height, serialized_len);
+
+        info!(target: "FileStore", "Adding block at height {} (hash: {:?}) to file {} at offset {}", 
+              height, &block_data.blockhash[..4], entry.file_number, entry.offset);
+
+        self.sync_if_due(&mut state, 1)
+    }
+
+    /// Adds a block like `add_block`, along with dust filtered sets of its tweaks that readers
+    /// can ask for instead of the full set (see `get_block_with_tier`). Every tier is a record
+    /// of its own, written after the block's, with the tweaks alone. Tiers the block is stored with already are left
+    /// as they are, so a call that failed partway can be repeated, but missing ones can only
+    /// be added while the block is the tip (`InvalidHeight` otherwise).
+    pub fn add_block_with_tiers(
+        &self,
+        block_data: &BlockData,
+        height: u32,
+        tiers: &[DustTier],
+    ) -> Result<(), StorageError> {
+        self.add_block(block_data, height)?;
+        if tiers.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.state();
+        // Reorged away again since
+        if self.index.get_height_by_blockhash(&block_data.blockhash)? != height {
+            return Err(StorageError::EntryNotFound);
+        }
+        let mut missing = Vec::new();
+        for tier in tiers {
+            match self
+                .index
+                .get_tier_entry(&block_data.blockhash, tier.dust_threshold)
+            {
+                Ok(_) => {}
+                Err(StorageError::EntryNotFound) => missing.push(tier),
+                Err(e) => return Err(e),
+            }
+        }
+        // Only the tip's records may follow it, see recover_tail
+        if !missing.is_empty() && self.index.get_current_height() != height as i32 {
+            return Err(StorageError::InvalidHeight);
+        }
+        for tier in missing {
+            let tier_block = BlockData {
+                blockhash: block_data.blockhash,
+                tweaks: tier.tweaks.clone(),
+                tweak_meta: None,
+            };
+            let tier_block = self.canonical(&tier_block);
+            let payload = self.record_payload(&tier_block)?;
+            let entry =
+                self.append_record(&mut state, TIER_RECORD_MAGIC, &payload, None, |entry| {
+                    self.index
+                        .insert_tier(&block_data.blockhash, tier.dust_threshold, entry)
+                })?;
+            state.totals.add(0, entry.length);
+            debug!(target: "FileStore", "Adding dust tier {} of block at height {} ({} tweaks) to file {} at offset {}",
+                   tier.dust_threshold, height, tier.tweaks.len(), entry.file_number, entry.offset);
+        }
+        self.save_chain_totals(&state.totals);
+        self.sync_if_due(&mut state, 0)
+    }
+
+    /// Replaces the tweaks stored for a block of the chain with `new_tweaks`, for cut-through:
+    /// a tweak is of no use to clients once every output behind it is spent. The new record is
+    /// appended to the current file and the block's entry repointed at it, its height stays,
+    /// and the old record is counted as dead space for `compact`. Its dust tiers are left as
+    /// they are, and its tweak metadata is dropped as it no longer lines up with the tweaks.
+    /// Readers that started before the call (a range stream already planned) may still serve
+    /// the old tweaks, without caching them, and those started after it get the new ones.
+    /// Nothing is written, and metadata is kept, if `new_tweaks` are the tweaks already stored
+    /// in whatever order (see `BlockData::diff`), unless the stored ones are to be put in
+    /// canonical order. A record that can't be read is replaced.
+    /// Once `compact` drops the old record, an index rebuilt from the block data files can no
+    /// longer tell the block's height.
+    pub fn replace_block_tweaks(
+        &self,
+        blockhash: &[u8; 32],
+        new_tweaks: Vec<[u8; TWEAK_SIZE]>,
+    ) -> Result<(), StorageError> {
+        self.integrity.check_writable()?;
+        let mut state = self.state();
+        let old_entry = self.block_entry(blockhash)?;
+        state.flush()?;
+        let old_block = self
+            .block_from_record(blockhash, &old_entry, self.read_entry(&old_entry))
+            .inspect_err(|e| {
+                warn!(target: "FileStore", "Could not read the tweaks of block {:?} being replaced: {}", &blockhash[..4], e);
+            })
+            .ok();
+        let old_tweaks = old_block.as_ref().map_or(0, |block| block.tweaks.len());
+
+        let mut block_data = BlockData {
+            blockhash: *blockhash,
+            tweaks: new_tweaks,
+            tweak_meta: None,
+        };
+        if self.canonical_tweaks {
+            block_data.canonicalize();
+        }
+        let diff = old_block
+            .as_ref()
+            .map(|old_block| sorted(old_block).diff(&sorted(&block_data)))
+            .transpose()?;
+        // A block stored out of order before `canonical_tweaks` was set is still rewritten
+        let in_order = old_block
+            .as_ref()
+            .is_some_and(|old_block| old_block.is_canonical() || !self.canonical_tweaks);
+        if in_order && diff.as_ref().is_some_and(TweakDiff::is_empty) {
+            debug!(target: "FileStore", "Tweaks of block {:?} are unchanged, not replacing them",
+                   &blockhash[..4]);
+            return Ok(());
+        }
+        let serialized_len = block_data.serialized_len();
+        if serialized_len > self.max_record_size as u64 {
+            return Err(StorageError::RecordTooLarge {
+                size: serialized_len as usize,
+                max: self.max_record_size,
+            });
+        }
+        let payload = self.record_payload(&block_data)?;
+        let entry = self.append_record(
+            &mut state,
+            REPLACED_RECORD_MAGIC,
+            &payload,
+            None,
+            |_| Ok(()),
+        )?;
+        // Unlike a tip a crash cut off, a record lost behind the entry pointing at it wouldn't
+        // be noticed on open, so it has to be on disk first
+        let repointed = state
+            .sync_data()
+            .map_err(StorageError::from)
+            .and_then(|()| self.repoint_block(blockhash, &entry, serialized_len));
+        if let Err(e) = repointed {
+            self.rollback_write(&mut state, entry.offset);
+            state.write_offset = entry.offset;
+            return Err(e);
+        }
+        self.cache.remove(blockhash);
+        self.count_dead_record(&old_entry);
+        self.mark_dead_record(&old_entry, true);
+        state.totals.remove(old_tweaks as u64, old_entry.length);
+        state
+            .totals
+            .add(block_data.tweaks.len() as u64, entry.length);
+        self.save_chain_totals(&state.totals);
+
+        let cut = diff.map_or(old_tweaks, |diff| diff.removed.len());
+        info!(target: "FileStore", "Replaced the tweaks of block {:?} ({} to {}, {} cut), now in file {} at offset {}",
+              &blockhash[..4], old_tweaks, block_data.tweaks.len(), cut, entry.file_number, entry.offset);
+        Ok(())
+    }
+
+    /// Appends the record for `payload` (see `record_payload`), framed with `magic`, to the
+    /// current file, starting a new file first if it doesn't fit, and indexes it with `insert`.
+    /// The record and its index entry go in together: if either the write or `insert` fails,
+    /// the file is truncated back so it never holds a record the index doesn't know about.
+    /// A block `journaled` as (height, blockhash) is in the journal while it is written, so a
+    /// crash before `insert` doesn't lose it either, see `replay_journal`.
+    fn append_record(
+        &self,
+        state: &mut WriteState,
+        magic: [u8; 4],
+        payload: &RecordPayload,
+        journaled: Option<(u32, &[u8; 32])>,
+        insert: impl FnOnce(&IndexEntry) -> Result<(), StorageError>,
+    ) -> Result<IndexEntry, StorageError> {
+        if self.needs_new_file(state, self.stored_len(payload.len())) {
+            debug!(target: "FileStore", "Current file size limit reached ({} bytes), creating new file", state.write_offset);
+            self.create_new_file(state)?;
+        }
+
+        let offset = state.write_offset;
+        let mut record = Vec::with_capacity(self.stored_len(payload.len()));
+        self.encode_payload_into(
+            magic,
+            state.current_file_number,
+            offset,
+            payload,
+            &mut record,
+        );
+        let entry = IndexEntry {
+            file_number: state.current_file_number,
+            offset,
+            length: record.len() as u64,
+        };
+        if let Some((height, blockhash)) = journaled {
+            state.journal.begin(&Intent {
+                height,
+                blockhash: *blockhash,
+                entry: IndexEntry {
+                    file_number: entry.file_number,
+                    offset,
+                    length: entry.length,
+                },
+            })?;
+        }
+        let result = match self
+            .writer(state)
+            .and_then(|writer| writer.write_all(&record))
+        {
+            Ok(()) => insert(&entry),
+            Err(e) => Err(e.into()),
+        };
+        if result.is_err() {
+            self.rollback_write(state, offset);
+        } else {
+            state.write_offset += entry.length;
+        }
+        if journaled.is_some() {
+            if let Err(e) = state.journal.finish() {
+                warn!(target: "FileStore", "Could not clear the journal after writing the record at offset {} of file {}: {}",
+                      offset, entry.file_number, e);
+            }
+        }
+        result.map(|()| entry)
+    }
+
+    fn rollback_write(&self, state: &mut WriteState, offset: u64) {
+        let file_path = self.current_file_path(state);
+        // Earlier records may still be buffered, they have to reach the file before it is cut
+        // back. The writer is reopened at `offset` for the next record.
+        let truncated = state
+            .close_writer()
+            .and_then(|()| self.cut_current_file(state, offset));
+        if let Err(e) = truncated {
+            self.integrity.report(Violation::new(
+                ViolationKind::IndexFileMismatch,
+                format!(
+                    "could not truncate {} back to {} after a failed write: {}",
+                    file_path.display(),
+                    offset,
+                    e
+                ),
+            ));
+        }
+    }
+
+    /// `block` as it is to be stored: with its tweaks canonicalized if `canonical_tweaks` is set.
+    fn canonical<'b>(&self, block: &'b BlockData) -> Cow<'b, BlockData> {
+        if !self.canonical_tweaks || block.is_canonical() {
+            return Cow::Borrowed(block);
+        }
+        let mut block = block.clone();
+        block.canonicalize();
+        Cow::Owned(block)
+    }
+
+    /// Adds consecutive blocks with a single write per block data file and a single index
+    /// batch. Leading blocks that are already stored are skipped, so a batch can be retried
+    /// as a whole. The rest is stored all together or not at all: `committed` in the result
+    /// covers the skipped blocks plus, on success, everything else.
+    pub fn add_block_bulk(&self, blocks: &[BlockData], heights: &[u32]) -> BulkResult {
+        let count = blocks.len().min(heights.len());
+        let mut committed = 0;
+        let tip = self.index.get_current_height();
+        while committed < count && tip >= 0 && heights[committed] <= tip as u32 {
+            if let Err(e) = self.add_block(&blocks[committed], heights[committed]) {
+                return BulkResult {
+                    committed: committed as u32,
+                    error: Some((heights[committed], e)),
+                };
+            }
+            committed += 1;
+        }
+
+        match self.append_blocks(&blocks[committed..count], &heights[committed..count]) {
+            Ok(()) => BulkResult {
+                committed: count as u32,
+                error: None,
+            },
+            Err(error) => BulkResult {
+                committed: committed as u32,
+                error: Some(error),
+            },
+        }
+    }
+
+    /// Stores blocks that continue the chain, all of them or none. Errors carry the height of
+    /// the block at fault, or the first height of the batch when the batch failed as a whole.
+    fn append_blocks(
+        &self,
+        blocks: &[BlockData],
+        heights: &[u32],
+    ) -> Result<(), (u32, StorageError)> {
+        let Some(&start_height) = heights.first() else {
+            return Ok(());
+        };
+        self.integrity
+            .check_writable()
+            .map_err(|e| (start_height, e))?;
+        let mut state = self.state();
+
+        let next_height = (self.index.get_current_height() + 1) as u32;
+        for ((block, &height), expected) in blocks.iter().zip(heights).zip(next_height..) {
+            if height != expected {
+                return Err((height, StorageError::InvalidHeight));
+            }
+            block.check_tweak_meta().map_err(|e| (height, e))?;
+            let serialized_len = block.serialized_len();
+            state.record_size.observe(serialized_len);
+            if serialized_len > self.max_record_size as u64 {
+                let error = StorageError::RecordTooLarge {
+                    size: serialized_len as usize,
+                    max: self.max_record_size,
+                };
+                return Err((height, error));
+            }
+        }
+
+        // Checked as given, canonicalizing only ever makes a record smaller
+        let blocks: Vec<Cow<BlockData>> =
+            blocks.iter().map(|block| self.canonical(block)).collect();
+
+        let file_number = state.current_file_number;
+        let offset = state.write_offset;
+        let result = self.write_records(&mut state, &blocks).and_then(|entries| {
+            // The records are in the files before the index points at them
+            state.flush()?;
+            self.index.insert_blocks(start_height, &entries)?;
+            Ok(entries)
+        });
+        let entries = match result {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.rollback_bulk_write(&mut state, file_number, offset);
+                return Err((start_height, e));
+            }
+        };
+        for (block, (_, entry)) in blocks.iter().zip(&entries) {
+            state.totals.add(block.tweaks.len() as u64, entry.length);
+        }
+        self.save_chain_totals(&state.totals);
+        for (height, block) in (start_height..).zip(&blocks) {
+            self.push_range_bytes(height, block.serialized_len());
+        }
+        self.sync_if_due(&mut state, blocks.len() as u32)
+            .map_err(|e| (start_height, e))?;
+
+        info!(target: "FileStore", "Added {} blocks at heights {}..={}",
+              blocks.len(), start_height, start_height as usize + blocks.len() - 1);
+        Ok(())
+    }
+
+    /// Appends the records for a bulk insert, with one write per file, and returns the index
+    /// entries for them. Leaves cleaning up after a failure to the caller.
+    fn write_records(
+        &self,
+        state: &mut WriteState,
+        blocks: &[Cow<BlockData>],
+    ) -> Result<Vec<([u8; 32], IndexEntry)>, StorageError> {
+        let mut entries = Vec::with_capacity(blocks.len());
+        let mut buffer = Vec::new();
+        // Opened before `write_offset` moves past what the file holds, the writer starts there
+        self.writer(state)?;
+        for block in blocks {
+            let payload = self.record_payload(block)?;
+            if self.needs_new_file(state, self.stored_len(payload.len())) {
+                self.writer(state)?.write_all(&buffer)?;
+                buffer.clear();
+                self.create_new_file(state)?;
+                self.writer(state)?;
+            }
+
+            let offset = state.write_offset;
+            let start = buffer.len();
+            self.encode_payload_into(
+                RECORD_MAGIC,
+                state.current_file_number,
+                offset,
+                &payload,
+                &mut buffer,
+            );
+            let length = (buffer.len() - start) as u64;
+            entries.push((
+                block.blockhash,
+                IndexEntry {
+                    file_number: state.current_file_number,
+                    offset,
+                    length,
+                },
+            ));
+            state.write_offset += length;
+        }
+        self.writer(state)?.write_all(&buffer)?;
+        Ok(entries)
+    }
+
+    /// Puts the block data files back the way they were before a failed bulk write: removes
+    /// the files it started and truncates the one it started in back to `offset`.
+    fn rollback_bulk_write(&self, state: &mut WriteState, file_number: u64, offset: u64) {
+        while state.current_file_number > file_number {
+            let file_path = self.current_file_path(state);
+            // Whatever is still buffered belongs to the file that is going away
+            let _ = state.close_writer();
+            if let Err(e) = fs::remove_file(&file_path) {
+                self.integrity.report(Violation::new(
+                    ViolationKind::IndexFileMismatch,
+                    format!(
+                        "could not remove {} after a failed bulk add: {}",
+                        file_path.display(),
+                        e
+                    ),
+                ));
+            }
+            state.current_file_number -= 1;
+        }
+        state.write_offset = offset;
+        self.rollback_write(state, offset);
+    }
+
+    /// Removes the current tip, as long as it is still `expected_hash`. Callers pass the
+    /// hash they believe is the tip so a stale view can't remove the wrong block.
+    /// The block's bytes stay in the flat file as dead space, marked so a rebuilt index leaves
+    /// them out, and its index entry is marked orphaned so later lookups return `OrphanedEntry`.
+    pub fn remove_tip_block(&self, expected_hash: &[u8; 32]) -> Result<RemovedBlock, StorageError> {
+        self.integrity.check_writable()?;
+        let mut state = self.state();
+        let height = self.index.get_current_height();
+        if height < 0 {
+            return Err(StorageError::EntryNotFound);
+        }
+        let height = height as u32;
+
+        let blockhash = self.index.get_blockhash_by_height(height)?;
+        if &blockhash != expected_hash {
+            return Err(StorageError::TipMismatch);
+        }
+
+        let entry = self.block_entry(&blockhash)?;
+        state.flush()?;
+        let tweaks = self.stored_tweak_count(&blockhash, &entry);
+        self.index.truncate_range_bytes(height)?;
+        self.index.remove_block(&blockhash)?;
+        self.cache.remove(&blockhash);
+        let tier_bytes = self.remove_tiers(&blockhash)?;
+        state.totals.remove(tweaks, entry.length + tier_bytes);
+        self.save_chain_totals(&state.totals);
+        self.count_dead_record(&entry);
+        self.mark_dead_record(&entry, false);
+
+        info!(target: "FileStore", "Removed tip block at height {} (hash: {:?}) from file {} at offset {}",
+              height, &blockhash[..4], entry.file_number, entry.offset);
+
+        Ok(RemovedBlock {
+            height,
+            blockhash,
+            entry,
+        })
+    }
+
+    /// Reorgs away every block above `height`, for reorgs deeper than one block. Like
+    /// `remove_tip_block`, the records stay in the flat files as dead space. Returns how many
+    /// blocks were removed; a call that was interrupted can simply be repeated.
+    pub fn remove_blocks_above(&self, height: u32) -> Result<u32, StorageError> {
+        self.integrity.check_writable()?;
+        let mut state = self.state();
+        let tip = self.index.get_current_height();
+        // Blocks below the pruned height can't be stored again, their heights would read as
+        // pruned
+        if tip > height as i32 && height + 1 < self.pruned_up_to {
+            return Err(StorageError::Pruned);
+        }
+        state.flush()?;
+        let mut removed_blocks = Vec::new();
+        let mut removed_entries = Vec::new();
+        let mut removed_totals = ChainTotals::default();
+        for height in (height + 1)..(tip + 1).max(0) as u32 {
+            let blockhash = self.index.get_blockhash_by_height(height)?;
+            // An interrupted removal leaves blocks orphaned, but still on the chain
+            if let Ok(entry) = self.block_entry(&blockhash) {
+                removed_totals.add(self.stored_tweak_count(&blockhash, &entry), entry.length);
+                removed_entries.push(entry);
+            }
+            removed_blocks.push(blockhash);
+        }
+        self.index.truncate_range_bytes(height + 1)?;
+        let removed = self.index.remove_blocks_above(height)?;
+        for blockhash in &removed_blocks {
+            self.cache.remove(blockhash);
+            let tier_bytes = self.remove_tiers(blockhash)?;
+            removed_totals.add(0, tier_bytes);
+        }
+        if removed > 0 {
+            state
+                .totals
+                .remove(removed_totals.tweaks, removed_totals.record_bytes);
+            self.save_chain_totals(&state.totals);
+        }
+        for entry in &removed_entries {
+            self.count_dead_record(entry);
+            self.mark_dead_record(entry, false);
+        }
+        if removed > 0 {
+            info!(target: "FileStore", "Removed {} blocks above height {} (previous tip {})",
+                  removed, height, tip);
+        }
+        Ok(removed)
+    }
+
+    /// Removes the current tip and returns it, and unlike `remove_tip_block` takes its record
+    /// back out of the flat file: the file is cut back to where the record starts, or removed
+    /// if the record was the only one in it. If something follows the record (dead space left
+    /// by `remove_tip_block`, a quarantined block, the block's dust tiers) the bytes stay.
+    pub fn pop_tip(&self) -> Result<BlockData, StorageError> {
+        self.integrity.check_writable()?;
+        let mut state = self.state();
+        let height = self.index.get_current_height();
+        if height < 0 {
+            return Err(StorageError::EntryNotFound);
+        }
+        let height = height as u32;
+        let blockhash = self.index.get_blockhash_by_height(height)?;
+        let entry = self.block_entry(&blockhash)?;
+        state.flush()?;
+        let block = self.block_from_record(&blockhash, &entry, self.read_entry(&entry))?;
+        self.index.truncate_range_bytes(height)?;
+        self.index.remove_block(&blockhash)?;
+        self.cache.remove(&blockhash);
+        let tier_bytes = self.remove_tiers(&blockhash)?;
+        state
+            .totals
+            .remove(block.tweaks.len() as u64, entry.length + tier_bytes);
+        self.save_chain_totals(&state.totals);
+
+        info!(target: "FileStore", "Popped tip block at height {} (hash: {:?}) from file {} at offset {}",
+              height, &blockhash[..4], entry.file_number, entry.offset);
+        if entry.file_number != state.current_file_number
+            || entry.offset + entry.length != state.write_offset
+        {
+            debug!(target: "FileStore", "Popped block is not the last record, leaving its bytes in file {}",
+                   entry.file_number);
+            self.count_dead_record(&entry);
+            self.mark_dead_record(&entry, false);
+            return Ok(block);
+        }
+        // The block is gone from the index either way, what's left behind is only dead space
+        match self.reclaim_record(&mut state, &entry) {
+            Ok(true) => {}
+            Ok(false) => {
+                self.count_dead_record(&entry);
+                self.mark_dead_record(&entry, false);
+            }
+            Err(e) => {
+                warn!(target: "FileStore", "Could not reclaim the record of the popped block in file {}: {}",
+                      entry.file_number, e);
+                self.count_dead_record(&entry);
+                self.mark_dead_record(&entry, false);
+            }
+        }
+        Ok(block)
+    }
+
+    /// Cuts the last record, `entry`, off the current file. Returns false if it had to stay.
+    fn reclaim_record(&self, state: &mut WriteState, entry: &IndexEntry) -> io::Result<bool> {
+        // Cutting a file short under a mapping crashes whoever reads it, the bytes stay as
+        // dead space instead
+        #[cfg(feature = "mmap")]
+        if self.is_mapped(state.current_file_number) {
+            debug!(target: "FileStore", "File {} is memory mapped, leaving the popped record in it",
+                   state.current_file_number);
+            return Ok(false);
+        }
+        state.close_writer()?;
+        let file_path = self.current_file_path(state);
+        if entry.offset == self.header_len() && state.current_file_number > self.first_file_number {
+            fs::remove_file(&file_path)?;
+            state.current_file_number -= 1;
+            state.write_offset = fs::metadata(self.current_file_path(state))?.len();
+        } else {
+            self.cut_current_file(state, entry.offset)?;
+            state.write_offset = entry.offset;
+        }
+        Ok(true)
+    }
+
+    /// Takes the dust tiers of a block taken off the chain out of the index, their records
+    /// become dead space. Returns the bytes the records take.
+    fn remove_tiers(&self, blockhash: &[u8; 32]) -> Result<u64, StorageError> {
+        let mut bytes = 0;
+        for entry in self.index.remove_tiers(blockhash)? {
+            self.count_dead_record(&entry);
+            bytes += entry.length;
+        }
+        Ok(bytes)
+    }
+
+    /// Counts the record of a block taken off the chain as dead space of its file, see
+    /// `compact`. The counters only guide compaction, failing to update one is no error.
+    fn count_dead_record(&self, entry: &IndexEntry) {
+        if let Err(e) = self.index.add_dead_bytes(entry.file_number, entry.length) {
+            warn!(target: "FileStore", "Failed to count the dead bytes of block data file {}: {}",
+                  entry.file_number, e);
+        }
+    }
+
+    /// Marks the record of a block taken off the chain, or with `superseded` the record of a
+    /// block `replace_block_tweaks` stored again, so rebuilding the index can tell it from a
+    /// block of the chain (see ORPHANED_RECORD_MAGIC). Its bytes have to be written out.
+    /// Only a rebuild looks at the mark, failing to set it is no error.
+    fn mark_dead_record(&self, entry: &IndexEntry, superseded: bool) {
+        if let Err(e) = self.write_dead_mark(entry, superseded) {
+            warn!(target: "FileStore", "Failed to mark the dead record in file {} at offset {}, rebuilding the index would count it: {}",
+                  entry.file_number, entry.offset, e);
+        }
+    }
+
+    fn write_dead_mark(&self, entry: &IndexEntry, superseded: bool) -> io::Result<()> {
+        let mut file = File::options().read(true).write(true).open(
+            self.block_data_dir
+                .join(block_file_name!(entry.file_number)),
+        )?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        let mark = match magic {
+            RECORD_MAGIC if superseded => SUPERSEDED_RECORD_MAGIC,
+            RECORD_MAGIC | REPLACED_RECORD_MAGIC => ORPHANED_RECORD_MAGIC,
+            // Dust tier records never get a height
+            _ => return Ok(()),
+        };
+        file.seek(SeekFrom::Start(entry.offset))?;
+        file.write_all(&mark)?;
+        file.sync_data()
+    }
+
+    /// Deletes the block data files that only hold blocks below `height`, for operators who
+    /// don't need to serve old blocks. Only whole files go, and never the current one, so some
+    /// blocks below `height` may be kept. Returns the height blocks are kept from now; reads
+    /// below it give `Pruned`.
+    /// The index entries of pruned blocks are removed, their height mappings stay. The new
+    /// state is recorded before anything is deleted, so an interrupted prune is finished when
+    /// the store is opened again.
+    pub fn prune_below(&mut self, height: u32) -> Result<u32, StorageError> {
+        self.integrity.check_writable()?;
+        if height <= self.pruned_up_to {
+            return Ok(self.pruned_up_to);
+        }
+        let tip = self.index.get_current_height();
+        // The first file holding a block at or above `height`
+        let keep_from_file = if tip >= 0 && height <= tip as u32 {
+            self.chain_entry(height)?.file_number
+        } else {
+            self.state_mut().current_file_number
+        };
+        if keep_from_file <= self.first_file_number {
+            return Ok(self.pruned_up_to);
+        }
+
+        // Blocks are appended in height order, so the blocks kept are those from the first
+        // height whose record is in a kept file
+        let (mut low, mut high) = (self.pruned_up_to, height.min((tip + 1) as u32));
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.chain_entry(middle)?.file_number < keep_from_file {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        // Counted before the records go
+        let mut totals = self.state_mut().totals;
+        let mut pruned_blocks = Vec::new();
+        for height in self.pruned_up_to..low {
+            let blockhash = self.index.get_blockhash_by_height(height)?;
+            let entry = self.block_entry(&blockhash)?;
+            totals.remove(self.stored_tweak_count(&blockhash, &entry), entry.length);
+            for (_, tier) in self.index.tier_entries(&blockhash)? {
+                totals.remove(0, tier.length);
+            }
+            pruned_blocks.push(blockhash);
+        }
+
+        let mut state = keep_from_file.to_le_bytes().to_vec();
+        state.extend_from_slice(&low.to_le_bytes());
+        self.index.set_meta(PRUNED_META_KEY, &state)?;
+        let pruned_files = keep_from_file - self.first_file_number;
+        self.first_file_number = keep_from_file;
+        self.pruned_up_to = low;
+        self.state_mut().totals = totals;
+        self.save_chain_totals(&totals);
+        self.remove_pruned_files()?;
+        // Tiers written to a kept file go along with their block
+        for blockhash in &pruned_blocks {
+            self.remove_tiers(blockhash)?;
+        }
+        self.cache.clear();
+
+        info!(target: "FileStore", "Pruned {} block data files, blocks are kept from height {}",
+              pruned_files, low);
+        Ok(low)
+    }
+
+    /// Hits and misses of the block cache, and what it holds.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// How much the store holds and how much disk it takes. Tweaks come from running totals,
+    /// so this only looks at the sizes of the files, never into them.
+    pub fn stats(&self) -> Result<StoreStats, StorageError> {
+        let mut state = self.state();
+        state.flush()?;
+        let next_height = (self.index.get_current_height() + 1) as u32;
+        let mut stats = StoreStats {
+            blocks: next_height.saturating_sub(self.pruned_up_to),
+            tweaks: state.totals.tweaks,
+            files: state.current_file_number - self.first_file_number + 1,
+            index_bytes: self.index.size_on_disk()?,
+            ..Default::default()
+        };
+        // What the files hold besides their headers, up to where the data ends in a
+        // preallocated current file
+        let mut data_bytes = 0;
+        for file_number in self.first_file_number..=state.current_file_number {
+            let size = fs::metadata(self.block_data_dir.join(block_file_name!(file_number)))?.len();
+            stats.file_bytes += size;
+            let data_end = match file_number == state.current_file_number {
+                true => state.write_offset,
+                false => size,
+            };
+            data_bytes += data_end.saturating_sub(self.header_len());
+        }
+        stats.dead_bytes = data_bytes.saturating_sub(state.totals.record_bytes);
+        Ok(stats)
+    }
+
+    /// Height blocks are kept from, everything below it was pruned.
+    pub fn pruned_up_to(&self) -> u32 {
+        self.pruned_up_to
+    }
+
+    /// Takes the entries pointing into pruned files and their dead bytes out of the index, then
+    /// deletes the files. Safe to repeat.
+    fn remove_pruned_files(&self) -> Result<(), StorageError> {
+        let pruned: Vec<EntryKey> = self
+            .index
+            .located_entries()?
+            .into_iter()
+            .filter(|(_, entry)| entry.file_number < self.first_file_number)
+            .map(|(key, _)| key)
+            .collect();
+        self.index.remove_entries(&pruned)?;
+        let pruned_files: Vec<u64> = self
+            .index
+            .dead_bytes()?
+            .into_iter()
+            .map(|(file_number, _)| file_number)
+            .filter(|&file_number| file_number < self.first_file_number)
+            .collect();
+        self.index.clear_dead_bytes(&pruned_files)?;
+
+        for file_number in 0..self.first_file_number {
+            let file_path = self.block_data_dir.join(block_file_name!(file_number));
+            match fs::remove_file(&file_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Completes a prune that was interrupted before all of its files were deleted.
+    fn finish_prune(&self) -> Result<(), StorageError> {
+        let interrupted = (0..self.first_file_number).any(|file_number| {
+            self.block_data_dir
+                .join(block_file_name!(file_number))
+                .exists()
+        });
+        if interrupted {
+            warn!(target: "FileStore", "Finishing an interrupted prune of the block data files before {}",
+                  self.first_file_number);
+            self.remove_pruned_files()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the block data files in which dead space (records of blocks reorged away, see
+    /// `remove_tip_block`) makes up more than `min_dead_ratio` of the data, keeping only the
+    /// records the index points to. The current file is never touched. Returns how many bytes
+    /// were freed.
+    /// A file is copied first, then its index entries are pointed into the copy, and only then
+    /// does the copy replace it. An interruption before the index update leaves a stray copy
+    /// behind, one after it is finished when the store is opened again.
+    pub fn compact(&mut self, min_dead_ratio: f32) -> Result<u64, StorageError> {
+        self.integrity.check_writable()?;
+        let current_file_number = self.state_mut().current_file_number;
+        let mut files = Vec::new();
+        for (file_number, dead_bytes) in self.index.dead_bytes()? {
+            if file_number < self.first_file_number || file_number >= current_file_number {
+                continue;
+            }
+            let file_path = self.block_data_dir.join(block_file_name!(file_number));
+            let data_bytes = fs::metadata(&file_path)?
+                .len()
+                .saturating_sub(self.header_len());
+            if data_bytes > 0 && dead_bytes as f64 > min_dead_ratio as f64 * data_bytes as f64 {
+                files.push(file_number);
+            }
+        }
+        if files.is_empty() {
+            return Ok(0);
+        }
+
+        let mut live: HashMap<u64, Vec<(EntryKey, IndexEntry)>> = HashMap::new();
+        for (key, entry) in self.index.located_entries()? {
+            if files.contains(&entry.file_number) {
+                live.entry(entry.file_number)
+                    .or_default()
+                    .push((key, entry));
+            }
+        }
+        let mut freed = 0;
+        for &file_number in &files {
+            let entries = live.remove(&file_number).unwrap_or_default();
+            freed += self.compact_file(file_number, entries)?;
+        }
+
+        info!(target: "FileStore", "Compacted {} block data files, freeing {} bytes", files.len(), freed);
+        Ok(freed)
+    }
+
+    /// Rewrites block data file `file_number` with only the records of `live`, the entries
+    /// pointing into it. Returns how many bytes were freed.
+    fn compact_file(
+        &self,
+        file_number: u64,
+        live: Vec<(EntryKey, IndexEntry)>,
+    ) -> Result<u64, StorageError> {
+        let file_path = self.block_data_dir.join(block_file_name!(file_number));
+        debug!(target: "FileStore", "Compacting block data file: {}", file_path.display());
+        let size = fs::metadata(&file_path)?.len();
+        let moved = self.write_compacted_copy(file_number, live)?;
+
+        // From here on the copy is the file, whether or not it is swapped in right away
+        self.index
+            .move_entries(&moved, COMPACTION_META_KEY, &file_number.to_le_bytes())?;
+        finish_compaction(&self.index, &self.block_data_dir)?;
+        Ok(size.saturating_sub(fs::metadata(&file_path)?.len()))
+    }
+
+    /// Copies the records of `live` out of block data file `file_number`, back to back, and
+    /// returns the entries pointing into the copy.
+    fn write_compacted_copy(
+        &self,
+        file_number: u64,
+        mut live: Vec<(EntryKey, IndexEntry)>,
+    ) -> Result<Vec<(EntryKey, IndexEntry)>, StorageError> {
+        let file_path = self.block_data_dir.join(block_file_name!(file_number));
+        let tmp_path = file_path.with_extension(COMPACTION_EXTENSION);
+        let mut header = vec![0u8; self.header_len() as usize];
+        File::open(&file_path)?.read_exact(&mut header)?;
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(&header)?;
+
+        live.sort_unstable_by_key(|(_, entry)| entry.offset);
+        let mut reader = RecordReader::new(self);
+        let mut offset = self.header_len();
+        let mut moved = Vec::with_capacity(live.len());
+        for (key, entry) in live {
+            let record = reader.read(&entry)?;
+            writer.write_all(&self.relocate_record(&entry, offset, &record)?)?;
+            moved.push((
+                key,
+                IndexEntry {
+                    file_number,
+                    offset,
+                    length: entry.length,
+                },
+            ));
+            offset += entry.length;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok(moved)
+    }
+
+    /// The stored record `entry` points to, as it has to be stored at `offset` of the same
+    /// file instead. Only an encrypted record changes, its nonce depends on where it is.
+    fn relocate_record(
+        &self,
+        entry: &IndexEntry,
+        offset: u64,
+        record: &[u8],
+    ) -> Result<Vec<u8>, StorageError> {
+        let payload = frame_payload(record).ok_or(StorageError::DeserializeError(
+            "record frame does not match the record",
+        ))?;
+        match &self.encryption_key {
+            Some(key) => {
+                let plaintext = key.decrypt_record(entry.file_number, entry.offset, payload)?;
+                Ok(self.encode_record_as(
+                    frame_magic(record),
+                    entry.file_number,
+                    offset,
+                    &plaintext,
+                ))
+            }
+            None => Ok(record.to_vec()),
+        }
+    }
+
+    /// The entry of the block at `height` on the current chain.
+    fn chain_entry(&self, height: u32) -> Result<IndexEntry, StorageError> {
+        let blockhash = self.index.get_blockhash_by_height(height)?;
+        self.block_entry(&blockhash)
+    }
+
+    /// The entry of a stored block, `Pruned` if its record went with a pruned file.
+    fn block_entry(&self, blockhash: &[u8; 32]) -> Result<IndexEntry, StorageError> {
+        match self.index.get_block_entry(blockhash) {
+            Err(StorageError::EntryNotFound)
+                if matches!(self.index.get_height_by_blockhash(blockhash),
+                            Ok(height) if height < self.pruned_up_to) =>
+            {
+                Err(StorageError::Pruned)
+            }
+            result => result,
+        }
+    }
+
+    /// This is an uninterrupted Buffered Stream of data that can be served to the client
+    /// It automatically moves to a new file (skips over magic bytes) when the end of current
+    /// file is reached.
+    /// Clients get the serialized blocks back to back: record frames are a storage detail and
+    /// are stripped, like encryption, so the served format doesn't change with the file format.
+    /// With a `limit` the stream ends after that many bytes of stored records, the
+    /// `IndexEntry::length` of a block or the sum of them over a range. Without one it runs
+    /// to the end of the last file.
+    pub fn get_block_stream_from_offset<'a>(
+        &'a self,
+        entry: &IndexEntry,
+        limit: Option<u64>,
+    ) -> Result<impl Read + 'a, StorageError> {
+        self.block_data_reader(entry, limit)
+    }
+
+    /// Streams the blocks at heights `start..=end`, `InvalidHeight` if that isn't a range of
+    /// stored heights.
+    /// Records are usually laid out back to back, so this is a single bounded stream. Blocks
+    /// removed by a reorg leave their records behind as dead space though, which is skipped.
+    /// See `BlockRangeReader::skip_blocks` to move ahead in the range without reading.
+    pub fn get_block_stream_range(
+        &self,
+        start: u32,
+        end: u32,
+    ) -> Result<BlockRangeReader<'_>, StorageError> {
+        self.get_block_stream_range_with_tier(start, end, None)
+    }
+
+    /// Bytes `get_block_stream_range(start, end)` streams, read from sizes the index keeps per
+    /// height rather than the records, so a response can announce its length up front. Tweak
+    /// metadata is counted, a `tweaks_only` stream of blocks stored with it is shorter.
+    pub fn size_of_range(&self, start: u32, end: u32) -> Result<u64, StorageError> {
+        let mut state = self.state();
+        let tip = self.index.get_current_height();
+        if start > end || tip < 0 || end > tip as u32 {
+            return Err(StorageError::InvalidHeight);
+        }
+        if start < self.pruned_up_to {
+            return Err(StorageError::Pruned);
+        }
+        match self.index.range_bytes(start, end) {
+            Err(StorageError::EntryNotFound) => {
+                state.flush()?;
+                self.count_range_bytes(end)?;
+                self.index.range_bytes(start, end)
+            }
+            bytes => bytes,
+        }
+    }
+
+    /// Tweaks in the stored block `blockhash`, from the header of its record: the tweaks
+    /// aren't read, nor is the record checksummed. Records of encrypted or compressed stores
+    /// are read whole, their headers can't be seen without. Errors as `get_block_by_hash`.
+    pub fn tweak_count(&self, blockhash: &[u8; 32]) -> Result<u32, StorageError> {
+        let entry = self.block_entry(blockhash)?;
+        if let Some(block) = self.cache.get(blockhash) {
+            return Ok(
+                BlockDataRef::parse_limited(&block, self.max_record_size)?.tweak_count() as u32,
+            );
+        }
+        self.state().flush()?;
+        self.count_record_tweaks(blockhash, &entry, &mut None)
+    }
+
+    /// `tweak_count` of every block at heights `start..=end`, in height order.
+    /// `InvalidHeight` if that isn't a range of stored heights.
+    pub fn tweak_counts(&self, start: u32, end: u32) -> Result<Vec<u32>, StorageError> {
+        let tip = self.index.get_current_height();
+        if start > end || tip < 0 || end > tip as u32 {
+            return Err(StorageError::InvalidHeight);
+        }
+        if start < self.pruned_up_to {
+            return Err(StorageError::Pruned);
+        }
+        self.state().flush()?;
+        let mut file = None;
+        (start..=end)
+            .map(|height| {
+                let blockhash = self.index.get_blockhash_by_height(height)?;
+                let entry = self.block_entry(&blockhash)?;
+                self.count_record_tweaks(&blockhash, &entry, &mut file)
+            })
+            .collect()
+    }
+
+    /// Same as `get_block_stream_range`, streaming the dust tier `dust_threshold` of every
+    /// block instead, or all tweaks for None. `EntryNotFound` if a block of the range wasn't
+    /// stored with that tier.
+    pub fn get_block_stream_range_with_tier(
+        &self,
+        start: u32,
+        end: u32,
+        dust_threshold: Option<u64>,
+    ) -> Result<BlockRangeReader<'_>, StorageError> {
+        let tip = self.index.get_current_height();
+        if start > end || tip < 0 || end > tip as u32 {
+            return Err(StorageError::InvalidHeight);
+        }
+        if start < self.pruned_up_to {
+            return Err(StorageError::Pruned);
+        }
+        self.state().flush()?;
+
+        Ok(BlockRangeReader {
+            store: self,
+            parts: self.range_parts(start, end, dust_threshold)?,
+            current: None,
+            part_start: start,
+            end,
+            dust_threshold,
+            tweaks_only: false,
+        })
+    }
+
+    /// Plans how to serve the blocks (or dust tiers of them) at heights `start..=end`: runs of
+    /// records that follow each other in the files, and blocks found in the cache.
+    fn range_parts(
+        &self,
+        start: u32,
+        end: u32,
+        dust_threshold: Option<u64>,
+    ) -> Result<VecDeque<RangePart>, StorageError> {
+        let mut parts: VecDeque<RangePart> = VecDeque::new();
+        let mut run_end = (0, 0);
+        for height in start..=end {
+            let blockhash = self.index.get_blockhash_by_height(height)?;
+            let entry = match dust_threshold {
+                Some(dust_threshold) => self.index.get_tier_entry(&blockhash, dust_threshold)?,
+                None => self.index.get_block_entry(&blockhash)?,
+            };
+            // The cache only holds blocks with all their tweaks
+            if let Some(block) = dust_threshold
+                .is_none()
+                .then(|| self.cache.get(&blockhash))
+                .flatten()
+            {
+                parts.push_back(RangePart::Cached(block));
+                continue;
+            }
+            let length = entry.length;
+            let entry_end = (entry.file_number, entry.offset + entry.length);
+            match parts.back_mut() {
+                Some(RangePart::Records(_, run_length, blocks))
+                    if self.follows(run_end, &entry)? =>
+                {
+                    *run_length += length;
+                    *blocks += 1;
+                }
+                _ => parts.push_back(RangePart::Records(entry, length, 1)),
+            }
+            run_end = entry_end;
+        }
+        Ok(parts)
+    }
+
+    /// The serialized blocks at heights `start..=end`, one slice per block, without copying
+    /// them: records are served straight from memory mapped block data files. Blocks in the
+    /// file still being appended to are read into memory instead, so no mapping ever covers
+    /// bytes a write may cut off again, and so are blocks in compressed files, decompressed.
+    /// Every record is checked in place (frame, checksum and blockhash) before it is handed
+    /// out. Only plaintext stores can be served this way, and blocks are served as stored,
+    /// tweak metadata included.
+    #[cfg(feature = "mmap")]
+    pub fn get_block_range_mmap(
+        &self,
+        start: u32,
+        end: u32,
+    ) -> Result<Vec<MappedSlice<'_>>, StorageError> {
+        if self.encryption_key.is_some() {
+            return Err(StorageError::EncryptionError(
+                "memory mapped reads need a plaintext store",
+            ));
+        }
+        let tip = self.index.get_current_height();
+        if start > end || tip < 0 || end > tip as u32 {
+            return Err(StorageError::InvalidHeight);
+        }
+        if start < self.pruned_up_to {
+            return Err(StorageError::Pruned);
+        }
+        let (current_file, _) = self.flushed_end()?;
+
+        let mut maps: HashMap<u64, Arc<Mmap>> = HashMap::new();
+        let mut slices = Vec::with_capacity((end - start) as usize + 1);
+        for height in start..=end {
+            let blockhash = self.index.get_blockhash_by_height(height)?;
+            let entry = self.index.get_block_entry(&blockhash)?;
+            if self.is_compressed(entry.file_number) {
+                let block = self
+                    .block_from_record(&blockhash, &entry, self.read_entry(&entry))?
+                    .serialize();
+                slices.push(MappedSlice {
+                    range: 0..block.len(),
+                    bytes: MappedBytes::Read(block),
+                    _store: PhantomData,
+                });
+                continue;
+            }
+            let bytes = if entry.file_number >= current_file {
+                let record = self.read_entry(&entry).map_err(|e| {
+                    self.fault_error(&blockhash, &entry, RecordFault::read_failed(&entry, e))
+                })?;
+                MappedBytes::Read(record)
+            } else {
+                let map = match maps.get(&entry.file_number) {
+                    Some(map) => Arc::clone(map),
+                    None => {
+                        let map = self.map_file(entry.file_number)?;
+                        maps.insert(entry.file_number, Arc::clone(&map));
+                        map
+                    }
+                };
+                MappedBytes::Mapped(map)
+            };
+
+            let offset = match &bytes {
+                MappedBytes::Mapped(_) => entry.offset as usize,
+                MappedBytes::Read(_) => 0,
+            };
+            let record = bytes
+                .get(offset..offset + entry.length as usize)
+                .ok_or_else(|| {
+                    let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+                    self.fault_error(&blockhash, &entry, RecordFault::read_failed(&entry, e))
+                })?;
+            let Some(serialized) = frame_payload(record) else {
+                let fault = RecordFault::frame_mismatch(&entry);
+                return Err(self.fault_error(&blockhash, &entry, fault));
+            };
+            // Checked in place, the slice is all that is copied out
+            if let Err(fault) = self.check_serialized(&blockhash, &entry, serialized) {
+                return Err(self.fault_error(&blockhash, &entry, fault));
+            }
+            slices.push(MappedSlice {
+                range: offset + FRAME_HEADER_SIZE..offset + entry.length as usize,
+                bytes,
+                _store: PhantomData,
+            });
+        }
+        Ok(slices)
+    }
+
+    /// Maps block data file `file_number`, or hands out the mapping it already has. The
+    /// mappings are remembered while they are in use, see `is_mapped`.
+    #[cfg(feature = "mmap")]
+    fn map_file(&self, file_number: u64) -> Result<Arc<Mmap>, StorageError> {
+        let mut mapped = self
+            .mapped_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(map) = mapped.get(&file_number).and_then(Weak::upgrade) {
+            return Ok(map);
+        }
+        mapped.retain(|_, map| map.strong_count() > 0);
+
+        let file = File::open(self.block_data_dir.join(block_file_name!(file_number)))?;
+        // SAFETY: a mapped file must not be cut short while the mapping is in use. Only files
+        // older than the current one are mapped, which are never written to again, and
+        // reclaim_record leaves files alone while a mapping of them is alive. Pruning and
+        // rekeying need `&mut self`, so no MappedSlice is around then.
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        mapped.insert(file_number, Arc::downgrade(&map));
+        Ok(map)
+    }
+
+    /// Whether a MappedSlice still points into block data file `file_number`.
+    #[cfg(feature = "mmap")]
+    fn is_mapped(&self, file_number: u64) -> bool {
+        self.mapped_files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&file_number)
+            .is_some_and(|map| map.strong_count() > 0)
+    }
+
+    /// Whether the record `entry` comes right after `end` (file number, offset) in a stream,
+    /// in the same file or at the start of the next one.
+    fn follows(&self, (file_number, offset): (u64, u64), entry: &IndexEntry) -> io::Result<bool> {
+        if entry.file_number == file_number {
+            return Ok(entry.offset == offset);
+        }
+        if entry.file_number != file_number + 1 || entry.offset != self.header_len() {
+            return Ok(false);
+        }
+        let file_path = self.block_data_dir.join(block_file_name!(file_number));
+        Ok(fs::metadata(file_path)?.len() == offset)
+    }
+
+    fn block_data_reader(
+        &self,
+        entry: &IndexEntry,
+        limit: Option<u64>,
+    ) -> Result<BlockDataReader<'_>, StorageError> {
+        let file_path = self
+            .block_data_dir
+            .join(&block_file_name!(entry.file_number));
+        let end = self.flushed_end()?;
+        let file = File::open(&file_path)?;
+        let reader = BufReader::new(file);
+
+        // Create a BlockDataReader that will handle reading across file boundaries if needed
+        Ok(BlockDataReader {
+            store: self,
+            current_file_number: entry.file_number,
+            reader,
+            current_position: entry.offset,
+            limit,
+            end,
+            block: Vec::new(),
+            block_position: 0,
+            records_loaded: 0,
+            tweaks_only: false,
+        })
+    }
+
+    /// Reads back the block at `height` on the current chain.
+    pub fn get_block(&self, height: u32) -> Result<BlockData, StorageError> {
+        if height < self.pruned_up_to {
+            return Err(StorageError::Pruned);
+        }
+        let blockhash = self.index.get_blockhash_by_height(height)?;
+        self.get_block_by_hash(&blockhash)
+    }
+
+    /// Reads back the dust tier `dust_threshold` of the block at `height` on the current chain,
+    /// or the block with all its tweaks for None. `EntryNotFound` if the block wasn't stored
+    /// with that tier.
+    pub fn get_block_with_tier(
+        &self,
+        height: u32,
+        dust_threshold: Option<u64>,
+    ) -> Result<BlockData, StorageError> {
+        let Some(dust_threshold) = dust_threshold else {
+            return self.get_block(height);
+        };
+        if height < self.pruned_up_to {
+            return Err(StorageError::Pruned);
+        }
+        let blockhash = self.index.get_blockhash_by_height(height)?;
+        let entry = self.index.get_tier_entry(&blockhash, dust_threshold)?;
+        self.state().flush()?;
+        self.check_record(
+            &blockhash,
+            &entry,
+            &[TIER_RECORD_MAGIC],
+            self.read_entry(&entry),
+        )
+        .map_err(|fault| fault.report(&self.integrity))
+    }
+
+    /// Reads back a stored block. Returns `OrphanedEntry` for a block removed by a reorg,
+    /// `Pruned` for one whose file was pruned and `EntryNotFound` for one that was never stored.
+    pub fn get_block_by_hash(&self, blockhash: &[u8; 32]) -> Result<BlockData, StorageError> {
+        let entry = self.block_entry(blockhash)?;
+        if let Some(block) = self.cache.get(blockhash) {
+            return BlockData::deserialize_limited(&block, self.max_record_size);
+        }
+        self.state().flush()?;
+        let block = self.block_from_record(blockhash, &entry, self.read_entry(&entry))?;
+        if self.cache.is_enabled() {
+            self.cache.insert(*blockhash, block.serialize().into());
+        }
+        Ok(block)
+    }
+
+    /// Walks the blocks of the current chain in height order, from the lowest height that
+    /// wasn't pruned up to the tip at the time of the call, as (height, block). Blocks orphaned
+    /// by a reorg aren't visited. Records are read one
+    /// at a time, through one open file at a time. The iteration ends after the first error.
+    pub fn iter_blocks(&self) -> impl Iterator<Item = Result<(u32, BlockData), StorageError>> + '_ {
+        BlockIter {
+            store: self,
+            next_height: self.pruned_up_to,
+            end: (self.index.get_current_height() + 1) as u32,
+            records: RecordReader::new(self),
+        }
+    }
+
+    /// Decodes the `record` read for `entry`, expected to hold `blockhash`, and reports whatever
+    /// doesn't match the index.
+    fn block_from_record(
+        &self,
+        blockhash: &[u8; 32],
+        entry: &IndexEntry,
+        record: io::Result<Vec<u8>>,
+    ) -> Result<BlockData, StorageError> {
+        self.check_record(blockhash, entry, &BLOCK_RECORD_MAGICS, record)
+            .map_err(|fault| self.fault_error(blockhash, entry, fault))
+    }
+
+    /// Reports `fault` found in the record of `entry`, unless the index moved on since `entry`
+    /// was looked up.
+    fn fault_error(
+        &self,
+        blockhash: &[u8; 32],
+        entry: &IndexEntry,
+        fault: RecordFault,
+    ) -> StorageError {
+        match self.index.get_block_entry(blockhash) {
+            Ok(current) if current == *entry => fault.report(&self.integrity),
+            // Another thread took the block out of the index (and maybe its record out of the
+            // file) after `entry` was looked up, that isn't corruption
+            Ok(_) => StorageError::EntryNotFound,
+            Err(e) => e,
+        }
+    }
+
+    /// Decodes the `record` read for `entry`, expected to hold `blockhash` framed with one of `magics`.
+    /// Whatever doesn't match the index comes back as a RecordFault, not reported yet.
+    fn check_record(
+        &self,
+        blockhash: &[u8; 32],
+        entry: &IndexEntry,
+        magics: &[[u8; 4]],
+        record: io::Result<Vec<u8>>,
+    ) -> Result<BlockData, RecordFault> {
+        self.check_record_with(blockhash, entry, magics, record, |block| {
+            block.to_block_data()
+        })
+    }
+
+    /// Same as `check_record`, handing the block to `read` in place rather than copying it
+    /// out.
+    fn check_record_with<T>(
+        &self,
+        blockhash: &[u8; 32],
+        entry: &IndexEntry,
+        magics: &[[u8; 4]],
+        record: io::Result<Vec<u8>>,
+        read: impl FnOnce(BlockDataRef<'_>) -> T,
+    ) -> Result<T, RecordFault> {
+        let record = record.map_err(|e| RecordFault::read_failed(entry, e))?;
+        // An entry whose length disagrees with the frame it points at is cut off from the
+        // records the index expects to be there, and so is one pointing at the other kind
+        let payload = frame_payload(&record).filter(|_| magics.contains(&frame_magic(&record)));
+        let Some(payload) = payload else {
+            return Err(RecordFault::frame_mismatch(entry));
+        };
+        let serialized = self
+            .serialized_payload(entry.file_number, entry.offset, payload)
+            .map_err(|e| RecordFault::unreadable(entry, e))?;
+        Ok(read(self.check_serialized(
+            blockhash,
+            entry,
+            &serialized,
+        )?))
+    }
+
+    /// Parses the serialized block of the record of `entry`, expected to be `blockhash`.
+    fn check_serialized<'s>(
+        &self,
+        blockhash: &[u8; 32],
+        entry: &IndexEntry,
+        serialized: &'s [u8],
+    ) -> Result<BlockDataRef<'s>, RecordFault> {
+        let block = BlockDataRef::parse_limited(serialized, self.max_record_size)
+            .map_err(|e| RecordFault::unreadable(entry, e))?;
+        if block.blockhash() != blockhash {
+            return Err(RecordFault::other_block(entry));
+        }
+        Ok(block)
+    }
+
+    /// Tweaks in the record of `entry`, expected to hold `blockhash`. `file` keeps the block
+    /// data file last opened, for counting the blocks of a range.
+    fn count_record_tweaks(
+        &self,
+        blockhash: &[u8; 32],
+        entry: &IndexEntry,
+        file: &mut Option<(u64, File)>,
+    ) -> Result<u32, StorageError> {
+        // Encrypted and compressed records have no plain header to peek at
+        let counted = match self.encryption_key.is_some() || self.is_compressed(entry.file_number) {
+            true => self.check_record_with(
+                blockhash,
+                entry,
+                &BLOCK_RECORD_MAGICS,
+                self.read_entry(entry),
+                |block| block.tweak_count() as u32,
+            ),
+            false => self.peek_tweak_count(blockhash, entry, file),
+        };
+        counted.map_err(|fault| self.fault_error(blockhash, entry, fault))
+    }
+
+    /// Reads the tweak count from the frame and record headers of `entry`, checking them
+    /// against the entry but leaving the tweaks (and so the checksum) alone.
+    fn peek_tweak_count(
+        &self,
+        blockhash: &[u8; 32],
+        entry: &IndexEntry,
+        file: &mut Option<(u64, File)>,
+    ) -> Result<u32, RecordFault> {
+        let mut headers = [0u8; FRAME_HEADER_SIZE + RECORD_HEADER_SIZE];
+        let mut read = || -> io::Result<()> {
+            if !matches!(file, Some((file_number, _)) if *file_number == entry.file_number) {
+                let file_path = self
+                    .block_data_dir
+                    .join(block_file_name!(entry.file_number));
+                *file = Some((entry.file_number, File::open(file_path)?));
+            }
+            let (_, file) = file.as_mut().unwrap();
+            file.seek(SeekFrom::Start(entry.offset))?;
+            file.read_exact(&mut headers)
+        };
+        read().map_err(|e| RecordFault::read_failed(entry, e))?;
+
+        let (frame_header, header) = headers.split_at(FRAME_HEADER_SIZE);
+        let framed_len = frame_payload_len(frame_header.try_into().unwrap())
+            .map(|payload_len| FRAME_HEADER_SIZE as u64 + payload_len);
+        if !BLOCK_RECORD_MAGICS.contains(&frame_magic(frame_header))
+            || framed_len != Some(entry.length)
+        {
+            return Err(RecordFault::frame_mismatch(entry));
+        }
+        let header: &[u8; RECORD_HEADER_SIZE] = header.try_into().unwrap();
+        // A count that doesn't add up to the record is a damaged header, the checksum would
+        // have caught it
+        if (FRAME_HEADER_SIZE + BlockData::serialized_len_from_header(header)) as u64
+            != entry.length
+        {
+            let len_tweaks = u32::from_le_bytes(header[32..36].try_into().unwrap());
+            return Err(RecordFault::unreadable(
+                entry,
+                StorageError::InvalidTweakCount(len_tweaks),
+            ));
+        }
+        if header[..32] != blockhash[..] {
+            return Err(RecordFault::other_block(entry));
+        }
+        Ok(BlockData::tweak_count_from_header(header))
+    }
+
+    /// Checks the whole store: every block of the chain against its record (frame, checksum
+    /// and blockhash), and the block data files against the index, for entries off the chain
+    /// and bytes nothing references. Problems with blocks of the chain are reported to the
+    /// integrity guard like on any read, but nothing is repaired.
+    /// Records are read one at a time in height order. Only their locations are kept, to be
+    /// sorted into file order for finding the dead space, as blocks whose tweaks were
+    /// replaced live out of height order.
+    pub fn verify(&self) -> Result<VerifyReport, StorageError> {
+        let data_end = self.flushed_end()?;
+        let last_file = data_end.0;
+        let mut report = VerifyReport {
+            files_checked: last_file - self.first_file_number + 1,
+            ..Default::default()
+        };
+
+        // Entries off the chain still reference their bytes
+        let mut referenced = Vec::new();
+        for (blockhash, entry) in self.index.off_chain_entries()? {
+            report.orphaned_entries.push(blockhash);
+            referenced.extend(entry);
+        }
+        referenced.extend(
+            self.index
+                .quarantined_blocks()?
+                .into_iter()
+                .filter_map(|block| block.entry),
+        );
+        // So do the dust tiers, which are read back through their block
+        for (key, entry) in self.index.located_entries()? {
+            if let EntryKey::Tier(_) = key {
+                report.referenced_bytes += entry.length;
+                referenced.push(entry);
+            }
+        }
+
+        let mut records = RecordReader::new(self);
+        let end = (self.index.get_current_height() + 1) as u32;
+        for height in self.pruned_up_to..end {
+            report.blocks_checked += 1;
+            if report
+                .blocks_checked
+                .is_multiple_of(VERIFY_PROGRESS_INTERVAL)
+            {
+                info!(target: "FileStore", "Verifying: {} of {} blocks checked", report.blocks_checked, end);
+            }
+            let located = self
+                .index
+                .get_blockhash_by_height(height)
+                .and_then(|blockhash| Ok((blockhash, self.index.get_block_entry(&blockhash)?)));
+            let (blockhash, entry) = match located {
+                Ok(located) => located,
+                Err(e) => {
+                    warn!(target: "FileStore", "Block at height {} has no usable index entry: {}", height, e);
+                    report.unreadable_heights.push(height);
+                    continue;
+                }
+            };
+
+            report.referenced_bytes += entry.length;
+
+            let record = records.read(&entry);
+            let checked =
+                self.check_record_with(&blockhash, &entry, &BLOCK_RECORD_MAGICS, record, |block| {
+                    block.is_canonical()
+                });
+            match checked {
+                Ok(canonical) => {
+                    if !canonical {
+                        report.non_canonical_heights.push(height);
+                    }
+                }
+                Err(fault) => {
+                    match fault {
+                        RecordFault::Unreadable(..) => report.unreadable_heights.push(height),
+                        RecordFault::Mismatch(..) => report.mismatched_heights.push(height),
+                    }
+                    let e = fault.report(&self.integrity);
+                    warn!(target: "FileStore", "Block at height {} (file {}, offset {}) failed verification: {}",
+                          height, entry.file_number, entry.offset, e);
+                }
+            }
+            referenced.push(entry);
+        }
+        referenced.sort_by_key(|entry| (entry.file_number, entry.offset));
+        let mut coverage = Coverage::new(self.first_file_number, self.header_len(), data_end);
+        for entry in &referenced {
+            coverage.cover(self, entry, &mut report.dead_space);
+        }
+        coverage.finish_files_before(self, last_file + 1, &mut report.dead_space);
+        report.dead_bytes = report.dead_space.iter().map(|dead| dead.length).sum();
+
+        info!(target: "FileStore", "Verified {} blocks in {} files: {} mismatched, {} unreadable, {} entries off the chain, {} dead bytes",
+              report.blocks_checked, report.files_checked, report.mismatched_heights.len(),
+              report.unreadable_heights.len(), report.orphaned_entries.len(), report.dead_bytes);
+        Ok(report)
+    }
+
+    /// Streams exactly one block.
+    pub fn get_block_stream<'a>(
+        &'a self,
+        blockhash: &[u8; 32],
+    ) -> Result<impl Read + 'a, StorageError> {
+        let entry = self.block_entry(blockhash)?;
+        self.get_block_stream_from_offset(&entry, Some(entry.length))
+    }
+
+    /// Just for testing. Streams from the block at `height` to the end of the store.
+    pub(crate) fn get_block_stream_from_height<'a>(
+        &'a self,
+        height: u32,
+    ) -> Result<impl Read + 'a, StorageError> {
+        let blockhash = self.index.get_blockhash_by_height(height)?;
+        let entry = self.index.get_block_entry(&blockhash)?;
+        self.get_block_stream_from_offset(&entry, None)
+    }
+
+    fn get_block_stream_from_genesis<'a>(&'a self) -> Result<impl Read + 'a, StorageError> {
+        self.get_block_stream_from_height(0)
+    }
+
+    /// Re-encrypts every block data file under `new_key`.
+    /// Records keep their offsets, so the index is left untouched. Every file is rewritten
+    /// into a temporary file first, and they are only swapped in once all of them succeeded.
+    pub fn rekey(&mut self, new_key: EncryptionKey) -> Result<(), StorageError> {
+        self.integrity.check_writable()?;
+        let old_key = self
+            .encryption_key
+            .clone()
+            .ok_or(StorageError::EncryptionError("store is not encrypted"))?;
+        let last_file = self.write_rekeyed_copies(&old_key, &new_key)?;
+
+        // From here on the copies are the files, whether or not they are all swapped in right
+        // away: an interrupted swap is finished on the next open, which needs the new key
+        let files = [
+            self.first_file_number.to_le_bytes(),
+            last_file.to_le_bytes(),
+        ]
+        .concat();
+        self.index.set_meta(REKEY_META_KEY, &files)?;
+        finish_rekey(&self.index, &self.block_data_dir)?;
+        self.encryption_key = Some(new_key);
+        self.prepare_current_file()?;
+        info!(target: "FileStore", "Re-encrypted {} block data files", last_file - self.first_file_number + 1);
+        Ok(())
+    }
+
+    /// Re-encrypts every block data file into a synced `.rekey` copy next to it, and returns
+    /// the number of the last one.
+    fn write_rekeyed_copies(
+        &mut self,
+        old_key: &EncryptionKey,
+        new_key: &EncryptionKey,
+    ) -> Result<u64, StorageError> {
+        // The files are about to be replaced, which fails on Windows while we hold them open
+        let state = self.state_mut();
+        state.close_writer()?;
+        // Records are read up to the end of the file, not into preallocated space
+        let data_end = state.write_offset;
+        let last_file = state.current_file_number;
+        File::options()
+            .write(true)
+            .open(self.block_data_dir.join(block_file_name!(last_file)))?
+            .set_len(data_end)?;
+
+        for file_number in self.first_file_number..=last_file {
+            let file_path = self.block_data_dir.join(block_file_name!(file_number));
+            let tmp_path = file_path.with_extension(REKEY_EXTENSION);
+            info!(target: "FileStore", "Re-encrypting block data file: {}", file_path.display());
+
+            let mut reader = BufReader::new(File::open(&file_path)?);
+            reader.seek(SeekFrom::Start(ENCRYPTED_HEADER_SIZE as u64))?;
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writer.write_all(&new_key.file_header(self.file_format_version(file_number)))?;
+
+            // Encrypted records keep their length, so their frames stay the same
+            let mut offset = ENCRYPTED_HEADER_SIZE as u64;
+            while let Some(record) = read_frame(&mut reader)? {
+                let plaintext =
+                    old_key.decrypt_record(file_number, offset, &record[FRAME_HEADER_SIZE..])?;
+                writer.write_all(&frame_record_as(
+                    frame_magic(&record),
+                    &new_key.encrypt_record(file_number, offset, &plaintext),
+                ))?;
+                offset += record.len() as u64;
+            }
+
+            writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+        }
+        Ok(last_file)
+    }
+
+    /// Writes the whole chain to `writer` as a snapshot a new server can be seeded from, see
+    /// `import_snapshot` and SNAPSHOT_MAGIC for the layout. Blocks are written decrypted and
+    /// unframed, so a snapshot doesn't depend on how the store keeps its files. A pruned store
+    /// is no longer complete and can't be exported. Returns the number of blocks written.
+    pub fn export_snapshot(&self, writer: impl Write) -> Result<u32, StorageError> {
+        if self.pruned_up_to > 0 {
+            return Err(StorageError::Pruned);
+        }
+        let network = self.index.get_meta(NETWORK_META_KEY)?.unwrap_or_default();
+        let network_len = u8::try_from(network.len())
+            .map_err(|_| StorageError::CorruptDB("network metadata is too long"))?;
+        let count = (self.index.get_current_height() + 1) as u32;
+
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(&SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&[network_len])?;
+        writer.write_all(&network)?;
+        writer.write_all(&count.to_le_bytes())?;
+        for item in self.iter_blocks() {
+            let (height, block) = item?;
+            let serialized = block.serialize();
+            writer.write_all(&(serialized.len() as u32).to_le_bytes())?;
+            writer.write_all(&serialized)?;
+            if (height + 1).is_multiple_of(SNAPSHOT_PROGRESS_INTERVAL) {
+                info!(target: "FileStore", "Exporting snapshot: {} of {} blocks written", height + 1, count);
+            }
+        }
+        writer.flush()?;
+
+        info!(target: "FileStore", "Exported a snapshot of {} blocks", count);
+        Ok(count)
+    }
+
+    /// Creates a store in `data_dir` from a snapshot written by `export_snapshot`.
+    pub fn import_snapshot(data_dir: PathBuf, reader: impl Read) -> Result<Self, StorageError> {
+        Self::import_snapshot_with_options(data_dir, reader, FlatFileStoreOptions::default())
+    }
+
+    /// Creates a store in `data_dir`, opened with `options`, from a snapshot written by
+    /// `export_snapshot`. The directory has to be empty or not exist yet. Every block is checked
+    /// against its checksum, and the import only succeeds if it ends at the tip the snapshot
+    /// was taken at. A failed import leaves the directory empty again.
+    pub fn import_snapshot_with_options(
+        data_dir: PathBuf,
+        reader: impl Read,
+        options: FlatFileStoreOptions,
+    ) -> Result<Self, StorageError> {
+        let existed = data_dir.exists();
+        if existed && fs::read_dir(&data_dir)?.next().is_some() {
+            return Err(StorageError::InvalidData(
+                "Snapshots can only be imported into an empty data directory",
+            ));
+        }
+
+        let result = Self::initialize_with_options(data_dir.clone(), options).and_then(|store| {
+            store.import_blocks(reader)?;
+            Ok(store)
+        });
+        if let Err(e) = &result {
+            warn!(target: "FileStore", "Snapshot import failed, removing what was imported: {}", e);
+            // The directory was empty, everything in it is from the import
+            fs::remove_dir_all(&data_dir)?;
+            if existed {
+                fs::create_dir(&data_dir)?;
+            }
+        }
+        result
+    }
+
+    /// Reads a snapshot into this new, empty store.
+    fn import_blocks(&self, reader: impl Read) -> Result<(), StorageError> {
+        let mut reader = BufReader::new(reader);
+        let mut prefix = [0u8; 7];
+        reader.read_exact(&mut prefix)?;
+        if prefix[..4] != SNAPSHOT_MAGIC {
+            return Err(StorageError::InvalidData("Not a snapshot"));
+        }
+        let record_version = match u16::from_le_bytes([prefix[4], prefix[5]]) {
+            SNAPSHOT_VERSION => RECORD_VERSION,
+            TWEAKS_CRC_SNAPSHOT_VERSION => TWEAKS_CRC_RECORD_VERSION,
+            _ => return Err(StorageError::InvalidData("Unsupported snapshot version")),
+        };
+        let mut network = vec![0u8; prefix[6] as usize];
+        reader.read_exact(&mut network)?;
+        let mut count = [0u8; 4];
+        reader.read_exact(&mut count)?;
+        let count = u32::from_le_bytes(count);
+        if !network.is_empty() {
+            // The store was opened for a network, which the snapshot has to be of
+            if let Some(stored) = self.index.get_meta(NETWORK_META_KEY)? {
+                if stored != network {
+                    return Err(StorageError::NetworkMismatch {
+                        stored: String::from_utf8_lossy(&network).into_owned(),
+                        requested: String::from_utf8_lossy(&stored).into_owned(),
+                    });
+                }
+            }
+            self.index.set_meta(NETWORK_META_KEY, &network)?;
+        }
+        info!(target: "FileStore", "Importing a snapshot of {} blocks", count);
+
+        let mut blocks = Vec::with_capacity(SNAPSHOT_IMPORT_BATCH);
+        let mut heights = Vec::with_capacity(SNAPSHOT_IMPORT_BATCH);
+        for height in 0..count {
+            let mut length = [0u8; 4];
+            reader.read_exact(&mut length)?;
+            let length = u32::from_le_bytes(length) as usize;
+            if length > self.max_record_size {
+                return Err(StorageError::RecordTooLarge {
+                    size: length,
+                    max: self.max_record_size,
+                });
+            }
+            let mut serialized = vec![0u8; length];
+            reader.read_exact(&mut serialized)?;
+            let block =
+                BlockData::deserialize_version(&serialized, record_version, self.max_record_size)?;
+            let header: &[u8; RECORD_HEADER_SIZE] =
+                serialized[..RECORD_HEADER_SIZE].try_into().unwrap();
+            if BlockData::serialized_len_from_header(header) != length {
+                return Err(StorageError::DeserializeError(
+                    "snapshot record length does not match the block",
+                ));
+            }
+            blocks.push(block);
+            heights.push(height);
+
+            if blocks.len() == SNAPSHOT_IMPORT_BATCH || height + 1 == count {
+                self.add_block_bulk(&blocks, &heights).into_result()?;
+                blocks.clear();
+                heights.clear();
+            }
+            if (height + 1).is_multiple_of(SNAPSHOT_PROGRESS_INTERVAL) {
+                info!(target: "FileStore", "Importing snapshot: {} of {} blocks stored", height + 1, count);
+            }
+        }
+
+        if reader.read(&mut [0u8; 1])? != 0 {
+            return Err(StorageError::InvalidData(
+                "Snapshot continues past its last block",
+            ));
+        }
+        if self.index.get_current_height() != count as i32 - 1 {
+            return Err(StorageError::InvalidData(
+                "Snapshot import did not end at the snapshot's tip",
+            ));
+        }
+        self.flush()?;
+        info!(target: "FileStore", "Imported a snapshot of {} blocks", count);
+        Ok(())
+    }
+}
+
+impl Drop for FlatFileStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.state_mut().close_writer() {
+            warn!(target: "FileStore", "Failed to flush block data on close: {}", e);
+        }
+    }
+}
+
+/// Whether a store has been created in `data_dir`.
+pub fn store_exists(data_dir: &Path) -> bool {
+    data_dir
+        .join(BLOCK_DATA_DIR_NAME)
+        .join(block_file_name!(0))
+        .exists()
+        || data_dir.join(INDEX_DIR_NAME).exists()
+}
+
+fn new_file_header(encryption_key: Option<&EncryptionKey>, version: u16) -> Vec<u8> {
+    match encryption_key {
+        Some(key) => key.file_header(version),
+        None => file_header_prefix(MAGIC_BYTES, version).to_vec(),
+    }
+}
+
+/// Format version of the files a store with `compression_level` writes.
+fn written_format_version(compression_level: Option<i32>) -> u16 {
+    match compression_level {
+        Some(_) => COMPRESSED_FORMAT_VERSION,
+        None => FRAMED_FORMAT_VERSION,
+    }
+}
+
+/// Extensions of the temporary copies block data files are rewritten into, by `rekey` and the
+/// migrations. The original stays in place until the copy is complete, so copies left behind
+/// by an interruption can be ignored.
+/// A compacted copy is the exception, once the index points into it, see `finish_compaction`.
+const REWRITE_EXTENSIONS: [&str; 5] = [
+    "rekey",
+    "migrate",
+    "frame",
+    "checksum",
+    COMPACTION_EXTENSION,
+];
+
+/// Numbers of the block data files in `block_data_dir`, in order. Files whose name starts
+/// like a block data file's but isn't one (`sps_backup.dat.old`) are refused, as something
+/// went wrong with the directory; anything else is left alone.
+fn find_block_files(block_data_dir: &Path) -> Result<Vec<u64>, StorageError> {
+    let mut file_numbers = Vec::new();
+    for file in fs::read_dir(block_data_dir)? {
+        let file = file?;
+        let name = file.file_name();
+        let name = name.to_string_lossy();
+        if !file.file_type()?.is_file() || !name.starts_with("sps") {
+            continue;
+        }
+        let (stem, extension) = name.split_once('.').unwrap_or((&name, ""));
+        let file_number = stem[3..]
+            .parse::<u64>()
+            .ok()
+            .filter(|&file_number| stem == format!("sps{:06}", file_number));
+        match (file_number, extension) {
+            (Some(file_number), "dat") => file_numbers.push(file_number),
+            (Some(_), extension) if REWRITE_EXTENSIONS.contains(&extension) => {
+                debug!(target: "FileStore", "Ignoring {}, left by an interrupted rewrite", name);
+            }
+            _ => {
+                warn!(target: "FileStore", "Unexpected file in the block data directory: {}", name);
+                return Err(StorageError::CorruptDB(
+                    "Unexpected file named like a block data file",
+                ));
+            }
+        }
+    }
+    file_numbers.sort_unstable();
+    Ok(file_numbers)
+}
+
+/// Swaps in the copy of the block data file `compact` was rewriting, if the index already
+/// points into it. Returns the number of the file, if there was a compaction to finish.
+fn finish_compaction(index: &Index, block_data_dir: &Path) -> Result<Option<u64>, StorageError> {
+    let Some(value) = index.get_meta(COMPACTION_META_KEY)? else {
+        return Ok(None);
+    };
+    let file_number = u64::from_le_bytes(
+        value
+            .try_into()
+            .map_err(|_| StorageError::CorruptDB("Invalid compaction metadata"))?,
+    );
+    let file_path = block_data_dir.join(block_file_name!(file_number));
+    let tmp_path = file_path.with_extension(COMPACTION_EXTENSION);
+    // Gone if the swap happened, but the metadata wasn't removed yet
+    if tmp_path.exists() {
+        platform::replace_file(&tmp_path, &file_path)?;
+    }
+    index.clear_dead_bytes(&[file_number])?;
+    index.remove_meta(COMPACTION_META_KEY)?;
+    Ok(Some(file_number))
+}
+
+/// Swaps in the re-encrypted copies of an interrupted `rekey`, those that weren't already.
+/// Returns how many were left to swap, None if no rekey was under way.
+fn finish_rekey(index: &Index, block_data_dir: &Path) -> Result<Option<u64>, StorageError> {
+    let Some(value) = index.get_meta(REKEY_META_KEY)? else {
+        return Ok(None);
+    };
+    if value.len() != 16 {
+        return Err(StorageError::CorruptDB("Invalid rekey metadata"));
+    }
+    let first_file = u64::from_le_bytes(value[..8].try_into().unwrap());
+    let last_file = u64::from_le_bytes(value[8..].try_into().unwrap());
+    let mut swapped = 0;
+    for file_number in first_file..=last_file {
+        let file_path = block_data_dir.join(block_file_name!(file_number));
+        let tmp_path = file_path.with_extension(REKEY_EXTENSION);
+        // Gone once swapped in
+        if tmp_path.exists() {
+            platform::replace_file(&tmp_path, &file_path)?;
+            swapped += 1;
+        }
+    }
+    index.remove_meta(REKEY_META_KEY)?;
+    Ok(Some(swapped))
+}
+
+/// Refuses a store recorded for another network than `requested`. A store that doesn't record
+/// its network yet (a new one, or one from before it was recorded) is taken to hold
+/// `requested` from now on.
+fn check_network(index: &Index, requested: Option<&str>) -> Result<(), StorageError> {
+    let Some(requested) = requested else {
+        return Ok(());
+    };
+    match index.get_meta(NETWORK_META_KEY)? {
+        Some(stored) if stored != requested.as_bytes() => Err(StorageError::NetworkMismatch {
+            stored: String::from_utf8_lossy(&stored).into_owned(),
+            requested: requested.to_string(),
+        }),
+        Some(_) => Ok(()),
+        None => {
+            info!(target: "FileStore", "Recording that the store holds {} data", requested);
+            index.set_meta(NETWORK_META_KEY, requested.as_bytes())
+        }
+    }
+}
+
+/// The first kept block data file and height of a pruned store, (0, 0) if it never was.
+fn read_prune_state(index: &Index) -> Result<(u64, u32), StorageError> {
+    match index.get_meta(PRUNED_META_KEY)? {
+        None => Ok((0, 0)),
+        Some(value) if value.len() == 12 => Ok((
+            u64::from_le_bytes(value[..8].try_into().unwrap()),
+            u32::from_le_bytes(value[8..].try_into().unwrap()),
+        )),
+        Some(_) => Err(StorageError::CorruptDB("pruned metadata is not 12 bytes")),
+    }
+}
+
+/// Magic, format version and reserved bytes, the start of every file header.
+pub(crate) fn file_header_prefix(magic: [u8; 4], version: u16) -> [u8; HEADER_PREFIX_SIZE] {
+    let mut prefix = [0u8; HEADER_PREFIX_SIZE];
+    prefix[..4].copy_from_slice(&magic);
+    prefix[4..6].copy_from_slice(&version.to_le_bytes());
+    prefix
+}
+
+/// Reads whether a block data file is encrypted, and its format version, off the start of
+/// its header.
+fn parse_header_prefix(prefix: &[u8; HEADER_PREFIX_SIZE]) -> Result<(bool, u16), StorageError> {
+    let version = u16::from_le_bytes([prefix[4], prefix[5]]);
+    if *prefix == LEGACY_MAGIC_BYTES {
+        Ok((false, 1))
+    } else if *prefix == LEGACY_ENCRYPTED_MAGIC_BYTES {
+        Ok((true, 1))
+    } else if prefix[..4] == MAGIC_BYTES {
+        Ok((false, version))
+    } else if prefix[..4] == ENCRYPTED_MAGIC_BYTES {
+        Ok((true, version))
+    } else {
+        Err(StorageError::CorruptDB("Unknown block data file magic"))
+    }
+}
+
+/// Brings the headers of all version 1 block data files in `block_data_dir` to version 2,
+/// see `migrate_block_data_file`. Returns how many files were rewritten.
+pub fn migrate_block_data_files(block_data_dir: &Path) -> Result<usize, StorageError> {
+    let mut migrated = 0;
+    let mut file_number = 0;
+    loop {
+        let file_path = block_data_dir.join(block_file_name!(file_number));
+        if !file_path.exists() {
+            return Ok(migrated);
+        }
+        if migrate_block_data_file(&file_path)? {
+            migrated += 1;
+        }
+        file_number += 1;
+    }
+}
+
+/// Rewrites a version 1 file with a version 2 header, through a temporary file swapped in
+/// once complete. The header keeps its length, so index entries stay valid, and the key check
+/// of an encrypted file stays valid too, so no key is needed. A file that is already past
+/// version 1 is left alone, which makes it safe to run again. Returns whether the file was
+/// rewritten.
+fn migrate_block_data_file(file_path: &Path) -> Result<bool, StorageError> {
+    let mut reader = BufReader::new(File::open(file_path)?);
+    let mut prefix = [0u8; HEADER_PREFIX_SIZE];
+    reader.read_exact(&mut prefix)?;
+    let (encrypted, version) = parse_header_prefix(&prefix)?;
+    if version > FILE_FORMAT_VERSION {
+        return Err(StorageError::UnsupportedVersion(version));
+    }
+    if version != 1 {
+        return Ok(false);
+    }
+
+    let magic = if encrypted {
+        ENCRYPTED_MAGIC_BYTES
+    } else {
+        MAGIC_BYTES
+    };
+    let tmp_path = file_path.with_extension("migrate");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&file_header_prefix(magic, UNFRAMED_FORMAT_VERSION))?;
+    io::copy(&mut reader, &mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    drop(reader);
+    platform::replace_file(&tmp_path, file_path)?;
+    info!(target: "FileStore", "Upgraded block data file {} from format version 1 to {}",
+          file_path.display(), UNFRAMED_FORMAT_VERSION);
+    Ok(true)
+}
+
+/// Frames the records of every version 2 block data file in `block_data_dir` and points the
+/// index entries at where the records moved, see `frame_block_data_file`. Encrypted records
+/// are bound to their offset, so an encrypted store needs its key. Returns how many files were
+/// rewritten.
+/// Each file is swapped in after its entries moved, and FRAME_MIGRATION_META_KEY records which
+/// files' entries did, so an interrupted run picks up where it stopped when run again.
+pub fn migrate_record_frames(
+    block_data_dir: &Path,
+    index: &Index,
+    encryption_key: Option<&EncryptionKey>,
+) -> Result<usize, StorageError> {
+    let entries_moved_below = match index.get_meta(FRAME_MIGRATION_META_KEY)? {
+        Some(value) => u64::from_le_bytes(
+            value
+                .as_slice()
+                .try_into()
+                .map_err(|_| StorageError::CorruptDB("frame migration metadata is not 8 bytes"))?,
+        ),
+        None => 0,
+    };
+    let mut entries_by_file: HashMap<u64, Vec<(EntryKey, IndexEntry)>> = HashMap::new();
+    for (key, entry) in index.located_entries()? {
+        entries_by_file
+            .entry(entry.file_number)
+            .or_default()
+            .push((key, entry));
+    }
+
+    let mut migrated = 0;
+    for file_number in 0.. {
+        let file_path = block_data_dir.join(block_file_name!(file_number));
+        if !file_path.exists() {
+            break;
+        }
+        let Some(header) = read_header_to_migrate(
+            &file_path,
+            encryption_key,
+            UNFRAMED_FORMAT_VERSION..=UNFRAMED_FORMAT_VERSION,
+        )?
+        else {
+            continue;
+        };
+
+        let tmp_path = file_path.with_extension("frame");
+        if file_number >= entries_moved_below {
+            let entries = entries_by_file.remove(&file_number).unwrap_or_default();
+            let moved = frame_block_data_file(
+                file_number,
+                &file_path,
+                &tmp_path,
+                &header,
+                encryption_key,
+                entries,
+            )?;
+            index.move_entries(
+                &moved,
+                FRAME_MIGRATION_META_KEY,
+                &(file_number + 1).to_le_bytes(),
+            )?;
+        }
+        // Moved entries point into the framed file, which was complete before they moved
+        platform::replace_file(&tmp_path, &file_path)?;
+        info!(target: "FileStore", "Framed the records of block data file {}", file_path.display());
+        migrated += 1;
+    }
+    index.remove_meta(FRAME_MIGRATION_META_KEY)?;
+    Ok(migrated)
+}
+
+/// Reads the header of a block data file that a migration rewriting `versions` has to
+/// rewrite, or None if the file is past them. Checks the key of an encrypted file.
+fn read_header_to_migrate(
+    file_path: &Path,
+    encryption_key: Option<&EncryptionKey>,
+    versions: RangeInclusive<u16>,
+) -> Result<Option<Vec<u8>>, StorageError> {
+    let mut file = File::open(file_path)?;
+    let mut prefix = [0u8; HEADER_PREFIX_SIZE];
+    file.read_exact(&mut prefix)?;
+    let (encrypted, version) = parse_header_prefix(&prefix)?;
+    if version > FILE_FORMAT_VERSION {
+        return Err(StorageError::UnsupportedVersion(version));
+    }
+    if version > *versions.end() {
+        return Ok(None);
+    }
+    if version < *versions.start() {
+        return Err(StorageError::CorruptDB(
+            "block data file is older than the data directory",
+        ));
+    }
+    if !encrypted {
+        return Ok(Some(prefix.to_vec()));
+    }
+
+    let key = encryption_key.ok_or(StorageError::EncryptionError(
+        "store is encrypted, an encryption key is required",
+    ))?;
+    let mut header = vec![0u8; ENCRYPTED_HEADER_SIZE];
+    header[..HEADER_PREFIX_SIZE].copy_from_slice(&prefix);
+    file.read_exact(&mut header[HEADER_PREFIX_SIZE..])?;
+    key.check_file_header(&header)?;
+    Ok(Some(header))
+}
+
+/// Writes the framed version of a version 2 file, with `header` (read from it) at the current
+/// format version, to `tmp_path`, and returns `entries` (the file's) pointing into it.
+/// Encrypted records are re-encrypted for their new offset. Whatever the index doesn't point
+/// at is framed along (dead space stays dead space), except for a partial record at the end.
+fn frame_block_data_file(
+    file_number: u64,
+    file_path: &Path,
+    tmp_path: &Path,
+    header: &[u8],
+    encryption_key: Option<&EncryptionKey>,
+    entries: Vec<(EntryKey, IndexEntry)>,
+) -> Result<Vec<(EntryKey, IndexEntry)>, StorageError> {
+    let file_size = fs::metadata(file_path)?.len();
+    let mut reader = BufReader::new(File::open(file_path)?);
+    reader.seek(SeekFrom::Start(header.len() as u64))?;
+    let mut writer = BufWriter::new(File::create(tmp_path)?);
+    writer.write_all(&header[..4])?;
+    writer.write_all(&TWEAKS_CRC_FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&header[6..])?;
+
+    // Old (offset, length) of every record -> where its frame went
+    let mut moved_to = HashMap::new();
+    let mut offset = header.len() as u64;
+    let mut new_offset = offset;
+    while offset < file_size {
+        let remaining = file_size - offset;
+        let Some(record) = read_unframed_record(&mut reader, encryption_key.is_some(), remaining)?
+        else {
+            warn!(target: "FileStore", "Dropping a partial record of {} bytes at the end of {} (offset {})",
+                  remaining, file_path.display(), offset);
+            break;
+        };
+        let framed = match encryption_key {
+            Some(key) => {
+                let plaintext = key.decrypt_record(file_number, offset, &record)?;
+                frame_record(&key.encrypt_record(file_number, new_offset, &plaintext))
+            }
+            None => frame_record(&record),
+        };
+        writer.write_all(&framed)?;
+        moved_to.insert((offset, record.len() as u64), new_offset);
+        offset += record.len() as u64;
+        new_offset += framed.len() as u64;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+
+    let frame_overhead = FRAME_HEADER_SIZE as u64;
+    entries
+        .into_iter()
+        .map(|(key, entry)| {
+            let new_offset =
+                moved_to
+                    .get(&(entry.offset, entry.length))
+                    .ok_or(StorageError::CorruptDB(
+                        "index entry does not point at a block data record",
+                    ))?;
+            let moved = IndexEntry {
+                file_number,
+                offset: *new_offset,
+                length: entry.length + frame_overhead,
+            };
+            Ok((key, moved))
+        })
+        .collect()
+}
+
+/// Reads a record of a version 2 file at the reader's position, or None if the `remaining`
+/// bytes of the file hold less than the whole record.
+fn read_unframed_record(
+    reader: &mut impl Read,
+    encrypted: bool,
+    remaining: u64,
+) -> Result<Option<Vec<u8>>, StorageError> {
+    let header_len = if encrypted { 4 } else { RECORD_HEADER_SIZE };
+    if remaining < header_len as u64 {
+        return Ok(None);
+    }
+    let mut record = vec![0u8; header_len];
+    reader.read_exact(&mut record)?;
+    let record_len = if encrypted {
+        encrypted_record_len(&record)?
+    } else {
+        BlockData::serialized_len_from_header(record[..].try_into().unwrap())
+    };
+    if remaining < record_len as u64 {
+        return Ok(None);
+    }
+    record.resize(record_len, 0);
+    reader.read_exact(&mut record[header_len..])?;
+    Ok(Some(record))
+}
+
+/// Checksums every record of the version 3 and 4 block data files in `block_data_dir` again
+/// for RECORD_VERSION, see `checksum_block_data_file`, and points the index entries at where
+/// the records went. Like `migrate_record_frames`, files are swapped in after their entries
+/// moved and CHECKSUM_MIGRATION_META_KEY keeps track, so an interrupted run can be repeated.
+/// Returns how many files were rewritten.
+pub fn migrate_record_checksums(
+    block_data_dir: &Path,
+    index: &Index,
+    encryption_key: Option<&EncryptionKey>,
+) -> Result<usize, StorageError> {
+    // A compacted copy is still in the old format
+    finish_compaction(index, block_data_dir)?;
+    let entries_moved_below =
+        match index.get_meta(CHECKSUM_MIGRATION_META_KEY)? {
+            Some(value) => u64::from_le_bytes(value.as_slice().try_into().map_err(|_| {
+                StorageError::CorruptDB("checksum migration metadata is not 8 bytes")
+            })?),
+            None => 0,
+        };
+    let mut entries_by_file: HashMap<u64, Vec<(EntryKey, IndexEntry)>> = HashMap::new();
+    for (key, entry) in index.located_entries()? {
+        entries_by_file
+            .entry(entry.file_number)
+            .or_default()
+            .push((key, entry));
+    }
+    let replaced_tail_file = index
+        .get_meta(REPLACED_TAIL_META_KEY)?
+        .and_then(|value| Some(u64::from_le_bytes(value.get(..8)?.try_into().unwrap())));
+
+    let (first_file_number, _) = read_prune_state(index)?;
+    let mut migrated = 0;
+    for file_number in first_file_number.. {
+        let file_path = block_data_dir.join(block_file_name!(file_number));
+        if !file_path.exists() {
+            break;
+        }
+        let Some(header) = read_header_to_migrate(
+            &file_path,
+            encryption_key,
+            TWEAKS_CRC_FORMAT_VERSION..=TWEAKS_CRC_COMPRESSED_FORMAT_VERSION,
+        )?
+        else {
+            continue;
+        };
+
+        let tmp_path = file_path.with_extension("checksum");
+        if file_number >= entries_moved_below {
+            let entries = entries_by_file.remove(&file_number).unwrap_or_default();
+            let (moved, data_end) = checksum_block_data_file(
+                file_number,
+                &file_path,
+                &tmp_path,
+                &header,
+                encryption_key,
+                entries,
+            )?;
+            // Compressed records change length, so records written by replace_block_tweaks
+            // may end elsewhere now. Their end only guards against cutting them off, so all
+            // of the file is kept.
+            if replaced_tail_file == Some(file_number) {
+                let mut tail = file_number.to_le_bytes().to_vec();
+                tail.extend_from_slice(&data_end.to_le_bytes());
+                index.set_meta(REPLACED_TAIL_META_KEY, &tail)?;
+            }
+            index.move_entries(
+                &moved,
+                CHECKSUM_MIGRATION_META_KEY,
+                &(file_number + 1).to_le_bytes(),
+            )?;
+        }
+        platform::replace_file(&tmp_path, &file_path)?;
+        info!(target: "FileStore", "Checksummed the records of block data file {} again", file_path.display());
+        migrated += 1;
+    }
+    index.remove_meta(CHECKSUM_MIGRATION_META_KEY)?;
+    // Record lengths changed with the compressed ones, the totals are counted again on open
+    index.remove_meta(CHAIN_TOTALS_META_KEY)?;
+    Ok(migrated)
+}
+
+/// Writes version 3 or 4 file `file_path`, with `header` (read from it), to `tmp_path` in
+/// version 5 or 6, every record checksummed again (and compressed and encrypted again, as
+/// the file's were). Returns `entries` (the file's) pointing into the copy, and where its
+/// data ends. Records that don't decode stay as they are unless the index points at them,
+/// bytes between records and preallocated space are left out.
+fn checksum_block_data_file(
+    file_number: u64,
+    file_path: &Path,
+    tmp_path: &Path,
+    header: &[u8],
+    encryption_key: Option<&EncryptionKey>,
+    entries: Vec<(EntryKey, IndexEntry)>,
+) -> Result<(Vec<(EntryKey, IndexEntry)>, u64), StorageError> {
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    let compressed = version == TWEAKS_CRC_COMPRESSED_FORMAT_VERSION;
+    let new_version = match compressed {
+        true => COMPRESSED_FORMAT_VERSION,
+        false => FRAMED_FORMAT_VERSION,
+    };
+    let mut writer = BufWriter::new(File::create(tmp_path)?);
+    writer.write_all(&header[..4])?;
+    writer.write_all(&new_version.to_le_bytes())?;
+    writer.write_all(&header[6..])?;
+
+    let referenced: HashSet<u64> = entries.iter().map(|(_, entry)| entry.offset).collect();
+    // Old (offset, length) of every record -> its new (offset, length)
+    let mut moved_to = HashMap::new();
+    let mut new_offset = header.len() as u64;
+    let mut scanner = FrameScanner::open(file_path, header.len() as u64)?;
+    while let Some(frame) = scanner.next_frame()? {
+        let (offset, record) = match frame {
+            ScannedFrame::Record { offset, record } => (offset, record),
+            ScannedFrame::Skipped { offset, length } => {
+                warn!(target: "FileStore", "Leaving out {} bytes at offset {} of {} that don't frame a record",
+                      length, offset, file_path.display());
+                continue;
+            }
+            ScannedFrame::Partial { offset } => {
+                warn!(target: "FileStore", "Dropping a partial record at the end of {} (offset {})",
+                      file_path.display(), offset);
+                break;
+            }
+            ScannedFrame::Zeros { .. } => break,
+        };
+        let rewritten = match checksum_record(
+            &record,
+            file_number,
+            offset,
+            new_offset,
+            compressed,
+            encryption_key,
+        ) {
+            Ok(rewritten) => rewritten,
+            Err(e) if !referenced.contains(&offset) => {
+                warn!(target: "FileStore", "Copying the unreadable record at offset {} of {} as it is, nothing refers to it: {}",
+                      offset, file_path.display(), e);
+                record.clone()
+            }
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&rewritten)?;
+        moved_to.insert(
+            (offset, record.len() as u64),
+            (new_offset, rewritten.len() as u64),
+        );
+        new_offset += rewritten.len() as u64;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+
+    let moved = entries
+        .into_iter()
+        .map(|(key, entry)| {
+            let (offset, length) =
+                moved_to
+                    .get(&(entry.offset, entry.length))
+                    .ok_or(StorageError::CorruptDB(
+                        "index entry does not point at a block data record",
+                    ))?;
+            let moved = IndexEntry {
+                file_number,
+                offset: *offset,
+                length: *length,
+            };
+            Ok((key, moved))
+        })
+        .collect::<Result<_, StorageError>>()?;
+    Ok((moved, new_offset))
+}
+
+/// The record stored at `offset`, whose BlockData is of TWEAKS_CRC_RECORD_VERSION, with the
+/// BlockData checksummed for RECORD_VERSION, to be stored at `new_offset`. Compressed again
+/// at zstd's default level, the level the store was written with isn't known here.
+fn checksum_record(
+    record: &[u8],
+    file_number: u64,
+    offset: u64,
+    new_offset: u64,
+    compressed: bool,
+    encryption_key: Option<&EncryptionKey>,
+) -> Result<Vec<u8>, StorageError> {
+    let payload =
+        frame_payload(record).ok_or(StorageError::CorruptDB("block data record is not framed"))?;
+    let mut serialized = match encryption_key {
+        Some(key) => key.decrypt_record(file_number, offset, payload)?,
+        None => payload.to_vec(),
+    };
+    if compressed {
+        serialized = decompress_record(&serialized)?;
+    }
+    // Whatever limit the store had when the record was written, it is stored
+    let block = BlockData::deserialize_version(&serialized, TWEAKS_CRC_RECORD_VERSION, usize::MAX)?;
+    let mut payload = block.serialize();
+    if compressed {
+        payload = compress_record(&payload, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    }
+    Ok(match encryption_key {
+        Some(key) => frame_record_as(
+            frame_magic(record),
+            &key.encrypt_record(file_number, new_offset, &payload),
+        ),
+        None => frame_record_as(frame_magic(record), &payload),
+    })
+}
+
+/// Whether the header of block data file `file_path` is incomplete: the file is shorter than
+/// a header, or the header is all zeros (the file was preallocated, but the header was lost).
+fn header_is_torn(file_path: &Path, header_len: usize) -> io::Result<bool> {
+    let mut header = Vec::with_capacity(header_len);
+    File::open(file_path)?
+        .take(header_len as u64)
+        .read_to_end(&mut header)?;
+    Ok(header.len() < header_len || header.iter().all(|&byte| byte == 0))
+}
+
+/// Checks that a block data file is in a current format and matches the encryption setting
+/// (and key) the store was opened with. Returns its format version.
+fn check_file_header(
+    file_path: &Path,
+    encryption_key: Option<&EncryptionKey>,
+) -> Result<u16, StorageError> {
+    let mut file = File::open(file_path)?;
+    let mut prefix = [0u8; HEADER_PREFIX_SIZE];
+    file.read_exact(&mut prefix)?;
+    let (encrypted, version) = parse_header_prefix(&prefix)?;
+    if version > FILE_FORMAT_VERSION {
+        return Err(StorageError::UnsupportedVersion(version));
+    }
+    if version < FRAMED_FORMAT_VERSION {
+        // The data directory version says its files were upgraded
+        return Err(StorageError::CorruptDB(
+            "block data file is older than the data directory",
+        ));
+    }
+
+    match encryption_key {
+        None if !encrypted => Ok(version),
+        None => Err(StorageError::EncryptionError(
+            "store is encrypted, an encryption key is required",
+        )),
+        Some(_) if !encrypted => Err(StorageError::EncryptionError(
+            "store is not encrypted, but an encryption key was provided",
+        )),
+        Some(key) => {
+            let mut header = [0u8; ENCRYPTED_HEADER_SIZE];
+            header[..HEADER_PREFIX_SIZE].copy_from_slice(&prefix);
+            file.read_exact(&mut header[HEADER_PREFIX_SIZE..])?;
+            key.check_file_header(&header).map(|()| version)
+        }
+    }
+}
+
+/// The tweaks of `block` sorted for `BlockData::diff`, duplicates kept so a replacement that
+/// only drops one still differs.
+fn sorted(block: &BlockData) -> Cow<'_, BlockData> {
+    if block.tweaks.is_sorted() {
+        return Cow::Borrowed(block);
+    }
+    let mut tweaks = block.tweaks.clone();
+    tweaks.sort_unstable();
+    Cow::Owned(BlockData {
+        blockhash: block.blockhash,
+        tweaks,
+        tweak_meta: None,
+    })
+}
+
+/// Compresses a serialized BlockData into the payload of a record in a compressed file:
+/// [serialized length (u32 LE)][zstd frame of the serialized BlockData].
+fn compress_record(serialized: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::with_capacity(COMPRESSED_HEADER_SIZE + serialized.len() / 2);
+    payload.extend_from_slice(&(serialized.len() as u32).to_le_bytes());
+    payload.extend(zstd::bulk::compress(serialized, level)?);
+    Ok(payload)
+}
+
+/// The serialized BlockData in the payload of a record in a compressed file. Decompresses no
+/// more than the length recorded in front of it, whatever the zstd frame says.
+fn decompress_record(payload: &[u8]) -> Result<Vec<u8>, StorageError> {
+    let (length, compressed) = payload
+        .split_first_chunk::<COMPRESSED_HEADER_SIZE>()
+        .ok_or(StorageError::DeserializeError(
+            "compressed record is too short",
+        ))?;
+    let length = u32::from_le_bytes(*length) as u64;
+    let mut serialized = Vec::new();
+    zstd::stream::read::Decoder::with_buffer(compressed)?
+        .take(length + 1)
+        .read_to_end(&mut serialized)
+        .map_err(|_| StorageError::DeserializeError("compressed record does not decompress"))?;
+    if serialized.len() as u64 != length {
+        return Err(StorageError::DeserializeError(
+            "compressed record does not decompress to its length",
+        ));
+    }
+    Ok(serialized)
+}
+
+/// Frames a record payload for storage, see RECORD_MAGIC.
+fn frame_record(payload: &[u8]) -> Vec<u8> {
+    frame_record_as(RECORD_MAGIC, payload)
+}
+
+/// Frames a record payload with `magic`, one of RECORD_MAGICS.
+fn frame_record_as(magic: [u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame_into(magic, payload, &mut record);
+    record
+}
+
+/// Same as `frame_record_as`, appending the record to `out`.
+fn frame_into(magic: [u8; 4], payload: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&magic);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// The magic a stored record is framed with. Only meaningful for a record that frames.
+fn frame_magic(record: &[u8]) -> [u8; 4] {
+    match record.get(..4) {
+        Some(&[a, b, c, d]) => [a, b, c, d],
+        _ => RECORD_MAGIC,
+    }
+}
+
+/// Payload length from a frame header, None if it doesn't start with a record magic.
+fn frame_payload_len(header: &[u8; FRAME_HEADER_SIZE]) -> Option<u64> {
+    RECORD_MAGICS
+        .contains(&header[..4].try_into().unwrap())
+        .then(|| u32::from_le_bytes(header[4..].try_into().unwrap()) as u64)
+}
+
+/// The payload of a stored record, None unless `record` is exactly one frame.
+fn frame_payload(record: &[u8]) -> Option<&[u8]> {
+    let header = record.get(..FRAME_HEADER_SIZE)?.try_into().unwrap();
+    let payload_len = frame_payload_len(header)?;
+    (record.len() as u64 == FRAME_HEADER_SIZE as u64 + payload_len)
+        .then(|| &record[FRAME_HEADER_SIZE..])
+}
+
+/// Reads one record, frame included, from the reader.
+/// Returns None if the reader is at end of file.
+fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>, StorageError> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    let mut filled = 0;
+    while filled < header.len() {
+        let n = reader.read(&mut header[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    match filled {
+        0 => return Ok(None),
+        FRAME_HEADER_SIZE => {}
+        _ => return Err(StorageError::DeserializeError("truncated record frame")),
+    }
+
+    let payload_len = frame_payload_len(&header).ok_or(StorageError::DeserializeError(
+        "record does not start with a frame",
+    ))?;
+    let mut record = vec![0u8; FRAME_HEADER_SIZE + payload_len as usize];
+    record[..FRAME_HEADER_SIZE].copy_from_slice(&header);
+    reader.read_exact(&mut record[FRAME_HEADER_SIZE..])?;
+    Ok(Some(record))
+}
+
+/// What a record holds before it is framed and, if the store is encrypted, encrypted. See
+/// `FlatFileStore::record_payload`.
+enum RecordPayload<'b> {
+    /// A block, serialized straight into the record.
+    Block(&'b BlockData),
+    /// A serialized block, compressed if the store compresses.
+    Encoded(Vec<u8>),
+}
+
+impl RecordPayload<'_> {
+    fn len(&self) -> usize {
+        match self {
+            RecordPayload::Block(block) => block.serialized_len() as usize,
+            RecordPayload::Encoded(payload) => payload.len(),
+        }
+    }
+}
+
+/// What FrameScanner found next in a block data file.
+#[derive(Debug)]
+enum ScannedFrame {
+    /// A whole record at `offset`, frame included. Its payload is still unchecked.
+    Record { offset: u64, record: Vec<u8> },
+    /// `length` bytes at `offset` that don't frame a record, up to the next record magic or
+    /// the end of the file.
+    Skipped { offset: u64, length: u64 },
+    /// A record at `offset` that the file ends in the middle of: a write cut short.
+    Partial { offset: u64 },
+    /// Nothing but zeros from `offset` to the end of the file: preallocated space.
+    Zeros { offset: u64 },
+}
+
+/// Walks the records of a block data file by their frames, without the index. Whatever
+/// doesn't frame a record is skipped up to the next record magic, which a damaged length or
+/// a few overwritten bytes don't hide the records that follow behind.
+struct FrameScanner {
+    reader: BufReader<File>,
+    offset: u64,
+    file_size: u64,
+}
+
+impl FrameScanner {
+    /// Starts at `offset`, the first record after the file header.
+    fn open(file_path: &Path, offset: u64) -> io::Result<Self> {
+        let file = File::open(file_path)?;
+        let file_size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(offset))?;
+        Ok(FrameScanner {
+            reader,
+            offset,
+            file_size,
+        })
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<ScannedFrame>> {
+        let offset = self.offset;
+        if offset >= self.file_size {
+            return Ok(None);
+        }
+        let remaining = self.file_size - offset;
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        let available = remaining.min(FRAME_HEADER_SIZE as u64) as usize;
+        self.reader.read_exact(&mut header[..available])?;
+
+        let cut_short = if available == FRAME_HEADER_SIZE {
+            match frame_payload_len(&header) {
+                Some(payload_len) if FRAME_HEADER_SIZE as u64 + payload_len <= remaining => {
+                    let mut record = vec![0u8; FRAME_HEADER_SIZE + payload_len as usize];
+                    record[..FRAME_HEADER_SIZE].copy_from_slice(&header);
+                    self.reader.read_exact(&mut record[FRAME_HEADER_SIZE..])?;
+                    self.offset += record.len() as u64;
+                    return Ok(Some(ScannedFrame::Record { offset, record }));
+                }
+                Some(_) => true,
+                None => false,
+            }
+        } else {
+            let start = &header[..available.min(RECORD_MAGIC.len())];
+            RECORD_MAGICS.iter().any(|magic| magic.starts_with(start))
+        };
+        if header[..available].iter().all(|&byte| byte == 0) && self.zeros_to_end(offset)? {
+            self.offset = self.file_size;
+            return Ok(Some(ScannedFrame::Zeros { offset }));
+        }
+
+        // A frame running past the end of the file is only a cut short write if no record
+        // follows it, otherwise its length is damaged
+        match self.find_magic(offset + 1)? {
+            Some(next) => {
+                self.offset = next;
+                Ok(Some(ScannedFrame::Skipped {
+                    offset,
+                    length: next - offset,
+                }))
+            }
+            None if cut_short => {
+                self.offset = self.file_size;
+                Ok(Some(ScannedFrame::Partial { offset }))
+            }
+            None => {
+                self.offset = self.file_size;
+                Ok(Some(ScannedFrame::Skipped {
+                    offset,
+                    length: remaining,
+                }))
+            }
+        }
+    }
+
+    /// Whether the file holds nothing but zeros from `from` on. Leaves the reader anywhere.
+    fn zeros_to_end(&mut self, from: u64) -> io::Result<bool> {
+        self.reader.seek(SeekFrom::Start(from))?;
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let n = self.reader.read(&mut buffer)?;
+            if n == 0 {
+                return Ok(true);
+            }
+            if buffer[..n].iter().any(|&byte| byte != 0) {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Offset of the first record magic at or after `from`, with the reader positioned there.
+    fn find_magic(&mut self, from: u64) -> io::Result<Option<u64>> {
+        self.reader.seek(SeekFrom::Start(from))?;
+        let mut window = [0u8; 4];
+        let mut position = from;
+        let mut found = None;
+        for byte in (&mut self.reader)
+            .take(self.file_size.saturating_sub(from))
+            .bytes()
+        {
+            window.rotate_left(1);
+            window[3] = byte?;
+            position += 1;
+            if position - from >= 4 && RECORD_MAGICS.contains(&window) {
+                found = Some(position - 4);
+                break;
+            }
+        }
+        if let Some(offset) = found {
+            self.reader.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(found)
+    }
+}
+
+/// A reader that hands out the serialized blocks of consecutive records, automatically
+/// handling file boundaries. Frames are stripped and encrypted records decrypted, so what it
+/// serves doesn't depend on how the files are laid out.
+struct BlockDataReader<'a> {
+    store: &'a FlatFileStore,
+    current_file_number: u64,
+    reader: BufReader<File>,
+    current_position: u64,
+    /// Bytes of stored records left to hand out, None to read to the end of the last file.
+    /// Counts what the records take on disk, frames included, and records are only handed out
+    /// whole.
+    limit: Option<u64>,
+    /// End of the data written when the stream was opened, as (file number, offset). Records
+    /// appended while the stream runs are left out, they may not be fully written yet.
+    end: (u64, u64),
+    /// Serialized block of the record being handed out.
+    block: Vec<u8>,
+    block_position: usize,
+    /// Records handed out so far, the one being handed out included.
+    records_loaded: u32,
+    /// Hand out the blocks without their tweak metadata, see `BlockRangeReader::tweaks_only`.
+    tweaks_only: bool,
+}
+
+impl<'a> BlockDataReader<'a> {
+    /// Opens the next file and positions the reader at the start of the data (after magic bytes).
+    /// Only called before the last file, so the next one not being there is corruption.
+    fn move_to_next_file(&mut self) -> Result<(), StorageError> {
+        self.current_file_number += 1;
+        let file_path = self
+            .store
+            .block_data_dir
+            .join(&block_file_name!(self.current_file_number));
+
+        if !file_path.exists() {
+            warn!(target: "FileStore", "Block data file {} is missing, but the store goes on to file {}",
+                  self.current_file_number, self.end.0);
+            return Err(StorageError::CorruptDB(
+                "a block data file before the last one is missing",
+            ));
+        }
+
+        debug!(target: "FileStore", "Moving to next block file: {}", file_path.display());
+        let file = File::open(&file_path)?;
+        self.reader = BufReader::new(file);
+
+        // Skip the header at the beginning of the file
+        let header_len = self.store.header_len();
+        self.reader.seek(SeekFrom::Start(header_len))?;
+        self.current_position = header_len;
+
+        Ok(())
+    }
+
+    /// Reads the record at the current position, crossing into the next file when needed.
+    /// Returns false once the end of the data or the limit has been reached.
+    fn load_next_record(&mut self) -> Result<bool, StorageError> {
+        if self.limit == Some(0) {
+            return Ok(false);
+        }
+        if self.reader.stream_position()? != self.current_position {
+            self.reader.seek(SeekFrom::Start(self.current_position))?;
+        }
+
+        loop {
+            // Checked again after moving to the next file: the current file may hold no
+            // record yet, only its header (and preallocated space)
+            if (self.current_file_number, self.current_position) >= self.end {
+                return Ok(false);
+            }
+            match read_frame(&mut self.reader)? {
+                Some(mut record) => {
+                    if let Some(limit) = self.limit.as_mut() {
+                        *limit = limit.checked_sub(record.len() as u64).ok_or(
+                            StorageError::InvalidData("Stream limit ends inside a record"),
+                        )?;
+                    }
+                    let record_len = record.len() as u64;
+                    let magic = frame_magic(&record);
+                    self.block = match self.store.encryption_key.as_ref() {
+                        Some(key) => key
+                            .decrypt_record(
+                                self.current_file_number,
+                                self.current_position,
+                                &record[FRAME_HEADER_SIZE..],
+                            )
+                            .inspect_err(|_| {
+                                self.store.integrity.report(Violation::new(
+                                    ViolationKind::Checksum,
+                                    format!(
+                                        "record in file {} at offset {} failed authentication",
+                                        self.current_file_number, self.current_position
+                                    ),
+                                ))
+                            })?,
+                        None => {
+                            record.drain(..FRAME_HEADER_SIZE);
+                            record
+                        }
+                    };
+                    if self.store.is_compressed(self.current_file_number) {
+                        self.block = decompress_record(&self.block).inspect_err(|e| {
+                            self.store.integrity.report(Violation::new(
+                                ViolationKind::Checksum,
+                                format!(
+                                    "record in file {} at offset {} is unreadable: {}",
+                                    self.current_file_number, self.current_position, e
+                                ),
+                            ))
+                        })?;
+                    }
+                    // Bounded streams only read records the index referenced when they were
+                    // planned, worth caching unless they hold a dust tier (the cache only holds
+                    // whole blocks) or the block's tweaks were replaced since
+                    if self.limit.is_some()
+                        && BLOCK_RECORD_MAGICS.contains(&magic)
+                        && self.store.cache.is_enabled()
+                    {
+                        let entry = IndexEntry {
+                            file_number: self.current_file_number,
+                            offset: self.current_position,
+                            length: record_len,
+                        };
+                        if let Some(blockhash) = self.block.first_chunk::<32>() {
+                            if self
+                                .store
+                                .index
+                                .get_block_entry(blockhash)
+                                .is_ok_and(|current| current == entry)
+                            {
+                                self.store
+                                    .cache
+                                    .insert(*blockhash, Arc::from(&self.block[..]));
+                            }
+                        }
+                    }
+                    if self.tweaks_only {
+                        let stripped = match BlockData::strip_tweak_meta(
+                            &self.block,
+                            self.store.max_record_size,
+                        )? {
+                            Cow::Owned(stripped) => Some(stripped),
+                            Cow::Borrowed(_) => None,
+                        };
+                        if let Some(stripped) = stripped {
+                            self.block = stripped;
+                        }
+                    }
+                    self.block_position = 0;
+                    self.current_position += record_len;
+                    self.records_loaded += 1;
+                    return Ok(true);
+                }
+                // The end of a file, which ends the data only if it is the last one
+                None => {
+                    if self.current_file_number >= self.end.0 {
+                        return Ok(false);
+                    }
+                    self.move_to_next_file()?;
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Read for BlockDataReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.block_position == self.block.len() {
+            match self.load_next_record() {
+                Ok(true) => {}
+                Ok(false) => return Ok(0),
+                Err(StorageError::IoError(e)) => return Err(e),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+
+        let remaining = &self.block[self.block_position..];
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.block_position += n;
+        Ok(n)
+    }
+}
+
+/// Reads a range of blocks as consecutive runs of records, see `get_block_stream_range`.
+pub struct BlockRangeReader<'a> {
+    store: &'a FlatFileStore,
+    parts: VecDeque<RangePart>,
+    current: Option<PartReader<'a>>,
+    /// Height of the first block of `current`, or of the next part if there is none.
+    part_start: u32,
+    /// Last height of the range.
+    end: u32,
+    /// Dust tier served, None for all tweaks.
+    dust_threshold: Option<u64>,
+    /// Serve the blocks without their tweak metadata.
+    tweaks_only: bool,
+}
+
+/// A stretch of the range a BlockRangeReader serves.
+enum RangePart {
+    /// Records following each other, as (first entry, length of the run, number of blocks).
+    Records(IndexEntry, u64, u32),
+    /// A block found in the block cache.
+    Cached(Arc<[u8]>),
+}
+
+/// Reads the RangePart a BlockRangeReader is in.
+enum PartReader<'a> {
+    Records(BlockDataReader<'a>, u32),
+    Cached(io::Cursor<Arc<[u8]>>),
+}
+
+impl PartReader<'_> {
+    fn blocks(&self) -> u32 {
+        match self {
+            PartReader::Records(_, blocks) => *blocks,
+            PartReader::Cached(_) => 1,
+        }
+    }
+
+    /// Blocks handed out so far, in full or in part.
+    fn blocks_started(&self) -> u32 {
+        match self {
+            PartReader::Records(reader, _) => reader.records_loaded,
+            PartReader::Cached(cursor) => u32::from(cursor.position() > 0),
+        }
+    }
+}
+
+impl BlockRangeReader<'_> {
+    /// Serves the blocks without their tweak metadata, for clients that only want the tweaks:
+    /// blocks stored with metadata are streamed as if they had been stored without. To be
+    /// called before reading. `size_of_range` counts the blocks as stored, metadata included.
+    pub fn tweaks_only(mut self) -> Self {
+        self.tweaks_only = true;
+        self
+    }
+
+    /// Skips the next `n` blocks without reading them: the stream goes on with the block `n`
+    /// heights past the next one it would have started on, wherever the index says its record
+    /// is. Whatever is left of a block read partway is dropped. `InvalidHeight` if that goes
+    /// past the end of the range; skipping right to its end leaves nothing to read.
+    pub fn skip_blocks(&mut self, n: u32) -> Result<(), StorageError> {
+        let next_height = self.part_start as u64
+            + self
+                .current
+                .as_ref()
+                .map_or(0, |current| current.blocks_started() as u64);
+        let target = next_height + n as u64;
+        if target > self.end as u64 + 1 {
+            return Err(StorageError::InvalidHeight);
+        }
+        let target = target as u32;
+        self.parts = match target <= self.end {
+            true => self
+                .store
+                .range_parts(target, self.end, self.dust_threshold)?,
+            false => VecDeque::new(),
+        };
+        self.current = None;
+        self.part_start = target;
+        Ok(())
+    }
+}
+
+impl Read for BlockRangeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(current) = self.current.as_mut() {
+                let bytes_read = match current {
+                    PartReader::Records(reader, _) => reader.read(buf)?,
+                    PartReader::Cached(cursor) => cursor.read(buf)?,
+                };
+                if bytes_read > 0 || buf.is_empty() {
+                    return Ok(bytes_read);
+                }
+                self.part_start += current.blocks();
+                self.current = None;
+            }
+            let Some(part) = self.parts.pop_front() else {
+                return Ok(0);
+            };
+            self.current = Some(self.part_reader(part).map_err(|e| match e {
+                StorageError::IoError(e) => e,
+                e => io::Error::new(io::ErrorKind::InvalidData, e),
+            })?);
+        }
+    }
+}
+
+impl<'a> BlockRangeReader<'a> {
+    fn part_reader(&self, part: RangePart) -> Result<PartReader<'a>, StorageError> {
+        match part {
+            RangePart::Cached(block) if self.tweaks_only => {
+                let stripped =
+                    match BlockData::strip_tweak_meta(&block, self.store.max_record_size)? {
+                        Cow::Owned(stripped) => Some(Arc::from(stripped)),
+                        Cow::Borrowed(_) => None,
+                    };
+                Ok(PartReader::Cached(io::Cursor::new(
+                    stripped.unwrap_or(block),
+                )))
+            }
+            RangePart::Cached(block) => Ok(PartReader::Cached(io::Cursor::new(block))),
+            RangePart::Records(entry, length, blocks) => {
+                let mut reader = self.store.block_data_reader(&entry, Some(length))?;
+                reader.tweaks_only = self.tweaks_only;
+                Ok(PartReader::Records(reader, blocks))
+            }
+        }
+    }
+}
+
+/// A serialized block handed out by `get_block_range_mmap`.
+#[cfg(feature = "mmap")]
+pub struct MappedSlice<'a> {
+    bytes: MappedBytes,
+    range: Range<usize>,
+    // Keeps the store, and with it pruning and rekeying, borrowed while the slice is around
+    _store: PhantomData<&'a FlatFileStore>,
+}
+
+#[cfg(feature = "mmap")]
+enum MappedBytes {
+    Mapped(Arc<Mmap>),
+    /// A record of the file still being appended to, which is never mapped.
+    Read(Vec<u8>),
+}
+
+#[cfg(feature = "mmap")]
+impl Deref for MappedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            MappedBytes::Mapped(map) => map,
+            MappedBytes::Read(record) => record,
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Deref for MappedSlice<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[self.range.clone()]
+    }
+}
+
+/// Why a record doesn't give back the block the index expects there.
+enum RecordFault {
+    /// The record can't be read: missing, cut short or failing its checksum.
+    Unreadable(StorageError, Option<Violation>),
+    /// The record reads fine, but isn't what the index describes.
+    Mismatch(StorageError, Violation),
+}
+
+impl RecordFault {
+    /// Reading the record of `entry` failed with `e`.
+    fn read_failed(entry: &IndexEntry, e: io::Error) -> Self {
+        let violation = (e.kind() == io::ErrorKind::UnexpectedEof).then(|| {
+            Violation::new(
+                ViolationKind::IndexFileMismatch,
+                format!(
+                    "record in file {} at offset {} ends past the end of the file",
+                    entry.file_number, entry.offset
+                ),
+            )
+        });
+        RecordFault::Unreadable(e.into(), violation)
+    }
+
+    /// The record of `entry` is framed right but doesn't decode: it fails its checksum or
+    /// authentication, or is cut short within its payload.
+    fn unreadable(entry: &IndexEntry, e: StorageError) -> Self {
+        let violation = Violation::new(
+            ViolationKind::Checksum,
+            format!(
+                "record in file {} at offset {} is unreadable",
+                entry.file_number, entry.offset
+            ),
+        );
+        RecordFault::Unreadable(e, Some(violation))
+    }
+
+    /// The record of `entry` holds a block other than the one indexed there.
+    fn other_block(entry: &IndexEntry) -> Self {
+        RecordFault::Mismatch(
+            StorageError::CorruptDB("index entry points at another block"),
+            Violation::new(
+                ViolationKind::IndexFileMismatch,
+                format!(
+                    "record in file {} at offset {} holds another block",
+                    entry.file_number, entry.offset
+                ),
+            ),
+        )
+    }
+
+    /// The bytes `entry` points at aren't exactly one frame.
+    fn frame_mismatch(entry: &IndexEntry) -> Self {
+        RecordFault::Mismatch(
+            StorageError::CorruptDB("index entry does not match the record frame"),
+            Violation::new(
+                ViolationKind::IndexFileMismatch,
+                format!(
+                    "record in file {} at offset {} does not match the frame there",
+                    entry.file_number, entry.offset
+                ),
+            ),
+        )
+    }
+
+    /// Reports the violation, if any, and returns the error for the caller.
+    fn report(self, integrity: &IntegrityGuard) -> StorageError {
+        let (e, violation) = match self {
+            RecordFault::Unreadable(e, violation) => (e, violation),
+            RecordFault::Mismatch(e, violation) => (e, Some(violation)),
+        };
+        if let Some(violation) = violation {
+            integrity.report(violation);
+        }
+        e
+    }
+}
+
+/// Follows the ranges of the block data files the index references, in file order, and
+/// collects the gaps between them as dead space. See `verify`.
+struct Coverage {
+    file_number: u64,
+    /// End of the referenced bytes in `file_number` so far.
+    covered_to: u64,
+    header_len: u64,
+    /// Where the data written so far ends, as (file number, offset).
+    data_end: (u64, u64),
+}
+
+impl Coverage {
+    fn new(first_file_number: u64, header_len: u64, data_end: (u64, u64)) -> Self {
+        Coverage {
+            file_number: first_file_number,
+            covered_to: header_len,
+            header_len,
+            data_end,
+        }
+    }
+
+    /// Marks the record of `entry` as referenced. Entries before the current position (only
+    /// a broken index has them) can't be placed and are left out.
+    fn cover(
+        &mut self,
+        store: &FlatFileStore,
+        entry: &IndexEntry,
+        dead_space: &mut Vec<DeadSpace>,
+    ) {
+        if entry.file_number < self.file_number {
+            return;
+        }
+        self.finish_files_before(store, entry.file_number, dead_space);
+        if entry.offset > self.covered_to {
+            dead_space.push(DeadSpace {
+                file_number: self.file_number,
+                offset: self.covered_to,
+                length: entry.offset - self.covered_to,
+            });
+        }
+        self.covered_to = self.covered_to.max(entry.offset + entry.length);
+    }
+
+    /// Whatever follows the last referenced record of the files before `file_number` is dead.
+    fn finish_files_before(
+        &mut self,
+        store: &FlatFileStore,
+        file_number: u64,
+        dead_space: &mut Vec<DeadSpace>,
+    ) {
+        while self.file_number < file_number {
+            let file_path = store
+                .block_data_dir
+                .join(block_file_name!(self.file_number));
+            // A missing file shows up as the unreadable records pointing into it, and the
+            // last file may be preallocated past its data
+            let size = match self.data_end {
+                (last_file, end) if last_file == self.file_number => end,
+                _ => fs::metadata(file_path).map_or(0, |metadata| metadata.len()),
+            };
+            if size > self.covered_to {
+                dead_space.push(DeadSpace {
+                    file_number: self.file_number,
+                    offset: self.covered_to,
+                    length: size - self.covered_to,
+                });
+            }
+            self.file_number += 1;
+            self.covered_to = self.header_len;
+        }
+    }
+}
+
+/// Reads the record of `entry` at the current position of `reader`.
+fn read_record(reader: &mut impl Read, entry: &IndexEntry) -> io::Result<Vec<u8>> {
+    let mut record = vec![0u8; entry.length as usize];
+    reader.read_exact(&mut record)?;
+    Ok(record)
+}
+
+/// Reads records through one open file at a time, without seeking between consecutive ones.
+struct RecordReader<'a> {
+    store: &'a FlatFileStore,
+    // Open file as (file number, reader, position of the reader in it)
+    file: Option<(u64, BufReader<File>, u64)>,
+}
+
+impl<'a> RecordReader<'a> {
+    fn new(store: &'a FlatFileStore) -> Self {
+        RecordReader { store, file: None }
+    }
+
+    fn read(&mut self, entry: &IndexEntry) -> io::Result<Vec<u8>> {
+        if !matches!(self.file, Some((file_number, _, _)) if file_number == entry.file_number) {
+            self.store.state().flush()?;
+            let file_path = self
+                .store
+                .block_data_dir
+                .join(block_file_name!(entry.file_number));
+            self.file = Some((entry.file_number, BufReader::new(File::open(file_path)?), 0));
+        }
+        let (_, reader, position) = self.file.as_mut().unwrap();
+        // Consecutive records need no seek, which would throw away the buffer
+        if *position != entry.offset {
+            reader.seek(SeekFrom::Start(entry.offset))?;
+        }
+        let record = read_record(reader, entry);
+        match record {
+            Ok(_) => *position = entry.offset + entry.length,
+            // Wherever the reader ended up, the next read seeks
+            Err(_) => *position = u64::MAX,
+        }
+        record
+    }
+}
+
+/// Iterator behind `iter_blocks`.
+struct BlockIter<'a> {
+    store: &'a FlatFileStore,
+    next_height: u32,
+    end: u32,
+    records: RecordReader<'a>,
+}
+
+impl<'a> BlockIter<'a> {
+    fn read_block(&mut self, height: u32) -> Result<BlockData, StorageError> {
+        let blockhash = self.store.index.get_blockhash_by_height(height)?;
+        let entry = self.store.index.get_block_entry(&blockhash)?;
+        let record = self.records.read(&entry);
+        self.store.block_from_record(&blockhash, &entry, record)
+    }
+}
+
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = Result<(u32, BlockData), StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_height >= self.end {
+            return None;
+        }
+        let height = self.next_height;
+        let result = self.read_block(height);
+        self.next_height = if result.is_ok() { height + 1 } else { self.end };
+        Some(result.map(|block| (height, block)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::block_data::{TweakMeta, TWEAK_SIZE};
+    use super::super::journal::JOURNAL_FILE_NAME;
+    use super::super::LOCK_FILE_NAME;
+    use super::*;
+    use crate::test_support::{generated_block, temp_dir, TestStore, TEST_MAX_FILE_SIZE};
+    use rand::Rng;
+    use std::fs;
+    use std::io::Read;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    fn create_random_block_data() -> BlockData {
+        let mut rng = rand::rng();
+        let mut blockhash = [0u8; 32];
+        for i in 0..32 {
+            blockhash[i] = rng.random();
+        }
+
+        let num_tweaks = rng.random_range(1..10);
+        let mut tweaks = Vec::with_capacity(num_tweaks);
+
+        for _ in 0..num_tweaks {
+            let mut tweak = [0u8; TWEAK_SIZE];
+            for i in 0..TWEAK_SIZE {
+                tweak[i] = rng.random();
+            }
+            tweaks.push(tweak);
+        }
+
+        BlockData {
+            blockhash,
+            tweaks,
+            tweak_meta: None,
+        }
+    }
+
+    #[test]
+    fn test_add_and_read_single_block() {
+        let test_dir = temp_dir("test_flat_file_store_single");
+
+        // Initialize store
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+
+        // Create and add a block
+        let block = create_random_block_data();
+        let height = 0;
+        store.add_block(&block, height).unwrap();
+
+        // Read the block back
+        let mut reader = store.get_block_stream_from_height(height).unwrap();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+
+        // Deserialize and verify
+        let read_block = BlockData::deserialize(&buffer).unwrap();
+        assert_eq!(block.blockhash, read_block.blockhash);
+        assert_eq!(block.tweaks, read_block.tweaks);
+    }
+
+    #[test]
+    fn test_add_and_read_multiple_blocks() {
+        let test_dir = temp_dir("test_flat_file_store_multiple");
+
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+
+        // Create and add multiple blocks
         let num_blocks = 10;
         let mut blocks = Vec::with_capacity(num_blocks);
         let mut heights = Vec::with_capacity(num_blocks);
 
-        for i in 0..num_blocks {
-            let block = create_random_block_data();
-            blocks.push(block);
-            heights.push(i as u32);
+        for i in 0..num_blocks {
+            let block = create_random_block_data();
+            blocks.push(block);
+            heights.push(i as u32);
+        }
+
+        assert_eq!(
+            store
+                .add_block_bulk(&blocks, &heights)
+                .into_result()
+                .unwrap(),
+            10
+        );
+
+        // Read and verify each block
+        for (i, original_block) in blocks.iter().enumerate() {
+            let height = i as u32;
+            let mut reader = store.get_block_stream_from_height(height).unwrap();
+            let mut buffer = Vec::new();
+            reader.read_to_end(&mut buffer).unwrap();
+
+            let read_block = BlockData::deserialize(&buffer).unwrap();
+            assert_eq!(original_block.blockhash, read_block.blockhash);
+            assert_eq!(original_block.tweaks, read_block.tweaks);
+        }
+    }
+
+    #[test]
+    fn test_cross_file_boundary() {
+        let test_dir = temp_dir("test_flat_file_store_boundary");
+
+        // Small files, so a hundred blocks span several of them
+        let store = FlatFileStore::initialize_with_options(
+            test_dir.clone(),
+            FlatFileStoreOptions {
+                max_file_size: 64 * 1024,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Create a large block with many tweaks to make it bigger
+        let mut large_block = create_random_block_data();
+        let mut rng = rand::rng();
+        for _ in 0..100 {
+            let mut tweak = [0u8; TWEAK_SIZE];
+            for i in 0..TWEAK_SIZE {
+                tweak[i] = rng.random();
+            }
+            large_block.tweaks.push(tweak);
+        }
+
+        for height in 0..100 {
+            store.add_block(&large_block, height).unwrap();
+        }
+        assert!(store.state().current_file_number >= 2);
+
+        // Test reading beyond the end of a file
+        let mut reader = store.get_block_stream_from_height(0).unwrap();
+
+        // The stream should contain all blocks concatenated
+        loop {
+            let block = match BlockData::deserialize_from(&mut reader) {
+                Ok((block, _)) => block,
+                Err(StorageError::EndOfStream) => break,
+                Err(e) => panic!("{}", e),
+            };
+            assert_eq!(large_block.blockhash, block.blockhash);
+            assert_eq!(large_block.tweaks.len(), block.tweaks.len());
+        }
+    }
+
+    #[test]
+    fn test_rotation_writes_to_new_file() {
+        let test_dir = temp_dir("test_flat_file_store_rotation");
+        let store = FlatFileStore::initialize_with_options(
+            test_dir.clone(),
+            FlatFileStoreOptions {
+                max_file_size: TEST_MAX_FILE_SIZE,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let blocks: Vec<BlockData> = (0..60).map(|_| create_random_block_data()).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        assert!(store.state().current_file_number >= 2);
+        store.flush().unwrap();
+
+        let mut previous_file = 0;
+        for (height, block) in blocks.iter().enumerate() {
+            let entry = store.index.get_block_entry(&block.blockhash).unwrap();
+            // The first block of every new file sits right after its header
+            if entry.file_number != previous_file {
+                assert_eq!(entry.offset, HEADER_PREFIX_SIZE as u64);
+                previous_file = entry.file_number;
+            }
+            let file_path = test_dir
+                .join(BLOCK_DATA_DIR_NAME)
+                .join(block_file_name!(entry.file_number));
+            assert!(fs::metadata(file_path).unwrap().len() >= entry.offset + entry.length);
+
+            // Every block reads back with a matching CRC
+            let mut reader = store.get_block_stream_from_height(height as u32).unwrap();
+            let mut buffer = vec![0u8; entry.length as usize - FRAME_HEADER_SIZE];
+            reader.read_exact(&mut buffer).unwrap();
+            assert_eq!(&BlockData::deserialize(&buffer).unwrap(), block);
+        }
+    }
+
+    #[test]
+    fn test_block_file_discovery() {
+        // "sps" in the path to the data directory is no block data file
+        let test_dir = temp_dir("test_flat_file_store_discovery");
+        let data_dir = test_dir.join("sps").join("sps000000.dat");
+        let options = || FlatFileStoreOptions {
+            max_file_size: TEST_MAX_FILE_SIZE,
+            ..Default::default()
+        };
+        let store = FlatFileStore::initialize_with_options(data_dir.clone(), options()).unwrap();
+        let blocks: Vec<BlockData> = (0..60).map(|height| generated_block(height, 3)).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        let last_file = store.state().current_file_number;
+        assert!(last_file >= 3);
+        drop(store);
+
+        // Unrelated files, and copies left by an interrupted rewrite, are left alone
+        let block_data_dir = data_dir.join(BLOCK_DATA_DIR_NAME);
+        fs::write(block_data_dir.join("notes.txt"), b"").unwrap();
+        fs::write(block_data_dir.join("sps000001.rekey"), b"").unwrap();
+        fs::create_dir(block_data_dir.join("sps000099.dat")).unwrap();
+        let store = FlatFileStore::initialize_with_options(data_dir.clone(), options()).unwrap();
+        assert_eq!(store.state().current_file_number, last_file);
+        assert_eq!(store.get_block(59).unwrap(), blocks[59]);
+        drop(store);
+
+        // Anything else named like a block data file is refused
+        for name in [
+            "sps_backup.dat.old",
+            "sps000001.dat.old",
+            "sps12.dat",
+            "sps0000001.dat",
+        ] {
+            let path = block_data_dir.join(name);
+            fs::write(&path, b"").unwrap();
+            assert!(
+                matches!(
+                    FlatFileStore::initialize_with_options(data_dir.clone(), options()),
+                    Err(StorageError::CorruptDB(_))
+                ),
+                "{} was not refused",
+                name
+            );
+            fs::remove_file(&path).unwrap();
+        }
+
+        // So is a gap in the numbering, which would hide the files after it
+        let file_2 = block_data_dir.join(block_file_name!(2));
+        let moved = block_data_dir.join("moved");
+        fs::rename(&file_2, &moved).unwrap();
+        assert!(matches!(
+            FlatFileStore::initialize_with_options(data_dir.clone(), options()),
+            Err(StorageError::CorruptDB(_))
+        ));
+        fs::rename(&moved, &file_2).unwrap();
+        let store = FlatFileStore::initialize_with_options(data_dir.clone(), options()).unwrap();
+        assert_eq!(store.get_block(59).unwrap(), blocks[59]);
+        assert!(store.verify().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_empty_trailing_file() {
+        let test_dir = temp_dir("test_flat_file_store_empty_trailing_file");
+        let options = |preallocate| FlatFileStoreOptions {
+            max_file_size: TEST_MAX_FILE_SIZE,
+            preallocate,
+            ..Default::default()
+        };
+        let read_all = |store: &FlatFileStore| {
+            let mut buffer = Vec::new();
+            store
+                .get_block_stream_from_height(0)
+                .unwrap()
+                .read_to_end(&mut buffer)
+                .unwrap();
+            buffer
+        };
+        let store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), options(false)).unwrap();
+        let mut blocks: Vec<BlockData> = (0..40).map(|height| generated_block(height, 3)).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        let header_len = store.header_len();
+        let last_file = store.state().current_file_number;
+        drop(store);
+
+        let block_data_dir = test_dir.join(BLOCK_DATA_DIR_NAME);
+        let header = fs::read(block_data_dir.join(block_file_name!(0))).unwrap()
+            [..header_len as usize]
+            .to_vec();
+        // What a crash can leave of a file just rotated to, and whether it was preallocated
+        let cases = [
+            ("header only", header.clone(), false),
+            ("torn header", header[..3].to_vec(), false),
+            ("zeroed header", vec![0u8; 4096], true),
+        ];
+        for (offset, (name, contents, preallocate)) in cases.into_iter().enumerate() {
+            let file_number = last_file + 1 + offset as u64;
+            let file_path = block_data_dir.join(block_file_name!(file_number));
+            fs::write(&file_path, contents).unwrap();
+
+            let store =
+                FlatFileStore::initialize_with_options(test_dir.clone(), options(preallocate))
+                    .unwrap_or_else(|e| panic!("{}: {:?}", name, e));
+            assert_eq!(store.state().current_file_number, file_number, "{}", name);
+            assert_eq!(
+                fs::read(&file_path).unwrap()[..header_len as usize],
+                header[..],
+                "{}",
+                name
+            );
+
+            // Reads across into the empty file end where the data does
+            let tip = blocks.len() as u32 - 1;
+            assert_eq!(read_range(&store, 0, tip), serialized(&blocks), "{}", name);
+            assert_eq!(read_all(&store), serialized(&blocks), "{}", name);
+
+            // and the next block is written right after the header
+            let block = generated_block(blocks.len() as u32, 3);
+            store.add_block(&block, blocks.len() as u32).unwrap();
+            let entry = store.index.get_block_entry(&block.blockhash).unwrap();
+            assert_eq!(
+                (entry.file_number, entry.offset),
+                (file_number, header_len),
+                "{}",
+                name
+            );
+            blocks.push(block);
+            assert_eq!(read_all(&store), serialized(&blocks), "{}", name);
+            assert!(store.verify().unwrap().is_clean(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_writes_are_flushed_before_reads() {
+        let test_dir = temp_dir("test_flat_file_store_buffered_writes");
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+
+        let blocks: Vec<BlockData> = (0..3).map(|_| create_random_block_data()).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        // Small records are still sitting in the writer's buffer
+        assert_eq!(
+            fs::metadata(&file_path).unwrap().len(),
+            HEADER_PREFIX_SIZE as u64
+        );
+
+        let mut buffer = Vec::new();
+        store
+            .get_block_stream_from_height(1)
+            .unwrap()
+            .read_to_end(&mut buffer)
+            .unwrap();
+        let pos = blocks[1].serialize().len();
+        assert_eq!(BlockData::deserialize(&buffer).unwrap(), blocks[1]);
+        assert_eq!(BlockData::deserialize(&buffer[pos..]).unwrap(), blocks[2]);
+
+        // Dropping the store flushes whatever was written since
+        let block = create_random_block_data();
+        store.add_block(&block, 3).unwrap();
+        drop(store);
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let entry = store.index.get_block_entry(&block.blockhash).unwrap();
+        assert_eq!(
+            fs::metadata(&file_path).unwrap().len(),
+            entry.offset + entry.length
+        );
+
+        drop(store);
+    }
+
+    fn encrypted_options(byte: u8) -> FlatFileStoreOptions {
+        FlatFileStoreOptions {
+            encryption_key: Some(EncryptionKey::from_bytes([byte; 32])),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_encrypted_round_trip() {
+        let test_dir = temp_dir("test_flat_file_store_encrypted");
+
+        let store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), encrypted_options(1)).unwrap();
+        let blocks: Vec<BlockData> = (0..5).map(|_| create_random_block_data()).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+
+        // The tweaks must not appear in plaintext on disk
+        store.flush().unwrap();
+        let raw = fs::read(test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0))).unwrap();
+        assert_eq!(&raw[..4], &ENCRYPTED_MAGIC_BYTES);
+        assert_eq!(&raw[4..6], &FRAMED_FORMAT_VERSION.to_le_bytes());
+        assert!(!raw.windows(32).any(|w| w == blocks[0].blockhash));
+
+        drop(store);
+        let store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), encrypted_options(1)).unwrap();
+
+        // Streams are transparently decrypted, across records
+        let mut reader = store.get_block_stream_from_height(2).unwrap();
+        for block in &blocks[2..] {
+            let (read_block, _) = BlockData::deserialize_from(&mut reader).unwrap();
+            assert_eq!(block, &read_block);
+        }
+        assert!(matches!(
+            BlockData::deserialize_from(&mut reader),
+            Err(StorageError::EndOfStream)
+        ));
+    }
+
+    #[test]
+    fn test_bounded_block_streams() {
+        for (name, options) in [
+            ("plain", FlatFileStoreOptions::default()),
+            ("encrypted", encrypted_options(1)),
+        ] {
+            let store =
+                TestStore::with_options(&format!("test_flat_file_store_bounded_{}", name), options);
+            let blocks = store.add_blocks(60);
+            assert!(store.state().current_file_number >= 2);
+
+            // Single blocks, including the last one of every file
+            for block in &blocks {
+                let mut buffer = Vec::new();
+                store
+                    .get_block_stream(&block.blockhash)
+                    .unwrap()
+                    .read_to_end(&mut buffer)
+                    .unwrap();
+                assert_eq!(buffer, block.serialize());
+            }
+
+            // A range crossing into the next files
+            let entries: Vec<IndexEntry> = blocks[10..40]
+                .iter()
+                .map(|block| store.index.get_block_entry(&block.blockhash).unwrap())
+                .collect();
+            assert_ne!(entries[0].file_number, entries[29].file_number);
+            let length = entries.iter().map(|entry| entry.length).sum();
+            let mut buffer = Vec::new();
+            store
+                .get_block_stream_from_offset(&entries[0], Some(length))
+                .unwrap()
+                .read_to_end(&mut buffer)
+                .unwrap();
+            let expected: Vec<u8> = blocks[10..40].iter().flat_map(|b| b.serialize()).collect();
+            assert_eq!(buffer, expected);
+
+            // Records are never handed out cut short
+            let mut buffer = Vec::new();
+            let result = store
+                .get_block_stream_from_offset(&entries[0], Some(entries[0].length + 1))
+                .unwrap()
+                .read_to_end(&mut buffer);
+            assert!(result.is_err());
+            assert_eq!(buffer, blocks[10].serialize());
+        }
+    }
+
+    #[test]
+    fn test_stream_missing_file() {
+        let store = TestStore::new("test_flat_file_store_stream_missing_file");
+        let blocks = store.add_blocks(60);
+        assert!(store.state().current_file_number >= 2);
+        let first = store.index.get_block_entry(&blocks[0].blockhash).unwrap();
+
+        // Read to the end of the last file, which ends the stream
+        let mut buffer = Vec::new();
+        store
+            .get_block_stream_from_offset(&first, None)
+            .unwrap()
+            .read_to_end(&mut buffer)
+            .unwrap();
+        assert_eq!(buffer, serialized(&blocks));
+
+        // A file missing in between fails the stream instead of ending it early
+        fs::remove_file(store.block_data_dir.join(block_file_name!(1))).unwrap();
+        let mut buffer = Vec::new();
+        let error = store
+            .get_block_stream_from_offset(&first, None)
+            .unwrap()
+            .read_to_end(&mut buffer)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let in_file_0 = blocks
+            .iter()
+            .take_while(|block| {
+                store
+                    .index
+                    .get_block_entry(&block.blockhash)
+                    .unwrap()
+                    .file_number
+                    == 0
+            })
+            .count();
+        assert_eq!(buffer, serialized(&blocks[..in_file_0]));
+    }
+
+    fn read_range(store: &FlatFileStore, start: u32, end: u32) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        store
+            .get_block_stream_range(start, end)
+            .unwrap()
+            .read_to_end(&mut buffer)
+            .unwrap();
+        buffer
+    }
+
+    fn serialized(blocks: &[BlockData]) -> Vec<u8> {
+        blocks.iter().flat_map(|block| block.serialize()).collect()
+    }
+
+    #[test]
+    fn test_block_stream_range() {
+        for (name, options) in [
+            ("plain", FlatFileStoreOptions::default()),
+            ("encrypted", encrypted_options(1)),
+        ] {
+            let store =
+                TestStore::with_options(&format!("test_flat_file_store_range_{}", name), options);
+            let mut blocks = store.add_blocks(40);
+            let file_of = |store: &FlatFileStore, block: &BlockData| {
+                store
+                    .index
+                    .get_block_entry(&block.blockhash)
+                    .unwrap()
+                    .file_number
+            };
+            let first_in_file_1 = blocks
+                .iter()
+                .position(|block| file_of(&store, block) == 1)
+                .unwrap();
+
+            // Starts in file 0, ends in file 1
+            let (start, end) = (first_in_file_1 - 3, first_in_file_1 + 2);
+            assert_eq!(file_of(&store, &blocks[start]), 0);
+            assert_eq!(
+                read_range(&store, start as u32, end as u32),
+                serialized(&blocks[start..=end])
+            );
+            assert_eq!(read_range(&store, 0, 39), serialized(&blocks));
+            assert_eq!(read_range(&store, 7, 7), blocks[7].serialize());
+
+            assert!(matches!(
+                store.get_block_stream_range(30, 40).err(),
+                Some(StorageError::InvalidHeight)
+            ));
+            assert!(matches!(
+                store.get_block_stream_range(8, 7).err(),
+                Some(StorageError::InvalidHeight)
+            ));
+
+            // A reorg leaves the old tip's record behind, the range skips over it
+            store.remove_tip_block(&blocks[39].blockhash).unwrap();
+            let mut replacement = BlockData {
+                blockhash: blocks[39].blockhash,
+                tweaks: blocks[39].tweaks.clone(),
+                tweak_meta: None,
+            };
+            replacement.blockhash[31] ^= 0xff;
+            store.add_block(&replacement, 39).unwrap();
+            blocks[39] = replacement;
+            let extra = store.add_blocks(2);
+            blocks.extend(extra);
+            assert_eq!(read_range(&store, 30, 41), serialized(&blocks[30..]));
+        }
+    }
+
+    #[test]
+    fn test_skip_blocks() {
+        let store = TestStore::new("test_flat_file_store_skip_blocks");
+        let blocks = store.add_blocks(80);
+        let first_in_file_2 = blocks
+            .iter()
+            .position(|block| {
+                store
+                    .index
+                    .get_block_entry(&block.blockhash)
+                    .unwrap()
+                    .file_number
+                    == 2
+            })
+            .unwrap();
+
+        // Partway through block 1 in file 0, skip to file 2: the rest of block 1 is dropped
+        let mut reader = store.get_block_stream_range(1, 79).unwrap();
+        let mut head = [0u8; 10];
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(&head[..], &blocks[1].serialize()[..10]);
+        reader.skip_blocks(first_in_file_2 as u32 - 2).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, serialized(&blocks[first_in_file_2..]));
+
+        // Before anything is read, skipping starts the stream further on
+        let mut reader = store.get_block_stream_range(0, 9).unwrap();
+        reader.skip_blocks(3).unwrap();
+        reader.skip_blocks(2).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, serialized(&blocks[5..=9]));
+
+        // Right to the end of the range leaves nothing, past it is an error
+        let mut reader = store.get_block_stream_range(0, 9).unwrap();
+        reader.skip_blocks(10).unwrap();
+        assert_eq!(reader.read(&mut head).unwrap(), 0);
+        assert!(matches!(
+            reader.skip_blocks(1),
+            Err(StorageError::InvalidHeight)
+        ));
+    }
+
+    #[test]
+    fn test_get_block() {
+        for (name, options) in [
+            ("plain", FlatFileStoreOptions::default()),
+            ("encrypted", encrypted_options(1)),
+        ] {
+            let store = TestStore::with_options(
+                &format!("test_flat_file_store_get_block_{}", name),
+                options,
+            );
+            let blocks = store.add_blocks(40);
+
+            for (height, block) in blocks.iter().enumerate() {
+                assert_eq!(&store.get_block(height as u32).unwrap(), block);
+                assert_eq!(&store.get_block_by_hash(&block.blockhash).unwrap(), block);
+            }
+
+            // The last block of the first file ends exactly at the end of it
+            let last_in_file_0 = blocks
+                .iter()
+                .map(|block| store.index.get_block_entry(&block.blockhash).unwrap())
+                .take_while(|entry| entry.file_number == 0)
+                .last()
+                .unwrap();
+            store.flush().unwrap();
+            let file_0 = store
+                .dir()
+                .join(BLOCK_DATA_DIR_NAME)
+                .join(block_file_name!(0));
+            assert_eq!(
+                fs::metadata(file_0).unwrap().len(),
+                last_in_file_0.offset + last_in_file_0.length
+            );
+
+            assert!(matches!(
+                store.get_block(40),
+                Err(StorageError::EntryNotFound)
+            ));
+            assert!(matches!(
+                store.get_block_by_hash(&[0x77; 32]),
+                Err(StorageError::EntryNotFound)
+            ));
+            store.remove_tip_block(&blocks[39].blockhash).unwrap();
+            assert!(matches!(
+                store.get_block_by_hash(&blocks[39].blockhash),
+                Err(StorageError::OrphanedEntry)
+            ));
+            assert!(matches!(
+                store.get_block(39),
+                Err(StorageError::EntryNotFound)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_iter_blocks() {
+        for (name, options) in [
+            ("plain", FlatFileStoreOptions::default()),
+            ("encrypted", encrypted_options(1)),
+        ] {
+            let store = TestStore::with_options(
+                &format!("test_flat_file_store_iter_blocks_{}", name),
+                options,
+            );
+            assert_eq!(store.iter_blocks().count(), 0);
+            let blocks = store.add_blocks(3000);
+            assert!(store.state().current_file_number > 0);
+
+            let mut count = 0;
+            for (expected_height, result) in store.iter_blocks().enumerate() {
+                let (height, block) = result.unwrap();
+                assert_eq!(height as usize, expected_height);
+                assert_eq!(block, blocks[expected_height]);
+                count += 1;
+            }
+            assert_eq!(count, 3000);
+
+            // Reorged blocks are gone, the replacement shows up in their place
+            store.remove_tip_block(&blocks[2999].blockhash).unwrap();
+            store.remove_tip_block(&blocks[2998].blockhash).unwrap();
+            let replacement = generated_block(1_000_000, 2);
+            store.add_block(&replacement, 2998).unwrap();
+            let iterated: Vec<_> = store.iter_blocks().map(Result::unwrap).collect();
+            assert_eq!(iterated.len(), 2999);
+            assert_eq!(iterated[2997].0, 2997);
+            assert_eq!(iterated[2997].1, blocks[2997]);
+            assert_eq!(iterated[2998].0, 2998);
+            assert_eq!(iterated[2998].1, replacement);
+        }
+    }
+
+    #[test]
+    fn test_iter_blocks_stops_at_error() {
+        let store = TestStore::new("test_flat_file_store_iter_blocks_error");
+        store.add_blocks(20);
+        let entry = store
+            .index
+            .get_block_entry(&store.index.get_blockhash_by_height(5).unwrap())
+            .unwrap();
+        store.flush().unwrap();
+        let file_path = store
+            .dir()
+            .join(BLOCK_DATA_DIR_NAME)
+            .join(block_file_name!(entry.file_number));
+        let mut data = fs::read(&file_path).unwrap();
+        data[(entry.offset + entry.length) as usize - 1] ^= 0xff;
+        fs::write(&file_path, data).unwrap();
+
+        let results: Vec<_> = store.iter_blocks().collect();
+        assert_eq!(results.len(), 6);
+        assert!(results[..5].iter().all(Result::is_ok));
+        assert!(results[5].is_err());
+    }
+
+    fn block_with_tweaks(num_tweaks: usize) -> BlockData {
+        BlockData {
+            blockhash: [0xab; 32],
+            tweaks: vec![[0xcd; TWEAK_SIZE]; num_tweaks],
+            tweak_meta: None,
+        }
+    }
+
+    #[test]
+    fn test_add_block_rolls_back_on_index_failure() {
+        let test_dir = temp_dir("test_flat_file_store_add_rollback");
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+
+        for height in 0..3 {
+            store
+                .add_block(&create_random_block_data(), height)
+                .unwrap();
+        }
+        store.flush().unwrap();
+        let size_before = fs::metadata(&file_path).unwrap().len();
+
+        // The index rejects the wrong height after the record has been written
+        let block = create_random_block_data();
+        assert!(matches!(
+            store.add_block(&block, 7),
+            Err(StorageError::InvalidHeight)
+        ));
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), size_before);
+        assert!(matches!(
+            store.index.get_block_entry(&block.blockhash),
+            Err(StorageError::EntryNotFound)
+        ));
+
+        // Retrying at the right height reuses the same offset
+        store.add_block(&block, 3).unwrap();
+        let entry = store.index.get_block_entry(&block.blockhash).unwrap();
+        assert_eq!(entry.offset, size_before);
+        store.flush().unwrap();
+        assert_eq!(
+            fs::metadata(&file_path).unwrap().len(),
+            size_before + entry.length
+        );
+    }
+
+    fn read_chain(store: &FlatFileStore) -> Vec<BlockData> {
+        let mut reader = store.get_block_stream_from_genesis().unwrap();
+        let mut blocks = Vec::new();
+        loop {
+            match BlockData::deserialize_from(&mut reader) {
+                Ok((block, _)) => blocks.push(block),
+                Err(StorageError::EndOfStream) => return blocks,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_bulk_failure_stores_nothing() {
+        let options = || FlatFileStoreOptions {
+            max_record_size: 1024,
+            ..Default::default()
+        };
+
+        for fail_at in [3, 6, 9] {
+            let test_dir = temp_dir(&format!("test_flat_file_store_bulk_failure_{}", fail_at));
+            let store =
+                FlatFileStore::initialize_with_options(test_dir.clone(), options()).unwrap();
+            let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+
+            let mut blocks: Vec<BlockData> = (0..10).map(|_| create_random_block_data()).collect();
+            for (height, block) in blocks[..3].iter().enumerate() {
+                store.add_block(block, height as u32).unwrap();
+            }
+            store.flush().unwrap();
+            let size_before = fs::metadata(&file_path).unwrap().len();
+
+            // The batch overlaps the stored blocks. Alternate between a height that doesn't
+            // continue the chain and a record the store rejects.
+            let mut heights: Vec<u32> = (1..10).collect();
+            let mut good_block = None;
+            if fail_at % 2 == 0 {
+                heights[fail_at - 1] = 100;
+            } else {
+                good_block = Some(std::mem::replace(
+                    &mut blocks[fail_at],
+                    block_with_tweaks(100),
+                ));
+            }
+
+            let result = store.add_block_bulk(&blocks[1..], &heights);
+            assert_eq!(result.committed, 2);
+            match result.error {
+                Some((100, StorageError::InvalidHeight)) if fail_at % 2 == 0 => {}
+                Some((height, StorageError::RecordTooLarge { .. })) if fail_at % 2 == 1 => {
+                    assert_eq!(height, fail_at as u32)
+                }
+                other => panic!("unexpected bulk error {:?}", other),
+            }
+
+            // A reopened store holds nothing past what was there before
+            drop(store);
+            let store =
+                FlatFileStore::initialize_with_options(test_dir.clone(), options()).unwrap();
+            assert_eq!(store.index.get_current_height(), 2);
+            assert_eq!(fs::metadata(&file_path).unwrap().len(), size_before);
+            for block in &blocks[3..] {
+                assert!(matches!(
+                    store.index.get_block_entry(&block.blockhash),
+                    Err(StorageError::EntryNotFound)
+                ));
+            }
+
+            // Retrying the whole batch works once it is fixed
+            if let Some(block) = good_block {
+                blocks[fail_at] = block;
+            }
+            let heights: Vec<u32> = (1..10).collect();
+            let result = store.add_block_bulk(&blocks[1..], &heights);
+            assert!(result.is_complete());
+            assert_eq!(result.committed, 9);
+            assert_eq!(read_chain(&store), blocks);
+
+            drop(store);
+        }
+    }
+
+    #[test]
+    fn test_bulk_spans_rotation() {
+        let test_dir = temp_dir("test_flat_file_store_bulk_rotation");
+        let store = FlatFileStore::initialize_with_options(
+            test_dir.clone(),
+            FlatFileStoreOptions {
+                max_file_size: TEST_MAX_FILE_SIZE,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let blocks: Vec<BlockData> = (0..80).map(|_| create_random_block_data()).collect();
+        let heights: Vec<u32> = (0..80).collect();
+        for (height, block) in blocks[..5].iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        store.flush().unwrap();
+        let first_file = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        let size_before = fs::metadata(&first_file).unwrap().len();
+
+        // Starting the second file fails partway through the batch
+        let second_file = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(1));
+        fs::create_dir(&second_file).unwrap();
+        let result = store.add_block_bulk(&blocks[5..], &heights[5..]);
+        assert!(matches!(result.error, Some((5, StorageError::IoError(_)))));
+        assert_eq!(result.committed, 0);
+        assert_eq!(store.state().current_file_number, 0);
+        assert_eq!(store.index.get_current_height(), 4);
+        assert_eq!(fs::metadata(&first_file).unwrap().len(), size_before);
+        fs::remove_dir(&second_file).unwrap();
+
+        let result = store.add_block_bulk(&blocks[5..], &heights[5..]);
+        assert!(result.is_complete());
+        assert!(store.state().current_file_number >= 2);
+
+        // Every file starts its records right after the header
+        let mut previous_file = 0;
+        for block in &blocks {
+            let entry = store.index.get_block_entry(&block.blockhash).unwrap();
+            if entry.file_number != previous_file {
+                assert_eq!(entry.offset, HEADER_PREFIX_SIZE as u64);
+                previous_file = entry.file_number;
+            }
+        }
+        assert_eq!(read_chain(&store), blocks);
+
+        drop(store);
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(read_chain(&store), blocks);
+
+        drop(store);
+    }
+
+    /// Every height with its blockhash and index entry.
+    fn index_contents(store: &FlatFileStore) -> Vec<([u8; 32], IndexEntry)> {
+        (0..store.index.get_current_height() + 1)
+            .map(|height| {
+                let blockhash = store.index.get_blockhash_by_height(height as u32).unwrap();
+                assert_eq!(
+                    store.index.get_height_by_blockhash(&blockhash).unwrap(),
+                    height as u32
+                );
+                (blockhash, store.index.get_block_entry(&blockhash).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rebuild_missing_index() {
+        for options in [FlatFileStoreOptions::default(), encrypted_options(1)] {
+            let test_dir = temp_dir("test_flat_file_store_rebuild_index");
+            let encrypted = options.encryption_key.is_some();
+            let options = FlatFileStoreOptions {
+                max_file_size: TEST_MAX_FILE_SIZE,
+                ..options
+            };
+            let store =
+                FlatFileStore::initialize_with_options(test_dir.clone(), options.clone()).unwrap();
+            let blocks: Vec<BlockData> = (0..40).map(|_| create_random_block_data()).collect();
+            for (height, block) in blocks.iter().enumerate() {
+                store.add_block(block, height as u32).unwrap();
+            }
+            assert!(store.state().current_file_number >= 2);
+            let expected = index_contents(&store);
+            drop(store);
+
+            fs::remove_dir_all(test_dir.join(INDEX_DIR_NAME)).unwrap();
+            let store =
+                FlatFileStore::initialize_with_options(test_dir.clone(), options.clone()).unwrap();
+            assert_eq!(index_contents(&store), expected, "encrypted: {}", encrypted);
+            assert_eq!(read_chain(&store), blocks);
+            drop(store);
+
+            // The rebuilt index carries the version metadata again
+            let store = FlatFileStore::initialize_with_options(test_dir.clone(), options).unwrap();
+            assert_eq!(index_contents(&store), expected);
+
+            drop(store);
+        }
+    }
+
+    #[test]
+    fn test_rebuild_cuts_partial_record() {
+        let test_dir = temp_dir("test_flat_file_store_rebuild_partial");
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let blocks: Vec<BlockData> = (0..5).map(|_| create_random_block_data()).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        let expected = index_contents(&store);
+        drop(store);
+
+        // A crash left half a record behind
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        let size = fs::metadata(&file_path).unwrap().len();
+        let partial = frame_record(&create_random_block_data().serialize());
+        let mut file = File::options().append(true).open(&file_path).unwrap();
+        file.write_all(&partial[..partial.len() / 2]).unwrap();
+        drop(file);
+
+        fs::remove_dir_all(test_dir.join(INDEX_DIR_NAME)).unwrap();
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(index_contents(&store), expected);
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), size);
+
+        // New blocks go right after the last whole record
+        let block = create_random_block_data();
+        store.add_block(&block, 5).unwrap();
+        assert_eq!(
+            store
+                .index
+                .get_block_entry(&block.blockhash)
+                .unwrap()
+                .offset,
+            size
+        );
+
+        drop(store);
+    }
+
+    #[test]
+    fn test_rebuild_refuses_corrupt_record() {
+        let test_dir = temp_dir("test_flat_file_store_rebuild_corrupt");
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        for height in 0..5 {
+            store
+                .add_block(&create_random_block_data(), height)
+                .unwrap();
+        }
+        let entry = store
+            .index
+            .get_block_entry(&store.index.get_blockhash_by_height(2).unwrap())
+            .unwrap();
+        drop(store);
+
+        // Flip a bit in the tweaks of a record in the middle of the file
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        let mut raw = fs::read(&file_path).unwrap();
+        raw[(entry.offset + entry.length) as usize - 1] ^= 1;
+        fs::write(&file_path, raw).unwrap();
+
+        fs::remove_dir_all(test_dir.join(INDEX_DIR_NAME)).unwrap();
+        assert!(matches!(
+            FlatFileStore::initialize(test_dir.clone()),
+            Err(StorageError::CorruptDB(_))
+        ));
+    }
+
+    #[test]
+    fn test_rebuild_after_reorg() {
+        let test_dir = temp_dir("test_flat_file_store_rebuild_reorg");
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let mut blocks: Vec<BlockData> = (0..5).map(|_| create_random_block_data()).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        // A one block reorg, then a deeper one taking a block whose tweaks were replaced
+        store.remove_tip_block(&blocks[4].blockhash).unwrap();
+        blocks.truncate(4);
+        for height in 4..6 {
+            blocks.push(create_random_block_data());
+            store.add_block(&blocks[height], height as u32).unwrap();
+        }
+        store
+            .replace_block_tweaks(&blocks[3].blockhash, Vec::new())
+            .unwrap();
+        assert_eq!(store.remove_blocks_above(2).unwrap(), 3);
+        blocks.truncate(3);
+        for height in 3..5 {
+            blocks.push(create_random_block_data());
+            store.add_block(&blocks[height], height as u32).unwrap();
+        }
+        let expected = index_contents(&store);
+        drop(store);
+
+        // The records of the blocks reorged away don't take heights from the new ones
+        fs::remove_dir_all(test_dir.join(INDEX_DIR_NAME)).unwrap();
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(index_contents(&store), expected);
+        assert_eq!(store.index.get_current_height(), 4);
+        for (height, block) in blocks.iter().enumerate() {
+            assert_eq!(&store.get_block(height as u32).unwrap(), block);
+        }
+
+        drop(store);
+    }
+
+    #[test]
+    fn test_frame_scanner_skips_to_next_record() {
+        let test_dir = temp_dir("test_flat_file_store_frame_scanner");
+        let blocks = store_with_blocks(&test_dir, 5);
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let entries: Vec<IndexEntry> = blocks
+            .iter()
+            .map(|block| store.index.get_block_entry(&block.blockhash).unwrap())
+            .collect();
+        drop(store);
+
+        // Overwrite the magic of record 1 and blow up the length of record 3, then cut the
+        // last record short
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        let mut raw = fs::read(&file_path).unwrap();
+        raw[entries[1].offset as usize] ^= 0xff;
+        raw[entries[3].offset as usize + 7] = 0x7f;
+        raw.truncate((entries[4].offset + entries[4].length) as usize - 3);
+        fs::write(&file_path, raw).unwrap();
+
+        let mut scanner = FrameScanner::open(&file_path, HEADER_PREFIX_SIZE as u64).unwrap();
+        let mut found = Vec::new();
+        while let Some(frame) = scanner.next_frame().unwrap() {
+            found.push(match frame {
+                ScannedFrame::Record { offset, record } => ("record", offset, record.len() as u64),
+                ScannedFrame::Skipped { offset, length } => ("skipped", offset, length),
+                ScannedFrame::Partial { offset } => ("partial", offset, 0),
+                ScannedFrame::Zeros { offset } => ("zeros", offset, 0),
+            });
+        }
+        assert_eq!(
+            found,
+            vec![
+                ("record", entries[0].offset, entries[0].length),
+                ("skipped", entries[1].offset, entries[1].length),
+                ("record", entries[2].offset, entries[2].length),
+                ("skipped", entries[3].offset, entries[3].length),
+                ("partial", entries[4].offset, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_entry_must_match_frame() {
+        let test_dir = temp_dir("test_flat_file_store_frame_mismatch");
+        let blocks = store_with_blocks(&test_dir, 3);
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let entry = store.index.get_block_entry(&blocks[1].blockhash).unwrap();
+        drop(store);
+
+        // The frame claims one tweak less than the index entry covers
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        let mut raw = fs::read(&file_path).unwrap();
+        let payload_len = entry.length as u32 - FRAME_HEADER_SIZE as u32 - TWEAK_SIZE as u32;
+        let length_at = entry.offset as usize + 4;
+        raw[length_at..length_at + 4].copy_from_slice(&payload_len.to_le_bytes());
+        fs::write(&file_path, raw).unwrap();
+
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert!(matches!(
+            store.get_block(1),
+            Err(StorageError::CorruptDB(
+                "index entry does not match the record frame"
+            ))
+        ));
+        assert_eq!(&store.get_block(2).unwrap(), &blocks[2]);
+    }
+
+    #[test]
+    fn test_verify() {
+        let test_dir = temp_dir("test_flat_file_store_verify");
+        let blocks = store_with_blocks(&test_dir, 8);
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let report = store.verify().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.blocks_checked, 8);
+        assert_eq!(report.files_checked, 1);
+        assert!(report.dead_space.is_empty());
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        assert_eq!(
+            report.referenced_bytes,
+            fs::metadata(&file_path).unwrap().len() - store.header_len()
+        );
+
+        // Reorg the tip away: its record is left as dead space, its entry off the chain
+        let removed = store.remove_tip_block(&blocks[7].blockhash).unwrap();
+        store.add_block(&create_random_block_data(), 7).unwrap();
+        let entries: Vec<IndexEntry> = [2, 4]
+            .iter()
+            .map(|&height| {
+                store
+                    .index
+                    .get_block_entry(&blocks[height].blockhash)
+                    .unwrap()
+            })
+            .collect();
+        drop(store);
+
+        // Flip a byte at the end of the record at height 2, and make the frame at height 4
+        // claim one tweak less than its entry covers
+        let mut raw = fs::read(&file_path).unwrap();
+        raw[(entries[0].offset + entries[0].length) as usize - 1] ^= 0xff;
+        let payload_len = entries[1].length as u32 - FRAME_HEADER_SIZE as u32 - TWEAK_SIZE as u32;
+        let length_at = entries[1].offset as usize + 4;
+        raw[length_at..length_at + 4].copy_from_slice(&payload_len.to_le_bytes());
+        fs::write(&file_path, raw).unwrap();
+
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let report = store.verify().unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(report.unreadable_heights, vec![2]);
+        assert_eq!(report.mismatched_heights, vec![4]);
+        assert_eq!(report.orphaned_entries, vec![blocks[7].blockhash]);
+        assert_eq!(
+            report.dead_space,
+            vec![DeadSpace {
+                file_number: 0,
+                offset: removed.entry.offset,
+                length: removed.entry.length,
+            }]
+        );
+        assert_eq!(report.dead_bytes, removed.entry.length);
+        assert_eq!(report.blocks_checked, 8);
+        // The rest of the chain still reads
+        assert_eq!(&store.get_block(3).unwrap(), &blocks[3]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let test_dir = temp_dir("test_flat_file_store_stats");
+        let options = || FlatFileStoreOptions {
+            max_file_size: TEST_MAX_FILE_SIZE,
+            ..Default::default()
+        };
+        let store = FlatFileStore::initialize_with_options(test_dir.clone(), options()).unwrap();
+        assert_eq!(store.stats().unwrap().blocks, 0);
+        assert_eq!(store.stats().unwrap().tweaks, 0);
+
+        let mut blocks: Vec<BlockData> = (0..40)
+            .map(|height| generated_block(height, 1 + height as usize % 5))
+            .collect();
+        for (height, block) in blocks[..20].iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        let heights: Vec<u32> = (20..40).collect();
+        assert!(store.add_block_bulk(&blocks[20..], &heights).is_complete());
+        let tweaks = |blocks: &[BlockData]| -> u64 {
+            blocks.iter().map(|block| block.tweaks.len() as u64).sum()
+        };
+        let file_bytes = || -> u64 {
+            fs::read_dir(test_dir.join(BLOCK_DATA_DIR_NAME))
+                .unwrap()
+                .map(|file| file.unwrap().metadata().unwrap().len())
+                .sum()
+        };
+        // The counters agree with what is stored, and with verify on the dead bytes
+        let check = |store: &FlatFileStore, blocks: &[BlockData]| {
+            let stats = store.stats().unwrap();
+            let report = store.verify().unwrap();
+            assert_eq!(stats.blocks, report.blocks_checked);
+            assert_eq!(stats.tweaks, tweaks(blocks));
+            assert_eq!(stats.files, report.files_checked);
+            assert_eq!(stats.file_bytes, file_bytes());
+            assert_eq!(stats.dead_bytes, report.dead_bytes);
+            assert!(stats.index_bytes > 0);
+        };
+        assert!(store.stats().unwrap().files > 2);
+        check(&store, &blocks);
+        assert_eq!(store.stats().unwrap().dead_bytes, 0);
+
+        // A reorg leaves dead bytes behind
+        let removed = store.remove_tip_block(&blocks[39].blockhash).unwrap();
+        blocks[39] = generated_block(1_000, 7);
+        store.add_block(&blocks[39], 39).unwrap();
+        check(&store, &blocks);
+        assert_eq!(store.stats().unwrap().dead_bytes, removed.entry.length);
+        assert_eq!(store.remove_blocks_above(34).unwrap(), 5);
+        blocks.truncate(35);
+        check(&store, &blocks);
+        store.pop_tip().unwrap();
+        blocks.pop();
+        check(&store, &blocks);
+
+        // Saved across a restart, and counted again when they don't match the chain
+        let stats = store.stats().unwrap();
+        drop(store);
+        let store = FlatFileStore::initialize_with_options(test_dir.clone(), options()).unwrap();
+        assert_eq!(store.stats().unwrap().tweaks, stats.tweaks);
+        store.index.remove_meta(CHAIN_TOTALS_META_KEY).unwrap();
+        drop(store);
+        let mut store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), options()).unwrap();
+        check(&store, &blocks);
+
+        let kept = store.prune_below(20).unwrap();
+        assert!(kept > 0);
+        check(&store, &blocks[kept as usize..]);
+        assert_eq!(store.stats().unwrap().blocks, 34 - kept);
+    }
+
+    #[test]
+    fn test_compact() {
+        for (name, options) in [
+            ("plain", FlatFileStoreOptions::default()),
+            ("encrypted", encrypted_options(5)),
+        ] {
+            let test_dir = temp_dir(&format!("test_flat_file_store_compact_{}", name));
+            let options = FlatFileStoreOptions {
+                max_file_size: TEST_MAX_FILE_SIZE,
+                ..options
+            };
+            let open = || {
+                FlatFileStore::initialize_with_options(test_dir.clone(), options.clone()).unwrap()
+            };
+            let chain = |store: &FlatFileStore| -> Vec<BlockData> {
+                store.iter_blocks().map(|item| item.unwrap().1).collect()
+            };
+            let mut store = open();
+            // Reorgs every few blocks leave dead records all through the files
+            let mut blocks = Vec::new();
+            let mut next_block = 0;
+            let mut reorg = |store: &FlatFileStore, blocks: &mut Vec<BlockData>| {
+                for _ in 0..6 {
+                    let block = generated_block(next_block, 3);
+                    next_block += 1;
+                    store.add_block(&block, blocks.len() as u32).unwrap();
+                    blocks.push(block);
+                }
+                store
+                    .remove_tip_block(&blocks.pop().unwrap().blockhash)
+                    .unwrap();
+                store.remove_blocks_above(blocks.len() as u32 - 3).unwrap();
+                blocks.truncate(blocks.len() - 2);
+            };
+            for _ in 0..12 {
+                reorg(&store, &mut blocks);
+            }
+            let current_file = store.state().current_file_number;
+            assert!(current_file >= 3);
+            let file_path = |file_number| {
+                test_dir
+                    .join(BLOCK_DATA_DIR_NAME)
+                    .join(block_file_name!(file_number))
+            };
+            let current_size = fs::metadata(file_path(current_file)).unwrap().len();
+            let stats = store.stats().unwrap();
+            assert!(stats.dead_bytes > 0);
+
+            // Nothing is dead enough yet
+            assert_eq!(store.compact(0.9).unwrap(), 0);
+            let freed = store.compact(0.0).unwrap();
+            assert!(freed > 0);
+            let compacted = store.stats().unwrap();
+            assert_eq!(compacted.file_bytes, stats.file_bytes - freed);
+            assert_eq!(compacted.dead_bytes, stats.dead_bytes - freed);
+            // The current file is left as it is, dead space and all
+            assert_eq!(
+                fs::metadata(file_path(current_file)).unwrap().len(),
+                current_size
+            );
+            let report = store.verify().unwrap();
+            assert!(report.is_clean());
+            assert!(report
+                .dead_space
+                .iter()
+                .all(|dead| dead.file_number == current_file));
+            assert!(store
+                .index
+                .dead_bytes()
+                .unwrap()
+                .iter()
+                .all(|&(file_number, _)| file_number == current_file));
+            assert_eq!(chain(&store), blocks);
+            let tip = blocks.len() as u32 - 1;
+            assert_eq!(read_range(&store, 0, tip), serialized(&blocks));
+            drop(store);
+            let mut store = open();
+            assert_eq!(chain(&store), blocks);
+            assert_eq!(store.compact(0.0).unwrap(), 0);
+
+            // Interrupted before the index points into the copy: the copy is ignored
+            for _ in 0..6 {
+                reorg(&store, &mut blocks);
+            }
+            let (file_number, _) = store.index.dead_bytes().unwrap()[0];
+            assert!(file_number < store.state().current_file_number);
+            let live = |store: &FlatFileStore| {
+                store
+                    .index
+                    .located_entries()
+                    .unwrap()
+                    .into_iter()
+                    .filter(|(_, entry)| entry.file_number == file_number)
+                    .collect::<Vec<_>>()
+            };
+            store
+                .write_compacted_copy(file_number, live(&store))
+                .unwrap();
+            drop(store);
+            let store = open();
+            assert_eq!(chain(&store), blocks);
+
+            // Interrupted after it: opening the store swaps the copy in
+            let moved = store
+                .write_compacted_copy(file_number, live(&store))
+                .unwrap();
+            store
+                .index
+                .move_entries(&moved, COMPACTION_META_KEY, &file_number.to_le_bytes())
+                .unwrap();
+            drop(store);
+            let store = open();
+            assert!(!file_path(file_number)
+                .with_extension(COMPACTION_EXTENSION)
+                .exists());
+            assert_eq!(store.index.get_meta(COMPACTION_META_KEY).unwrap(), None);
+            assert_eq!(chain(&store), blocks);
+            assert!(store.verify().unwrap().is_clean());
+        }
+    }
+
+    #[test]
+    fn test_dust_tiers() {
+        for (name, options) in [
+            ("plain", FlatFileStoreOptions::default()),
+            ("encrypted", encrypted_options(6)),
+        ] {
+            let test_dir = temp_dir(&format!("test_flat_file_store_dust_tiers_{}", name));
+            let options = FlatFileStoreOptions {
+                max_file_size: TEST_MAX_FILE_SIZE,
+                ..options
+            };
+            let open = || {
+                FlatFileStore::initialize_with_options(test_dir.clone(), options.clone()).unwrap()
+            };
+            // Two tiers with fewer tweaks the higher the threshold
+            let tiers_of = |block: &BlockData| {
+                vec![
+                    DustTier {
+                        dust_threshold: 1000,
+                        tweaks: block.tweaks[..2].to_vec(),
+                    },
+                    DustTier {
+                        dust_threshold: 10000,
+                        tweaks: block.tweaks[..1].to_vec(),
+                    },
+                ]
+            };
+            let tier = |block: &BlockData, dust_threshold: u64| BlockData {
+                blockhash: block.blockhash,
+                tweaks: tiers_of(block)
+                    .into_iter()
+                    .find(|tier| tier.dust_threshold == dust_threshold)
+                    .unwrap()
+                    .tweaks,
+                tweak_meta: None,
+            };
+            let read_tier_range = |store: &FlatFileStore, start, end, dust_threshold| {
+                let mut buffer = Vec::new();
+                store
+                    .get_block_stream_range_with_tier(start, end, dust_threshold)
+                    .unwrap()
+                    .read_to_end(&mut buffer)
+                    .unwrap();
+                buffer
+            };
+
+            let mut store = open();
+            let blocks: Vec<BlockData> = (0..20).map(|height| generated_block(height, 4)).collect();
+            for (height, block) in blocks.iter().enumerate() {
+                store
+                    .add_block_with_tiers(block, height as u32, &tiers_of(block))
+                    .unwrap();
+            }
+            // Added again, nothing changes
+            let stats = store.stats().unwrap();
+            store
+                .add_block_with_tiers(&blocks[19], 19, &tiers_of(&blocks[19]))
+                .unwrap();
+            assert_eq!(store.stats().unwrap(), stats);
+            assert_eq!(stats.tweaks, 80);
+
+            let check = |store: &FlatFileStore, blocks: &[BlockData]| {
+                for (height, block) in blocks.iter().enumerate() {
+                    let height = height as u32;
+                    assert_eq!(store.get_block_with_tier(height, None).unwrap(), *block);
+                    let low = store.get_block_with_tier(height, Some(1000)).unwrap();
+                    assert_eq!(low, tier(block, 1000));
+                    assert_eq!(low.tweaks.len(), 2);
+                    let high = store.get_block_with_tier(height, Some(10000)).unwrap();
+                    assert_eq!(high, tier(block, 10000));
+                    assert_eq!(high.tweaks.len(), 1);
+                    assert!(matches!(
+                        store.get_block_with_tier(height, Some(500)),
+                        Err(StorageError::EntryNotFound)
+                    ));
+                }
+                let tip = blocks.len() as u32 - 1;
+                assert_eq!(read_tier_range(store, 0, tip, None), serialized(blocks));
+                let tiered: Vec<BlockData> = blocks.iter().map(|block| tier(block, 1000)).collect();
+                assert_eq!(
+                    read_tier_range(store, 3, tip, Some(1000)),
+                    serialized(&tiered[3..])
+                );
+                assert!(matches!(
+                    store.get_block_stream_range_with_tier(0, tip, Some(500)),
+                    Err(StorageError::EntryNotFound)
+                ));
+                assert!(store.verify().unwrap().is_clean());
+            };
+            check(&store, &blocks);
+            assert!(store.verify().unwrap().dead_space.is_empty());
+            // A block stored without tiers has none to serve
+            let mut blocks = blocks;
+            let untiered = generated_block(20, 4);
+            store.add_block(&untiered, 20).unwrap();
+            assert!(matches!(
+                store.get_block_with_tier(20, Some(1000)),
+                Err(StorageError::EntryNotFound)
+            ));
+            store.remove_tip_block(&untiered.blockhash).unwrap();
+            // Tiers missing from a block below the tip can't be added any more
+            let tier_500 = DustTier {
+                dust_threshold: 500,
+                tweaks: Vec::new(),
+            };
+            assert!(matches!(
+                store.add_block_with_tiers(&blocks[5], 5, &[tier_500]),
+                Err(StorageError::InvalidHeight)
+            ));
+            drop(store);
+            store = open();
+            check(&store, &blocks);
+
+            // Tiers of the tip cut short by a crash are dropped, the block stays
+            let tip_block = generated_block(20, 4);
+            store
+                .add_block_with_tiers(&tip_block, 20, &tiers_of(&tip_block))
+                .unwrap();
+            let (_, last_tier) = store
+                .index
+                .tier_entries(&tip_block.blockhash)
+                .unwrap()
+                .into_iter()
+                .max_by_key(|(_, entry)| (entry.file_number, entry.offset))
+                .unwrap();
+            drop(store);
+            File::options()
+                .write(true)
+                .open(
+                    test_dir
+                        .join(BLOCK_DATA_DIR_NAME)
+                        .join(block_file_name!(last_tier.file_number)),
+                )
+                .unwrap()
+                .set_len(last_tier.offset + last_tier.length - 1)
+                .unwrap();
+            store = open();
+            assert_eq!(store.get_block(20).unwrap(), tip_block);
+            assert!(store
+                .index
+                .tier_entries(&tip_block.blockhash)
+                .unwrap()
+                .is_empty());
+            store
+                .add_block_with_tiers(&tip_block, 20, &tiers_of(&tip_block))
+                .unwrap();
+            assert_eq!(
+                store.get_block_with_tier(20, Some(10000)).unwrap(),
+                tier(&tip_block, 10000)
+            );
+            store.remove_tip_block(&tip_block.blockhash).unwrap();
+            check(&store, &blocks);
+
+            // Reorged blocks take their tiers along, and their bytes are dead
+            let dead_before = store.stats().unwrap().dead_bytes;
+            store.remove_blocks_above(14).unwrap();
+            store.remove_tip_block(&blocks[14].blockhash).unwrap();
+            blocks.truncate(14);
+            for removed in 14..20 {
+                let blockhash = generated_block(removed, 4).blockhash;
+                assert!(store.index.tier_entries(&blockhash).unwrap().is_empty());
+            }
+            // Replaced by the blocks of the new chain
+            for height in 14..18 {
+                let block = generated_block(height + 100, 4);
+                store
+                    .add_block_with_tiers(&block, height, &tiers_of(&block))
+                    .unwrap();
+                blocks.push(block);
+            }
+            let stats = store.stats().unwrap();
+            assert!(stats.dead_bytes > dead_before);
+            assert_eq!(stats.tweaks, 18 * 4);
+            drop(store);
+            store = open();
+            assert_eq!(store.stats().unwrap(), stats);
+
+            // Compaction moves tier records along with the blocks
+            assert!(store.compact(0.0).unwrap() > 0);
+            check(&store, &blocks);
+        }
+    }
+
+    #[test]
+    fn test_replace_block_tweaks() {
+        for (name, options) in [
+            ("plain", FlatFileStoreOptions::default()),
+            ("encrypted", encrypted_options(7)),
+        ] {
+            let test_dir = temp_dir(&format!("test_flat_file_store_replace_tweaks_{}", name));
+            let options = FlatFileStoreOptions {
+                max_file_size: TEST_MAX_FILE_SIZE,
+                ..options
+            };
+            let open = || {
+                FlatFileStore::initialize_with_options(test_dir.clone(), options.clone()).unwrap()
+            };
+            let mut store = open();
+            let mut blocks: Vec<BlockData> =
+                (0..40).map(|height| generated_block(height, 4)).collect();
+            for (height, block) in blocks.iter().enumerate() {
+                store.add_block(block, height as u32).unwrap();
+            }
+            // Planned before the replacement, so it may serve either
+            let mut in_flight = store.get_block_stream_range(5, 10).unwrap();
+            let stats = store.stats().unwrap();
+
+            // Cut-through drops all but one tweak of a block mid-chain
+            let old_entry = store.index.get_block_entry(&blocks[7].blockhash).unwrap();
+            blocks[7].tweaks.truncate(1);
+            store
+                .replace_block_tweaks(&blocks[7].blockhash, blocks[7].tweaks.clone())
+                .unwrap();
+            let check = |store: &FlatFileStore, blocks: &[BlockData]| {
+                assert_eq!(store.get_block(7).unwrap(), blocks[7]);
+                assert_eq!(
+                    store.get_block_by_hash(&blocks[7].blockhash).unwrap(),
+                    blocks[7]
+                );
+                assert_eq!(
+                    store
+                        .index
+                        .get_height_by_blockhash(&blocks[7].blockhash)
+                        .unwrap(),
+                    7
+                );
+                assert_eq!(read_range(store, 5, 10), serialized(&blocks[5..=10]));
+                let tip = blocks.len() as u32 - 1;
+                assert_eq!(read_range(store, 0, tip), serialized(blocks));
+                let report = store.verify().unwrap();
+                assert!(report.is_clean());
+            };
+            check(&store, &blocks);
+            let mut old = Vec::new();
+            in_flight.read_to_end(&mut old).unwrap();
+            assert_eq!(
+                old.len(),
+                serialized(&blocks[5..=10]).len() + 3 * TWEAK_SIZE
+            );
+
+            let replaced = store.stats().unwrap();
+            assert_eq!(replaced.tweaks, stats.tweaks - 3);
+            assert!(replaced.dead_bytes >= old_entry.length);
+            let report = store.verify().unwrap();
+            assert!(report.dead_space.contains(&DeadSpace {
+                file_number: old_entry.file_number,
+                offset: old_entry.offset,
+                length: old_entry.length,
+            }));
+
+            // Replacing the tweaks with the same ones writes nothing
+            let entry = store.index.get_block_entry(&blocks[9].blockhash).unwrap();
+            let mut same = blocks[9].tweaks.clone();
+            same.reverse();
+            store
+                .replace_block_tweaks(&blocks[9].blockhash, same)
+                .unwrap();
+            assert_eq!(
+                store.index.get_block_entry(&blocks[9].blockhash).unwrap(),
+                entry
+            );
+            assert_eq!(store.stats().unwrap(), replaced);
+
+            // Dropping a duplicate tweak is a real change, even though the set stays the same
+            let mut with_duplicate = blocks[9].tweaks.clone();
+            with_duplicate.push(with_duplicate[0]);
+            store
+                .replace_block_tweaks(&blocks[9].blockhash, with_duplicate.clone())
+                .unwrap();
+            assert_eq!(store.get_block(9).unwrap().tweaks, with_duplicate);
+            store
+                .replace_block_tweaks(&blocks[9].blockhash, blocks[9].tweaks.clone())
+                .unwrap();
+            assert_eq!(store.get_block(9).unwrap(), blocks[9]);
+            assert_ne!(
+                store.index.get_block_entry(&blocks[9].blockhash).unwrap(),
+                entry
+            );
+            assert!(matches!(
+                store.replace_block_tweaks(&[9; 32], Vec::new()),
+                Err(StorageError::EntryNotFound)
+            ));
+
+            // The record follows the tip and survives a reopen, and so do blocks added after
+            drop(store);
+            store = open();
+            check(&store, &blocks);
+            let block = generated_block(40, 4);
+            store.add_block(&block, 40).unwrap();
+            blocks.push(block);
+            drop(store);
+            store = open();
+            check(&store, &blocks);
+
+            // A rebuilt index serves the later record at the block's height
+            drop(store);
+            fs::remove_dir_all(test_dir.join(INDEX_DIR_NAME)).unwrap();
+            store = open();
+            check(&store, &blocks);
+            assert_eq!(store.stats().unwrap().blocks, 41);
+
+            // Compaction drops the old record, after which the block's height can't be told
+            // from the block data files any more
+            blocks[12].tweaks.clear();
+            store
+                .replace_block_tweaks(&blocks[12].blockhash, Vec::new())
+                .unwrap();
+            assert!(store.compact(0.0).unwrap() > 0);
+            assert_eq!(store.get_block(12).unwrap(), blocks[12]);
+            check(&store, &blocks);
+            drop(store);
+            fs::remove_dir_all(test_dir.join(INDEX_DIR_NAME)).unwrap();
+            assert!(matches!(
+                FlatFileStore::initialize_with_options(test_dir.clone(), options.clone()),
+                Err(StorageError::CorruptDB(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_tweak_count() {
+        for (name, options) in [
+            ("plain", FlatFileStoreOptions::default()),
+            ("encrypted", encrypted_options(4)),
+            (
+                "compressed",
+                FlatFileStoreOptions {
+                    compression_level: Some(3),
+                    ..Default::default()
+                },
+            ),
+        ] {
+            let store = TestStore::with_options(
+                &format!("test_flat_file_store_tweak_count_{}", name),
+                FlatFileStoreOptions {
+                    max_file_size: TEST_MAX_FILE_SIZE,
+                    ..options
+                },
+            );
+            let mut rng = rand::rng();
+            let blocks: Vec<BlockData> = (0..300)
+                .map(|_| {
+                    let mut block = create_random_block_data();
+                    if rng.random_bool(0.3) {
+                        block.tweak_meta = Some(
+                            (0..block.tweaks.len())
+                                .map(|i| TweakMeta {
+                                    txid: rng.random(),
+                                    vout: i as u32,
+                                    value: rng.random(),
+                                    output_key: rng.random(),
+                                })
+                                .collect(),
+                        );
+                    }
+                    block
+                })
+                .collect();
+            for (height, block) in blocks.iter().enumerate() {
+                store.add_block(block, height as u32).unwrap();
+            }
+
+            let counts = store.tweak_counts(0, 299).unwrap();
+            assert_eq!(counts.len(), 300);
+            for (height, block) in blocks.iter().enumerate() {
+                let stored = store.get_block(height as u32).unwrap();
+                assert_eq!(counts[height] as usize, stored.tweaks.len());
+                assert_eq!(
+                    store.tweak_count(&block.blockhash).unwrap() as usize,
+                    stored.tweaks.len()
+                );
+            }
+            assert_eq!(store.tweak_counts(120, 120).unwrap(), counts[120..=120]);
+            assert!(matches!(
+                store.tweak_counts(290, 300),
+                Err(StorageError::InvalidHeight)
+            ));
+            assert!(matches!(
+                store.tweak_count(&[0u8; 32]),
+                Err(StorageError::EntryNotFound)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_size_of_range() {
+        for (name, options) in [
+            ("plain", FlatFileStoreOptions::default()),
+            ("encrypted", encrypted_options(3)),
+        ] {
+            let test_dir = temp_dir(&format!("test_flat_file_store_size_of_range_{}", name));
+            let options = FlatFileStoreOptions {
+                max_file_size: TEST_MAX_FILE_SIZE,
+                ..options
+            };
+            let open = || {
+                FlatFileStore::initialize_with_options(test_dir.clone(), options.clone()).unwrap()
+            };
+            let mut store = open();
+            let blocks: Vec<BlockData> = (0..30)
+                .map(|height| generated_block(height, height as usize % 5))
+                .collect();
+            for (height, block) in blocks[..10].iter().enumerate() {
+                store.add_block(block, height as u32).unwrap();
+            }
+            let heights: Vec<u32> = (10..30).collect();
+            assert!(store
+                .add_block_bulk(&blocks[10..], &heights)
+                .error
+                .is_none());
+            let check = |store: &FlatFileStore, tip: u32| {
+                for (start, end) in [(0, tip), (0, 0), (3, 17), (tip, tip), (11, tip)] {
+                    assert_eq!(
+                        store.size_of_range(start, end).unwrap(),
+                        read_range(store, start, end).len() as u64
+                    );
+                }
+            };
+            assert!(store.state().current_file_number > 0);
+            check(&store, 29);
+            assert!(matches!(
+                store.size_of_range(20, 30),
+                Err(StorageError::InvalidHeight)
+            ));
+            assert!(matches!(
+                store.size_of_range(8, 7),
+                Err(StorageError::InvalidHeight)
+            ));
+
+            // Reorgs, popped tips and cut-through
+            store.pop_tip().unwrap();
+            check(&store, 28);
+            store.remove_blocks_above(25).unwrap();
+            check(&store, 25);
+            store.add_block(&generated_block(26, 9), 26).unwrap();
+            check(&store, 26);
+            store
+                .replace_block_tweaks(&blocks[13].blockhash, Vec::new())
+                .unwrap();
+            check(&store, 26);
+
+            // Sizes the index lost are counted again from the records
+            store.index.truncate_range_bytes(4).unwrap();
+            check(&store, 26);
+            store.index.truncate_range_bytes(20).unwrap();
+            drop(store);
+            store = open();
+            assert_eq!(store.index.range_bytes_count().unwrap(), 20);
+            check(&store, 26);
+            assert_eq!(store.index.range_bytes_count().unwrap(), 27);
+
+            // Heights are only counted as far as a range needs them, so a bad record past it
+            // doesn't fail the open or the range
+            let entry = store.chain_entry(24).unwrap();
+            store.index.truncate_range_bytes(10).unwrap();
+            drop(store);
+            let file_path = test_dir
+                .join(BLOCK_DATA_DIR_NAME)
+                .join(block_file_name!(entry.file_number));
+            let mut raw = fs::read(&file_path).unwrap();
+            raw[(entry.offset + entry.length) as usize - 1] ^= 1;
+            fs::write(&file_path, raw).unwrap();
+            store = open();
+            assert_eq!(
+                store.size_of_range(3, 17).unwrap(),
+                read_range(&store, 3, 17).len() as u64
+            );
+            assert_eq!(store.index.range_bytes_count().unwrap(), 18);
+            assert!(store.size_of_range(3, 26).is_err());
+        }
+    }
+
+    #[test]
+    fn test_prune_below() {
+        let test_dir = temp_dir("test_flat_file_store_prune");
+        let options = || FlatFileStoreOptions {
+            max_file_size: TEST_MAX_FILE_SIZE,
+            ..Default::default()
+        };
+        let mut store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), options()).unwrap();
+        let blocks: Vec<BlockData> = (0..60).map(|height| generated_block(height, 8)).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        // The first height in each file
+        let first_height_in = |store: &FlatFileStore, file_number| {
+            (store.pruned_up_to()..60)
+                .find(|&height| store.chain_entry(height).unwrap().file_number == file_number)
+                .unwrap()
+        };
+        let keep_from_file = store.chain_entry(30).unwrap().file_number;
+        assert!(keep_from_file >= 2);
+        let kept = first_height_in(&store, keep_from_file);
+        assert!(kept <= 30);
+
+        assert_eq!(store.prune_below(30).unwrap(), kept);
+        let file_exists = |file_number: u64| {
+            test_dir
+                .join(BLOCK_DATA_DIR_NAME)
+                .join(block_file_name!(file_number))
+                .exists()
+        };
+        let check_pruned = |store: &FlatFileStore| {
+            assert_eq!(store.pruned_up_to(), kept);
+            assert!((0..keep_from_file).all(|file_number| !file_exists(file_number)));
+            assert!((keep_from_file..=store.state().current_file_number).all(file_exists));
+            assert!(matches!(
+                store.get_block(kept - 1),
+                Err(StorageError::Pruned)
+            ));
+            assert!(matches!(
+                store.get_block_by_hash(&blocks[0].blockhash),
+                Err(StorageError::Pruned)
+            ));
+            assert!(matches!(
+                store.get_block_stream(&blocks[kept as usize - 1].blockhash),
+                Err(StorageError::Pruned)
+            ));
+            assert!(matches!(
+                store.get_block_stream_range(kept - 1, 40),
+                Err(StorageError::Pruned)
+            ));
+            assert_eq!(&store.get_block(kept).unwrap(), &blocks[kept as usize]);
+            assert_eq!(
+                read_range(store, kept, 59),
+                serialized(&blocks[kept as usize..])
+            );
+            let iterated: Vec<u32> = store.iter_blocks().map(|item| item.unwrap().0).collect();
+            assert_eq!(iterated, (kept..60).collect::<Vec<_>>());
+        };
+        check_pruned(&store);
+
+        // Pruning no further is a no-op, reorgs into pruned heights are refused
+        assert_eq!(store.prune_below(10).unwrap(), kept);
+        assert!(matches!(
+            store.remove_blocks_above(kept - 2),
+            Err(StorageError::Pruned)
+        ));
+
+        drop(store);
+        let store = FlatFileStore::initialize_with_options(test_dir.clone(), options()).unwrap();
+        check_pruned(&store);
+        let report = store.verify().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.blocks_checked, 60 - kept);
+        assert!(report.dead_space.is_empty());
+        let block = generated_block(60, 3);
+        store.add_block(&block, 60).unwrap();
+        assert_eq!(store.get_block(60).unwrap(), block);
+
+        // A prune interrupted after recording its state is finished on open
+        let next_file = keep_from_file + 1;
+        let next_kept = first_height_in(&store, next_file);
+        let mut state = next_file.to_le_bytes().to_vec();
+        state.extend_from_slice(&next_kept.to_le_bytes());
+        store.index.set_meta(PRUNED_META_KEY, &state).unwrap();
+        drop(store);
+        let store = FlatFileStore::initialize_with_options(test_dir.clone(), options()).unwrap();
+        assert!(!file_exists(keep_from_file));
+        assert!(matches!(
+            store
+                .index
+                .get_block_entry(&blocks[kept as usize].blockhash),
+            Err(StorageError::EntryNotFound)
+        ));
+        assert!(matches!(store.get_block(kept), Err(StorageError::Pruned)));
+        assert_eq!(
+            &store.get_block(next_kept).unwrap(),
+            &blocks[next_kept as usize]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        // The source is encrypted, snapshots aren't
+        let source =
+            TestStore::with_options("test_flat_file_store_snapshot_source", encrypted_options(7));
+        let blocks: Vec<BlockData> = (0..3000).map(|_| create_random_block_data()).collect();
+        let heights: Vec<u32> = (0..3000).collect();
+        source
+            .add_block_bulk(&blocks, &heights)
+            .into_result()
+            .unwrap();
+        source.index.set_meta(NETWORK_META_KEY, b"signet").unwrap();
+        let mut snapshot = Vec::new();
+        assert_eq!(source.export_snapshot(&mut snapshot).unwrap(), 3000);
+
+        let test_dir = temp_dir("test_flat_file_store_snapshot_import");
+        let imported_dir = test_dir.join("imported");
+        let imported = FlatFileStore::import_snapshot(imported_dir.clone(), &snapshot[..]).unwrap();
+        assert_eq!(imported.get_current_height(), 2999);
+        assert_eq!(
+            imported
+                .index
+                .get_meta(NETWORK_META_KEY)
+                .unwrap()
+                .as_deref(),
+            Some(&b"signet"[..])
+        );
+        for (height, block) in blocks.iter().enumerate() {
+            assert_eq!(&imported.get_block(height as u32).unwrap(), block);
+        }
+        assert_eq!(read_range(&imported, 0, 2999), read_range(&source, 0, 2999));
+        assert!(imported.verify().unwrap().is_clean());
+
+        // Only into an empty directory
+        assert!(matches!(
+            FlatFileStore::import_snapshot(imported_dir.clone(), &snapshot[..]),
+            Err(StorageError::InvalidData(_))
+        ));
+        drop(imported);
+
+        // A flipped tweak byte fails the import, and leaves the directory empty again
+        let header_len = 4 + 2 + 1 + b"signet".len() + 4;
+        let mut offset = header_len;
+        for block in &blocks[..1500] {
+            offset += 4 + block.serialize().len();
+        }
+        let mut corrupt = snapshot.clone();
+        corrupt[offset + 4 + blocks[1500].serialize().len() - 1] ^= 0xff;
+        let empty_dir = test_dir.join("empty");
+        fs::create_dir(&empty_dir).unwrap();
+        assert!(matches!(
+            FlatFileStore::import_snapshot(empty_dir.clone(), &corrupt[..]),
+            Err(StorageError::CrcMismatch)
+        ));
+        assert_eq!(fs::read_dir(&empty_dir).unwrap().count(), 0);
+
+        // So does a snapshot that was cut short
+        let truncated_dir = test_dir.join("truncated");
+        assert!(matches!(
+            FlatFileStore::import_snapshot(truncated_dir.clone(), &snapshot[..offset]),
+            Err(StorageError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        assert!(!truncated_dir.exists());
+
+        // Nor does a snapshot of another network than the store is opened for
+        let mainnet_dir = test_dir.join("mainnet");
+        let options = FlatFileStoreOptions {
+            network: Some("mainnet".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            FlatFileStore::import_snapshot_with_options(mainnet_dir.clone(), &snapshot[..], options),
+            Err(StorageError::NetworkMismatch { stored, requested })
+                if stored == "signet" && requested == "mainnet"
+        ));
+        assert!(!mainnet_dir.exists());
+    }
+
+    #[test]
+    fn test_network() {
+        let test_dir = temp_dir("test_flat_file_store_network");
+        let open = |network: Option<&str>| {
+            let options = FlatFileStoreOptions {
+                network: network.map(str::to_string),
+                ..Default::default()
+            };
+            FlatFileStore::initialize_with_options(test_dir.to_path_buf(), options)
+        };
+        let stored_network =
+            |store: &FlatFileStore| store.index.get_meta(NETWORK_META_KEY).unwrap();
+
+        // A store that doesn't record its network takes the one it is opened for
+        let store = open(None).unwrap();
+        store.add_block(&generated_block(0, 2), 0).unwrap();
+        assert_eq!(stored_network(&store), None);
+        drop(store);
+        let store = open(Some("signet")).unwrap();
+        assert_eq!(stored_network(&store).as_deref(), Some(&b"signet"[..]));
+        drop(store);
+
+        assert!(open(Some("signet")).is_ok());
+        assert!(open(None).is_ok());
+        assert!(matches!(
+            open(Some("mainnet")),
+            Err(StorageError::NetworkMismatch { stored, requested })
+                if stored == "signet" && requested == "mainnet"
+        ));
+        // Refused before anything changed
+        let store = open(Some("signet")).unwrap();
+        assert_eq!(store.get_block(0).unwrap(), generated_block(0, 2));
+        assert_eq!(stored_network(&store).as_deref(), Some(&b"signet"[..]));
+
+        // A new store records its network right away
+        let new_dir = temp_dir("test_flat_file_store_network_new");
+        let options = FlatFileStoreOptions {
+            network: Some("regtest".to_string()),
+            ..Default::default()
+        };
+        let store = FlatFileStore::initialize_with_options(new_dir.to_path_buf(), options).unwrap();
+        assert_eq!(stored_network(&store).as_deref(), Some(&b"regtest"[..]));
+    }
+
+    #[test]
+    fn test_concurrent_readers() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<FlatFileStore>();
+
+        let store = Arc::new(TestStore::new("test_flat_file_store_concurrent"));
+        let expected = |height: u32| generated_block(height, 1 + height as usize % 4);
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                let done = Arc::clone(&done);
+                thread::spawn(move || {
+                    let mut rng = rand::rng();
+                    while !done.load(Ordering::Acquire) {
+                        let tip = store.get_current_height();
+                        if tip < 0 {
+                            continue;
+                        }
+                        let tip = tip as u32;
+                        let height = rng.random_range(0..=tip);
+                        assert_eq!(store.get_block(height).unwrap(), expected(height));
+
+                        let start = height.saturating_sub(20);
+                        let mut served = Vec::new();
+                        store
+                            .get_block_stream_range(start, height)
+                            .unwrap()
+                            .read_to_end(&mut served)
+                            .unwrap();
+                        let range: Vec<u8> = (start..=height)
+                            .flat_map(|h| expected(h).serialize())
+                            .collect();
+                        assert_eq!(served, range);
+
+                        // An open-ended stream stops at the data written when it was opened,
+                        // even with blocks being appended and files started behind it
+                        let start = tip.saturating_sub(20);
+                        served.clear();
+                        store
+                            .get_block_stream_from_height(start)
+                            .unwrap()
+                            .read_to_end(&mut served)
+                            .unwrap();
+                        let mut blocks = Vec::new();
+                        let mut h = start;
+                        while blocks.len() < served.len() {
+                            blocks.extend(expected(h).serialize());
+                            h += 1;
+                        }
+                        assert!(h > tip);
+                        assert_eq!(served, blocks);
+                    }
+                })
+            })
+            .collect();
+
+        store.add_blocks(10_000);
+        done.store(true, Ordering::Release);
+        for reader in readers {
+            reader.join().unwrap();
         }
+        assert_eq!(store.get_current_height(), 9_999);
+        assert!(store.verify().unwrap().is_clean());
+    }
 
-        store.add_block_bulk(&blocks, &heights).unwrap();
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_block_range_mmap() {
+        let store = TestStore::new("test_flat_file_store_mmap");
+        let blocks = store.add_blocks(200);
+        let file_path = |file_number| store.block_data_dir.join(block_file_name!(file_number));
 
-        // Read and verify each block
-        for (i, original_block) in blocks.iter().enumerate() {
-            let height = i as u32;
-            let mut reader = store.get_block_stream_from_height(height).unwrap();
-            let mut buffer = Vec::new();
-            reader.read_to_end(&mut buffer).unwrap();
+        // The range ends in the current file, which is read rather than mapped
+        let slices = store.get_block_range_mmap(10, 199).unwrap();
+        assert_eq!(slices.len(), 190);
+        for (slice, block) in slices.iter().zip(&blocks[10..]) {
+            assert_eq!(&slice[..], &block.serialize()[..]);
+        }
+        let mut streamed = Vec::new();
+        store
+            .get_block_stream_range(10, 199)
+            .unwrap()
+            .read_to_end(&mut streamed)
+            .unwrap();
+        assert_eq!(
+            slices
+                .iter()
+                .map(|slice| &slice[..])
+                .collect::<Vec<_>>()
+                .concat(),
+            streamed
+        );
 
-            let read_block = BlockData::deserialize(&buffer).unwrap();
-            assert_eq!(original_block.blockhash, read_block.blockhash);
-            assert_eq!(original_block.tweaks, read_block.tweaks);
+        // Popping back into a mapped file leaves its bytes alone while the slices are around
+        let mapped_file = store.state().current_file_number - 1;
+        let mapped_len = fs::metadata(file_path(mapped_file)).unwrap().len();
+        while store.state().current_file_number > mapped_file {
+            store.pop_tip().unwrap();
         }
+        let popped = store.pop_tip().unwrap();
+        assert_eq!(
+            fs::metadata(file_path(mapped_file)).unwrap().len(),
+            mapped_len
+        );
+        let height = blocks.iter().position(|block| *block == popped).unwrap();
+        assert_eq!(&slices[height - 10][..], &popped.serialize()[..]);
+        drop(slices);
 
-        // Clean up
-        let _ = fs::remove_dir_all(test_dir);
+        assert!(matches!(
+            store.get_block_range_mmap(5, 0),
+            Err(StorageError::InvalidHeight)
+        ));
+        let encrypted =
+            TestStore::with_options("test_flat_file_store_mmap_encrypted", encrypted_options(3));
+        encrypted.add_blocks(1);
+        assert!(matches!(
+            encrypted.get_block_range_mmap(0, 0),
+            Err(StorageError::EncryptionError(_))
+        ));
+
+        // Mapped records are checksummed before they are handed out
+        let entry = store.index.get_block_entry(&blocks[3].blockhash).unwrap();
+        assert!(entry.file_number < mapped_file);
+        let mut raw = fs::read(file_path(entry.file_number)).unwrap();
+        raw[(entry.offset + entry.length) as usize - 1] ^= 1;
+        fs::write(file_path(entry.file_number), raw).unwrap();
+        assert!(matches!(
+            store.get_block_range_mmap(2, 4),
+            Err(StorageError::CrcMismatch)
+        ));
+        assert_eq!(store.get_block_range_mmap(4, 6).unwrap().len(), 3);
     }
 
     #[test]
-    fn test_cross_file_boundary() {
-        let test_dir = temp_dir("test_flat_file_store_boundary");
+    fn test_durability_policy() {
+        let test_dir = temp_dir("test_flat_file_store_durability");
+        let open = |name: &str, durability| {
+            let options = FlatFileStoreOptions {
+                durability,
+                ..Default::default()
+            };
+            FlatFileStore::initialize_with_options(test_dir.join(name), options)
+        };
+        // What reached the file, as opposed to what is still buffered
+        let on_disk = |store: &FlatFileStore| {
+            fs::metadata(store.block_data_dir.join(block_file_name!(0)))
+                .unwrap()
+                .len()
+        };
 
-        // Initialize store
-        let mut store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let store = open("every", DurabilityPolicy::EveryNBlocks(3)).unwrap();
+        for height in 0..2 {
+            store
+                .add_block(&generated_block(height, 2), height)
+                .unwrap();
+        }
+        assert_eq!(on_disk(&store), store.header_len());
+        store.add_block(&generated_block(2, 2), 2).unwrap();
+        assert_eq!(on_disk(&store), store.state().write_offset);
+        // Bulk adds count every block
+        let blocks: Vec<BlockData> = (3..6).map(|height| generated_block(height, 2)).collect();
+        store
+            .add_block_bulk(&blocks, &[3, 4, 5])
+            .into_result()
+            .unwrap();
+        assert_eq!(on_disk(&store), store.state().write_offset);
 
-        // Create a large block with many tweaks to make it bigger
-        let mut large_block = create_random_block_data();
-        let mut rng = rand::rng();
-        for _ in 0..100 {
-            let mut tweak = [0u8; TWEAK_SIZE];
-            for i in 0..TWEAK_SIZE {
-                tweak[i] = rng.random();
+        let store = open("always", DurabilityPolicy::Always).unwrap();
+        store.add_block(&generated_block(0, 2), 0).unwrap();
+        assert_eq!(on_disk(&store), store.state().write_offset);
+
+        let store = open("never", DurabilityPolicy::Never).unwrap();
+        store.add_block(&generated_block(0, 2), 0).unwrap();
+        assert_eq!(on_disk(&store), store.header_len());
+        store.flush().unwrap();
+        assert_eq!(on_disk(&store), store.state().write_offset);
+
+        assert!(matches!(
+            open("zero", DurabilityPolicy::EveryNBlocks(0)),
+            Err(StorageError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let options = FlatFileStoreOptions {
+            block_cache_size: 1 << 20,
+            ..Default::default()
+        };
+        let store = TestStore::with_options("test_flat_file_store_cache", options);
+        let blocks = store.add_blocks(20);
+
+        let read = store.get_block_by_hash(&blocks[5].blockhash).unwrap();
+        assert_eq!(read.tweaks, blocks[5].tweaks);
+        assert_eq!(store.get_block_by_hash(&blocks[5].blockhash).unwrap(), read);
+        assert_eq!(
+            (store.cache_stats().hits, store.cache_stats().misses),
+            (1, 1)
+        );
+
+        // Ranges are served from the cache where they can be, and cache what they read
+        let mut range = Vec::new();
+        store
+            .get_block_stream_range(4, 6)
+            .unwrap()
+            .read_to_end(&mut range)
+            .unwrap();
+        let expected: Vec<u8> = blocks[4..=6].iter().flat_map(|b| b.serialize()).collect();
+        assert_eq!(range, expected);
+        assert_eq!(store.cache_stats().hits, 2);
+        assert_eq!(store.cache_stats().blocks, 3);
+
+        // Removed blocks are dropped from the cache
+        store.get_block_by_hash(&blocks[19].blockhash).unwrap();
+        store.remove_tip_block(&blocks[19].blockhash).unwrap();
+        assert!(matches!(
+            store.get_block_by_hash(&blocks[19].blockhash),
+            Err(StorageError::OrphanedEntry)
+        ));
+        store.pop_tip().unwrap();
+        store.remove_blocks_above(5).unwrap();
+        assert!(store.cache.get(&blocks[6].blockhash).is_none());
+        assert_eq!(store.cache_stats().blocks, 2);
+
+        // Streaming a dust tier doesn't cache it as the whole block
+        let block = generated_block(6, 6);
+        let tier = DustTier {
+            dust_threshold: 1000,
+            tweaks: block.tweaks[..2].to_vec(),
+        };
+        store.add_block_with_tiers(&block, 6, &[tier]).unwrap();
+        let mut range = Vec::new();
+        store
+            .get_block_stream_range_with_tier(6, 6, Some(1000))
+            .unwrap()
+            .read_to_end(&mut range)
+            .unwrap();
+        assert_eq!(BlockData::deserialize(&range).unwrap().tweaks.len(), 2);
+        assert_eq!(store.get_block(6).unwrap(), block);
+
+        // Nor does a stream planned before the tweaks of a block were replaced cache the old
+        // ones
+        let mut stale = store.get_block_stream_range(2, 3).unwrap();
+        store
+            .replace_block_tweaks(&blocks[3].blockhash, blocks[3].tweaks[..1].to_vec())
+            .unwrap();
+        stale.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(store.get_block(3).unwrap().tweaks, blocks[3].tweaks[..1]);
+        assert!(store.cache.get(&blocks[2].blockhash).is_some());
+    }
+
+    #[test]
+    fn test_tweak_meta_store() {
+        for (name, options) in [
+            ("plain", FlatFileStoreOptions::default()),
+            ("encrypted", encrypted_options(2)),
+            (
+                "cached",
+                FlatFileStoreOptions {
+                    block_cache_size: 1 << 20,
+                    ..Default::default()
+                },
+            ),
+        ] {
+            let store = TestStore::with_options(
+                &format!("test_flat_file_store_tweak_meta_{}", name),
+                options,
+            );
+            // Every other block carries metadata
+            let blocks: Vec<BlockData> = (0..30)
+                .map(|height| {
+                    let mut block = generated_block(height, 5 + height as usize % 4);
+                    if height % 2 == 0 {
+                        block.tweak_meta = Some(
+                            (0..block.tweaks.len())
+                                .map(|i| TweakMeta {
+                                    txid: [height as u8; 32],
+                                    vout: i as u32,
+                                    value: 546 + i as u64,
+                                    output_key: [i as u8; 32],
+                                })
+                                .collect(),
+                        );
+                    }
+                    block
+                })
+                .collect();
+            store
+                .add_block_bulk(&blocks[..20], &(0..20).collect::<Vec<_>>())
+                .into_result()
+                .unwrap();
+            for (height, block) in (20..).zip(&blocks[20..]) {
+                store.add_block(block, height).unwrap();
             }
-            large_block.tweaks.push(tweak);
+
+            assert_eq!(store.get_block(4).unwrap(), blocks[4]);
+            assert_eq!(store.get_block(5).unwrap(), blocks[5]);
+            assert_eq!(read_chain(&store), blocks);
+            assert_eq!(read_range(&store, 0, 29), serialized(&blocks));
+            assert_eq!(
+                store.size_of_range(0, 29).unwrap(),
+                serialized(&blocks).len() as u64
+            );
+
+            // Twice, the second time from the cache if there is one
+            let compact: Vec<BlockData> = blocks
+                .iter()
+                .map(|block| BlockData {
+                    blockhash: block.blockhash,
+                    tweaks: block.tweaks.clone(),
+                    tweak_meta: None,
+                })
+                .collect();
+            for _ in 0..2 {
+                let mut buffer = Vec::new();
+                store
+                    .get_block_stream_range(3, 17)
+                    .unwrap()
+                    .tweaks_only()
+                    .read_to_end(&mut buffer)
+                    .unwrap();
+                assert_eq!(buffer, serialized(&compact[3..=17]));
+            }
+
+            // Metadata has to match the tweaks
+            let mut mismatched = generated_block(30, 3);
+            mismatched.tweak_meta = Some(Vec::new());
+            assert!(matches!(
+                store.add_block(&mismatched, 30),
+                Err(StorageError::InvalidData(_))
+            ));
+            let result = store.add_block_bulk(&[mismatched], &[30]);
+            assert!(matches!(
+                result.error,
+                Some((30, StorageError::InvalidData(_)))
+            ));
+            assert_eq!(store.get_current_height(), 29);
         }
+    }
 
-        // Add the block 10,000 times
-        for height in 0..10_000 {
-            store.add_block(&large_block, height).unwrap();
+    #[test]
+    fn test_canonical_tweaks() {
+        let test_dir = temp_dir("test_flat_file_store_canonical_tweaks");
+        let options = |canonical_tweaks| FlatFileStoreOptions {
+            canonical_tweaks,
+            max_file_size: TEST_MAX_FILE_SIZE,
+            ..Default::default()
+        };
+        // Tweaks out of order, and one of them twice
+        let block = |height| {
+            let mut block = generated_block(height, 6);
+            block.tweaks.push(block.tweaks[0]);
+            block
+        };
+        let canonical = |mut block: BlockData| {
+            block.canonicalize();
+            block
+        };
+
+        let store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), options(false)).unwrap();
+        for height in 0..3 {
+            store.add_block(&block(height), height).unwrap();
         }
+        assert_eq!(store.get_block(1).unwrap(), block(1));
+        drop(store);
+
+        let store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), options(true)).unwrap();
+        store.add_block(&block(3), 3).unwrap();
+        let bulk: Vec<BlockData> = (4..8).map(block).collect();
+        store
+            .add_block_bulk(&bulk, &[4, 5, 6, 7])
+            .into_result()
+            .unwrap();
+        for height in 3..8 {
+            let stored = store.get_block(height).unwrap();
+            assert!(stored.is_canonical());
+            assert_eq!(stored, canonical(block(height)));
+        }
+        // Counted as stored, one tweak less
+        let range: u64 = (0..8)
+            .map(|height| store.get_block(height).unwrap().serialized_len())
+            .sum();
+        assert_eq!(store.size_of_range(0, 7).unwrap(), range);
+        assert_eq!(store.stats().unwrap().tweaks, 3 * 7 + 5 * 6);
+
+        let mut replacement = block(2).tweaks;
+        replacement.reverse();
+        store
+            .replace_block_tweaks(&block(2).blockhash, replacement)
+            .unwrap();
+        assert_eq!(store.get_block(2).unwrap(), canonical(block(2)));
+
+        // Blocks from before the option are still there as they were, and verify lists them
+        let report = store.verify().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.non_canonical_heights, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_compression() {
+        let test_dir = temp_dir("test_flat_file_store_compression");
+        let open = |compression_level, encryption_key| {
+            let options = FlatFileStoreOptions {
+                compression_level,
+                encryption_key,
+                max_file_size: TEST_MAX_FILE_SIZE,
+                ..Default::default()
+            };
+            FlatFileStore::initialize_with_options(test_dir.to_path_buf(), options)
+        };
+        let file_version = |store: &FlatFileStore, height| {
+            let entry = store.chain_entry(height).unwrap();
+            let raw = fs::read(
+                store
+                    .block_data_dir
+                    .join(block_file_name!(entry.file_number)),
+            )
+            .unwrap();
+            (entry, u16::from_le_bytes([raw[4], raw[5]]))
+        };
+        let blocks: Vec<BlockData> = (0..90).map(|height| generated_block(height, 12)).collect();
+        let add = |store: &FlatFileStore, heights: std::ops::Range<u32>| {
+            for height in heights {
+                store.add_block(&blocks[height as usize], height).unwrap();
+            }
+        };
+
+        // Uncompressed, compressed, then uncompressed again: every switch starts a new file
+        add(&open(None, None).unwrap(), 0..30);
+        add(&open(Some(3), None).unwrap(), 30..60);
+        let store = open(None, None).unwrap();
+        add(&store, 60..90);
+        let (before, version) = file_version(&store, 29);
+        assert_eq!(version, FRAMED_FORMAT_VERSION);
+        let (compressed, version) = file_version(&store, 30);
+        assert_eq!(version, COMPRESSED_FORMAT_VERSION);
+        assert_eq!(compressed.file_number, before.file_number + 1);
+        // Index entries keep describing what is on disk
+        assert!(compressed.length < before.length);
+        let (after, version) = file_version(&store, 60);
+        assert_eq!(version, FRAMED_FORMAT_VERSION);
+        assert!(after.file_number > file_version(&store, 59).0.file_number);
+
+        for (height, block) in blocks.iter().enumerate() {
+            assert_eq!(store.get_block(height as u32).unwrap(), *block);
+        }
+        let mut streamed = Vec::new();
+        store
+            .get_block_stream_range(0, 89)
+            .unwrap()
+            .read_to_end(&mut streamed)
+            .unwrap();
+        let serialized: Vec<u8> = blocks.iter().flat_map(|block| block.serialize()).collect();
+        assert_eq!(streamed, serialized);
+        #[cfg(feature = "mmap")]
+        {
+            let slices = store.get_block_range_mmap(0, 89).unwrap();
+            let mapped: Vec<&[u8]> = slices.iter().map(|slice| &slice[..]).collect();
+            assert_eq!(mapped.concat(), serialized);
+        }
+        assert!(store.verify().unwrap().is_clean());
+        drop(store);
+
+        // The index can be rebuilt from a mix of files
+        fs::remove_dir_all(test_dir.join(INDEX_DIR_NAME)).unwrap();
+        let store = open(Some(3), None).unwrap();
+        assert_eq!(store.get_current_height(), 89);
+        assert_eq!(store.get_block(45).unwrap(), blocks[45]);
+        drop(store);
+
+        assert!(matches!(
+            open(Some(100), None),
+            Err(StorageError::InvalidData(_))
+        ));
+
+        // Records are compressed before they are encrypted
+        let key = EncryptionKey::from_bytes([9; 32]);
+        fs::remove_dir_all(&*test_dir).unwrap();
+        let store = open(Some(3), Some(key)).unwrap();
+        add(&store, 0..30);
+        assert_eq!(file_version(&store, 29).1, COMPRESSED_FORMAT_VERSION);
+        for (height, block) in blocks[..30].iter().enumerate() {
+            assert_eq!(store.get_block(height as u32).unwrap(), *block);
+        }
+    }
+
+    #[test]
+    fn test_preallocation() {
+        let test_dir = temp_dir("test_flat_file_store_preallocate");
+        let open = |preallocate| {
+            let options = FlatFileStoreOptions {
+                preallocate,
+                max_file_size: TEST_MAX_FILE_SIZE,
+                ..Default::default()
+            };
+            FlatFileStore::initialize_with_options(test_dir.to_path_buf(), options).unwrap()
+        };
+        let file_len = |store: &FlatFileStore, file_number| {
+            fs::metadata(store.block_data_dir.join(block_file_name!(file_number)))
+                .unwrap()
+                .len()
+        };
+        let blocks: Vec<BlockData> = (0..60).map(|height| generated_block(height, 3)).collect();
+        let add = |store: &FlatFileStore, heights: std::ops::Range<u32>| {
+            for height in heights {
+                store.add_block(&blocks[height as usize], height).unwrap();
+            }
+        };
+
+        let store = open(true);
+        assert_eq!(file_len(&store, 0), TEST_MAX_FILE_SIZE);
+        add(&store, 0..40);
+        let current_file = store.state().current_file_number;
+        assert!(current_file > 0);
+        assert_eq!(file_len(&store, current_file), TEST_MAX_FILE_SIZE);
+        // Files before the current one are cut back to the end of their data
+        let last_in_file_0 = (0..40)
+            .map(|height| store.chain_entry(height).unwrap())
+            .filter(|entry| entry.file_number == 0)
+            .last()
+            .unwrap();
+        assert_eq!(
+            file_len(&store, 0),
+            last_in_file_0.offset + last_in_file_0.length
+        );
+        let mut streamed = Vec::new();
+        store
+            .get_block_stream_range(0, 39)
+            .unwrap()
+            .read_to_end(&mut streamed)
+            .unwrap();
+        let serialized: Vec<u8> = blocks[..40].iter().flat_map(|b| b.serialize()).collect();
+        assert_eq!(streamed, serialized);
+        assert!(store.verify().unwrap().is_clean());
+        let data_end = store.state().write_offset;
+        drop(store);
+
+        // A write cut short by a crash, in the preallocated space
+        let file_path = test_dir
+            .join(BLOCK_DATA_DIR_NAME)
+            .join(block_file_name!(current_file));
+        let mut file = File::options().write(true).open(&file_path).unwrap();
+        file.seek(SeekFrom::Start(data_end)).unwrap();
+        file.write_all(&frame_record(&[0xab; 200])[..100]).unwrap();
+        drop(file);
+
+        // The next block lands where the data ended, not at the end of the file
+        let store = open(true);
+        add(&store, 40..41);
+        let entry = store.chain_entry(40).unwrap();
+        assert_eq!((entry.file_number, entry.offset), (current_file, data_end));
+        assert_eq!(file_len(&store, current_file), TEST_MAX_FILE_SIZE);
+        assert_eq!(store.get_block(40).unwrap(), blocks[40]);
+        let data_end = store.state().write_offset;
+        drop(store);
+
+        // Rebuilding the index stops at the preallocated space
+        fs::remove_dir_all(test_dir.join(INDEX_DIR_NAME)).unwrap();
+        let store = open(true);
+        assert_eq!(store.get_current_height(), 40);
+        assert_eq!(store.state().write_offset, data_end);
+        add(&store, 41..42);
+        assert_eq!(store.chain_entry(41).unwrap().offset, data_end);
+        drop(store);
+
+        // Without preallocation the file ends with its data again
+        let store = open(false);
+        let data_end = store.state().write_offset;
+        assert_eq!(file_len(&store, current_file), data_end);
+        add(&store, 42..60);
+        for (height, block) in blocks.iter().enumerate() {
+            assert_eq!(store.get_block(height as u32).unwrap(), *block);
+        }
+    }
+
+    #[test]
+    fn test_data_dir_lock() {
+        let test_dir = temp_dir("test_flat_file_store_lock");
+        let store = FlatFileStore::initialize(test_dir.to_path_buf()).unwrap();
+        assert!(matches!(
+            FlatFileStore::initialize(test_dir.to_path_buf()),
+            Err(StorageError::AlreadyLocked(path)) if path == test_dir.join(LOCK_FILE_NAME)
+        ));
+
+        drop(store);
+        assert!(FlatFileStore::initialize(test_dir.to_path_buf()).is_ok());
+    }
+
+    /// A store with `count` random blocks, closed again.
+    fn store_with_blocks(test_dir: &Path, count: u32) -> Vec<BlockData> {
+        let store = FlatFileStore::initialize(test_dir.to_path_buf()).unwrap();
+        let blocks: Vec<BlockData> = (0..count).map(|_| create_random_block_data()).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        blocks
+    }
+
+    fn block_end(store: &FlatFileStore, block: &BlockData) -> u64 {
+        let entry = store.index.get_block_entry(&block.blockhash).unwrap();
+        entry.offset + entry.length
+    }
+
+    #[test]
+    fn test_recover_junk_after_tip() {
+        let test_dir = temp_dir("test_flat_file_store_recover_junk");
+        let blocks = store_with_blocks(&test_dir, 5);
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        let size = fs::metadata(&file_path).unwrap().len();
+
+        // A write the index never heard of was cut short
+        let partial = create_random_block_data().serialize();
+        let mut file = File::options().append(true).open(&file_path).unwrap();
+        file.write_all(&partial[..partial.len() / 2]).unwrap();
+        drop(file);
+
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(store.index.get_current_height(), 4);
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), size);
+        assert_eq!(read_chain(&store), blocks);
+
+        let block = create_random_block_data();
+        store.add_block(&block, 5).unwrap();
+        assert_eq!(
+            store
+                .index
+                .get_block_entry(&block.blockhash)
+                .unwrap()
+                .offset,
+            size
+        );
+
+        drop(store);
+    }
+
+    #[test]
+    fn test_recover_incomplete_tip() {
+        let test_dir = temp_dir("test_flat_file_store_recover_incomplete");
+        let blocks = store_with_blocks(&test_dir, 5);
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        let size = fs::metadata(&file_path).unwrap().len();
+
+        // The tip only made it to disk partially
+        File::options()
+            .write(true)
+            .open(&file_path)
+            .unwrap()
+            .set_len(size - 10)
+            .unwrap();
+
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(store.index.get_current_height(), 3);
+        assert_eq!(
+            fs::metadata(&file_path).unwrap().len(),
+            block_end(&store, &blocks[3])
+        );
+        assert!(matches!(
+            store.index.get_block_entry(&blocks[4].blockhash),
+            Err(StorageError::EntryNotFound)
+        ));
+        assert_eq!(read_chain(&store), blocks[..4]);
+
+        // The lost block can simply be added again
+        store.add_block(&blocks[4], 4).unwrap();
+        assert_eq!(read_chain(&store), blocks);
+
+        drop(store);
+    }
+
+    #[test]
+    fn test_recover_corrupt_tip() {
+        let test_dir = temp_dir("test_flat_file_store_recover_corrupt");
+        let blocks = store_with_blocks(&test_dir, 5);
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+
+        // The tip is all there but fails its CRC
+        let mut raw = fs::read(&file_path).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        fs::write(&file_path, raw).unwrap();
+
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(store.index.get_current_height(), 3);
+        assert_eq!(read_chain(&store), blocks[..4]);
+
+        drop(store);
+    }
+
+    #[test]
+    fn test_recover_lost_records_across_files() {
+        let test_dir = temp_dir("test_flat_file_store_recover_lost");
+        let store = FlatFileStore::initialize_with_options(
+            test_dir.clone(),
+            FlatFileStoreOptions {
+                max_file_size: TEST_MAX_FILE_SIZE,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let blocks: Vec<BlockData> = (0..30).map(|_| create_random_block_data()).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        let last_file = store.state().current_file_number;
+        assert!(last_file >= 1);
+        let first_in_last_file = blocks
+            .iter()
+            .position(|block| {
+                store
+                    .index
+                    .get_block_entry(&block.blockhash)
+                    .unwrap()
+                    .file_number
+                    == last_file
+            })
+            .unwrap();
+        drop(store);
+
+        // Nothing written to the last file survived the crash
+        let file_path = test_dir
+            .join(BLOCK_DATA_DIR_NAME)
+            .join(block_file_name!(last_file));
+        File::options()
+            .write(true)
+            .open(&file_path)
+            .unwrap()
+            .set_len(HEADER_PREFIX_SIZE as u64)
+            .unwrap();
+
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(
+            store.index.get_current_height(),
+            first_in_last_file as i32 - 1
+        );
+        assert_eq!(read_chain(&store), blocks[..first_in_last_file]);
+        store.index.check_consistency().unwrap();
+
+        drop(store);
+    }
+
+    /// Adds `block` at `height` to the store in `test_dir`, then takes it back out of the index
+    /// and leaves its intent in the journal, as a crash between the append and the index
+    /// insert would. Returns the record's entry.
+    fn crash_before_indexing(test_dir: &Path, block: &BlockData, height: u32) -> IndexEntry {
+        let store = FlatFileStore::initialize(test_dir.to_path_buf()).unwrap();
+        store.add_block(block, height).unwrap();
+        let entry = store.index.get_block_entry(&block.blockhash).unwrap();
+        store.index.discard_tip(&block.blockhash).unwrap();
+        drop(store);
+        write_intent(test_dir, block, height, &entry);
+        entry
+    }
+
+    fn write_intent(test_dir: &Path, block: &BlockData, height: u32, entry: &IndexEntry) {
+        let (mut journal, _) = Journal::open(test_dir).unwrap();
+        journal
+            .begin(&Intent {
+                height,
+                blockhash: block.blockhash,
+                entry: IndexEntry::deserialize(&entry.serialize()).unwrap(),
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_replay_journal_indexes_written_record() {
+        let test_dir = temp_dir("test_flat_file_store_journal_written");
+        let mut blocks = store_with_blocks(&test_dir, 5);
+        let block = create_random_block_data();
+        let entry = crash_before_indexing(&test_dir, &block, 5);
+        blocks.push(block);
+
+        // The record is all there, so the block is kept
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(store.index.get_current_height(), 5);
+        assert_eq!(
+            store.index.get_block_entry(&blocks[5].blockhash).unwrap(),
+            entry
+        );
+        assert_eq!(read_chain(&store), blocks);
+        assert_eq!(store.stats().unwrap().blocks, 6);
+        assert_eq!(
+            fs::metadata(test_dir.join(JOURNAL_FILE_NAME))
+                .unwrap()
+                .len(),
+            0
+        );
+        store.index.check_consistency().unwrap();
+
+        let block = create_random_block_data();
+        store.add_block(&block, 6).unwrap();
+        blocks.push(block);
+        assert_eq!(read_chain(&store), blocks);
+
+        drop(store);
+    }
+
+    #[test]
+    fn test_replay_journal_cuts_torn_record() {
+        let test_dir = temp_dir("test_flat_file_store_journal_torn");
+        let blocks = store_with_blocks(&test_dir, 5);
+        let block = create_random_block_data();
+        let entry = crash_before_indexing(&test_dir, &block, 5);
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        File::options()
+            .write(true)
+            .open(&file_path)
+            .unwrap()
+            .set_len(entry.offset + entry.length / 2)
+            .unwrap();
+
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(store.index.get_current_height(), 4);
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), entry.offset);
+        assert_eq!(read_chain(&store), blocks);
+
+        // Lost, but it can simply be added again
+        store.add_block(&block, 5).unwrap();
+        assert_eq!(
+            store.index.get_block_entry(&block.blockhash).unwrap(),
+            entry
+        );
+
+        drop(store);
+    }
+
+    #[test]
+    fn test_replay_journal_ignores_stale_intents() {
+        let test_dir = temp_dir("test_flat_file_store_journal_stale");
+        let mut blocks = store_with_blocks(&test_dir, 5);
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let block = create_random_block_data();
+        store.add_block(&block, 5).unwrap();
+        let entry = store.index.get_block_entry(&block.blockhash).unwrap();
+        blocks.push(block);
+        drop(store);
+
+        // The block was indexed before the crash, only clearing the journal didn't happen
+        write_intent(&test_dir, &blocks[5], 5, &entry);
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(read_chain(&store), blocks);
+        drop(store);
+
+        // A crash before the record was appended, and one while the intent was written
+        let unwritten = create_random_block_data();
+        let next = IndexEntry {
+            file_number: entry.file_number,
+            offset: entry.offset + entry.length,
+            length: entry.length,
+        };
+        write_intent(&test_dir, &unwritten, 6, &next);
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(read_chain(&store), blocks);
+        drop(store);
+        fs::write(test_dir.join(JOURNAL_FILE_NAME), b"SPSJ\x06\0\0").unwrap();
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        assert_eq!(read_chain(&store), blocks);
+
+        drop(store);
+    }
+
+    #[test]
+    fn test_add_block_is_idempotent() {
+        let test_dir = temp_dir("test_flat_file_store_idempotent");
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let blocks: Vec<BlockData> = (0..3).map(|_| create_random_block_data()).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        store.flush().unwrap();
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        let size = fs::metadata(&file_path).unwrap().len();
+
+        // The same block again is a no-op, a different one at a stored height is not
+        store.add_block(&blocks[2], 2).unwrap();
+        store.add_block(&blocks[1], 1).unwrap();
+        assert!(matches!(
+            store.add_block(&create_random_block_data(), 2),
+            Err(StorageError::InvalidHeight)
+        ));
+        store.flush().unwrap();
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), size);
+        assert_eq!(store.index.get_current_height(), 2);
+
+        drop(store);
+    }
+
+    #[test]
+    fn test_record_size_cap() {
+        let test_dir = temp_dir("test_flat_file_store_record_cap");
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+
+        // The biggest block that still fits under the default 8 MB cap
+        let overhead = block_with_tweaks(0).serialize().len();
+        let max_tweaks = (DEFAULT_MAX_RECORD_SIZE - overhead) / TWEAK_SIZE;
+        let largest = block_with_tweaks(max_tweaks);
+        store.add_block(&largest, 0).unwrap();
+        store.flush().unwrap();
+
+        let size_before = fs::metadata(&file_path).unwrap().len();
+        let too_large = block_with_tweaks(max_tweaks + 1);
+        match store.add_block(&too_large, 1) {
+            Err(StorageError::RecordTooLarge { size, max }) => {
+                assert_eq!(size, too_large.serialize().len());
+                assert_eq!(max, DEFAULT_MAX_RECORD_SIZE);
+            }
+            other => panic!("expected RecordTooLarge, got {:?}", other),
+        }
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), size_before);
+
+        // The store is still usable, and the large record reads back intact
+        let small = create_random_block_data();
+        store.add_block(&small, 1).unwrap();
+
+        let mut buffer = Vec::new();
+        store
+            .get_block_stream_from_height(0)
+            .unwrap()
+            .read_to_end(&mut buffer)
+            .unwrap();
+        let read_largest = BlockData::deserialize(&buffer).unwrap();
+        assert_eq!(read_largest, largest);
+        let pos = largest.serialize().len();
+        assert_eq!(BlockData::deserialize(&buffer[pos..]).unwrap(), small);
+    }
+
+    #[test]
+    fn test_max_file_size_metadata() {
+        let test_dir = temp_dir("test_flat_file_store_max_file_size");
+        let options = |max_file_size| FlatFileStoreOptions {
+            max_file_size,
+            ..Default::default()
+        };
+        let stored_size = |store: &FlatFileStore| {
+            store
+                .index
+                .get_meta(MAX_FILE_SIZE_META_KEY)
+                .unwrap()
+                .map(|value| u64::from_le_bytes(value.try_into().unwrap()))
+        };
+
+        let store =
+            FlatFileStore::initialize_with_options(test_dir.to_path_buf(), options(1024)).unwrap();
+        assert_eq!(stored_size(&store), Some(1024));
+        let blocks: Vec<BlockData> = (0..20).map(|height| generated_block(height, 4)).collect();
+        for (height, block) in (0..10).zip(&blocks) {
+            store.add_block(block, height).unwrap();
+        }
+        let files_before = store.state().current_file_number;
+        assert!(files_before > 0);
+        drop(store);
+
+        // Reopening with a bigger size adapts: the existing files stay as they are and the
+        // next records fill up the current file further
+        let store =
+            FlatFileStore::initialize_with_options(test_dir.to_path_buf(), options(64 * 1024))
+                .unwrap();
+        assert_eq!(stored_size(&store), Some(64 * 1024));
+        for (height, block) in (10..20).zip(&blocks[10..]) {
+            store.add_block(block, height).unwrap();
+        }
+        assert_eq!(store.state().current_file_number, files_before);
+        for (height, block) in blocks.iter().enumerate() {
+            assert_eq!(&store.get_block(height as u32).unwrap(), block);
+        }
+    }
+
+    #[test]
+    fn test_record_size_soft_limit() {
+        let test_dir = temp_dir("test_flat_file_store_record_soft_limit");
+        let options = FlatFileStoreOptions {
+            max_record_size: 1000,
+            ..Default::default()
+        };
+        let store = FlatFileStore::initialize_with_options(test_dir.clone(), options).unwrap();
+        let overhead = block_with_tweaks(0).serialize().len();
+
+        let mut height = 0u32;
+        let mut add = |store: &FlatFileStore, size: usize| {
+            let mut block = block_with_tweaks((size - overhead) / TWEAK_SIZE);
+            block.blockhash[..4].copy_from_slice(&height.to_be_bytes());
+            let result = store.add_block(&block, height);
+            height += 1;
+            result
+        };
+
+        add(&store, 500).unwrap();
+        assert!(!store.watermarks()[0].above_soft);
+        // Past 80% of the cap the store warns, and still takes the record
+        add(&store, 900).unwrap();
+        let status = &store.watermarks()[0];
+        assert!(status.above_soft);
+        assert_eq!((status.soft_limit, status.hard_limit), (800, 1000));
+        assert!(matches!(
+            add(&store, 1100),
+            Err(StorageError::RecordTooLarge { .. })
+        ));
+
+        drop(store);
+        let options = FlatFileStoreOptions {
+            soft_limit_fraction: 1.2,
+            ..Default::default()
+        };
+        assert!(matches!(
+            FlatFileStore::initialize_with_options(test_dir.clone(), options),
+            Err(StorageError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_wrong_key() {
+        let test_dir = temp_dir("test_flat_file_store_wrong_key");
+
+        let store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), encrypted_options(1)).unwrap();
+        store.add_block(&create_random_block_data(), 0).unwrap();
+        drop(store);
+
+        assert!(matches!(
+            FlatFileStore::initialize_with_options(test_dir.clone(), encrypted_options(2)),
+            Err(StorageError::WrongKey)
+        ));
+        assert!(matches!(
+            FlatFileStore::initialize(test_dir.clone()),
+            Err(StorageError::EncryptionError(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_tamper_detection() {
+        let test_dir = temp_dir("test_flat_file_store_tamper");
+
+        let store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), encrypted_options(1)).unwrap();
+        store.add_block(&create_random_block_data(), 0).unwrap();
+        store.flush().unwrap();
+
+        // Flip a bit in the middle of the ciphertext
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        let mut raw = fs::read(&file_path).unwrap();
+        let middle = ENCRYPTED_HEADER_SIZE + RECORD_OVERHEAD;
+        raw[middle] ^= 1;
+        fs::write(&file_path, raw).unwrap();
 
-        // Test reading beyond the end of a file
         let mut reader = store.get_block_stream_from_height(0).unwrap();
         let mut buffer = Vec::new();
+        let err = reader.read_to_end(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_rekey() {
+        let test_dir = temp_dir("test_flat_file_store_rekey");
+
+        let mut store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), encrypted_options(1)).unwrap();
+        let blocks: Vec<BlockData> = (0..5).map(|_| create_random_block_data()).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        store.rekey(EncryptionKey::from_bytes([2; 32])).unwrap();
+        drop(store);
+
+        assert!(matches!(
+            FlatFileStore::initialize_with_options(test_dir.clone(), encrypted_options(1)),
+            Err(StorageError::WrongKey)
+        ));
+        let store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), encrypted_options(2)).unwrap();
+
+        let mut reader = store.get_block_stream_from_genesis().unwrap();
+        let mut buffer = Vec::new();
         reader.read_to_end(&mut buffer).unwrap();
+        let read_block = BlockData::deserialize(&buffer).unwrap();
+        assert_eq!(blocks[0], read_block);
+    }
 
-        // The buffer should contain all blocks concatenated
-        let mut pos = 0;
-        while pos < buffer.len() {
-            let block = BlockData::deserialize(&buffer[pos..]).unwrap();
-            assert_eq!(large_block.blockhash, block.blockhash);
-            assert_eq!(large_block.tweaks.len(), block.tweaks.len());
-            pos += block.serialize().len();
+    #[test]
+    fn test_rekey_interrupted() {
+        let test_dir = temp_dir("test_flat_file_store_rekey_interrupted");
+        let options = |byte| FlatFileStoreOptions {
+            max_file_size: TEST_MAX_FILE_SIZE,
+            ..encrypted_options(byte)
+        };
+        let mut store =
+            FlatFileStore::initialize_with_options(test_dir.clone(), options(1)).unwrap();
+        let blocks: Vec<BlockData> = (0..30).map(|height| generated_block(height, 4)).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        let last_file = store.state().current_file_number;
+        assert!(last_file > 1);
+
+        // Interrupted after recording the copies and swapping in the first one only
+        let (old_key, new_key) = (
+            EncryptionKey::from_bytes([1; 32]),
+            EncryptionKey::from_bytes([2; 32]),
+        );
+        assert_eq!(
+            store.write_rekeyed_copies(&old_key, &new_key).unwrap(),
+            last_file
+        );
+        let files = [0u64.to_le_bytes(), last_file.to_le_bytes()].concat();
+        store.index.set_meta(REKEY_META_KEY, &files).unwrap();
+        let file_path = test_dir.join(BLOCK_DATA_DIR_NAME).join(block_file_name!(0));
+        platform::replace_file(&file_path.with_extension(REKEY_EXTENSION), &file_path).unwrap();
+        drop(store);
+
+        // The next open finishes the swap, after which only the new key opens the store
+        let store = FlatFileStore::initialize_with_options(test_dir.clone(), options(2)).unwrap();
+        assert_eq!(store.index.get_meta(REKEY_META_KEY).unwrap(), None);
+        for (height, block) in blocks.iter().enumerate() {
+            assert_eq!(&store.get_block(height as u32).unwrap(), block);
+        }
+        assert!(store.verify().unwrap().is_clean());
+        drop(store);
+        assert!(matches!(
+            FlatFileStore::initialize_with_options(test_dir.clone(), options(1)),
+            Err(StorageError::WrongKey)
+        ));
+        let leftover = fs::read_dir(test_dir.join(BLOCK_DATA_DIR_NAME))
+            .unwrap()
+            .any(|entry| entry.unwrap().path().extension() == Some(REKEY_EXTENSION.as_ref()));
+        assert!(!leftover);
+    }
+
+    #[test]
+    fn test_remove_tip_block() {
+        let test_dir = temp_dir("test_flat_file_store_remove_tip");
+
+        let store = FlatFileStore::initialize(test_dir.clone()).unwrap();
+        let blocks: Vec<BlockData> = (0..3).map(|_| create_random_block_data()).collect();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+
+        // Only the tip can be removed
+        assert!(matches!(
+            store.remove_tip_block(&blocks[1].blockhash),
+            Err(StorageError::TipMismatch)
+        ));
+
+        let removed = store.remove_tip_block(&blocks[2].blockhash).unwrap();
+        assert_eq!(removed.height, 2);
+        assert_eq!(removed.blockhash, blocks[2].blockhash);
+        assert_eq!(store.index.get_current_height(), 1);
+        assert!(matches!(
+            store.index.get_block_entry(&blocks[2].blockhash),
+            Err(StorageError::OrphanedEntry)
+        ));
+
+        // A second attempt with the now stale hash fails cleanly
+        assert!(matches!(
+            store.remove_tip_block(&blocks[2].blockhash),
+            Err(StorageError::TipMismatch)
+        ));
+
+        store.remove_tip_block(&blocks[1].blockhash).unwrap();
+        store.remove_tip_block(&blocks[0].blockhash).unwrap();
+        assert!(matches!(
+            store.remove_tip_block(&blocks[0].blockhash),
+            Err(StorageError::EntryNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_remove_blocks_above() {
+        let store = TestStore::new("test_flat_file_store_remove_blocks_above");
+        let blocks = store.add_blocks(40);
+
+        assert_eq!(store.remove_blocks_above(29).unwrap(), 10);
+        assert_eq!(store.get_current_height(), 29);
+        for (height, block) in blocks.iter().enumerate().skip(30) {
+            assert!(matches!(
+                store.get_block(height as u32),
+                Err(StorageError::EntryNotFound)
+            ));
+            assert!(matches!(
+                store.get_block_by_hash(&block.blockhash),
+                Err(StorageError::OrphanedEntry)
+            ));
+        }
+        assert_eq!(store.remove_blocks_above(29).unwrap(), 0);
+
+        // Another chain at the same heights
+        let other: Vec<BlockData> = (30..40).map(|height| generated_block(height, 7)).collect();
+        for (height, block) in (30..).zip(&other) {
+            store.add_block(block, height).unwrap();
+        }
+        let iterated: Vec<_> = store.iter_blocks().map(Result::unwrap).collect();
+        assert_eq!(iterated.len(), 40);
+        for (height, block) in &iterated {
+            let expected = match *height {
+                0..=29 => &blocks[*height as usize],
+                _ => &other[*height as usize - 30],
+            };
+            assert_eq!(block, expected);
+        }
+    }
+
+    #[test]
+    fn test_pop_tip() {
+        for (name, options) in [
+            ("plain", FlatFileStoreOptions::default()),
+            ("encrypted", encrypted_options(1)),
+        ] {
+            let store =
+                TestStore::with_options(&format!("test_flat_file_store_pop_tip_{}", name), options);
+            assert!(matches!(store.pop_tip(), Err(StorageError::EntryNotFound)));
+            let data_dir = store.dir().join(BLOCK_DATA_DIR_NAME);
+            let file_len = |file_number: u64| {
+                fs::metadata(data_dir.join(block_file_name!(file_number)))
+                    .map(|metadata| metadata.len())
+            };
+
+            // Add blocks until one starts a fresh file
+            let mut blocks = store.add_blocks(1);
+            while store.state().current_file_number == 0 {
+                blocks.extend(store.add_blocks(1));
+            }
+            let tip = blocks.last().unwrap();
+            let tip_height = blocks.len() as u32 - 1;
+            let entry = store.index.get_block_entry(&tip.blockhash).unwrap();
+            assert_eq!((entry.file_number, entry.offset), (1, store.header_len()));
+            store.flush().unwrap();
+            let file_0_len = file_len(0).unwrap();
+
+            assert_eq!(&store.pop_tip().unwrap(), tip);
+            assert_eq!(store.state().current_file_number, 0);
+            assert!(file_len(1).is_err());
+            assert_eq!(file_len(0).unwrap(), file_0_len);
+            assert!(matches!(
+                store.get_block_by_hash(&tip.blockhash),
+                Err(StorageError::OrphanedEntry)
+            ));
+
+            // Popping from the middle of a file cuts it back to the record
+            let previous = &blocks[blocks.len() - 2];
+            let entry = store.index.get_block_entry(&previous.blockhash).unwrap();
+            assert_eq!(&store.pop_tip().unwrap(), previous);
+            store.flush().unwrap();
+            assert_eq!(file_len(0).unwrap(), entry.offset);
+
+            // Both heights can be stored again, and the new tip starts a new file once more
+            let replacement = generated_block(1_000_000, 2);
+            store.add_block(&replacement, tip_height - 1).unwrap();
+            store.add_block(tip, tip_height).unwrap();
+            for (height, block) in blocks[..blocks.len() - 2].iter().enumerate() {
+                assert_eq!(&store.get_block(height as u32).unwrap(), block);
+            }
+            assert_eq!(store.get_block(tip_height - 1).unwrap(), replacement);
+            assert_eq!(&store.get_block(tip_height).unwrap(), tip);
+        }
+    }
+
+    #[test]
+    fn test_tip() {
+        let store = TestStore::new("test_flat_file_store_tip");
+        assert_eq!(store.tip(), None);
+
+        let mut blocks = store.add_blocks(1);
+        assert_eq!(store.tip(), Some((0, blocks[0].blockhash)));
+
+        blocks.extend(store.add_blocks(2));
+        assert_eq!(store.tip(), Some((2, blocks[2].blockhash)));
+        store.pop_tip().unwrap();
+        assert_eq!(store.tip(), Some((1, blocks[1].blockhash)));
+        store.pop_tip().unwrap();
+        store.pop_tip().unwrap();
+        assert_eq!(store.tip(), None);
+    }
+
+    #[test]
+    fn test_pop_tip_after_remove_tip_block() {
+        let store = TestStore::new("test_flat_file_store_pop_tip_dead_space");
+        let blocks = store.add_blocks(3);
+        store.remove_tip_block(&blocks[2].blockhash).unwrap();
+        store.flush().unwrap();
+        let file_path = store
+            .dir()
+            .join(BLOCK_DATA_DIR_NAME)
+            .join(block_file_name!(0));
+        let len = fs::metadata(&file_path).unwrap().len();
+
+        // The dead space after the tip keeps its bytes in place
+        assert_eq!(store.pop_tip().unwrap(), blocks[1]);
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), len);
+        assert_eq!(store.pop_tip().unwrap(), blocks[0]);
+        assert_eq!(store.get_current_height(), -1);
+    }
+
+    fn open_with_height_hole(test_dir: &Path, strict: bool) -> FlatFileStore {
+        let store = FlatFileStore::initialize(test_dir.to_path_buf()).unwrap();
+        for height in 0..4 {
+            store
+                .add_block(&create_random_block_data(), height)
+                .unwrap();
         }
+        drop(store);
+
+        // Knock out height 2, leaving height 3 past a hole
+        let index_db = sled::open(test_dir.join(INDEX_DIR_NAME)).unwrap();
+        let height_to_hash = index_db.open_tree("height_to_hash").unwrap();
+        height_to_hash.remove(2u32.to_be_bytes()).unwrap();
+        drop(height_to_hash);
+        drop(index_db);
+
+        let options = FlatFileStoreOptions {
+            strict,
+            ..Default::default()
+        };
+        FlatFileStore::initialize_with_options(test_dir.to_path_buf(), options).unwrap()
+    }
+
+    #[test]
+    fn test_strict_mode_freezes_on_violation() {
+        let test_dir = temp_dir("test_flat_file_store_strict");
+
+        let store = open_with_height_hole(&test_dir, true);
+        assert!(store.integrity_guard().is_frozen());
+        assert!(matches!(
+            store.add_block(&create_random_block_data(), 2),
+            Err(StorageError::Frozen)
+        ));
+
+        // Already stored data can still be read
+        let mut reader = store.get_block_stream_from_height(1).unwrap();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        assert!(BlockData::deserialize(&buffer).is_ok());
+
+        let incident = fs::read_dir(&test_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .find(|name| name.starts_with("incident-"))
+            .expect("strict mode should write an incident report");
+        let report = fs::read_to_string(test_dir.join(incident)).unwrap();
+        assert!(report.contains("not contiguous past 2"));
+    }
+
+    #[test]
+    fn test_non_strict_mode_continues_after_violation() {
+        let test_dir = temp_dir("test_flat_file_store_non_strict");
 
-        // Clean up
-        let _ = fs::remove_dir_all(test_dir);
+        let store = open_with_height_hole(&test_dir, false);
+        assert!(!store.integrity_guard().is_frozen());
+        store.add_block(&create_random_block_data(), 2).unwrap();
     }
 }
@@ -0,0 +1,183 @@
+use log::{error, warn};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::StorageError;
+
+/// Kinds of internal inconsistencies the storage layer can run into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// A record failed its checksum or authentication.
+    Checksum,
+    /// The index and the flat files disagree about a record.
+    IndexFileMismatch,
+    /// The index trees disagree with each other.
+    TreeInconsistency,
+    /// Heights are not contiguous, or the tip is not where it should be.
+    ChainInvariant,
+}
+
+impl std::fmt::Display for ViolationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ViolationKind::Checksum => write!(f, "checksum failure"),
+            ViolationKind::IndexFileMismatch => write!(f, "index/file mismatch"),
+            ViolationKind::TreeInconsistency => write!(f, "index tree inconsistency"),
+            ViolationKind::ChainInvariant => write!(f, "chain invariant violated"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    /// What failed and where (height, file, offset, ... whatever is known).
+    pub detail: String,
+}
+
+impl Violation {
+    pub fn new(kind: ViolationKind, detail: impl Into<String>) -> Self {
+        Violation {
+            kind,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.detail)
+    }
+}
+
+/// Storage code reports every internal inconsistency here instead of warning and moving on.
+/// Outside of strict mode the guard only logs. In strict mode the first violation freezes the
+/// store: writes are refused from then on, and an incident report is written to the data
+/// directory so the problem gets noticed.
+#[derive(Debug)]
+pub struct IntegrityGuard {
+    strict: bool,
+    incident_dir: PathBuf,
+    frozen_by: Mutex<Option<Violation>>,
+}
+
+impl IntegrityGuard {
+    pub fn new(strict: bool, incident_dir: PathBuf) -> Self {
+        IntegrityGuard {
+            strict,
+            incident_dir,
+            frozen_by: Mutex::new(None),
+        }
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    pub fn report(&self, violation: Violation) {
+        warn!(target: "Integrity", "Integrity violation: {}", violation);
+        if !self.strict {
+            return;
+        }
+
+        let mut frozen_by = self.frozen_by.lock().unwrap();
+        if frozen_by.is_some() {
+            return;
+        }
+        error!(target: "Integrity", "Strict mode: freezing the store, no further writes will be accepted");
+        match self.write_incident_report(&violation) {
+            Ok(path) => {
+                error!(target: "Integrity", "Incident report written to {}", path.display())
+            }
+            Err(e) => error!(target: "Integrity", "Failed to write incident report: {}", e),
+        }
+        *frozen_by = Some(violation);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen_by.lock().unwrap().is_some()
+    }
+
+    /// The violation that froze the store, if any.
+    pub fn frozen_by(&self) -> Option<Violation> {
+        self.frozen_by.lock().unwrap().clone()
+    }
+
+    /// Returns `StorageError::Frozen` once a violation froze the store.
+    pub fn check_writable(&self) -> Result<(), StorageError> {
+        if self.is_frozen() {
+            return Err(StorageError::Frozen);
+        }
+        Ok(())
+    }
+
+    fn write_incident_report(&self, violation: &Violation) -> io::Result<PathBuf> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let path = self
+            .incident_dir
+            .join(format!("incident-{}.txt", timestamp));
+        let report = format!(
+            "time: {}\nkind: {}\ndetail: {}\n",
+            timestamp, violation.kind, violation.detail
+        );
+        fs::write(&path, report)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+    use std::path::Path;
+
+    fn incident_files(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with("incident-")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_strict_guard_freezes() {
+        let dir = temp_dir("test_integrity_strict");
+        let guard = IntegrityGuard::new(true, dir.clone());
+        assert!(guard.check_writable().is_ok());
+
+        let violation = Violation::new(ViolationKind::Checksum, "height 7, file 0, offset 1234");
+        guard.report(violation.clone());
+        guard.report(Violation::new(ViolationKind::ChainInvariant, "second"));
+
+        assert!(matches!(guard.check_writable(), Err(StorageError::Frozen)));
+        assert_eq!(guard.frozen_by(), Some(violation));
+
+        let files = incident_files(&dir);
+        assert_eq!(files.len(), 1);
+        let report = fs::read_to_string(&files[0]).unwrap();
+        assert!(report.contains("kind: checksum failure"));
+        assert!(report.contains("detail: height 7, file 0, offset 1234"));
+    }
+
+    #[test]
+    fn test_non_strict_guard_only_logs() {
+        let dir = temp_dir("test_integrity_non_strict");
+        let guard = IntegrityGuard::new(false, dir.clone());
+
+        guard.report(Violation::new(ViolationKind::Checksum, "height 7"));
+        assert!(!guard.is_frozen());
+        assert!(guard.check_writable().is_ok());
+        assert!(incident_files(&dir).is_empty());
+    }
+}
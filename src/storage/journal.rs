@@ -0,0 +1,125 @@
+//! Write-ahead journal for `FlatFileStore::add_block`. The record is appended to a block data
+//! file and then indexed, and a crash in between used to cost the block: the record was cut
+//! off on open as the index didn't reference it. The journal says what was being written, so
+//! the store can tell a record that made it to disk (and index it) from a torn one.
+use log::warn;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::IndexEntry;
+
+/// The journal file in the data directory.
+pub const JOURNAL_FILE_NAME: &str = "JOURNAL";
+
+const INTENT_MAGIC: [u8; 4] = *b"SPSJ";
+
+/// Length of an encoded Intent:
+/// [magic (4 bytes)] [height (4 bytes)] [blockhash (32 bytes)] [index entry (24 bytes)]
+/// [CRC32 of the preceding bytes (4 bytes)]
+const INTENT_SIZE: usize = 68;
+
+/// A block about to be appended, at `entry`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Intent {
+    pub height: u32,
+    pub blockhash: [u8; 32],
+    pub entry: IndexEntry,
+}
+
+impl Intent {
+    fn encode(&self) -> [u8; INTENT_SIZE] {
+        let mut buf = [0u8; INTENT_SIZE];
+        buf[0..4].copy_from_slice(&INTENT_MAGIC);
+        buf[4..8].copy_from_slice(&self.height.to_le_bytes());
+        buf[8..40].copy_from_slice(&self.blockhash);
+        buf[40..64].copy_from_slice(&self.entry.serialize());
+        let crc = crc32fast::hash(&buf[..64]);
+        buf[64..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// The intent `data` holds, None unless it is a complete one.
+    fn decode(data: &[u8]) -> Option<Intent> {
+        if data.len() != INTENT_SIZE || data[0..4] != INTENT_MAGIC {
+            return None;
+        }
+        if crc32fast::hash(&data[..64]).to_le_bytes() != data[64..] {
+            return None;
+        }
+        Some(Intent {
+            height: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            blockhash: data[8..40].try_into().unwrap(),
+            entry: IndexEntry::deserialize(&data[40..64])?,
+        })
+    }
+}
+
+/// Holds at most one Intent, written and synced before the record is, and cleared once the
+/// block is indexed (or the write was rolled back). Both are synced: an intent left pending
+/// by mistake could bring back a block reorged away since.
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Opens the journal in `data_dir`, creating it if needed, along with the intent a crash
+    /// left pending. An intent that was never fully written is ignored, the record it was for
+    /// wasn't written yet.
+    pub fn open(data_dir: &Path) -> io::Result<(Journal, Option<Intent>)> {
+        let mut file = File::options()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(data_dir.join(JOURNAL_FILE_NAME))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let pending = Intent::decode(&data);
+        if pending.is_none() && !data.is_empty() {
+            warn!(target: "FileStore", "Ignoring an incomplete intent in the journal ({} bytes)", data.len());
+        }
+        Ok((Journal { file }, pending))
+    }
+
+    /// Records `intent`, durably, before its record is appended.
+    pub fn begin(&mut self, intent: &Intent) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&intent.encode())?;
+        self.file.sync_data()
+    }
+
+    /// Clears the pending intent, durably.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intent_encoding() {
+        let intent = Intent {
+            height: 812,
+            blockhash: [5u8; 32],
+            entry: IndexEntry {
+                file_number: 3,
+                offset: 1024,
+                length: 377,
+            },
+        };
+        let encoded = intent.encode();
+        assert_eq!(Intent::decode(&encoded), Some(intent));
+
+        // Torn or damaged intents are not intents
+        assert_eq!(Intent::decode(&encoded[..40]), None);
+        let mut damaged = encoded;
+        damaged[20] ^= 1;
+        assert_eq!(Intent::decode(&damaged), None);
+        assert_eq!(Intent::decode(&[]), None);
+    }
+}
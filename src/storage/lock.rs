@@ -0,0 +1,42 @@
+//! Keeps two processes from opening the same data directory at once.
+use log::warn;
+use std::fs::{self, File};
+use std::path::Path;
+
+use super::StorageError;
+use crate::platform;
+
+/// The lock file in the data directory. It is left in place on close, only the lock on it
+/// means anything.
+pub const LOCK_FILE_NAME: &str = "LOCK";
+
+/// An exclusive lock on a data directory, released on drop.
+pub struct DataDirLock {
+    file: File,
+}
+
+impl DataDirLock {
+    /// Locks `data_dir`, creating the directory if needed. Fails with `AlreadyLocked` while
+    /// another store, in this process or another one, has it open.
+    pub fn acquire(data_dir: &Path) -> Result<Self, StorageError> {
+        fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(LOCK_FILE_NAME);
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        if !platform::try_lock_exclusive(&file)? {
+            return Err(StorageError::AlreadyLocked(path));
+        }
+        Ok(DataDirLock { file })
+    }
+}
+
+impl Drop for DataDirLock {
+    fn drop(&mut self) {
+        if let Err(e) = platform::unlock(&self.file) {
+            warn!(target: "FileStore", "Failed to unlock the data directory: {}", e);
+        }
+    }
+}
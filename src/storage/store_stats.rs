@@ -0,0 +1,104 @@
+use serde::Serialize;
+
+/// How big a store is, for operators watching it grow. See `FlatFileStore::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct StoreStats {
+    /// Blocks of the chain the store holds, pruned ones not included.
+    pub blocks: u32,
+    /// Tweaks in those blocks.
+    pub tweaks: u64,
+    /// Block data files.
+    pub files: u64,
+    /// Size of the block data files on disk, preallocated space included.
+    pub file_bytes: u64,
+    /// Bytes of block data files that hold no record of the chain, such as the records of
+    /// reorged blocks. Estimated from the totals rather than found by walking the files, see
+    /// `FlatFileStore::verify` for where exactly they are.
+    pub dead_bytes: u64,
+    /// Size of the index database on disk.
+    pub index_bytes: u64,
+}
+
+/// Running totals over the blocks of the chain a store holds, updated as blocks are added
+/// and removed so `stats` doesn't have to read every record.
+/// They are saved in the metadata along with where the chain stood at the time. Totals saved
+/// at another tip (a store from before they were kept, or a crash between an index update
+/// and saving them) are counted again from the records when the store is opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChainTotals {
+    pub tweaks: u64,
+    /// Bytes of the stored records, frames included.
+    pub record_bytes: u64,
+}
+
+/// Length of saved ChainTotals:
+/// [pruned_up_to (4 bytes)] [next height (4 bytes)] [tip blockhash (32 bytes)]
+/// [tweaks (8 bytes)] [record bytes (8 bytes)]
+const SAVED_TOTALS_SIZE: usize = 56;
+
+impl ChainTotals {
+    pub fn add(&mut self, tweaks: u64, record_bytes: u64) {
+        self.tweaks += tweaks;
+        self.record_bytes += record_bytes;
+    }
+
+    pub fn remove(&mut self, tweaks: u64, record_bytes: u64) {
+        self.tweaks = self.tweaks.saturating_sub(tweaks);
+        self.record_bytes = self.record_bytes.saturating_sub(record_bytes);
+    }
+
+    /// The totals as saved for a chain pruned up to `pruned_up_to` with tip `tip`.
+    pub fn encode(&self, pruned_up_to: u32, tip: Option<(u32, [u8; 32])>) -> Vec<u8> {
+        let (next_height, blockhash) = tip.map_or((0, [0u8; 32]), |(height, blockhash)| {
+            (height + 1, blockhash)
+        });
+        let mut buf = Vec::with_capacity(SAVED_TOTALS_SIZE);
+        buf.extend_from_slice(&pruned_up_to.to_le_bytes());
+        buf.extend_from_slice(&next_height.to_le_bytes());
+        buf.extend_from_slice(&blockhash);
+        buf.extend_from_slice(&self.tweaks.to_le_bytes());
+        buf.extend_from_slice(&self.record_bytes.to_le_bytes());
+        buf
+    }
+
+    /// Saved totals, if they were taken where the chain stands now.
+    pub fn decode(data: &[u8], pruned_up_to: u32, tip: Option<(u32, [u8; 32])>) -> Option<Self> {
+        if data.len() != SAVED_TOTALS_SIZE {
+            return None;
+        }
+        let totals = ChainTotals {
+            tweaks: u64::from_le_bytes(data[40..48].try_into().unwrap()),
+            record_bytes: u64::from_le_bytes(data[48..56].try_into().unwrap()),
+        };
+        (totals.encode(pruned_up_to, tip) == data).then_some(totals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_saved_totals_match_the_chain() {
+        let totals = ChainTotals {
+            tweaks: 1234,
+            record_bytes: 56789,
+        };
+        let tip = Some((41, [7u8; 32]));
+        let saved = totals.encode(10, tip);
+        assert_eq!(saved.len(), SAVED_TOTALS_SIZE);
+        assert_eq!(ChainTotals::decode(&saved, 10, tip), Some(totals));
+
+        // Taken at another tip, pruned height, or cut short: not to be trusted
+        assert_eq!(ChainTotals::decode(&saved, 10, Some((41, [8u8; 32]))), None);
+        assert_eq!(ChainTotals::decode(&saved, 10, Some((42, [7u8; 32]))), None);
+        assert_eq!(ChainTotals::decode(&saved, 11, tip), None);
+        assert_eq!(ChainTotals::decode(&saved[..40], 10, tip), None);
+
+        let empty = ChainTotals::default().encode(0, None);
+        assert_eq!(
+            ChainTotals::decode(&empty, 0, None),
+            Some(ChainTotals::default())
+        );
+    }
+}
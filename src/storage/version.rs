@@ -0,0 +1,1108 @@
+use log::info;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{
+    migrate_block_data_files, migrate_record_checksums, migrate_record_frames, store_exists,
+    DataDirLock, EncryptionKey, FlatFileStore, Index, StorageError, BLOCK_DATA_DIR_NAME,
+    INDEX_DIR_NAME,
+};
+use crate::platform;
+
+/// Version of the data directory layout (record format, index schema, metadata) this binary
+/// reads and writes. Bump it together with a new entry in MIGRATIONS.
+pub const DATA_DIR_VERSION: u32 = 8;
+
+/// The version is stamped in a plain file in the data directory, so it can be checked before
+/// opening anything else, and mirrored in the index metadata.
+pub const VERSION_FILE_NAME: &str = "version";
+pub const VERSION_META_KEY: &[u8] = b"version";
+/// Name of the network the store holds data for ("mainnet", "signet", ...).
+pub const NETWORK_META_KEY: &[u8] = b"network";
+
+/// One step of `upgrade`, taking a data directory from version `from` to `from + 1`.
+/// Steps have to be safe to run again: a crash between applying a step and stamping the
+/// new version runs it a second time on the next upgrade.
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    apply: fn(&Path, &Index, Option<&EncryptionKey>) -> Result<(), StorageError>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "stamp the data directory version (version file and index metadata)",
+        // Nothing to rewrite, version 0 only lacks the stamp written after every step
+        apply: |_, _, _| Ok(()),
+    },
+    Migration {
+        from: 1,
+        description: "re-encode the index height keys big-endian",
+        apply: reencode_height_keys,
+    },
+    Migration {
+        from: 2,
+        description: "add the format version to the block data file headers",
+        apply: migrate_file_headers,
+    },
+    Migration {
+        from: 3,
+        description: "frame every block data record",
+        apply: frame_records,
+    },
+    Migration {
+        from: 4,
+        description: "allow dust tier records in the block data files and index",
+        // Nothing to rewrite, older binaries just mustn't open a store that has tiers
+        apply: |_, _, _| Ok(()),
+    },
+    Migration {
+        from: 5,
+        description: "allow records replacing the tweaks of a block",
+        // Nothing to rewrite either
+        apply: |_, _, _| Ok(()),
+    },
+    Migration {
+        from: 6,
+        description: "checksum the blockhash and tweak count of every record",
+        apply: checksum_records,
+    },
+    Migration {
+        from: 7,
+        description: "allow tweak metadata in records",
+        // Nothing to rewrite, records without metadata are unchanged
+        apply: |_, _, _| Ok(()),
+    },
+];
+
+/// Migrations get an index opened with `Index::open_for_migration`.
+fn reencode_height_keys(
+    _: &Path,
+    index: &Index,
+    _: Option<&EncryptionKey>,
+) -> Result<(), StorageError> {
+    let rewritten = index.reencode_height_keys()?;
+    info!(target: "Upgrade", "Re-encoded {} height keys", rewritten);
+    Ok(())
+}
+
+fn migrate_file_headers(
+    data_dir: &Path,
+    _: &Index,
+    _: Option<&EncryptionKey>,
+) -> Result<(), StorageError> {
+    let migrated = migrate_block_data_files(&data_dir.join(BLOCK_DATA_DIR_NAME))?;
+    info!(target: "Upgrade", "Upgraded the header of {} block data files", migrated);
+    Ok(())
+}
+
+/// Records move when they are framed, and encrypted ones are bound to their offset, so this
+/// is the one step that needs the key of an encrypted store.
+fn frame_records(
+    data_dir: &Path,
+    index: &Index,
+    encryption_key: Option<&EncryptionKey>,
+) -> Result<(), StorageError> {
+    let framed = migrate_record_frames(&data_dir.join(BLOCK_DATA_DIR_NAME), index, encryption_key)?;
+    info!(target: "Upgrade", "Framed the records of {} block data files", framed);
+    Ok(())
+}
+
+/// Compressed records change length when checksummed again, so records move here too.
+fn checksum_records(
+    data_dir: &Path,
+    index: &Index,
+    encryption_key: Option<&EncryptionKey>,
+) -> Result<(), StorageError> {
+    let checksummed =
+        migrate_record_checksums(&data_dir.join(BLOCK_DATA_DIR_NAME), index, encryption_key)?;
+    info!(target: "Upgrade", "Checksummed the records of {} block data files again", checksummed);
+    Ok(())
+}
+
+/// What `upgrade` did, or would do on a dry run.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UpgradePlan {
+    pub from: u32,
+    pub to: u32,
+    pub steps: Vec<&'static str>,
+}
+
+/// The version of the data directory on disk. None for a directory no store was created in,
+/// version 0 for stores created before the version stamp existed.
+pub fn data_dir_version(data_dir: &Path) -> Result<Option<u32>, StorageError> {
+    match fs::read_to_string(data_dir.join(VERSION_FILE_NAME)) {
+        Ok(contents) => contents
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| StorageError::InvalidData("Unreadable data directory version file")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(if store_exists(data_dir) {
+            Some(0)
+        } else {
+            None
+        }),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataDirState {
+    /// No store has been created in the directory yet.
+    New,
+    /// Stamped with DATA_DIR_VERSION.
+    Current,
+    /// Written by the pre-release code, before version stamps existed.
+    Version0,
+}
+
+/// Refuses to open data directories written by another version. We never migrate implicitly,
+/// so that operators can still roll back to the previous binary until they run `upgrade`.
+/// The one exception is version 0: those stores are version 1 stores without the stamp, so
+/// they are adopted in place (see `FlatFileStore::adopt_version_0`) as long as nothing about
+/// them is ambiguous.
+pub fn check_data_dir_version(data_dir: &Path) -> Result<DataDirState, StorageError> {
+    match data_dir_version(data_dir)? {
+        None => Ok(DataDirState::New),
+        Some(0) => Ok(DataDirState::Version0),
+        Some(version) if version > DATA_DIR_VERSION => Err(StorageError::DataDirTooNew(version)),
+        Some(version) if version < DATA_DIR_VERSION => Err(StorageError::UpgradeRequired(version)),
+        Some(_) => Ok(DataDirState::Current),
+    }
+}
+
+/// Checks that the index metadata agrees with the version file.
+pub fn check_meta_version(index: &Index) -> Result<(), StorageError> {
+    match index.get_meta(VERSION_META_KEY)? {
+        Some(value) if value == DATA_DIR_VERSION.to_le_bytes() => Ok(()),
+        _ => Err(StorageError::CorruptDB(
+            "Index metadata does not match the data directory version file",
+        )),
+    }
+}
+
+/// Stamps a data directory with `version`. The version file goes last, it is what
+/// `check_data_dir_version` trusts.
+pub fn stamp_data_dir_version(
+    data_dir: &Path,
+    index: &Index,
+    version: u32,
+) -> Result<(), StorageError> {
+    index.set_meta(VERSION_META_KEY, &version.to_le_bytes())?;
+
+    let tmp_path = data_dir.join(format!("{}.tmp", VERSION_FILE_NAME));
+    fs::write(&tmp_path, format!("{}\n", version))?;
+    fs::File::open(&tmp_path)?.sync_all()?;
+    platform::replace_file(&tmp_path, &data_dir.join(VERSION_FILE_NAME))?;
+    Ok(())
+}
+
+pub fn plan_upgrade(data_dir: &Path) -> Result<UpgradePlan, StorageError> {
+    let from = data_dir_version(data_dir)?.ok_or(StorageError::InvalidData(
+        "No store found in the data directory",
+    ))?;
+    if from > DATA_DIR_VERSION {
+        return Err(StorageError::DataDirTooNew(from));
+    }
+
+    let steps = MIGRATIONS
+        .iter()
+        .filter(|migration| migration.from >= from)
+        .map(|migration| migration.description)
+        .collect();
+    Ok(UpgradePlan {
+        from,
+        to: DATA_DIR_VERSION,
+        steps,
+    })
+}
+
+/// Brings a data directory up to DATA_DIR_VERSION: backs up the small metadata, runs every
+/// pending migration, stamping the version after each one, and finishes with a consistency
+/// check of the index. A dry run only returns the plan and touches nothing.
+/// The index is only opened normally, finding its tip, once every migration has run: an older
+/// layout can't be read until then. Encrypted stores need their `encryption_key` for some
+/// steps.
+/// Version 0 doesn't record its network, so it is only upgraded with `assume_network`, which is
+/// recorded, and after the same checks as adopting it on open (see `FlatFileStore`).
+pub fn upgrade(
+    data_dir: &Path,
+    encryption_key: Option<&EncryptionKey>,
+    assume_network: Option<&str>,
+    dry_run: bool,
+) -> Result<UpgradePlan, StorageError> {
+    let plan = plan_upgrade(data_dir)?;
+    if plan.from == 0 && assume_network.is_none() {
+        return Err(StorageError::AssumeNetworkRequired);
+    }
+    if dry_run || plan.steps.is_empty() {
+        return Ok(plan);
+    }
+
+    let _lock = DataDirLock::acquire(data_dir)?;
+    let index_dir = data_dir.join(INDEX_DIR_NAME);
+    let index = match plan.from {
+        0 => FlatFileStore::check_version_0(
+            &index_dir,
+            &data_dir.join(BLOCK_DATA_DIR_NAME),
+            encryption_key.is_some(),
+            assume_network.is_some(),
+        )?,
+        _ => Index::open_for_migration(&index_dir)?,
+    };
+    let backup_dir = backup_metadata(data_dir, &index)?;
+    info!(target: "Upgrade", "Backed up data directory metadata to {}", backup_dir.display());
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.from >= plan.from)
+    {
+        info!(
+            target: "Upgrade",
+            "Migrating data directory from version {} to {}: {}",
+            migration.from,
+            migration.from + 1,
+            migration.description
+        );
+        (migration.apply)(data_dir, &index, encryption_key)?;
+        if let (0, Some(network)) = (migration.from, assume_network) {
+            index.set_meta(NETWORK_META_KEY, network.as_bytes())?;
+        }
+        stamp_data_dir_version(data_dir, &index, migration.from + 1)?;
+    }
+    drop(index);
+
+    info!(target: "Upgrade", "Verifying the index");
+    let (index, _) = Index::initialize(&index_dir)?;
+    index.check_consistency()?;
+    check_meta_version(&index)?;
+    info!(target: "Upgrade", "Data directory upgraded to version {}", DATA_DIR_VERSION);
+    Ok(plan)
+}
+
+/// Copies the version file and the index metadata into a fresh `upgrade-backup-<time>` dir.
+/// Block data and the index itself are far too big to copy and are left to the operator.
+fn backup_metadata(data_dir: &Path, index: &Index) -> Result<PathBuf, StorageError> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut backup_dir = data_dir.join(format!("upgrade-backup-{}", timestamp));
+    // An upgrade retried within the same second, after a failed one, gets its own backup
+    let mut attempt = 1;
+    while let Err(e) = fs::create_dir(&backup_dir) {
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(e.into());
+        }
+        backup_dir = data_dir.join(format!("upgrade-backup-{}-{}", timestamp, attempt));
+        attempt += 1;
+    }
+
+    let version_file = data_dir.join(VERSION_FILE_NAME);
+    if version_file.exists() {
+        fs::copy(&version_file, backup_dir.join(VERSION_FILE_NAME))?;
+    }
+
+    // [key length (u32 LE)][key][value length (u32 LE)][value] per entry
+    let mut meta = Vec::new();
+    for (key, value) in index.meta_entries()? {
+        meta.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        meta.extend_from_slice(&key);
+        meta.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        meta.extend_from_slice(&value);
+    }
+    fs::write(backup_dir.join("meta"), meta)?;
+    Ok(backup_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{
+        migrate_record_checksums, migrate_record_frames, open_db, BlockData, EncryptionKey,
+        FlatFileStore, FlatFileStoreOptions, IndexEntry, COMPRESSED_FORMAT_VERSION,
+        ENCRYPTED_HEADER_SIZE, FILE_FORMAT_VERSION, FRAMED_FORMAT_VERSION, FRAME_HEADER_SIZE,
+        HEADER_PREFIX_SIZE, LEGACY_ENCRYPTED_MAGIC_BYTES, ORPHANED_RECORD_MAGIC,
+        RECORD_HEADER_SIZE, RECORD_MAGIC, TWEAKS_CRC_COMPRESSED_FORMAT_VERSION,
+        TWEAKS_CRC_FORMAT_VERSION,
+    };
+    use crate::test_support::temp_dir;
+    use std::collections::HashMap;
+
+    /// Block data file written by the pre-release code for `version_0_blocks()`.
+    const VERSION_0_FIXTURE: &[u8] =
+        include_bytes!("../../tests/fixtures/v0_store/block_data/sps000000.dat");
+
+    fn test_block(i: u8) -> BlockData {
+        BlockData {
+            blockhash: [i; 32],
+            tweaks: vec![[i; 33]],
+            tweak_meta: None,
+        }
+    }
+
+    fn create_store(dir: &Path, blocks: u8) {
+        let store = FlatFileStore::initialize(dir.to_path_buf()).unwrap();
+        for i in 0..blocks {
+            store.add_block(&test_block(i), i as u32).unwrap();
+        }
+    }
+
+    /// Turns a freshly created store into one written before the version stamp existed.
+    fn make_version_0(dir: &Path) {
+        fs::remove_file(dir.join(VERSION_FILE_NAME)).unwrap();
+        let db = open_db(&dir.join(INDEX_DIR_NAME)).unwrap();
+        db.drop_tree("meta").unwrap();
+        db.flush().unwrap();
+    }
+
+    #[test]
+    fn test_new_store_is_stamped() {
+        let dir = temp_dir("test_version_new_store");
+        assert_eq!(data_dir_version(&dir).unwrap(), None);
+
+        create_store(&dir, 3);
+        assert_eq!(data_dir_version(&dir).unwrap(), Some(DATA_DIR_VERSION));
+        assert!(FlatFileStore::initialize(dir.clone()).is_ok());
+    }
+
+    #[test]
+    fn test_upgrade_from_version_0() {
+        let dir = temp_dir("test_version_upgrade_0");
+        create_store(&dir, 10);
+        make_version_0(&dir);
+
+        assert_eq!(data_dir_version(&dir).unwrap(), Some(0));
+        assert!(matches!(
+            FlatFileStore::initialize(dir.clone()),
+            Err(StorageError::AssumeNetworkRequired)
+        ));
+
+        // The network has to be confirmed for the upgrade too
+        assert!(matches!(
+            upgrade(&dir, None, None, true),
+            Err(StorageError::AssumeNetworkRequired)
+        ));
+        assert!(matches!(
+            upgrade(&dir, None, None, false),
+            Err(StorageError::AssumeNetworkRequired)
+        ));
+        assert_eq!(data_dir_version(&dir).unwrap(), Some(0));
+
+        // A dry run reports the steps and changes nothing
+        let plan = upgrade(&dir, None, Some("signet"), true).unwrap();
+        assert_eq!(plan.from, 0);
+        assert_eq!(plan.to, DATA_DIR_VERSION);
+        assert_eq!(plan.steps.len(), 8);
+        assert_eq!(data_dir_version(&dir).unwrap(), Some(0));
+
+        let plan = upgrade(&dir, None, Some("signet"), false).unwrap();
+        assert_eq!(plan.steps.len(), 8);
+        assert_eq!(data_dir_version(&dir).unwrap(), Some(DATA_DIR_VERSION));
+        let backups = fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("upgrade-backup-")
+            })
+            .count();
+        assert_eq!(backups, 1);
+
+        // Recorded as the store's network, so another one is refused
+        let options = |network: &str| FlatFileStoreOptions {
+            network: Some(network.to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            FlatFileStore::initialize_with_options(dir.clone(), options("mainnet")),
+            Err(StorageError::NetworkMismatch { .. })
+        ));
+        let store = FlatFileStore::initialize_with_options(dir.clone(), options("signet")).unwrap();
+        store.add_block(&test_block(10), 10).unwrap();
+
+        // Nothing left to do
+        drop(store);
+        assert!(upgrade(&dir, None, None, false).unwrap().steps.is_empty());
+    }
+
+    #[test]
+    fn test_upgrade_refuses_ambiguous_version_0() {
+        let dir = temp_dir("test_version_upgrade_ambiguous_0");
+        write_version_0_store(&dir, &version_0_blocks());
+        {
+            let db = open_db(&dir.join(INDEX_DIR_NAME)).unwrap();
+            db.open_tree("filters").unwrap().insert(b"x", b"y").unwrap();
+            db.flush().unwrap();
+        }
+        assert!(matches!(
+            upgrade(&dir, None, Some("mainnet"), false),
+            Err(StorageError::IncompatibleDataDir(_))
+        ));
+        assert_eq!(data_dir_version(&dir).unwrap(), Some(0));
+    }
+
+    fn tall_block(height: u32) -> BlockData {
+        let mut blockhash = [0x5a; 32];
+        blockhash[..4].copy_from_slice(&height.to_be_bytes());
+        BlockData {
+            blockhash,
+            tweaks: vec![[height as u8; 33]],
+            tweak_meta: None,
+        }
+    }
+
+    /// Puts the version 1 header, from before the format version, back on every block data file.
+    fn write_legacy_headers(dir: &Path) {
+        for entry in fs::read_dir(dir.join(BLOCK_DATA_DIR_NAME)).unwrap() {
+            let path = entry.unwrap().path();
+            let mut data = fs::read(&path).unwrap();
+            let legacy: &[u8; 8] = match &data[..4] {
+                b"SPSD" => b"SPSDATA1",
+                _ => &LEGACY_ENCRYPTED_MAGIC_BYTES,
+            };
+            data[..8].copy_from_slice(legacy);
+            fs::write(&path, data).unwrap();
+        }
+    }
+
+    /// Strips the frames off the records of every block data file, as written before format
+    /// version 3, with their checksums from before record version 2, and points the index
+    /// entries at the bare records. Encrypted records are re-encrypted for their new offset.
+    fn unframe_records(dir: &Path, db: &sled::Db, key: Option<&EncryptionKey>) {
+        let header_len = match key {
+            Some(_) => ENCRYPTED_HEADER_SIZE,
+            None => HEADER_PREFIX_SIZE,
+        };
+        let entries: Vec<_> = db.iter().map(|item| item.unwrap()).collect();
+        for file in fs::read_dir(dir.join(BLOCK_DATA_DIR_NAME)).unwrap() {
+            let path = file.unwrap().path();
+            let file_stem = path.file_stem().unwrap().to_string_lossy();
+            let file_number: u64 = file_stem["sps".len()..].parse().unwrap();
+            let data = fs::read(&path).unwrap();
+            let mut unframed = data[..header_len].to_vec();
+            unframed[4..6].copy_from_slice(&2u16.to_le_bytes());
+
+            let mut moved = HashMap::new();
+            let mut offset = header_len;
+            while offset < data.len() {
+                // The mark on the record of a block reorged away goes with the frame
+                assert!([RECORD_MAGIC, ORPHANED_RECORD_MAGIC]
+                    .contains(&data[offset..offset + 4].try_into().unwrap()));
+                let len = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap());
+                let payload_start = offset + FRAME_HEADER_SIZE;
+                let payload = &data[payload_start..payload_start + len as usize];
+                let new_offset = unframed.len() as u64;
+                match key {
+                    Some(key) => {
+                        let mut plaintext = key
+                            .decrypt_record(file_number, offset as u64, payload)
+                            .unwrap();
+                        checksum_tweaks_only(&mut plaintext);
+                        unframed.extend(key.encrypt_record(file_number, new_offset, &plaintext));
+                    }
+                    None => {
+                        let mut serialized = payload.to_vec();
+                        checksum_tweaks_only(&mut serialized);
+                        unframed.extend(serialized);
+                    }
+                }
+                moved.insert(offset as u64, new_offset);
+                offset = payload_start + len as usize;
+            }
+            fs::write(&path, unframed).unwrap();
+
+            for (blockhash, value) in &entries {
+                let Some(mut entry) = IndexEntry::deserialize(value) else {
+                    continue;
+                };
+                if entry.file_number == file_number {
+                    entry.offset = moved[&entry.offset];
+                    entry.length -= FRAME_HEADER_SIZE as u64;
+                    db.insert(blockhash, &entry.serialize()[..]).unwrap();
+                }
+            }
+        }
+        db.flush().unwrap();
+    }
+
+    /// Replaces the checksum of a serialized BlockData with one of its tweaks only, as records
+    /// were checksummed before record version 2.
+    fn checksum_tweaks_only(serialized: &mut [u8]) {
+        let crc = crc32fast::hash(&serialized[RECORD_HEADER_SIZE..]);
+        serialized[36..40].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Checksums the records of every block data file as before record version 2, in format
+    /// version 3 or 4 files, and points the index entries at them. Compressed records change
+    /// length, and encrypted ones are re-encrypted for their new offset.
+    fn checksum_records_tweaks_only(dir: &Path, db: &sled::Db, key: Option<&EncryptionKey>) {
+        let header_len = match key {
+            Some(_) => ENCRYPTED_HEADER_SIZE,
+            None => HEADER_PREFIX_SIZE,
+        };
+        let entries: Vec<_> = db.iter().map(|item| item.unwrap()).collect();
+        for file in fs::read_dir(dir.join(BLOCK_DATA_DIR_NAME)).unwrap() {
+            let path = file.unwrap().path();
+            let file_stem = path.file_stem().unwrap().to_string_lossy();
+            let file_number: u64 = file_stem["sps".len()..].parse().unwrap();
+            let data = fs::read(&path).unwrap();
+            let compressed = data[4..6] == COMPRESSED_FORMAT_VERSION.to_le_bytes();
+            let version = match compressed {
+                true => TWEAKS_CRC_COMPRESSED_FORMAT_VERSION,
+                false => TWEAKS_CRC_FORMAT_VERSION,
+            };
+            let mut rewritten = data[..header_len].to_vec();
+            rewritten[4..6].copy_from_slice(&version.to_le_bytes());
+
+            let mut moved = HashMap::new();
+            let mut offset = header_len;
+            // Preallocated space is left out
+            while offset < data.len() && data[offset..offset + 4] != [0u8; 4] {
+                let len = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap());
+                let payload_start = offset + FRAME_HEADER_SIZE;
+                let payload = &data[payload_start..payload_start + len as usize];
+                let mut plaintext = match key {
+                    Some(key) => key
+                        .decrypt_record(file_number, offset as u64, payload)
+                        .unwrap(),
+                    None => payload.to_vec(),
+                };
+                if compressed {
+                    let mut serialized = zstd::decode_all(&plaintext[4..]).unwrap();
+                    checksum_tweaks_only(&mut serialized);
+                    plaintext = plaintext[..4].to_vec();
+                    plaintext.extend(zstd::bulk::compress(&serialized, 0).unwrap());
+                } else {
+                    checksum_tweaks_only(&mut plaintext);
+                }
+                let new_offset = rewritten.len() as u64;
+                let new_payload = match key {
+                    Some(key) => key.encrypt_record(file_number, new_offset, &plaintext),
+                    None => plaintext,
+                };
+                rewritten.extend_from_slice(&data[offset..offset + 4]);
+                rewritten.extend_from_slice(&(new_payload.len() as u32).to_le_bytes());
+                rewritten.extend(new_payload);
+                moved.insert(
+                    offset as u64,
+                    (new_offset, rewritten.len() as u64 - new_offset),
+                );
+                offset = payload_start + len as usize;
+            }
+            fs::write(&path, rewritten).unwrap();
+
+            for (blockhash, value) in &entries {
+                let Some(mut entry) = IndexEntry::deserialize(value) else {
+                    continue;
+                };
+                if entry.file_number == file_number {
+                    (entry.offset, entry.length) = moved[&entry.offset];
+                    db.insert(blockhash, &entry.serialize()[..]).unwrap();
+                }
+            }
+        }
+        db.flush().unwrap();
+    }
+
+    /// Stamps `version` without going through the store, for the make_version_* helpers.
+    fn stamp_version(dir: &Path, db: &sled::Db, version: u32) {
+        db.open_tree("meta")
+            .unwrap()
+            .insert(VERSION_META_KEY, &version.to_le_bytes())
+            .unwrap();
+        db.flush().unwrap();
+        fs::write(dir.join(VERSION_FILE_NAME), format!("{}\n", version)).unwrap();
+    }
+
+    /// Turns a store into a version 6 one, with version 3 and 4 block data files.
+    fn make_version_6(dir: &Path, key: Option<&EncryptionKey>) {
+        let db = open_db(&dir.join(INDEX_DIR_NAME)).unwrap();
+        checksum_records_tweaks_only(dir, &db, key);
+        stamp_version(dir, &db, 6);
+    }
+
+    /// Turns a store into a version 3 one, with version 2 block data files.
+    fn make_version_3(dir: &Path, key: Option<&EncryptionKey>) {
+        let db = open_db(&dir.join(INDEX_DIR_NAME)).unwrap();
+        unframe_records(dir, &db, key);
+        stamp_version(dir, &db, 3);
+    }
+
+    /// Turns a store into a version 2 one, with version 1 block data files.
+    fn make_version_2(dir: &Path, key: Option<&EncryptionKey>) {
+        let db = open_db(&dir.join(INDEX_DIR_NAME)).unwrap();
+        unframe_records(dir, &db, key);
+        write_legacy_headers(dir);
+        stamp_version(dir, &db, 2);
+    }
+
+    /// Turns a store into a version 1 one, whose height keys were little-endian.
+    fn make_version_1(dir: &Path) {
+        let db = open_db(&dir.join(INDEX_DIR_NAME)).unwrap();
+        unframe_records(dir, &db, None);
+        write_legacy_headers(dir);
+        let height_to_hash = db.open_tree("height_to_hash").unwrap();
+        let entries: Vec<_> = height_to_hash.iter().map(|item| item.unwrap()).collect();
+        height_to_hash.clear().unwrap();
+        for (key, blockhash) in entries {
+            let height = u32::from_be_bytes(key[..].try_into().unwrap());
+            height_to_hash
+                .insert(height.to_le_bytes(), blockhash)
+                .unwrap();
+        }
+        stamp_version(dir, &db, 1);
+    }
+
+    #[test]
+    fn test_upgrade_from_version_1() {
+        let dir = temp_dir("test_version_upgrade_1");
+        // Enough blocks for little-endian keys to sort out of height order
+        let blocks: Vec<BlockData> = (0..300).map(tall_block).collect();
+        let store = FlatFileStore::initialize(dir.clone()).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+        drop(store);
+        make_version_1(&dir);
+
+        assert!(matches!(
+            FlatFileStore::initialize(dir.clone()),
+            Err(StorageError::UpgradeRequired(1))
+        ));
+        let plan = upgrade(&dir, None, None, false).unwrap();
+        assert_eq!(plan.from, 1);
+        assert_eq!(
+            plan.steps,
+            vec![
+                "re-encode the index height keys big-endian",
+                "add the format version to the block data file headers",
+                "frame every block data record",
+                "allow dust tier records in the block data files and index",
+                "allow records replacing the tweaks of a block",
+                "checksum the blockhash and tweak count of every record",
+                "allow tweak metadata in records"
+            ]
+        );
+        assert_eq!(data_dir_version(&dir).unwrap(), Some(DATA_DIR_VERSION));
+
+        let store = FlatFileStore::initialize(dir.clone()).unwrap();
+        assert_eq!(read_all_blocks(&store), blocks);
+        store.add_block(&tall_block(300), 300).unwrap();
+        drop(store);
+
+        // A crash before the version stamp runs the step again
+        let index = Index::open_for_migration(&dir.join(INDEX_DIR_NAME)).unwrap();
+        reencode_height_keys(&dir, &index, None).unwrap();
+        assert_eq!(index.reencode_height_keys().unwrap(), 0);
+        drop(index);
+        let store = FlatFileStore::initialize(dir.clone()).unwrap();
+        assert_eq!(read_all_blocks(&store).len(), 301);
+    }
+
+    fn encrypted_options() -> FlatFileStoreOptions {
+        FlatFileStoreOptions {
+            encryption_key: Some(EncryptionKey::from_bytes([7; 32])),
+            ..Default::default()
+        }
+    }
+
+    /// A store of `blocks` spread over several block data files.
+    fn create_multi_file_store(dir: &Path, options: &FlatFileStoreOptions, blocks: &[BlockData]) {
+        let store = FlatFileStore::initialize_with_options(
+            dir.to_path_buf(),
+            FlatFileStoreOptions {
+                max_file_size: 1024,
+                ..options.clone()
+            },
+        )
+        .unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            store.add_block(block, height as u32).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_upgrade_file_headers_from_version_2() {
+        for options in [FlatFileStoreOptions::default(), encrypted_options()] {
+            let dir = temp_dir("test_version_upgrade_2");
+            let key = options.encryption_key.as_ref();
+            let blocks: Vec<BlockData> = (0..40).map(tall_block).collect();
+            create_multi_file_store(&dir, &options, &blocks);
+            make_version_2(&dir, key);
+
+            assert!(matches!(
+                FlatFileStore::initialize_with_options(dir.clone(), options.clone()),
+                Err(StorageError::UpgradeRequired(2))
+            ));
+            let plan = upgrade(&dir, key, None, false).unwrap();
+            assert_eq!(
+                plan.steps,
+                vec![
+                    "add the format version to the block data file headers",
+                    "frame every block data record",
+                    "allow dust tier records in the block data files and index",
+                    "allow records replacing the tweaks of a block",
+                    "checksum the blockhash and tweak count of every record",
+                    "allow tweak metadata in records"
+                ]
+            );
+
+            let data = fs::read(dir.join(BLOCK_DATA_DIR_NAME).join("sps000000.dat")).unwrap();
+            assert_eq!(&data[4..6], &FRAMED_FORMAT_VERSION.to_le_bytes());
+            let store =
+                FlatFileStore::initialize_with_options(dir.clone(), options.clone()).unwrap();
+            assert_eq!(read_all_blocks(&store), blocks);
+            drop(store);
+
+            // A crash before the version stamp runs the step again, which finds nothing to do
+            assert_eq!(
+                migrate_block_data_files(&dir.join(BLOCK_DATA_DIR_NAME)).unwrap(),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn test_upgrade_record_frames_from_version_3() {
+        for options in [FlatFileStoreOptions::default(), encrypted_options()] {
+            let dir = temp_dir("test_version_upgrade_3");
+            let key = options.encryption_key.as_ref();
+            let mut blocks: Vec<BlockData> = (0..40).map(tall_block).collect();
+            create_multi_file_store(&dir, &options, &blocks);
+            // The record of a reorged block stays behind as dead space
+            let store =
+                FlatFileStore::initialize_with_options(dir.clone(), options.clone()).unwrap();
+            store.remove_tip_block(&blocks[39].blockhash).unwrap();
+            blocks.pop();
+            drop(store);
+            make_version_3(&dir, key);
+
+            if key.is_some() {
+                assert!(matches!(
+                    upgrade(&dir, None, None, false),
+                    Err(StorageError::EncryptionError(_))
+                ));
+                assert_eq!(data_dir_version(&dir).unwrap(), Some(3));
+            }
+            let plan = upgrade(&dir, key, None, false).unwrap();
+            assert_eq!(
+                plan.steps,
+                vec![
+                    "frame every block data record",
+                    "allow dust tier records in the block data files and index",
+                    "allow records replacing the tweaks of a block",
+                    "checksum the blockhash and tweak count of every record",
+                    "allow tweak metadata in records"
+                ]
+            );
+
+            let file_0 = dir.join(BLOCK_DATA_DIR_NAME).join("sps000000.dat");
+            let data = fs::read(&file_0).unwrap();
+            let header_len = if key.is_some() {
+                ENCRYPTED_HEADER_SIZE
+            } else {
+                HEADER_PREFIX_SIZE
+            };
+            assert_eq!(&data[header_len..header_len + 4], &RECORD_MAGIC);
+            let store =
+                FlatFileStore::initialize_with_options(dir.clone(), options.clone()).unwrap();
+            assert_eq!(read_all_blocks(&store), blocks);
+            drop(store);
+
+            // Interrupted after the entries of file 0 moved, before the framed file was
+            // swapped in: the next run only has to swap it in
+            let framed = fs::read(&file_0).unwrap();
+            make_version_3(&dir, key);
+            let unframed = fs::read(&file_0).unwrap();
+            let index = Index::open_for_migration(&dir.join(INDEX_DIR_NAME)).unwrap();
+            migrate_record_frames(&dir.join(BLOCK_DATA_DIR_NAME), &index, key).unwrap();
+            index
+                .set_meta(b"frame_migration", &1u64.to_le_bytes())
+                .unwrap();
+            stamp_data_dir_version(&dir, &index, 3).unwrap();
+            drop(index);
+            fs::write(file_0.with_extension("frame"), &framed).unwrap();
+            fs::write(&file_0, &unframed).unwrap();
+
+            upgrade(&dir, key, None, false).unwrap();
+            assert_eq!(fs::read(&file_0).unwrap(), framed);
+            assert!(!file_0.with_extension("frame").exists());
+            let store = FlatFileStore::initialize_with_options(dir.clone(), options).unwrap();
+            assert_eq!(read_all_blocks(&store), blocks);
+        }
+    }
+
+    #[test]
+    fn test_upgrade_record_checksums_from_version_6() {
+        let compressed = FlatFileStoreOptions {
+            compression_level: Some(3),
+            ..Default::default()
+        };
+        for options in [
+            FlatFileStoreOptions::default(),
+            encrypted_options(),
+            compressed,
+        ] {
+            let dir = temp_dir("test_version_upgrade_6");
+            let key = options.encryption_key.as_ref();
+            let blocks: Vec<BlockData> = (0..40).map(tall_block).collect();
+            create_multi_file_store(&dir, &options, &blocks);
+            make_version_6(&dir, key);
+
+            assert!(matches!(
+                FlatFileStore::initialize_with_options(dir.clone(), options.clone()),
+                Err(StorageError::UpgradeRequired(6))
+            ));
+            let plan = upgrade(&dir, key, None, false).unwrap();
+            assert_eq!(
+                plan.steps,
+                vec![
+                    "checksum the blockhash and tweak count of every record",
+                    "allow tweak metadata in records"
+                ]
+            );
+
+            let data = fs::read(dir.join(BLOCK_DATA_DIR_NAME).join("sps000000.dat")).unwrap();
+            let version = match options.compression_level {
+                Some(_) => COMPRESSED_FORMAT_VERSION,
+                None => FRAMED_FORMAT_VERSION,
+            };
+            assert_eq!(&data[4..6], &version.to_le_bytes());
+            let store =
+                FlatFileStore::initialize_with_options(dir.clone(), options.clone()).unwrap();
+            assert_eq!(read_all_blocks(&store), blocks);
+            drop(store);
+
+            // A crash before the version stamp runs the step again, which finds nothing to do
+            let index = Index::open_for_migration(&dir.join(INDEX_DIR_NAME)).unwrap();
+            assert_eq!(
+                migrate_record_checksums(&dir.join(BLOCK_DATA_DIR_NAME), &index, key).unwrap(),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn test_refuses_newer_file_format() {
+        let dir = temp_dir("test_version_newer_file_format");
+        create_store(&dir, 3);
+        let file_0 = dir.join(BLOCK_DATA_DIR_NAME).join("sps000000.dat");
+        let mut data = fs::read(&file_0).unwrap();
+        data[4..6].copy_from_slice(&(FILE_FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&file_0, data).unwrap();
+
+        let newer = FILE_FORMAT_VERSION + 1;
+        assert!(matches!(
+            FlatFileStore::initialize(dir.clone()),
+            Err(StorageError::UnsupportedVersion(v)) if v == newer
+        ));
+        assert!(matches!(
+            migrate_block_data_files(&dir.join(BLOCK_DATA_DIR_NAME)),
+            Err(StorageError::UnsupportedVersion(v)) if v == newer
+        ));
+    }
+
+    #[test]
+    fn test_refuses_newer_data_dir() {
+        let dir = temp_dir("test_version_too_new");
+        create_store(&dir, 1);
+        fs::write(
+            dir.join(VERSION_FILE_NAME),
+            format!("{}\n", DATA_DIR_VERSION + 1),
+        )
+        .unwrap();
+
+        let newer = DATA_DIR_VERSION + 1;
+        assert!(matches!(
+            FlatFileStore::initialize(dir.clone()),
+            Err(StorageError::DataDirTooNew(v)) if v == newer
+        ));
+        assert!(matches!(
+            upgrade(&dir, None, None, true),
+            Err(StorageError::DataDirTooNew(_))
+        ));
+        assert!(matches!(
+            upgrade(&dir, None, None, false),
+            Err(StorageError::DataDirTooNew(_))
+        ));
+    }
+
+    #[test]
+    fn test_meta_must_match_version_file() {
+        let dir = temp_dir("test_version_meta_mismatch");
+        create_store(&dir, 1);
+        {
+            let db = open_db(&dir.join(INDEX_DIR_NAME)).unwrap();
+            db.open_tree("meta")
+                .unwrap()
+                .insert(VERSION_META_KEY, &(DATA_DIR_VERSION + 1).to_le_bytes())
+                .unwrap();
+            db.flush().unwrap();
+        }
+
+        assert!(matches!(
+            FlatFileStore::initialize(dir.clone()),
+            Err(StorageError::CorruptDB(_))
+        ));
+    }
+
+    fn version_0_blocks() -> Vec<BlockData> {
+        (0..3u8)
+            .map(|height| BlockData {
+                blockhash: [0xa0 + height; 32],
+                tweaks: (0..=height).map(|i| [height * 16 + i; 33]).collect(),
+                tweak_meta: None,
+            })
+            .collect()
+    }
+
+    /// Writes a data directory the way the pre-release code did: a SPSDATA1 file of
+    /// [blockhash][tweak count (u32 LE)][CRC32 of tweaks (u32 LE)][tweaks] records, and the
+    /// bare index trees with little-endian height keys and 24 byte entries. Deliberately
+    /// doesn't use BlockData or Index, so it keeps producing version 0 stores as they evolve.
+    fn write_version_0_store(dir: &Path, blocks: &[BlockData]) {
+        let block_data_dir = dir.join(BLOCK_DATA_DIR_NAME);
+        fs::create_dir_all(&block_data_dir).unwrap();
+        let db = open_db(&dir.join(INDEX_DIR_NAME)).unwrap();
+        let height_to_hash = db.open_tree("height_to_hash").unwrap();
+        let hash_to_height = db.open_tree("hash_to_height").unwrap();
+
+        let mut data = b"SPSDATA1".to_vec();
+        for (height, block) in blocks.iter().enumerate() {
+            let offset = data.len() as u64;
+            data.extend_from_slice(&block.blockhash);
+            data.extend_from_slice(&(block.tweaks.len() as u32).to_le_bytes());
+            let mut crc = crc32fast::Hasher::new();
+            for tweak in &block.tweaks {
+                crc.update(tweak);
+            }
+            data.extend_from_slice(&crc.finalize().to_le_bytes());
+            for tweak in &block.tweaks {
+                data.extend_from_slice(tweak);
+            }
+
+            let mut entry = Vec::new();
+            entry.extend_from_slice(&0u64.to_le_bytes());
+            entry.extend_from_slice(&offset.to_le_bytes());
+            entry.extend_from_slice(&(data.len() as u64 - offset).to_le_bytes());
+            let height = (height as u32).to_le_bytes();
+            db.insert(block.blockhash, entry).unwrap();
+            height_to_hash.insert(height, &block.blockhash).unwrap();
+            hash_to_height.insert(block.blockhash, &height).unwrap();
+        }
+        fs::write(block_data_dir.join("sps000000.dat"), data).unwrap();
+        db.flush().unwrap();
+    }
+
+    fn assume_network(network: &str) -> FlatFileStoreOptions {
+        FlatFileStoreOptions {
+            assume_network: Some(network.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn read_all_blocks(store: &FlatFileStore) -> Vec<BlockData> {
+        let mut reader = store.get_block_stream_from_height(0).unwrap();
+        let mut blocks = Vec::new();
+        loop {
+            match BlockData::deserialize_from(&mut reader) {
+                Ok((block, _)) => blocks.push(block),
+                Err(StorageError::EndOfStream) => return blocks,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_adopt_version_0_store() {
+        let dir = temp_dir("test_version_adopt_0");
+        let mut blocks = version_0_blocks();
+        write_version_0_store(&dir, &blocks);
+        let data_file = dir.join(BLOCK_DATA_DIR_NAME).join("sps000000.dat");
+        assert_eq!(fs::read(&data_file).unwrap(), VERSION_0_FIXTURE);
+
+        // The network has to be confirmed, and nothing is stamped until it is
+        assert!(matches!(
+            FlatFileStore::initialize(dir.clone()),
+            Err(StorageError::AssumeNetworkRequired)
+        ));
+        assert_eq!(data_dir_version(&dir).unwrap(), Some(0));
+
+        let store =
+            FlatFileStore::initialize_with_options(dir.clone(), assume_network("signet")).unwrap();
+        assert_eq!(data_dir_version(&dir).unwrap(), Some(DATA_DIR_VERSION));
+        // Adopting upgrades the file header and frames the records, which are otherwise the
+        // same as they were
+        let data = fs::read(&data_file).unwrap();
+        assert_eq!(&data[..4], b"SPSD");
+        let framed: Vec<u8> = blocks
+            .iter()
+            .flat_map(|block| {
+                let record = block.serialize();
+                [
+                    &RECORD_MAGIC[..],
+                    &(record.len() as u32).to_le_bytes(),
+                    &record,
+                ]
+                .concat()
+            })
+            .collect();
+        assert_eq!(&data[HEADER_PREFIX_SIZE..], &framed[..]);
+        assert_eq!(read_all_blocks(&store), blocks);
+
+        let new_block = BlockData {
+            blockhash: [0xa3; 32],
+            tweaks: vec![[0x30; 33]],
+            tweak_meta: None,
+        };
+        store.add_block(&new_block, 3).unwrap();
+        blocks.push(new_block);
+        drop(store);
+
+        let store = FlatFileStore::initialize(dir.clone()).unwrap();
+        assert_eq!(read_all_blocks(&store), blocks);
+        drop(store);
+
+        let (index, _) = Index::initialize(&dir.join(INDEX_DIR_NAME)).unwrap();
+        index.check_consistency().unwrap();
+        assert_eq!(
+            index.get_meta(NETWORK_META_KEY).unwrap(),
+            Some(b"signet".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_refuse_ambiguous_version_0_store() {
+        // Trees the pre-release code never wrote
+        let dir = temp_dir("test_version_ambiguous_0");
+        write_version_0_store(&dir, &version_0_blocks());
+        {
+            let db = open_db(&dir.join(INDEX_DIR_NAME)).unwrap();
+            db.open_tree("filters").unwrap().insert(b"x", b"y").unwrap();
+            db.flush().unwrap();
+        }
+        assert!(matches!(
+            FlatFileStore::initialize_with_options(dir.clone(), assume_network("mainnet")),
+            Err(StorageError::IncompatibleDataDir(_))
+        ));
+        assert_eq!(data_dir_version(&dir).unwrap(), Some(0));
+
+        // Index entries pointing past the end of the data, as left by the old rotation bug
+        let dir = temp_dir("test_version_ambiguous_0");
+        write_version_0_store(&dir, &version_0_blocks());
+        let data_file = dir.join(BLOCK_DATA_DIR_NAME).join("sps000000.dat");
+        fs::write(
+            &data_file,
+            &VERSION_0_FIXTURE[..VERSION_0_FIXTURE.len() - 10],
+        )
+        .unwrap();
+        assert!(matches!(
+            FlatFileStore::initialize_with_options(dir.clone(), assume_network("mainnet")),
+            Err(StorageError::IncompatibleDataDir(_))
+        ));
+        assert_eq!(data_dir_version(&dir).unwrap(), Some(0));
+    }
+}
@@ -0,0 +1,182 @@
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Fraction of a hard limit at which a Watermark starts warning, unless configured otherwise.
+pub const DEFAULT_SOFT_LIMIT_FRACTION: f64 = 0.8;
+/// How far below the soft limit (as a fraction of it) a value has to drop before the next
+/// crossing warns again, so a value hovering around the soft limit doesn't flood the log.
+const HYSTERESIS: f64 = 0.1;
+/// Number of recent observations the time-to-limit estimate is based on.
+const TREND_SAMPLES: usize = 16;
+
+/// Early warning for a hard limit: a warning is logged when an observed value crosses the
+/// soft limit (a fraction of the hard one), and again only after it fell back well below.
+/// Recent observations give a rough estimate of when the hard limit will be reached.
+#[derive(Debug, Clone)]
+pub struct Watermark {
+    name: &'static str,
+    hard_limit: u64,
+    soft_limit: u64,
+    clear_limit: u64,
+    above_soft: bool,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+/// Snapshot of a Watermark, for status reporting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatermarkStatus {
+    pub name: &'static str,
+    pub value: u64,
+    pub soft_limit: u64,
+    pub hard_limit: u64,
+    pub above_soft: bool,
+    pub time_to_limit: Option<Duration>,
+}
+
+impl Watermark {
+    /// `soft_fraction` has to be in (0, 1], see `valid_soft_fraction`.
+    pub fn new(name: &'static str, hard_limit: u64, soft_fraction: f64) -> Self {
+        let soft_limit = (hard_limit as f64 * soft_fraction) as u64;
+        Watermark {
+            name,
+            hard_limit,
+            soft_limit,
+            clear_limit: (soft_limit as f64 * (1.0 - HYSTERESIS)) as u64,
+            above_soft: false,
+            samples: VecDeque::with_capacity(TREND_SAMPLES),
+        }
+    }
+
+    pub fn valid_soft_fraction(soft_fraction: f64) -> bool {
+        soft_fraction > 0.0 && soft_fraction <= 1.0
+    }
+
+    /// Records the current value of whatever the limit applies to.
+    /// Returns true if this observation crossed the soft limit (and logged the warning).
+    pub fn observe(&mut self, value: u64) -> bool {
+        self.observe_at(value, Instant::now())
+    }
+
+    fn observe_at(&mut self, value: u64, at: Instant) -> bool {
+        if self.samples.len() == TREND_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, value));
+
+        if self.above_soft {
+            if value < self.clear_limit {
+                self.above_soft = false;
+                info!(target: "Limits", "{} is back to {} (soft limit {}, hard limit {})",
+                      self.name, value, self.soft_limit, self.hard_limit);
+            }
+            return false;
+        }
+        if value < self.soft_limit {
+            return false;
+        }
+
+        self.above_soft = true;
+        match self.time_to_limit() {
+            Some(eta) => {
+                warn!(target: "Limits", "{} reached {} of its hard limit {} (soft limit {}), at this rate it hits the limit in about {}s",
+                               self.name, value, self.hard_limit, self.soft_limit, eta.as_secs())
+            }
+            None => warn!(target: "Limits", "{} reached {} of its hard limit {} (soft limit {})",
+                          self.name, value, self.hard_limit, self.soft_limit),
+        }
+        true
+    }
+
+    /// Whether the last crossing of the soft limit hasn't cleared yet.
+    pub fn is_above_soft(&self) -> bool {
+        self.above_soft
+    }
+
+    /// Linear estimate, from the oldest and newest recent observations, of how long until the
+    /// value reaches the hard limit. None if it isn't growing or there isn't enough to go on.
+    pub fn time_to_limit(&self) -> Option<Duration> {
+        let (&(first_at, first), &(last_at, last)) = (self.samples.front()?, self.samples.back()?);
+        let elapsed = last_at.duration_since(first_at).as_secs_f64();
+        if last <= first || elapsed <= 0.0 {
+            return None;
+        }
+        let rate = (last - first) as f64 / elapsed;
+        Some(Duration::from_secs_f64(
+            self.hard_limit.saturating_sub(last) as f64 / rate,
+        ))
+    }
+
+    pub fn status(&self) -> WatermarkStatus {
+        WatermarkStatus {
+            name: self.name,
+            value: self.samples.back().map_or(0, |&(_, value)| value),
+            soft_limit: self.soft_limit,
+            hard_limit: self.hard_limit,
+            above_soft: self.above_soft,
+            time_to_limit: self.time_to_limit(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warns_once_per_crossing() {
+        let mut watermark = Watermark::new("test", 1000, 0.8);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(!watermark.observe_at(500, at(0)));
+        assert!(watermark.observe_at(800, at(1)));
+        assert!(watermark.is_above_soft());
+        // Hovering around the soft limit doesn't warn again
+        assert!(!watermark.observe_at(790, at(2)));
+        assert!(!watermark.observe_at(810, at(3)));
+        assert!(!watermark.observe_at(730, at(4)));
+        assert!(watermark.is_above_soft());
+
+        // Only once it dropped below 720 (soft limit less 10%)
+        assert!(!watermark.observe_at(700, at(5)));
+        assert!(!watermark.is_above_soft());
+        assert!(watermark.observe_at(850, at(6)));
+    }
+
+    #[test]
+    fn test_time_to_limit() {
+        let mut watermark = Watermark::new("test", 1000, 0.8);
+        let start = Instant::now();
+        assert_eq!(watermark.time_to_limit(), None);
+
+        // Growing by 10 per second, 800 to go from 200
+        for secs in 0..=10 {
+            watermark.observe_at(100 + secs * 10, start + Duration::from_secs(secs));
+        }
+        assert_eq!(watermark.time_to_limit(), Some(Duration::from_secs(80)));
+        let status = watermark.status();
+        assert_eq!(status.value, 200);
+        assert_eq!(status.soft_limit, 800);
+        assert!(!status.above_soft);
+
+        // Shrinking or flat values give no estimate
+        watermark.observe_at(50, start + Duration::from_secs(11));
+        assert_eq!(watermark.time_to_limit(), None);
+
+        // Only the recent samples count
+        for secs in 12..12 + TREND_SAMPLES as u64 {
+            watermark.observe_at(500, start + Duration::from_secs(secs));
+        }
+        assert_eq!(watermark.time_to_limit(), None);
+    }
+
+    #[test]
+    fn test_soft_fraction() {
+        assert!(Watermark::valid_soft_fraction(DEFAULT_SOFT_LIMIT_FRACTION));
+        assert!(Watermark::valid_soft_fraction(1.0));
+        assert!(!Watermark::valid_soft_fraction(0.0));
+        assert!(!Watermark::valid_soft_fraction(1.5));
+        assert!(!Watermark::valid_soft_fraction(f64::NAN));
+    }
+}
@@ -0,0 +1,179 @@
+//! Fixtures shared by the unit tests and the benches: temp dirs that can't collide with a
+//! concurrent run and clean up after themselves, and a store built on top of one.
+use std::env;
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::storage::{BlockData, FlatFileStore, FlatFileStoreOptions, TWEAK_SIZE};
+
+/// Block data files of a TestStore are kept this small, so a few dozen blocks span files.
+pub const TEST_MAX_FILE_SIZE: u64 = 2 * 1024;
+
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+/// A fresh directory under the system temp dir, removed again on drop (panics included).
+/// The name is only a prefix: process id, a counter and the time make it unique, so tests
+/// running in parallel, or another run of the suite, never share a directory.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let dir = env::temp_dir().join(format!(
+            "{}-{}-{}-{:08x}",
+            name,
+            process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed),
+            nanos
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+pub fn temp_dir(name: &str) -> TestDir {
+    TestDir::new(name)
+}
+
+/// Deterministic block for `height`: a blockhash unique to the height and `num_tweaks`
+/// tweaks derived from it.
+pub fn generated_block(height: u32, num_tweaks: usize) -> BlockData {
+    let mut blockhash = [0x42; 32];
+    blockhash[..4].copy_from_slice(&height.to_be_bytes());
+    let tweaks = (0..num_tweaks)
+        .map(|i| {
+            let mut tweak = [(i as u8) ^ (height as u8); TWEAK_SIZE];
+            tweak[..4].copy_from_slice(&height.to_le_bytes());
+            tweak
+        })
+        .collect();
+    BlockData {
+        blockhash,
+        tweaks,
+        tweak_meta: None,
+    }
+}
+
+/// A FlatFileStore in its own TestDir, with small block data files. Derefs to the store.
+pub struct TestStore {
+    // Declared first so the store is closed before its directory is removed
+    store: FlatFileStore,
+    dir: TestDir,
+}
+
+impl TestStore {
+    pub fn new(name: &str) -> Self {
+        Self::with_options(name, FlatFileStoreOptions::default())
+    }
+
+    /// `options` as given, except for the file size.
+    pub fn with_options(name: &str, options: FlatFileStoreOptions) -> Self {
+        let dir = TestDir::new(name);
+        let options = FlatFileStoreOptions {
+            max_file_size: TEST_MAX_FILE_SIZE,
+            ..options
+        };
+        let store = FlatFileStore::initialize_with_options(dir.to_path_buf(), options).unwrap();
+        TestStore { store, dir }
+    }
+
+    pub fn dir(&self) -> &TestDir {
+        &self.dir
+    }
+
+    /// Adds `count` generated blocks on top of the tip and returns them.
+    pub fn add_blocks(&self, count: u32) -> Vec<BlockData> {
+        let start = (self.store.get_current_height() + 1) as u32;
+        (start..start + count)
+            .map(|height| {
+                let block = generated_block(height, 1 + height as usize % 4);
+                self.store.add_block(&block, height).unwrap();
+                block
+            })
+            .collect()
+    }
+}
+
+impl Deref for TestStore {
+    type Target = FlatFileStore;
+
+    fn deref(&self) -> &FlatFileStore {
+        &self.store
+    }
+}
+
+impl DerefMut for TestStore {
+    fn deref_mut(&mut self) -> &mut FlatFileStore {
+        &mut self.store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    fn test_stores_are_isolated() {
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                thread::spawn(|| {
+                    let store = TestStore::new("test_support_isolated");
+                    let blocks = store.add_blocks(50);
+                    for (height, block) in blocks.iter().enumerate() {
+                        assert_eq!(&store.get_block(height as u32).unwrap(), block);
+                    }
+                    assert_eq!(store.get_current_height(), 49);
+                    store.dir().to_path_buf()
+                })
+            })
+            .collect();
+
+        let dirs: HashSet<PathBuf> = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        assert_eq!(dirs.len(), 8);
+        assert!(dirs.iter().all(|dir| !dir.exists()));
+    }
+
+    #[test]
+    fn test_dir_removed_on_panic() {
+        let dir = TestDir::new("test_support_panic");
+        let path = dir.to_path_buf();
+        let result = std::panic::catch_unwind(move || {
+            let _dir = dir;
+            panic!("test failure");
+        });
+        assert!(result.is_err());
+        assert!(!path.exists());
+    }
+}